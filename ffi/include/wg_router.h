@@ -0,0 +1,57 @@
+/*
+ * C bindings for the wireguard-router WireGuard packet parser.
+ *
+ * Link against libwg_router (cdylib or staticlib, built with
+ * `cargo build -p wireguard-router-ffi --release`).
+ *
+ * This ABI only grows: new status codes, new functions, and new fields at the
+ * end of wg_router_header. Pass sizeof(wg_router_header) as out_size so older
+ * libraries can reject headers they do not know.
+ */
+
+#ifndef WG_ROUTER_H
+#define WG_ROUTER_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WG_ROUTER_OK 0
+/* the message type is known, but the datagram is shorter than its messages */
+#define WG_ROUTER_TOO_SHORT (-1)
+/* the datagram does not start with a known message type */
+#define WG_ROUTER_INVALID (-2)
+/* data or out was NULL */
+#define WG_ROUTER_NULL (-3)
+/* out_size is smaller than the wg_router_header known to the library */
+#define WG_ROUTER_HEADER_SIZE (-4)
+/* the message type is that of a fixed size message, but the datagram is longer */
+#define WG_ROUTER_TOO_LONG (-5)
+
+/* The fields of a parsed message, indices absent from a message type are zero. */
+typedef struct wg_router_header {
+    /* the WireGuard message type, 1 to 4 */
+    uint8_t message_type;
+    uint32_t sender_index;
+    uint32_t receiver_index;
+    /* the nonce counter of transport data */
+    uint64_t counter;
+    /* the number of bytes following the header of transport data */
+    size_t payload_len;
+} wg_router_header;
+
+/* Returns whether the datagram looks like WireGuard traffic. */
+bool wg_router_is_wg_packet(const uint8_t *data, size_t len);
+
+/* Parses the datagram into out, returning WG_ROUTER_OK or a negative status. */
+int wg_router_parse(const uint8_t *data, size_t len, wg_router_header *out, size_t out_size);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WG_ROUTER_H */
@@ -0,0 +1,113 @@
+/*
+* C bindings for the WireGuard header classification of wireguard-router
+*
+* The ABI is declared in include/wg_router.h, keep both in sync. Only additions are allowed:
+* new status codes, new functions, and new fields at the end of wg_router_header, which is
+* why callers pass its size.
+*/
+
+use std::ffi::c_int;
+
+use wireguard_router_packet::{ParseError, WireguardPacket, is_wg_packet};
+
+pub const WG_ROUTER_OK: c_int = 0;
+pub const WG_ROUTER_TOO_SHORT: c_int = -1;
+pub const WG_ROUTER_INVALID: c_int = -2;
+pub const WG_ROUTER_NULL: c_int = -3;
+pub const WG_ROUTER_HEADER_SIZE: c_int = -4;
+pub const WG_ROUTER_TOO_LONG: c_int = -5;
+
+/// The fields of a parsed message, indices absent from a message type are zero
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct wg_router_header {
+    /// the WireGuard message type, 1 to 4
+    pub message_type: u8,
+    pub sender_index: u32,
+    pub receiver_index: u32,
+    /// the nonce counter of transport data
+    pub counter: u64,
+    /// the number of bytes following the header of transport data
+    pub payload_len: usize,
+}
+
+/// # Safety
+///
+/// `data` must be valid for reads of `len` bytes, or null.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        return None;
+    }
+    // SAFETY: upheld by the caller
+    Some(unsafe { std::slice::from_raw_parts(data, len) })
+}
+
+/// Returns whether the datagram looks like WireGuard traffic
+///
+/// # Safety
+///
+/// `data` must be valid for reads of `len` bytes, or null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wg_router_is_wg_packet(data: *const u8, len: usize) -> bool {
+    // SAFETY: upheld by the caller
+    unsafe { bytes(data, len) }.is_some_and(is_wg_packet)
+}
+
+/// Parses the datagram into `out`, returning `WG_ROUTER_OK` or a negative status
+///
+/// # Safety
+///
+/// `data` must be valid for reads of `len` bytes, `out` must be valid for writes of
+/// `out_size` bytes. Either may be null.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wg_router_parse(
+    data: *const u8,
+    len: usize,
+    out: *mut wg_router_header,
+    out_size: usize,
+) -> c_int {
+    // SAFETY: upheld by the caller
+    let Some(data) = (unsafe { bytes(data, len) }) else {
+        return WG_ROUTER_NULL;
+    };
+    if out.is_null() {
+        return WG_ROUTER_NULL;
+    }
+    if out_size < size_of::<wg_router_header>() {
+        return WG_ROUTER_HEADER_SIZE;
+    }
+
+    let header = match WireguardPacket::parse(data) {
+        Ok(WireguardPacket::HandshakeInitiation(packet)) => wg_router_header {
+            message_type: 1,
+            sender_index: packet.sender_index(),
+            ..Default::default()
+        },
+        Ok(WireguardPacket::HandshakeResponse(packet)) => wg_router_header {
+            message_type: 2,
+            sender_index: packet.sender_index(),
+            receiver_index: packet.receiver_index(),
+            ..Default::default()
+        },
+        Ok(WireguardPacket::CookieReply(packet)) => wg_router_header {
+            message_type: 3,
+            receiver_index: packet.receiver_index(),
+            ..Default::default()
+        },
+        Ok(WireguardPacket::TransportData(header, payload)) => wg_router_header {
+            message_type: 4,
+            receiver_index: header.receiver_index(),
+            counter: header.counter(),
+            payload_len: payload.len(),
+            ..Default::default()
+        },
+        // a message type added to the protocol later, which the header has no fields for
+        Ok(_) => return WG_ROUTER_INVALID,
+        Err(ParseError::TooShort) => return WG_ROUTER_TOO_SHORT,
+        Err(ParseError::TooLong) => return WG_ROUTER_TOO_LONG,
+        Err(ParseError::Invalid) => return WG_ROUTER_INVALID,
+    };
+    // SAFETY: checked for null and size above, the struct is unaligned-safe to write
+    unsafe { out.write_unaligned(header) };
+    WG_ROUTER_OK
+}
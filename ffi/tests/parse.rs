@@ -0,0 +1,66 @@
+use std::ptr;
+
+use wg_router::*;
+
+fn transport(receiver: u32, counter: u64, payload_len: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; 16 + payload_len];
+    packet[0] = 0x04;
+    packet[4..8].copy_from_slice(&receiver.to_le_bytes());
+    packet[8..16].copy_from_slice(&counter.to_le_bytes());
+    packet
+}
+
+fn parse(data: &[u8]) -> Result<wg_router_header, i32> {
+    let mut header = wg_router_header::default();
+    let status = unsafe {
+        wg_router_parse(
+            data.as_ptr(),
+            data.len(),
+            &mut header,
+            size_of::<wg_router_header>(),
+        )
+    };
+    if status == WG_ROUTER_OK {
+        Ok(header)
+    } else {
+        Err(status)
+    }
+}
+
+#[test]
+fn parses_transport_data() {
+    assert_eq!(
+        parse(&transport(7, 42, 32)),
+        Ok(wg_router_header {
+            message_type: 4,
+            receiver_index: 7,
+            counter: 42,
+            payload_len: 32,
+            ..Default::default()
+        })
+    );
+}
+
+#[test]
+fn reports_invalid_input() {
+    assert_eq!(parse(&transport(7, 42, 8)), Err(WG_ROUTER_TOO_SHORT));
+    assert_eq!(parse(&[0x09; 32]), Err(WG_ROUTER_INVALID));
+    let mut initiation = vec![0u8; 149];
+    initiation[0] = 0x01;
+    assert_eq!(parse(&initiation), Err(WG_ROUTER_TOO_LONG));
+    let status = unsafe { wg_router_parse(ptr::null(), 0, ptr::null_mut(), 0) };
+    assert_eq!(status, WG_ROUTER_NULL);
+
+    let data = transport(7, 42, 32);
+    let mut header = wg_router_header::default();
+    let status = unsafe { wg_router_parse(data.as_ptr(), data.len(), &mut header, 8) };
+    assert_eq!(status, WG_ROUTER_HEADER_SIZE);
+}
+
+#[test]
+fn classifies_packets() {
+    let data = transport(7, 42, 32);
+    assert!(unsafe { wg_router_is_wg_packet(data.as_ptr(), data.len()) });
+    assert!(!unsafe { wg_router_is_wg_packet(b"hello".as_ptr(), 5) });
+    assert!(!unsafe { wg_router_is_wg_packet(ptr::null(), 0) });
+}
@@ -0,0 +1,36 @@
+//! Loads arbitrary bytes as a TOML and a JSON config file and as a peers file, which may fail but
+//! must never panic, or a bad config reloaded by a whole fleet would crash it
+
+#![no_main]
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+
+// the binary's own config module, which isn't part of the library
+#[allow(dead_code, unexpected_cfgs)]
+#[path = "../../src/config.rs"]
+mod config;
+
+/// Directory the inputs are written to, with the peers file already named in the environment
+fn dir() -> &'static PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("wg-router-fuzz-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // SAFETY: set once before any config is loaded, and libFuzzer runs inputs on one thread
+        unsafe { std::env::set_var(config::PEERS_FILE_ENV, dir.join("peers")) };
+        dir
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let dir = dir();
+    std::fs::write(dir.join("peers"), data).unwrap();
+    for name in ["config.toml", "config.json"] {
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        let _ = config::load_from(&path);
+    }
+});
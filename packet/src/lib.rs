@@ -0,0 +1,365 @@
+/*
+* The zero-copy parser for WireGuard message headers used by wireguard-router
+*
+* The header structs are borrowed straight from the packet bytes, their fields are only
+* exposed through accessors so the layout can stay an implementation detail.
+* This crate only depends on core, so it can be reused by embedded and eBPF-adjacent tooling.
+*/
+
+#![no_std]
+
+use core::fmt;
+
+use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
+
+/// Why a datagram could not be parsed as a WireGuard message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// the message type is known, but the datagram is shorter than its messages
+    TooShort,
+    /// the message type is that of a fixed size message, but the datagram is longer
+    TooLong,
+    /// the datagram does not start with a known message type
+    Invalid,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TooShort => f.write_str("Packet too short"),
+            ParseError::TooLong => f.write_str("Packet too long"),
+            ParseError::Invalid => f.write_str("Invalid Packet"),
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+/// heuristics taken from https://wiki.wireshark.org/WireGuard
+/// It tests the first byte for a valid message type (1, 2, 3, or 4) and checks that the next three reserved bytes are zero.
+pub fn is_wg_packet(packet: &[u8]) -> bool {
+    packet.len() > 4
+        && 0x01 <= packet[0]
+        && packet[0] <= 0x04
+        && (packet[1] | packet[2] | packet[3]) == 0x00
+}
+
+/// A 32-bit session index chosen by a peer, in its on-wire byte order
+///
+/// Indices are little-endian integers on the wire, [`as_u32`](Self::as_u32) is the value
+/// WireGuard implementations work with. It is displayed as 8 hex digits of that value.
+#[derive(FromBytes, Immutable, KnownLayout, Unaligned, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct Identity(pub [u8; 4]);
+
+impl Identity {
+    pub const fn from_u32(index: u32) -> Self {
+        Self(index.to_le_bytes())
+    }
+
+    pub const fn as_u32(self) -> u32 {
+        u32::from_le_bytes(self.0)
+    }
+}
+
+impl From<[u8; 4]> for Identity {
+    fn from(value: [u8; 4]) -> Self {
+        Self(value)
+    }
+}
+
+impl From<u32> for Identity {
+    fn from(index: u32) -> Self {
+        Self::from_u32(index)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.as_u32())
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({:08x})", self.as_u32())
+    }
+}
+
+/// Parses the [`Display`](fmt::Display) form, optionally prefixed with `0x`
+impl core::str::FromStr for Identity {
+    type Err = core::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix("0x").unwrap_or(s);
+        u32::from_str_radix(digits, 16).map(Self::from_u32)
+    }
+}
+
+/// Serialized as its [`Display`](fmt::Display) form
+#[cfg(feature = "serde")]
+impl serde::Serialize for Identity {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Identity {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IdentityVisitor;
+
+        impl serde::de::Visitor<'_> for IdentityVisitor {
+            type Value = Identity;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a session index as hex digits")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Identity, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(IdentityVisitor)
+    }
+}
+
+/// Message type 1, sent by the initiator to start a handshake
+#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
+#[repr(C)]
+pub struct HandshakeInitiation {
+    r#type: u8,
+    reserved: [u8; 3],
+    sender: Identity,
+    ephemeral: [u8; 32],
+    r#static: [u8; 48],
+    timestamp: [u8; 28],
+    mac1: [u8; 16],
+    mac2: [u8; 16],
+}
+
+impl HandshakeInitiation {
+    pub const SIZE: usize = 148;
+    /// mac1 covers all bytes of the message before it
+    pub const MAC1_OFFSET: usize = 116;
+
+    pub fn sender(&self) -> Identity {
+        self.sender
+    }
+
+    pub fn sender_index(&self) -> u32 {
+        self.sender.as_u32()
+    }
+
+    pub fn ephemeral(&self) -> &[u8; 32] {
+        &self.ephemeral
+    }
+
+    pub fn encrypted_static(&self) -> &[u8; 48] {
+        &self.r#static
+    }
+
+    pub fn encrypted_timestamp(&self) -> &[u8; 28] {
+        &self.timestamp
+    }
+
+    pub fn mac1(&self) -> &[u8; 16] {
+        &self.mac1
+    }
+
+    pub fn mac2(&self) -> &[u8; 16] {
+        &self.mac2
+    }
+}
+
+/// Message type 2, sent by the responder to complete a handshake
+#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
+#[repr(C)]
+pub struct HandshakeResponse {
+    r#type: u8,
+    reserved: [u8; 3],
+    sender: Identity,
+    receiver: Identity,
+    ephemeral: [u8; 32],
+    empty: [u8; 16],
+    mac1: [u8; 16],
+    mac2: [u8; 16],
+}
+
+impl HandshakeResponse {
+    pub const SIZE: usize = 92;
+    /// mac1 covers all bytes of the message before it
+    pub const MAC1_OFFSET: usize = 60;
+
+    pub fn sender(&self) -> Identity {
+        self.sender
+    }
+
+    pub fn sender_index(&self) -> u32 {
+        self.sender.as_u32()
+    }
+
+    pub fn receiver(&self) -> Identity {
+        self.receiver
+    }
+
+    pub fn receiver_index(&self) -> u32 {
+        self.receiver.as_u32()
+    }
+
+    pub fn ephemeral(&self) -> &[u8; 32] {
+        &self.ephemeral
+    }
+
+    pub fn encrypted_nothing(&self) -> &[u8; 16] {
+        &self.empty
+    }
+
+    pub fn mac1(&self) -> &[u8; 16] {
+        &self.mac1
+    }
+
+    pub fn mac2(&self) -> &[u8; 16] {
+        &self.mac2
+    }
+}
+
+/// Message type 3, sent instead of a response when the responder is under load
+#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
+#[repr(C)]
+pub struct CookieReply {
+    r#type: u8,
+    reserved: [u8; 3],
+    receiver: Identity,
+    nonce: [u8; 24],
+    cookie: [u8; 32],
+}
+
+impl CookieReply {
+    pub const SIZE: usize = 64;
+
+    pub fn receiver(&self) -> Identity {
+        self.receiver
+    }
+
+    pub fn receiver_index(&self) -> u32 {
+        self.receiver.as_u32()
+    }
+
+    pub fn nonce(&self) -> &[u8; 24] {
+        &self.nonce
+    }
+
+    pub fn encrypted_cookie(&self) -> &[u8; 32] {
+        &self.cookie
+    }
+}
+
+/// The header of message type 4, which carries the encrypted tunnel traffic
+#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
+#[repr(C)]
+pub struct TransportDataHeader {
+    r#type: u8,
+    reserved: [u8; 3],
+    receiver: Identity,
+    counter: [u8; 8],
+}
+
+impl TransportDataHeader {
+    pub const SIZE: usize = 16;
+    /// the header followed by an empty payload and its 16 byte authentication tag, i.e. a keepalive
+    pub const MIN_PACKET_SIZE: usize = 32;
+
+    pub fn receiver(&self) -> Identity {
+        self.receiver
+    }
+
+    pub fn receiver_index(&self) -> u32 {
+        self.receiver.as_u32()
+    }
+
+    /// The nonce counter of the packet
+    pub fn counter(&self) -> u64 {
+        u64::from_le_bytes(self.counter)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MessageType {
+    HandshakeInitiation,
+    HandshakeResponse,
+    CookieReply,
+    TransportData,
+}
+
+impl MessageType {
+    /// The type byte identifying the message on the wire
+    pub fn code(self) -> u8 {
+        match self {
+            MessageType::HandshakeInitiation => 0x01,
+            MessageType::HandshakeResponse => 0x02,
+            MessageType::CookieReply => 0x03,
+            MessageType::TransportData => 0x04,
+        }
+    }
+}
+
+/// A parsed WireGuard message, borrowing from the packet bytes
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum WireguardPacket<'a> {
+    HandshakeInitiation(&'a HandshakeInitiation),
+    HandshakeResponse(&'a HandshakeResponse),
+    CookieReply(&'a CookieReply),
+    /// the header and the encrypted payload following it
+    TransportData(&'a TransportDataHeader, &'a [u8]),
+}
+
+impl<'a> WireguardPacket<'a> {
+    /// Parses `data`, which must contain exactly one message
+    pub fn parse(data: &'a [u8]) -> Result<Self, ParseError> {
+        let Some(&r#type) = data.first() else {
+            return Err(ParseError::TooShort);
+        };
+        match (r#type, data.len()) {
+            (0x01, HandshakeInitiation::SIZE) => Ok(WireguardPacket::HandshakeInitiation(
+                HandshakeInitiation::ref_from_bytes(data).map_err(|_| ParseError::Invalid)?,
+            )),
+            (0x02, HandshakeResponse::SIZE) => Ok(WireguardPacket::HandshakeResponse(
+                HandshakeResponse::ref_from_bytes(data).map_err(|_| ParseError::Invalid)?,
+            )),
+            (0x03, CookieReply::SIZE) => Ok(WireguardPacket::CookieReply(
+                CookieReply::ref_from_bytes(data).map_err(|_| ParseError::Invalid)?,
+            )),
+            (0x04, TransportDataHeader::MIN_PACKET_SIZE..) => {
+                let (header, payload) =
+                    TransportDataHeader::ref_from_prefix(data).map_err(|_| ParseError::Invalid)?;
+                Ok(WireguardPacket::TransportData(header, payload))
+            }
+            (0x01, HandshakeInitiation::SIZE..)
+            | (0x02, HandshakeResponse::SIZE..)
+            | (0x03, CookieReply::SIZE..) => Err(ParseError::TooLong),
+            (0x01..=0x04, _) => Err(ParseError::TooShort),
+            _ => Err(ParseError::Invalid),
+        }
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            WireguardPacket::HandshakeInitiation(_) => MessageType::HandshakeInitiation,
+            WireguardPacket::HandshakeResponse(_) => MessageType::HandshakeResponse,
+            WireguardPacket::CookieReply(_) => MessageType::CookieReply,
+            WireguardPacket::TransportData(..) => MessageType::TransportData,
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for WireguardPacket<'a> {
+    type Error = ParseError;
+
+    fn try_from(data: &'a [u8]) -> Result<Self, Self::Error> {
+        Self::parse(data)
+    }
+}
@@ -0,0 +1,1692 @@
+/*
+* admin.rs serves the HTTP admin API, reporting on the running router
+*/
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::Write;
+use std::fs::{File, OpenOptions};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, FromRef, Query, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tower_http::timeout::TimeoutLayer;
+use wireguard_router::error::Error;
+use wireguard_router::event::{DropReason, RouterEvent};
+use wireguard_router::health::{BackendState, Health};
+use wireguard_router::metrics::{Metrics, Rates, Totals};
+use wireguard_router::packet::{Identity, MessageType};
+use wireguard_router::router::{
+    Datagram, Decision, Explainer, Explanation, GcTrigger, Injector, Lockdown, MemoryMeter, Pins,
+    SessionQuery, SessionTable,
+};
+use wireguard_router::timeline::{SessionTimeline, TimelineEvent};
+use wireguard_router::utils;
+
+use crate::config::{self, BackendLabels, Credential, MetricLabels, RateLimit, Role};
+
+/// Sessions listed per page unless the request asks for fewer
+const SESSIONS_PER_PAGE: usize = 1000;
+/// Drops listed on the status page, the most recent ones
+const RECENT_DROPS: usize = 20;
+/// Requests taking longer, e.g. from stalled clients, are answered with a timeout
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Rate limited requesters tracked at most, those idle the longest are forgotten beyond this
+const MAX_BUCKETS: usize = 4096;
+
+/// Binds the API's listener, which must happen before privileges are dropped and the sandbox is set up
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener, Error> {
+    let listener = TcpListener::bind(addr).await.map_err(Error::Bind)?;
+    tracing::info!("admin API listening on: {}", addr);
+    Ok(listener)
+}
+
+/// Serves the API on `listener` until the process exits
+///
+/// - `GET /metrics`: the router's counters, traffic rates and the config checksum, in the Prometheus text format
+/// - `GET /config`: the checksum of the loaded config
+/// - `GET /clients?backend=<address>`: the client endpoints with sessions to the backend, or all
+/// - `GET /sessions?client=<ip>&backend=<address>&min_age=<secs>&after=<index>&limit=<n>`: the
+///   sessions clients initiated, a page at a time, continued with the `next` of the last page
+/// - `GET /sessions/export?format=csv|json`: every session with its counters at once, the JSON
+///   also carrying the router's counters, for offline analysis or routers warm-starting from this
+///   one; it requires the configured bearer token if there is one
+/// - `GET /sessions/timeline?index=<index>` or `?client=<ip>`: the recent events of the session
+///   with either index, or of each recent session of the client, kept after the sessions ended
+/// - `POST /sessions/gc`: forgets the idle sessions and those of removed peers now, reporting
+///   which sessions were evicted and why
+/// - `POST /explain`: how the router would route a packet, given as JSON with its `source`,
+///   optionally the `local` address it arrives on and either the `packet` as hex or base64 or the
+///   `pubkey` of the peer an initiation is for
+/// - `POST /inject`: routes a packet given like to `/explain` as if it was received from its
+///   `source`, creating sessions and sending as for any other, and returns the events it caused;
+///   it requires the configured bearer token and is disabled without one
+/// - `GET /lockdown`: whether new tunnels are paused, and `POST /lockdown?active=true|false` to
+///   pause or resume them
+/// - `GET /pins`: the clients pinned to a backend, `POST /pins?client=<ip>&backend=<address>`
+///   pins one ahead of time, `POST /pins?index=<index>` the client of a session to its backend,
+///   and `DELETE /pins?client=<ip>` unpins one
+/// - `GET /status`: a self-contained HTML page summing up the listeners, backends, sessions and
+///   recent drops
+/// - `GET /debug/pprof/profile?seconds=<n>&format=flamegraph|pprof&frequency=<hz>`: a CPU
+///   profile of the router over the next seconds, as an SVG flamegraph or in the pprof format,
+///   if enabled and built with the `profiling` feature
+///
+/// With credentials configured, every request requires the bearer token of one whose role
+/// allows the endpoint, see [`required_role`], besides the tokens of exports and injection.
+/// Without any, only the endpoints reading are served, and the others are refused with 403.
+/// Requests beyond the rate limit of their credential, or address, are answered with 429, and
+/// every request changing the router is recorded in the [`Audit`] log.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    sessions: SessionTable,
+    labels: MetricLabels,
+    status: Status,
+) {
+    let drops = Arc::new(Mutex::new(VecDeque::new()));
+    tokio::spawn(record_drops(status.events, drops.clone()));
+    let api = Api {
+        metrics,
+        sessions,
+        labels,
+        listeners: status.listeners,
+        health: status.health,
+        gc: status.gc,
+        lockdown: status.lockdown,
+        pins: status.pins,
+        memory: status.memory,
+        explainer: status.explainer,
+        injection: status
+            .injection
+            .map(|(injector, token)| (injector, token.into())),
+        export_token: status.export_token.map(Into::into),
+        credentials: status.credentials.into(),
+        limiter: status.rate_limit.map(|rate| {
+            Arc::new(Limiter {
+                rate,
+                buckets: Mutex::default(),
+            })
+        }),
+        audit: Arc::new(status.audit),
+        drops,
+    };
+    let app = Router::new()
+        .route("/metrics", get(prometheus))
+        .route("/config", get(config))
+        .route("/clients", get(clients))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/export", get(export_sessions))
+        .route("/sessions/timeline", get(session_timeline))
+        .route("/sessions/gc", post(collect_garbage))
+        .route("/lockdown", get(lockdown).post(set_lockdown))
+        .route("/pins", get(pins).post(pin).delete(unpin))
+        .route("/explain", post(explain))
+        .route("/inject", post(inject))
+        .route("/status", get(status_page))
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            REQUEST_TIMEOUT,
+        ));
+    // profiles take as long as they were asked for, so they are exempt from the timeout
+    #[cfg(all(unix, feature = "profiling"))]
+    let app = match status.profiling {
+        true => app.route("/debug/pprof/profile", get(profile)),
+        false => app,
+    };
+    let app = app
+        .layer(middleware::from_fn_with_state(api.clone(), authorize))
+        .with_state(api);
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!("admin API failed: {}", e);
+    }
+}
+
+/// What the status page reports on besides the metrics and sessions
+pub struct Status {
+    /// the addresses the router listens on
+    pub listeners: Vec<SocketAddr>,
+    pub health: Arc<Health>,
+    pub events: broadcast::Receiver<RouterEvent>,
+    pub gc: GcTrigger,
+    pub lockdown: Lockdown,
+    pub pins: Pins,
+    pub memory: MemoryMeter,
+    pub explainer: Explainer,
+    /// injects packets for requests with this bearer token
+    pub injection: Option<(Injector, String)>,
+    /// bearer token session exports require, if any
+    pub export_token: Option<String>,
+    /// bearer tokens every request requires one of, if any
+    pub credentials: Vec<Credential>,
+    pub rate_limit: Option<RateLimit>,
+    pub audit: Audit,
+    /// whether CPU profiles are served
+    #[cfg(all(unix, feature = "profiling"))]
+    pub profiling: bool,
+}
+
+/// A dropped packet, as listed on the status page
+struct Drop {
+    at: Instant,
+    message: Option<MessageType>,
+    source: SocketAddr,
+    reason: DropReason,
+}
+
+/// What the handlers report on
+#[derive(Clone)]
+struct Api {
+    metrics: Arc<Metrics>,
+    sessions: SessionTable,
+    labels: MetricLabels,
+    listeners: Vec<SocketAddr>,
+    health: Arc<Health>,
+    gc: GcTrigger,
+    lockdown: Lockdown,
+    pins: Pins,
+    memory: MemoryMeter,
+    explainer: Explainer,
+    injection: Option<(Injector, Arc<str>)>,
+    export_token: Option<Arc<str>>,
+    credentials: Arc<[Credential]>,
+    limiter: Option<Arc<Limiter>>,
+    audit: Arc<Audit>,
+    /// the most recent first
+    drops: Arc<Mutex<VecDeque<Drop>>>,
+}
+
+impl FromRef<Api> for Arc<Metrics> {
+    fn from_ref(api: &Api) -> Self {
+        api.metrics.clone()
+    }
+}
+
+impl FromRef<Api> for MetricLabels {
+    fn from_ref(api: &Api) -> Self {
+        api.labels
+    }
+}
+
+impl FromRef<Api> for Arc<Health> {
+    fn from_ref(api: &Api) -> Self {
+        api.health.clone()
+    }
+}
+
+impl FromRef<Api> for Arc<Audit> {
+    fn from_ref(api: &Api) -> Self {
+        api.audit.clone()
+    }
+}
+
+impl FromRef<Api> for GcTrigger {
+    fn from_ref(api: &Api) -> Self {
+        api.gc.clone()
+    }
+}
+
+impl FromRef<Api> for Lockdown {
+    fn from_ref(api: &Api) -> Self {
+        api.lockdown.clone()
+    }
+}
+
+impl FromRef<Api> for Pins {
+    fn from_ref(api: &Api) -> Self {
+        api.pins.clone()
+    }
+}
+
+impl FromRef<Api> for MemoryMeter {
+    fn from_ref(api: &Api) -> Self {
+        api.memory.clone()
+    }
+}
+
+impl FromRef<Api> for Explainer {
+    fn from_ref(api: &Api) -> Self {
+        api.explainer.clone()
+    }
+}
+
+impl FromRef<Api> for SessionTable {
+    fn from_ref(api: &Api) -> Self {
+        api.sessions.clone()
+    }
+}
+
+/// Name, description and value of a gauge of [`Rates`]
+type Gauge = (&'static str, &'static str, fn(&Rates) -> f64);
+/// Name, description and value of a counter of [`Totals`]
+type Counter = (&'static str, &'static str, fn(&Totals) -> u64);
+
+async fn prometheus(
+    State(metrics): State<Arc<Metrics>>,
+    State(labels): State<MetricLabels>,
+    State(health): State<Arc<Health>>,
+    State(lockdown): State<Lockdown>,
+    State(memory): State<MemoryMeter>,
+) -> impl IntoResponse {
+    let snapshot = metrics.snapshot();
+    let names = metrics.peer_names();
+    let mut body = String::new();
+    for (name, help, value) in [
+        (
+            "packets_received_total",
+            "Datagrams read from the listeners",
+            snapshot.received,
+        ),
+        (
+            "packets_forwarded_total",
+            "Packets sent on to a client or backend",
+            snapshot.forwarded,
+        ),
+        (
+            "packets_dropped_total",
+            "Packets discarded, e.g. because they matched no session",
+            snapshot.dropped,
+        ),
+        (
+            "sessions_created_total",
+            "Sessions routed to a backend",
+            snapshot.sessions_created,
+        ),
+        (
+            "sessions_expired_total",
+            "Sessions forgotten after seeing no packets",
+            snapshot.sessions_expired,
+        ),
+        (
+            "sessions_purged_total",
+            "Sessions forgotten because their backend went down or was removed",
+            snapshot.sessions_purged,
+        ),
+        (
+            "excessive_rekeys_total",
+            "Times a client's tunnel started rekeying far more often than every 2 minutes",
+            snapshot.excessive_rekeys,
+        ),
+        (
+            "honeypot_initiations_total",
+            "Initiations matching no peer that were routed to the honeypot",
+            snapshot.honeypot_initiations,
+        ),
+        (
+            "lockdown_dropped_total",
+            "Initiations of new tunnels dropped during a lockdown",
+            snapshot.locked_out,
+        ),
+        (
+            "denylist_dropped_total",
+            "Datagrams dropped as their source is denylisted",
+            snapshot.denied,
+        ),
+        (
+            "oversized_dropped_total",
+            "Datagrams dropped as they are longer than the maximum datagram size",
+            snapshot.oversized,
+        ),
+        (
+            "truncated_reads_total",
+            "Datagrams dropped as they didn't fit the receive buffer",
+            snapshot.truncated,
+        ),
+        (
+            "packet_panics_total",
+            "Datagrams dropped as handling them panicked",
+            snapshot.panics,
+        ),
+        (
+            "memory_evicted_sessions_total",
+            "Sessions evicted to keep the router within its memory limit",
+            snapshot.sessions_shed,
+        ),
+        (
+            "handshake_races_lost_total",
+            "Handshake responses dropped as another backend responded to the raced initiation first",
+            snapshot.races_lost,
+        ),
+        (
+            "sessions_limited_total",
+            "Initiations dropped at the session limit of the router or their backend",
+            snapshot.sessions_limited,
+        ),
+        (
+            "unmatched_data_total",
+            "Transport data matching no session, e.g. after a restart",
+            snapshot.unmatched_data,
+        ),
+        (
+            "queue_dropped_handshakes_total",
+            "Handshake messages dropped under backpressure",
+            snapshot.queue_dropped_handshakes,
+        ),
+        (
+            "queue_dropped_data_total",
+            "Transport data dropped under backpressure",
+            snapshot.queue_dropped_data,
+        ),
+        (
+            "queue_blocked_total",
+            "Times reading stopped at a full batch, leaving datagrams in the socket buffer",
+            snapshot.queue_blocked,
+        ),
+    ] {
+        let _ = writeln!(body, "# HELP wireguard_router_{name} {help}");
+        let _ = writeln!(body, "# TYPE wireguard_router_{name} counter");
+        let _ = writeln!(body, "wireguard_router_{name} {value}");
+    }
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_lockdown Whether new tunnels are paused"
+    );
+    let _ = writeln!(body, "# TYPE wireguard_router_lockdown gauge");
+    let _ = writeln!(
+        body,
+        "wireguard_router_lockdown {}",
+        u8::from(lockdown.is_active())
+    );
+    let usage = memory.usage().await;
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_memory_bytes Approximate memory held by the router's state, by component"
+    );
+    let _ = writeln!(body, "# TYPE wireguard_router_memory_bytes gauge");
+    for (component, bytes) in [
+        ("sessions", usage.sessions),
+        ("timelines", usage.timelines),
+        ("affinity", usage.affinity),
+        ("queues", usage.queues),
+    ] {
+        let _ = writeln!(
+            body,
+            "wireguard_router_memory_bytes{{component=\"{component}\"}} {bytes}"
+        );
+    }
+    if let Some(limit) = memory.limit() {
+        let _ = writeln!(
+            body,
+            "# HELP wireguard_router_memory_limit_bytes The memory the router's state is kept below"
+        );
+        let _ = writeln!(body, "# TYPE wireguard_router_memory_limit_bytes gauge");
+        let _ = writeln!(body, "wireguard_router_memory_limit_bytes {limit}");
+    }
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_received_messages_total Datagrams received that parsed as WireGuard messages, by type"
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE wireguard_router_received_messages_total counter"
+    );
+    for (message, count) in metrics.received_by_type() {
+        let _ = writeln!(
+            body,
+            "wireguard_router_received_messages_total{{type=\"{}\"}} {count}",
+            message_label(message)
+        );
+    }
+    let sizes = metrics.received_sizes();
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_received_datagram_bytes Sizes of the datagrams received"
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE wireguard_router_received_datagram_bytes histogram"
+    );
+    for (bound, count) in &sizes.buckets {
+        let _ = writeln!(
+            body,
+            "wireguard_router_received_datagram_bytes_bucket{{le=\"{bound}\"}} {count}"
+        );
+    }
+    let _ = writeln!(
+        body,
+        "wireguard_router_received_datagram_bytes_bucket{{le=\"+Inf\"}} {}",
+        sizes.count
+    );
+    let _ = writeln!(
+        body,
+        "wireguard_router_received_datagram_bytes_sum {}",
+        sizes.sum
+    );
+    let _ = writeln!(
+        body,
+        "wireguard_router_received_datagram_bytes_count {}",
+        sizes.count
+    );
+    let rates = metrics.rates();
+    let backend_rates = metrics.backend_rates();
+    let gauges: [Gauge; 3] = [
+        (
+            "handshakes_per_minute",
+            "Initiations forwarded to backends",
+            |rates| rates.handshakes_per_min,
+        ),
+        ("packets_per_second", "Packets forwarded", |rates| {
+            rates.packets_per_sec
+        }),
+        ("bytes_per_second", "Bytes forwarded", |rates| {
+            rates.bytes_per_sec
+        }),
+    ];
+    for (name, help, rate) in gauges {
+        let _ = writeln!(
+            body,
+            "# HELP wireguard_router_{name} {help}, averaged over the last minute"
+        );
+        let _ = writeln!(body, "# TYPE wireguard_router_{name} gauge");
+        let _ = writeln!(body, "wireguard_router_{name} {}", rate(&rates));
+        let _ = writeln!(
+            body,
+            "# HELP wireguard_router_backend_{name} {help} to and from a backend, averaged over the last minute"
+        );
+        let _ = writeln!(body, "# TYPE wireguard_router_backend_{name} gauge");
+        let rates = backend_rates
+            .iter()
+            .map(|(backend, rates)| (*backend, rate(rates)));
+        for (label, rates) in group(labels.backends, &names, rates) {
+            let rate: f64 = rates.into_iter().sum();
+            let _ = writeln!(body, "wireguard_router_backend_{name}{{{label}}} {rate}");
+        }
+    }
+    if labels.clients {
+        let mut clients: Vec<(IpAddr, Rates)> = metrics.client_rates().into_iter().collect();
+        clients.sort_by(|(_, a), (_, b)| b.bytes_per_sec.total_cmp(&a.bytes_per_sec));
+        clients.truncate(labels.top_clients);
+        for (name, help, rate) in gauges {
+            let _ = writeln!(
+                body,
+                "# HELP wireguard_router_client_{name} {help} to and from the busiest client addresses, averaged over the last minute"
+            );
+            let _ = writeln!(body, "# TYPE wireguard_router_client_{name} gauge");
+            for (client, rates) in &clients {
+                let _ = writeln!(
+                    body,
+                    "wireguard_router_client_{name}{{client=\"{client}\"}} {}",
+                    rate(rates)
+                );
+            }
+        }
+    }
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_backend_handshake_rtt_seconds Moving average of the time from forwarding an initiation to its response"
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE wireguard_router_backend_handshake_rtt_seconds gauge"
+    );
+    let rtts = metrics
+        .handshake_rtts()
+        .into_iter()
+        .map(|(backend, rtt)| (backend, rtt.as_secs_f64()));
+    for (label, rtts) in group(labels.backends, &names, rtts) {
+        let mean = rtts.iter().sum::<f64>() / rtts.len() as f64;
+        let _ = writeln!(
+            body,
+            "wireguard_router_backend_handshake_rtt_seconds{{{label}}} {mean}"
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_backend_handshake_loss_ratio Estimated share of the initiations forwarded to a backend over the last minute that went unanswered"
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE wireguard_router_backend_handshake_loss_ratio gauge"
+    );
+    for (label, losses) in group(labels.backends, &names, metrics.handshake_loss()) {
+        let mean = losses.iter().sum::<f64>() / losses.len() as f64;
+        let _ = writeln!(
+            body,
+            "wireguard_router_backend_handshake_loss_ratio{{{label}}} {mean}"
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_backend_send_failures_total Sends to a backend that failed with an error that was not transient"
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE wireguard_router_backend_send_failures_total counter"
+    );
+    for (label, failures) in group(labels.backends, &names, metrics.backend_send_failures()) {
+        let failures: u64 = failures.into_iter().sum();
+        let _ = writeln!(
+            body,
+            "wireguard_router_backend_send_failures_total{{{label}}} {failures}"
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_backend_health_state Backends in each health state other than up"
+    );
+    let _ = writeln!(body, "# TYPE wireguard_router_backend_health_state gauge");
+    let states = health.states();
+    for state in [
+        BackendState::Failing,
+        BackendState::Down,
+        BackendState::Recovering,
+        BackendState::Ejected,
+    ] {
+        let backends = states
+            .iter()
+            .filter(|(_, s)| **s == state)
+            .map(|(backend, _)| (*backend, ()));
+        for (label, backends) in group(labels.backends, &names, backends) {
+            let _ = writeln!(
+                body,
+                "wireguard_router_backend_health_state{{{label},state=\"{}\"}} {}",
+                state.as_str(),
+                backends.len()
+            );
+        }
+    }
+    let totals = metrics.backend_totals();
+    let counters: [Counter; 4] = [
+        (
+            "handshakes_total",
+            "Initiations forwarded to a backend",
+            |totals| totals.handshakes,
+        ),
+        (
+            "handshakes_answered_total",
+            "Initiations forwarded to a backend that it answered",
+            |totals| totals.answered,
+        ),
+        (
+            "packets_total",
+            "Packets forwarded to and from a backend",
+            |totals| totals.packets,
+        ),
+        (
+            "bytes_total",
+            "Bytes forwarded to and from a backend",
+            |totals| totals.bytes,
+        ),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(body, "# HELP wireguard_router_backend_{name} {help}");
+        let _ = writeln!(body, "# TYPE wireguard_router_backend_{name} counter");
+        let values = totals
+            .iter()
+            .map(|(backend, totals)| (*backend, value(totals)));
+        for (label, values) in group(labels.backends, &names, values) {
+            let total: u64 = values.into_iter().sum();
+            let _ = writeln!(body, "wireguard_router_backend_{name}{{{label}}} {total}");
+        }
+    }
+    let checksum = config_checksum();
+    let _ = writeln!(
+        body,
+        "# HELP wireguard_router_config_info Checksum of the loaded config"
+    );
+    let _ = writeln!(body, "# TYPE wireguard_router_config_info gauge");
+    let _ = writeln!(
+        body,
+        "wireguard_router_config_info{{checksum=\"{checksum}\"}} 1"
+    );
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Groups `values` by the labels of the series about their backend, skipping them all if
+/// backends aren't broken down
+fn group<T>(
+    labels: BackendLabels,
+    names: &HashMap<SocketAddr, String>,
+    values: impl IntoIterator<Item = (SocketAddr, T)>,
+) -> BTreeMap<String, Vec<T>> {
+    let mut groups: BTreeMap<String, Vec<T>> = BTreeMap::new();
+    for (backend, value) in values {
+        let label = match labels {
+            BackendLabels::Backend => format!("backend=\"{backend}\""),
+            BackendLabels::Peer => {
+                let name = names.get(&backend).map_or("", String::as_str);
+                format!("peer=\"{}\"", escape_label(name))
+            }
+            BackendLabels::None => continue,
+        };
+        groups.entry(label).or_default().push(value);
+    }
+    groups
+}
+
+/// The `type` label of a message type
+fn message_label(message: MessageType) -> &'static str {
+    match message {
+        MessageType::HandshakeInitiation => "handshake_initiation",
+        MessageType::HandshakeResponse => "handshake_response",
+        MessageType::CookieReply => "cookie_reply",
+        MessageType::TransportData => "transport_data",
+        _ => "other",
+    }
+}
+
+/// Escapes a label value of the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The checksum of the loaded config, which is loaded before the API is served
+fn config_checksum() -> String {
+    config::settings()
+        .map(|config| config.read().unwrap().checksum.clone())
+        .unwrap_or_default()
+}
+
+async fn config() -> Json<serde_json::Value> {
+    let checksum = config_checksum();
+    Json(json!({ "checksum": checksum }))
+}
+
+#[derive(Deserialize)]
+struct ClientsQuery {
+    backend: Option<SocketAddr>,
+}
+
+async fn clients(
+    State(sessions): State<SessionTable>,
+    Query(query): Query<ClientsQuery>,
+) -> Json<serde_json::Value> {
+    let clients: Vec<serde_json::Value> = sessions
+        .clients(query.backend)
+        .await
+        .into_iter()
+        .map(|client| {
+            json!({
+                "client": client.client,
+                "backend": client.backend,
+                "sessions": client.sessions,
+                "idle_secs": client.idle.as_secs(),
+            })
+        })
+        .collect();
+    Json(json!(clients))
+}
+
+#[derive(Deserialize)]
+struct SessionsQuery {
+    client: Option<IpAddr>,
+    backend: Option<SocketAddr>,
+    min_age: Option<u64>,
+    after: Option<Identity>,
+    limit: Option<usize>,
+}
+
+async fn list_sessions(
+    State(sessions): State<SessionTable>,
+    Query(query): Query<SessionsQuery>,
+) -> Json<serde_json::Value> {
+    let filter = SessionQuery {
+        client: query.client,
+        backend: query.backend,
+        min_age: query.min_age.map(Duration::from_secs),
+        after: query.after,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(SESSIONS_PER_PAGE)
+        .min(SESSIONS_PER_PAGE);
+    let page = sessions.sessions(&filter, limit).await;
+    let listed: Vec<serde_json::Value> = page
+        .sessions
+        .into_iter()
+        .map(|session| {
+            json!({
+                "client": session.client,
+                "backend": session.backend,
+                "client_index": session.client_index,
+                "backend_index": session.backend_index,
+                "age_secs": session.age.as_secs(),
+                "idle_secs": session.idle.as_secs(),
+                "packets": session.packets,
+                "bytes": session.bytes,
+            })
+        })
+        .collect();
+    Json(json!({ "sessions": listed, "next": page.next }))
+}
+
+/// Formats of a session export
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: Format,
+}
+
+async fn export_sessions(
+    State(api): State<Api>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    let taken_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let page = api
+        .sessions
+        .sessions(&SessionQuery::default(), usize::MAX)
+        .await;
+    match query.format {
+        Format::Json => {
+            let sessions: Vec<serde_json::Value> = page
+                .sessions
+                .into_iter()
+                .map(|session| {
+                    json!({
+                        "client": session.client,
+                        "backend": session.backend,
+                        "client_index": session.client_index,
+                        "backend_index": session.backend_index,
+                        "age_secs": session.age.as_secs(),
+                        "idle_secs": session.idle.as_secs(),
+                        "packets": session.packets,
+                        "bytes": session.bytes,
+                    })
+                })
+                .collect();
+            let checkpoint = api.metrics.checkpoint();
+            let backends: BTreeMap<String, Totals> = checkpoint
+                .backends
+                .into_iter()
+                .map(|(backend, totals)| (backend.to_string(), totals))
+                .collect();
+            let export = json!({
+                "taken_at": taken_at,
+                "counters": checkpoint.counters,
+                "backends": backends,
+                "sessions": sessions,
+            });
+            Json(export).into_response()
+        }
+        Format::Csv => {
+            let mut csv = String::from(
+                "client,backend,client_index,backend_index,age_secs,idle_secs,packets,bytes\n",
+            );
+            for session in page.sessions {
+                let _ = writeln!(
+                    csv,
+                    "{},{},{},{},{},{},{},{}",
+                    session.client,
+                    session.backend,
+                    session.client_index,
+                    session
+                        .backend_index
+                        .map(|i| i.to_string())
+                        .unwrap_or_default(),
+                    session.age.as_secs(),
+                    session.idle.as_secs(),
+                    session.packets,
+                    session.bytes,
+                );
+            }
+            ([(CONTENT_TYPE, "text/csv; charset=utf-8")], csv).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TimelineQuery {
+    index: Option<Identity>,
+    client: Option<IpAddr>,
+}
+
+async fn session_timeline(
+    State(sessions): State<SessionTable>,
+    Query(query): Query<TimelineQuery>,
+) -> impl IntoResponse {
+    let timelines = match (query.index, query.client) {
+        (Some(index), _) => sessions.timeline(index).await.into_iter().collect(),
+        (None, Some(client)) => sessions.timelines(client).await,
+        (None, None) => {
+            let message = "either index or client is required";
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+    };
+    let timelines: Vec<serde_json::Value> = timelines.iter().map(timeline_json).collect();
+    Json(json!({ "timelines": timelines })).into_response()
+}
+
+fn timeline_json(timeline: &SessionTimeline) -> serde_json::Value {
+    let events: Vec<serde_json::Value> = timeline
+        .events
+        .iter()
+        .map(|entry| {
+            let at = chrono::DateTime::<chrono::Utc>::from(entry.at)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            let mut event = match &entry.event {
+                TimelineEvent::Initiation => json!({ "event": "initiation" }),
+                TimelineEvent::Retransmit => json!({ "event": "retransmit" }),
+                TimelineEvent::Response { backend_index } => {
+                    json!({ "event": "response", "backend_index": backend_index })
+                }
+                TimelineEvent::CookieReply => json!({ "event": "cookie_reply" }),
+                TimelineEvent::EndpointChanged { from, to } => {
+                    json!({ "event": "endpoint_changed", "from": from, "to": to })
+                }
+                TimelineEvent::Expired { idle } => {
+                    json!({ "event": "expired", "idle_secs": idle.as_secs() })
+                }
+                TimelineEvent::Purged => json!({ "event": "purged" }),
+                TimelineEvent::Evicted => json!({ "event": "evicted" }),
+                TimelineEvent::Dropped { message, reason } => json!({
+                    "event": "dropped",
+                    "message": message_label(*message),
+                    "reason": reason.to_string(),
+                }),
+            };
+            event["at"] = json!(at);
+            event
+        })
+        .collect();
+    json!({
+        "client": timeline.client,
+        "backend": timeline.backend,
+        "client_index": timeline.client_index,
+        "backend_index": timeline.backend_index,
+        "events": events,
+    })
+}
+
+async fn collect_garbage(
+    State(gc): State<GcTrigger>,
+    State(audit): State<Arc<Audit>>,
+    Extension(caller): Extension<Caller>,
+) -> impl IntoResponse {
+    let Some(report) = gc.run().await else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "the router stopped").into_response();
+    };
+    audit.record(
+        &caller,
+        json!({ "sessions": report.remaining + report.evicted.len() }),
+        json!({ "evicted": report.evicted.len(), "remaining": report.remaining }),
+    );
+    let mut by_reason: BTreeMap<&str, usize> = BTreeMap::new();
+    for eviction in &report.evicted {
+        *by_reason.entry(eviction.reason.as_str()).or_default() += 1;
+    }
+    let sessions: Vec<serde_json::Value> = report
+        .evicted
+        .iter()
+        .map(|eviction| {
+            json!({
+                "client": eviction.client,
+                "backend": eviction.backend,
+                "client_index": eviction.client_index,
+                "reason": eviction.reason.as_str(),
+            })
+        })
+        .collect();
+    Json(json!({
+        "evicted": report.evicted.len(),
+        "by_reason": by_reason,
+        "remaining": report.remaining,
+        "took_ms": report.took.as_millis() as u64,
+        "sessions": sessions,
+    }))
+    .into_response()
+}
+
+async fn lockdown(State(lockdown): State<Lockdown>) -> Json<serde_json::Value> {
+    Json(json!({ "active": lockdown.is_active() }))
+}
+
+#[derive(Deserialize)]
+struct LockdownQuery {
+    active: bool,
+}
+
+async fn set_lockdown(
+    State(lockdown): State<Lockdown>,
+    State(audit): State<Arc<Audit>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<LockdownQuery>,
+) -> Json<serde_json::Value> {
+    let was_active = lockdown.set(query.active);
+    audit.record(
+        &caller,
+        json!({ "active": was_active }),
+        json!({ "active": query.active }),
+    );
+    match (was_active, query.active) {
+        (false, true) => tracing::warn!("lockdown started, new tunnels are dropped"),
+        (true, false) => tracing::warn!("lockdown ended, new tunnels are routed again"),
+        _ => {}
+    }
+    Json(json!({ "active": query.active, "was_active": was_active }))
+}
+
+async fn pins(State(pins): State<Pins>) -> Json<serde_json::Value> {
+    let pins: Vec<serde_json::Value> = pins
+        .list()
+        .into_iter()
+        .map(|(client, backend)| json!({ "client": client, "backend": backend }))
+        .collect();
+    Json(json!({ "pins": pins }))
+}
+
+#[derive(Deserialize)]
+struct PinQuery {
+    client: Option<IpAddr>,
+    backend: Option<SocketAddr>,
+    index: Option<Identity>,
+}
+
+async fn pin(
+    State(pins): State<Pins>,
+    State(sessions): State<SessionTable>,
+    State(audit): State<Arc<Audit>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<PinQuery>,
+) -> impl IntoResponse {
+    let (client, backend) = match (query.index, query.client, query.backend) {
+        (Some(index), None, None) => match sessions.endpoints(index).await {
+            Some((client, backend)) => (client.ip(), backend),
+            None => return (StatusCode::NOT_FOUND, "no such session").into_response(),
+        },
+        (None, Some(client), Some(backend)) => (client, backend),
+        _ => {
+            let message = "either index or both client and backend are required";
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+    };
+    let previous = pins.pin(client, backend);
+    tracing::info!("pinned client {} to backend {}", client, backend);
+    audit.record(
+        &caller,
+        json!({ "client": client, "backend": previous }),
+        json!({ "client": client, "backend": backend }),
+    );
+    Json(json!({ "client": client, "backend": backend, "previous": previous })).into_response()
+}
+
+#[derive(Deserialize)]
+struct UnpinQuery {
+    client: IpAddr,
+}
+
+async fn unpin(
+    State(pins): State<Pins>,
+    State(audit): State<Arc<Audit>>,
+    Extension(caller): Extension<Caller>,
+    Query(query): Query<UnpinQuery>,
+) -> impl IntoResponse {
+    let Some(backend) = pins.unpin(query.client) else {
+        return (StatusCode::NOT_FOUND, "the client is not pinned").into_response();
+    };
+    tracing::info!("unpinned client {} from backend {}", query.client, backend);
+    audit.record(
+        &caller,
+        json!({ "client": query.client, "backend": backend }),
+        json!({ "client": query.client, "backend": null }),
+    );
+    Json(json!({ "client": query.client, "backend": backend })).into_response()
+}
+
+/// Longest CPU profile taken
+#[cfg(all(unix, feature = "profiling"))]
+const MAX_PROFILE: Duration = Duration::from_secs(300);
+
+#[cfg(all(unix, feature = "profiling"))]
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ProfileFormat {
+    #[default]
+    Flamegraph,
+    Pprof,
+}
+
+#[cfg(all(unix, feature = "profiling"))]
+#[derive(Deserialize)]
+struct ProfileQuery {
+    #[serde(default = "ProfileQuery::default_seconds")]
+    seconds: u64,
+    #[serde(default)]
+    format: ProfileFormat,
+    /// samples per second
+    #[serde(default = "ProfileQuery::default_frequency")]
+    frequency: i32,
+}
+
+#[cfg(all(unix, feature = "profiling"))]
+impl ProfileQuery {
+    fn default_seconds() -> u64 {
+        30
+    }
+
+    fn default_frequency() -> i32 {
+        99
+    }
+}
+
+/// Samples the whole process for the requested duration, one profile at a time as the
+/// profiler is process-wide
+#[cfg(all(unix, feature = "profiling"))]
+async fn profile(Query(query): Query<ProfileQuery>) -> impl IntoResponse {
+    static RUNNING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    let duration = Duration::from_secs(query.seconds);
+    if duration.is_zero() || duration > MAX_PROFILE || !(1..=1000).contains(&query.frequency) {
+        let message = "seconds must be within 1 and 300, and frequency within 1 and 1000";
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    if RUNNING.swap(true, std::sync::atomic::Ordering::AcqRel) {
+        let message = "another profile is being taken";
+        return (StatusCode::CONFLICT, message).into_response();
+    }
+    tracing::info!("taking a CPU profile over {:?}", duration);
+    let taken = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, pprof::Error> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(query.frequency)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()?;
+        std::thread::sleep(duration);
+        let report = guard.report().build()?;
+        let mut body = Vec::new();
+        // a flamegraph of no samples is no SVG at all
+        if report.data.is_empty() {
+            return Ok(body);
+        }
+        match query.format {
+            ProfileFormat::Flamegraph => report.flamegraph(&mut body)?,
+            ProfileFormat::Pprof => {
+                use pprof::protos::Message;
+                let profile = report.pprof()?;
+                profile.encode(&mut body).map_err(std::io::Error::other)?;
+            }
+        }
+        Ok(body)
+    })
+    .await;
+    RUNNING.store(false, std::sync::atomic::Ordering::Release);
+    let content_type = match query.format {
+        ProfileFormat::Flamegraph => "image/svg+xml",
+        ProfileFormat::Pprof => "application/octet-stream",
+    };
+    match taken {
+        Ok(Ok(body)) if body.is_empty() => {
+            (StatusCode::NO_CONTENT, "no samples, the router was idle").into_response()
+        }
+        Ok(Ok(body)) => ([(CONTENT_TYPE, content_type)], body).into_response(),
+        Ok(Err(e)) => {
+            tracing::warn!("CPU profile failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// A datagram as given to `/explain` and `/inject`
+#[derive(Deserialize)]
+struct DatagramBody {
+    source: SocketAddr,
+    local: Option<IpAddr>,
+    /// hex or base64
+    packet: Option<String>,
+    /// base64, standing in for an initiation to the peer with this key
+    pubkey: Option<String>,
+}
+
+impl DatagramBody {
+    fn datagram(self) -> Result<Datagram, (StatusCode, &'static str)> {
+        let packet = match (&self.packet, &self.pubkey) {
+            (Some(packet), _) => crate::decode::parse_bytes(packet),
+            (None, Some(pubkey)) => base64::engine::general_purpose::STANDARD
+                .decode(pubkey.trim())
+                .ok()
+                .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .map(|key| utils::initiation_to(&key, OsRng.next_u32())),
+            (None, None) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "either packet or pubkey is required",
+                ));
+            }
+        };
+        let Some(packet) = packet else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "packet must be hex or base64, pubkey a base64 key of 32 bytes",
+            ));
+        };
+        Ok(Datagram {
+            source: self.source,
+            local: self.local,
+            packet,
+        })
+    }
+}
+
+async fn explain(
+    State(explainer): State<Explainer>,
+    Json(body): Json<DatagramBody>,
+) -> impl IntoResponse {
+    let request = match body.datagram() {
+        Ok(request) => request,
+        Err(rejection) => return rejection.into_response(),
+    };
+    match explainer.explain(request).await {
+        Some(explanation) => Json(explanation_json(&explanation)).into_response(),
+        None => (StatusCode::SERVICE_UNAVAILABLE, "the router stopped").into_response(),
+    }
+}
+
+/// The least role allowed to call an endpoint: reading takes [`Role::ReadOnly`], changing
+/// sessions and pins [`Role::SessionAdmin`] and anything else [`Role::PeerAdmin`]
+fn required_role(method: &Method, path: &str) -> Role {
+    match (method, path) {
+        // profiles take a share of the CPU for as long as asked for
+        (_, "/debug/pprof/profile") => Role::SessionAdmin,
+        (&Method::GET, _) | (_, "/explain") => Role::ReadOnly,
+        (_, "/sessions/gc" | "/pins" | "/inject") => Role::SessionAdmin,
+        _ => Role::PeerAdmin,
+    }
+}
+
+/// Who made a request, as the [`Audit`] log records it
+#[derive(Clone)]
+struct Caller {
+    /// the name of the credential whose token the request carried, if any
+    credential: Option<String>,
+    address: SocketAddr,
+    /// the method and URI of the request
+    request: String,
+}
+
+/// Lets requests through that carry the token of their endpoint, or that of a credential whose
+/// role allows it if any are configured, within the rate limit of their credential or address
+async fn authorize(
+    State(api): State<Api>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let own_token = match path.as_str() {
+        "/inject" => api.injection.as_ref().map(|(_, token)| token),
+        "/sessions/export" => api.export_token.as_ref(),
+        _ => None,
+    };
+    let headers = request.headers();
+    let holds_own_token = own_token.is_some_and(|token| authorized(headers, token));
+    let credential = api
+        .credentials
+        .iter()
+        .find(|credential| authorized(headers, &credential.token));
+
+    if let Some(limiter) = &api.limiter {
+        let requester = match credential {
+            Some(credential) => Requester::Credential(credential.name.clone()),
+            None => Requester::Address(rate_limited(address.ip())),
+        };
+        if let Err(wait) = limiter.take(requester, Instant::now()) {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let headers = [(RETRY_AFTER, retry_after.to_string())];
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                "rate limit exceeded",
+            )
+                .into_response();
+        }
+    }
+
+    let unauthorized =
+        || (StatusCode::UNAUTHORIZED, "a valid bearer token is required").into_response();
+    match (holds_own_token, credential) {
+        (true, _) => {}
+        (false, None) if own_token.is_some() || !api.credentials.is_empty() => {
+            return unauthorized();
+        }
+        // an open API mustn't let whoever reaches it change the router, injection has its own token
+        (false, None) if required_role(&method, &path) > Role::ReadOnly && path != "/inject" => {
+            let message = format!("{method} {path} is disabled, it needs admin credentials");
+            return (StatusCode::FORBIDDEN, message).into_response();
+        }
+        (false, None) => {}
+        (false, Some(credential)) => {
+            let required = required_role(&method, &path);
+            if credential.role < required {
+                tracing::info!(
+                    "admin API refused {} {} to {}, a {} credential",
+                    method,
+                    path,
+                    credential.name,
+                    credential.role
+                );
+                let message = format!("{method} {path} requires the {required} role");
+                return (StatusCode::FORBIDDEN, message).into_response();
+            }
+        }
+    }
+
+    let caller = Caller {
+        credential: credential.map(|credential| credential.name.clone()),
+        address,
+        request: format!("{} {}", method, request.uri()),
+    };
+    request.extensions_mut().insert(caller);
+    next.run(request).await
+}
+
+/// Whose token bucket a request takes from
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Requester {
+    Credential(String),
+    /// of requests without a credential's token, see [`rate_limited`]
+    Address(IpAddr),
+}
+
+/// The address whose bucket a request without a credential takes from, the /64 for IPv6 as a
+/// client usually holds all of it
+fn rate_limited(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        ip => ip,
+    }
+}
+
+/// The token buckets of the requesters of the API, see `[admin] rate_limit`
+struct Limiter {
+    rate: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    /// requester -> tokens left and when they were counted
+    tokens: HashMap<Requester, (f64, Instant)>,
+    /// the requesters by when their tokens were counted, to forget the longest idle first
+    by_use: BTreeSet<(Instant, Requester)>,
+}
+
+impl Limiter {
+    /// Takes a token from the bucket of `requester`, or tells how long until there is one
+    fn take(&self, requester: Requester, now: Instant) -> Result<(), Duration> {
+        let burst = f64::from(self.rate.burst.max(1));
+        let refill = |tokens: f64, at: Instant| {
+            (tokens + now.duration_since(at).as_secs_f64() * self.rate.requests_per_sec).min(burst)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets {
+            tokens: all,
+            by_use,
+        } = &mut *buckets;
+        let mut tokens = match all.get(&requester) {
+            Some(&(tokens, at)) => {
+                by_use.remove(&(at, requester.clone()));
+                refill(tokens, at)
+            }
+            None => {
+                if all.len() >= MAX_BUCKETS
+                    && let Some((_, idle)) = by_use.pop_first()
+                {
+                    all.remove(&idle);
+                }
+                burst
+            }
+        };
+        let taken = if tokens >= 1.0 {
+            tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - tokens) / self.rate.requests_per_sec,
+            ))
+        };
+        by_use.insert((now, requester.clone()));
+        all.insert(requester, (tokens, now));
+        taken
+    }
+}
+
+/// The append-only record of the requests that changed the router, a line of JSON each with
+/// who made it, when, what it asked for, what it replaced and what it did
+#[derive(Default)]
+pub struct Audit {
+    file: Option<Mutex<File>>,
+}
+
+impl Audit {
+    /// Opens `path` to append to, there being no record without one
+    pub fn open(path: Option<&Path>) -> Result<Self, Error> {
+        let Some(path) = path else {
+            return Ok(Audit::default());
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| Error::WriteFile {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        tracing::info!("recording admin API changes in {}", path.display());
+        Ok(Audit {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Records that the request of `caller` replaced `previous` and did `result`
+    fn record(&self, caller: &Caller, previous: serde_json::Value, result: serde_json::Value) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let mut line = json!({
+            "at": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "credential": caller.credential,
+            "address": caller.address,
+            "request": caller.request,
+            "previous": previous,
+            "result": result,
+        })
+        .to_string();
+        line.push('\n');
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = std::io::Write::write_all(&mut *file, line.as_bytes()) {
+            tracing::error!(
+                "failed to record {} in the audit log: {}",
+                caller.request,
+                e
+            );
+        }
+    }
+}
+
+/// Whether the request carries `token` as its bearer token
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // compared by hash, so the time taken tells nothing about the token
+    given.is_some_and(|given| utils::hash(given.as_bytes()) == utils::hash(token.as_bytes()))
+}
+
+async fn inject(
+    State(api): State<Api>,
+    State(audit): State<Arc<Audit>>,
+    Extension(caller): Extension<Caller>,
+    Json(body): Json<DatagramBody>,
+) -> impl IntoResponse {
+    let Some((injector, _)) = &api.injection else {
+        let message = "packet injection is disabled, it needs an inject_token";
+        return (StatusCode::FORBIDDEN, message).into_response();
+    };
+    let datagram = match body.datagram() {
+        Ok(datagram) => datagram,
+        Err(rejection) => return rejection.into_response(),
+    };
+    tracing::info!("admin API injects a datagram as from {}", datagram.source);
+    let source = datagram.source;
+    match injector.inject(datagram).await {
+        Some(events) => {
+            let events: Vec<serde_json::Value> = events.iter().map(event_json).collect();
+            audit.record(
+                &caller,
+                serde_json::Value::Null,
+                json!({ "source": source, "events": events }),
+            );
+            Json(json!({ "events": events })).into_response()
+        }
+        None => (StatusCode::SERVICE_UNAVAILABLE, "the router stopped").into_response(),
+    }
+}
+
+fn event_json(event: &RouterEvent) -> serde_json::Value {
+    match event {
+        RouterEvent::SessionCreated {
+            client,
+            backend,
+            client_index,
+        } => json!({
+            "event": "session_created",
+            "client": client,
+            "backend": backend,
+            "client_index": client_index,
+        }),
+        RouterEvent::SessionEstablished {
+            client,
+            backend,
+            client_index,
+            backend_index,
+        } => json!({
+            "event": "session_established",
+            "client": client,
+            "backend": backend,
+            "client_index": client_index,
+            "backend_index": backend_index,
+        }),
+        RouterEvent::Forwarded {
+            message,
+            source,
+            destination,
+        } => json!({
+            "event": "forwarded",
+            "message": message_label(*message),
+            "source": source,
+            "destination": destination,
+        }),
+        RouterEvent::ExcessiveRekeys {
+            client,
+            backend,
+            handshakes,
+        } => json!({
+            "event": "excessive_rekeys",
+            "client": client,
+            "backend": backend,
+            "handshakes": handshakes,
+        }),
+        RouterEvent::BackendDown { backend } => {
+            json!({ "event": "backend_down", "backend": backend })
+        }
+        RouterEvent::BackendUp { backend } => json!({ "event": "backend_up", "backend": backend }),
+        RouterEvent::Dropped {
+            message,
+            source,
+            reason,
+        } => json!({
+            "event": "dropped",
+            "message": message.map(message_label),
+            "source": source,
+            "reason": reason.to_string(),
+        }),
+    }
+}
+
+fn explanation_json(explanation: &Explanation) -> serde_json::Value {
+    let matched: Vec<serde_json::Value> = explanation
+        .matched
+        .iter()
+        .map(|check| {
+            json!({
+                "backend": check.backend,
+                "name": check.name,
+                "sessions": check.sessions,
+                "max_sessions": check.max_sessions,
+                "maintenance": check.maintenance,
+                "state": check.state.as_str(),
+                "primary": check.primary,
+                "weight": check.weight,
+            })
+        })
+        .collect();
+    let session = explanation.session.as_ref().map(|hit| {
+        json!({
+            "index": hit.index,
+            "client": hit.client,
+            "backend": hit.backend,
+        })
+    });
+    let mut json = json!({
+        "message": explanation.message.map(message_label),
+        "session": session,
+        "matched": matched,
+        "candidates": explanation.candidates,
+        "pinned": explanation.pinned,
+        "lockdown": explanation.lockdown,
+        "sessions": explanation.sessions,
+        "max_sessions": explanation.max_sessions,
+    });
+    match &explanation.decision {
+        Decision::Forward(destination) => {
+            json["decision"] = json!("forward");
+            json["destination"] = json!(destination);
+        }
+        Decision::Race(backends) => {
+            json["decision"] = json!("race");
+            json["destinations"] = json!(backends);
+        }
+        Decision::Drop(reason) => {
+            json["decision"] = json!("drop");
+            json["reason"] = json!(reason.to_string());
+        }
+    }
+    json
+}
+
+/// Keeps the last [`RECENT_DROPS`] drops among `events` until the router stops
+async fn record_drops(
+    mut events: broadcast::Receiver<RouterEvent>,
+    drops: Arc<Mutex<VecDeque<Drop>>>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(RouterEvent::Dropped {
+                message,
+                source,
+                reason,
+            }) => {
+                let mut drops = drops.lock().unwrap();
+                drops.push_front(Drop {
+                    at: Instant::now(),
+                    message,
+                    source,
+                    reason,
+                });
+                drops.truncate(RECENT_DROPS);
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn status_page(State(api): State<Api>) -> Html<String> {
+    let snapshot = api.metrics.snapshot();
+    let names = api.metrics.peer_names();
+    let rates = api.metrics.backend_rates();
+    let rtts = api.metrics.handshake_rtts();
+    let losses = api.metrics.handshake_loss();
+    let states = api.health.states();
+    let clients = api.sessions.clients(None).await;
+    let mut sessions: BTreeMap<SocketAddr, usize> = BTreeMap::new();
+    for client in &clients {
+        *sessions.entry(client.backend).or_default() += client.sessions;
+    }
+    for backend in names.keys().chain(rates.keys()).chain(states.keys()) {
+        sessions.entry(*backend).or_default();
+    }
+    let checksum = config_checksum();
+
+    let mut body = String::new();
+    let _ = write!(
+        body,
+        "<!doctype html><html><head><meta charset=\"utf-8\"><title>wireguard-router</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         td,th{{border:1px solid #ccc;padding:.2em .6em;text-align:left}}\
+         .down{{color:#b00;font-weight:bold}}</style></head><body><h1>wireguard-router</h1>\
+         <p>config {}</p>",
+        escape_html(&checksum)
+    );
+    let _ = write!(body, "<h2>Listeners</h2><ul>");
+    for address in &api.listeners {
+        let _ = write!(body, "<li>{address}</li>");
+    }
+    let _ = write!(
+        body,
+        "</ul><h2>Traffic</h2><table><tr><th>sessions</th><th>received</th><th>forwarded</th>\
+         <th>dropped</th></tr><tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr></table>",
+        api.sessions.count().await,
+        snapshot.received,
+        snapshot.forwarded,
+        snapshot.dropped
+    );
+    let _ = write!(
+        body,
+        "<h2>Backends</h2><table><tr><th>backend</th><th>peer</th><th>state</th><th>sessions</th>\
+         <th>handshake RTT</th><th>handshake loss</th><th>packets/s</th></tr>"
+    );
+    for (backend, count) in &sessions {
+        let name = names.get(backend).map_or("", String::as_str);
+        let state = match states.get(backend).copied().unwrap_or(BackendState::Up) {
+            BackendState::Up => "<td>up</td>".to_string(),
+            state => format!("<td class=\"down\">{}</td>", state.as_str()),
+        };
+        let rtt = rtts.get(backend).map_or(String::new(), |rtt| {
+            format!("{:.1} ms", rtt.as_secs_f64() * 1000.0)
+        });
+        let loss = losses
+            .get(backend)
+            .map_or(String::new(), |loss| format!("{:.0}%", loss * 100.0));
+        let packets = rates
+            .get(backend)
+            .map_or(0.0, |rates| rates.packets_per_sec);
+        let _ = write!(
+            body,
+            "<tr><td>{backend}</td><td>{}</td>{state}<td>{count}</td><td>{rtt}</td><td>{loss}</td><td>{packets:.1}</td></tr>",
+            escape_html(name)
+        );
+    }
+    let _ = write!(
+        body,
+        "</table><h2>Recent drops</h2><table><tr><th>seconds ago</th><th>source</th>\
+         <th>message</th><th>reason</th></tr>"
+    );
+    for drop in api.drops.lock().unwrap().iter() {
+        let message = drop
+            .message
+            .map_or(String::new(), |message| format!("{message:?}"));
+        let _ = write!(
+            body,
+            "<tr><td>{}</td><td>{}</td><td>{message}</td><td>{}</td></tr>",
+            drop.at.elapsed().as_secs(),
+            drop.source,
+            escape_html(&drop.reason.to_string())
+        );
+    }
+    let _ = write!(body, "</table></body></html>");
+    Html(body)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
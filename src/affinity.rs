@@ -0,0 +1,131 @@
+/*
+* affinity.rs remembers which backend each client was routed to, so it returns there after a new handshake
+*/
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Length of the IPv6 prefix clients are identified by, as their addresses change within it
+pub const V6_PREFIX: u8 = 56;
+
+/// The backend a client, identified by its IP or IPv6 prefix, was last routed to
+///
+/// Unlike sessions this survives restarts, through [`AffinityTable::entries`] and
+/// [`AffinityTable::restore`], so clients reach the backend still holding their state.
+#[derive(Debug)]
+pub struct AffinityTable {
+    ttl: Duration,
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    backend: SocketAddr,
+    /// when a session of the client was last routed to the backend
+    used: SystemTime,
+}
+
+/// One entry of an [`AffinityTable`], in the form it is stored in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Affinity {
+    /// the client's IPv4 address, or the network address of its IPv6 prefix
+    pub client: IpAddr,
+    pub backend: SocketAddr,
+    /// seconds since the Unix epoch
+    pub used: u64,
+}
+
+/// The key of `client` in the table, its address or that of its IPv6 prefix
+fn key(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX << (128 - V6_PREFIX);
+            IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & mask))
+        }
+        ip => ip,
+    }
+}
+
+impl AffinityTable {
+    /// Forgets clients that had no new session for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        AffinityTable {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// The backend `client` was last routed to within the TTL
+    pub fn get(&self, client: IpAddr) -> Option<SocketAddr> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key(client))?;
+        self.is_live(entry, SystemTime::now())
+            .then_some(entry.backend)
+    }
+
+    /// Records that a session of `client` was routed to `backend`
+    pub fn record(&self, client: IpAddr, backend: SocketAddr) {
+        let entry = Entry {
+            backend,
+            used: SystemTime::now(),
+        };
+        self.entries.lock().unwrap().insert(key(client), entry);
+    }
+
+    /// Approximate bytes held by the entries, including those past the TTL not forgotten yet
+    pub fn footprint(&self) -> usize {
+        self.entries.lock().unwrap().len() * size_of::<(IpAddr, Entry)>()
+    }
+
+    /// The entries within the TTL, forgetting the others
+    pub fn entries(&self) -> Vec<Affinity> {
+        let now = SystemTime::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| self.is_live(entry, now));
+        entries
+            .iter()
+            .map(|(client, entry)| Affinity {
+                client: *client,
+                backend: entry.backend,
+                used: entry
+                    .used
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect()
+    }
+
+    /// Adds `entries`, e.g. stored before a restart, unless a client already has a newer one
+    pub fn restore(&self, restored: &[Affinity]) {
+        let now = SystemTime::now();
+        let mut entries = self.entries.lock().unwrap();
+        for affinity in restored {
+            let entry = Entry {
+                backend: affinity.backend,
+                used: UNIX_EPOCH + Duration::from_secs(affinity.used),
+            };
+            if !self.is_live(&entry, now) {
+                continue;
+            }
+            let key = key(affinity.client);
+            if entries
+                .get(&key)
+                .is_none_or(|current| current.used < entry.used)
+            {
+                entries.insert(key, entry);
+            }
+        }
+    }
+
+    fn is_live(&self, entry: &Entry, now: SystemTime) -> bool {
+        now.duration_since(entry.used)
+            .is_ok_and(|age| age < self.ttl)
+            // clocks may be set back
+            || entry.used > now
+    }
+}
@@ -0,0 +1,301 @@
+/*
+* alarm.rs watches the router's traffic for anomalies, warning about them in the log and through a webhook
+*/
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::event::{DropReason, RouterEvent};
+use crate::router::SessionTable;
+
+/// How often the detectors are evaluated
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Webhook requests taking longer are abandoned
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Thresholds of the built-in detectors
+///
+/// A detector is disabled by a threshold it can't exceed, e.g. `session_drop_percent = 100`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// URL every alarm firing or resolving is POSTed to as JSON
+    pub webhook: Option<String>,
+    /// fires when the number of sessions fell by this many percent within `window_minutes`
+    pub session_drop_percent: f64,
+    /// fires when this share of initiations routed within `window_minutes` went unanswered
+    pub handshake_failure_ratio: f64,
+    /// initiations needed within `window_minutes` before `handshake_failure_ratio` is judged
+    pub min_handshakes: u64,
+    /// fires when more packets than this failed to be sent within a minute
+    pub forward_errors_per_min: u64,
+    /// fires when more client tunnels than this started rekeying excessively within
+    /// `window_minutes`, see `Router::rekey_threshold`
+    pub excessive_rekeys: u64,
+    pub window_minutes: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            webhook: None,
+            session_drop_percent: 50.0,
+            handshake_failure_ratio: 0.5,
+            min_handshakes: 20,
+            forward_errors_per_min: 100,
+            excessive_rekeys: 10,
+            window_minutes: 5,
+        }
+    }
+}
+
+/// What a detector watches for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    SessionDrop,
+    HandshakeFailures,
+    ForwardErrors,
+    ExcessiveRekeys,
+}
+
+/// An alarm firing, or resolving once its condition no longer holds
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alarm {
+    pub kind: Kind,
+    pub firing: bool,
+    /// the measurement that crossed the threshold, or fell back below it
+    pub value: f64,
+    pub threshold: f64,
+    pub message: String,
+}
+
+/// The state of the detectors, fed with router events and session counts
+///
+/// Time is passed in, so the detectors can be driven without waiting.
+pub struct Detectors {
+    config: Config,
+    window: Duration,
+    /// (when, session count), covering the window
+    sessions: VecDeque<(Instant, usize)>,
+    /// when initiations were routed to a new backend
+    created: VecDeque<Instant>,
+    /// when backends answered an initiation
+    established: VecDeque<Instant>,
+    /// when sending a packet failed, covering a minute
+    send_failures: VecDeque<Instant>,
+    /// when client tunnels started rekeying excessively
+    rekeys: VecDeque<(Instant, SocketAddr)>,
+    firing: Vec<Kind>,
+}
+
+impl Detectors {
+    pub fn new(config: Config) -> Self {
+        Detectors {
+            window: Duration::from_secs(config.window_minutes.max(1) * 60),
+            config,
+            sessions: VecDeque::new(),
+            created: VecDeque::new(),
+            established: VecDeque::new(),
+            send_failures: VecDeque::new(),
+            rekeys: VecDeque::new(),
+            firing: Vec::new(),
+        }
+    }
+
+    /// Takes note of a routing outcome at `now`
+    pub fn observe(&mut self, event: &RouterEvent, now: Instant) {
+        match event {
+            RouterEvent::SessionCreated { .. } => self.created.push_back(now),
+            RouterEvent::SessionEstablished { .. } => self.established.push_back(now),
+            RouterEvent::Dropped {
+                reason: DropReason::SendFailed { .. },
+                ..
+            } => self.send_failures.push_back(now),
+            RouterEvent::ExcessiveRekeys { client, .. } => self.rekeys.push_back((now, *client)),
+            _ => {}
+        }
+    }
+
+    /// Evaluates every detector with `sessions` tracked at `now`, returning the alarms that
+    /// started or stopped firing
+    pub fn check(&mut self, sessions: usize, now: Instant) -> Vec<Alarm> {
+        let window_start = now.checked_sub(self.window).unwrap_or(now);
+        let minute_start = now.checked_sub(Duration::from_secs(60)).unwrap_or(now);
+        self.sessions.push_back((now, sessions));
+        while self
+            .sessions
+            .front()
+            .is_some_and(|(at, _)| *at < window_start)
+        {
+            self.sessions.pop_front();
+        }
+        for times in [&mut self.created, &mut self.established] {
+            while times.front().is_some_and(|at| *at < window_start) {
+                times.pop_front();
+            }
+        }
+        while self
+            .rekeys
+            .front()
+            .is_some_and(|(at, _)| *at < window_start)
+        {
+            self.rekeys.pop_front();
+        }
+        while self
+            .send_failures
+            .front()
+            .is_some_and(|at| *at < minute_start)
+        {
+            self.send_failures.pop_front();
+        }
+
+        let mut alarms = Vec::new();
+        let peak = self.sessions.iter().map(|(_, count)| *count).max();
+        let dropped = match peak {
+            Some(peak) if peak > 0 => (1.0 - sessions as f64 / peak as f64) * 100.0,
+            _ => 0.0,
+        };
+        let message = format!(
+            "sessions dropped by {:.0}% within {} minutes, to {}",
+            dropped,
+            self.window.as_secs() / 60,
+            sessions
+        );
+        let threshold = self.config.session_drop_percent;
+        self.transition(Kind::SessionDrop, dropped, threshold, message, &mut alarms);
+
+        let created = self.created.len();
+        let failed = created.saturating_sub(self.established.len());
+        let failures = match created as u64 >= self.config.min_handshakes.max(1) {
+            true => failed as f64 / created as f64,
+            false => 0.0,
+        };
+        let message = format!(
+            "{} of {} initiations within {} minutes went unanswered",
+            failed,
+            created,
+            self.window.as_secs() / 60
+        );
+        let threshold = self.config.handshake_failure_ratio;
+        self.transition(
+            Kind::HandshakeFailures,
+            failures,
+            threshold,
+            message,
+            &mut alarms,
+        );
+
+        let errors = self.send_failures.len();
+        let message = format!("{} packets failed to be sent within a minute", errors);
+        let threshold = self.config.forward_errors_per_min as f64;
+        self.transition(
+            Kind::ForwardErrors,
+            errors as f64,
+            threshold,
+            message,
+            &mut alarms,
+        );
+
+        let rekeys = self.rekeys.len();
+        let latest = self
+            .rekeys
+            .back()
+            .map_or(String::new(), |(_, client)| format!(", latest {client}"));
+        let message = format!(
+            "{} client tunnels started rekeying excessively within {} minutes{}",
+            rekeys,
+            self.window.as_secs() / 60,
+            latest
+        );
+        let threshold = self.config.excessive_rekeys as f64;
+        self.transition(
+            Kind::ExcessiveRekeys,
+            rekeys as f64,
+            threshold,
+            message,
+            &mut alarms,
+        );
+        alarms
+    }
+
+    /// Adds an alarm to `alarms` if `value` crossed `threshold` since the last check
+    fn transition(
+        &mut self,
+        kind: Kind,
+        value: f64,
+        threshold: f64,
+        message: String,
+        alarms: &mut Vec<Alarm>,
+    ) {
+        let firing = value > threshold;
+        if firing == self.firing.contains(&kind) {
+            return;
+        }
+        match firing {
+            true => self.firing.push(kind),
+            false => self.firing.retain(|k| *k != kind),
+        }
+        alarms.push(Alarm {
+            kind,
+            firing,
+            value,
+            threshold,
+            message,
+        });
+    }
+}
+
+/// Runs the detectors on the router's `events` and `sessions` until the router stops
+///
+/// Alarms are logged as warnings when they fire and as info when they resolve, and POSTed to the
+/// webhook if one is configured. Failed webhook requests are logged and not retried.
+pub async fn watch(
+    config: Config,
+    mut events: broadcast::Receiver<RouterEvent>,
+    sessions: SessionTable,
+) {
+    let webhook = config.webhook.clone().and_then(|url| {
+        match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+            Ok(client) => Some((client, url)),
+            Err(e) => {
+                tracing::error!("alarm webhooks are disabled: {}", e);
+                None
+            }
+        }
+    });
+    let mut detectors = Detectors::new(config);
+    let mut checks = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => detectors.observe(&event, Instant::now()),
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::debug!("alarm detectors missed {} events", missed);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = checks.tick() => {
+                let count = sessions.count().await;
+                for alarm in detectors.check(count, Instant::now()) {
+                    match alarm.firing {
+                        true => tracing::warn!("alarm {:?} firing: {}", alarm.kind, alarm.message),
+                        false => tracing::info!("alarm {:?} resolved: {}", alarm.kind, alarm.message),
+                    }
+                    if let Some((client, url)) = &webhook {
+                        let request = client.post(url).json(&alarm);
+                        tokio::spawn(async move {
+                            if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                                tracing::warn!("failed to send alarm to its webhook: {}", e);
+                            }
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
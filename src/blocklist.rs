@@ -0,0 +1,137 @@
+/*
+* blocklist.rs fetches IP blocklists, e.g. threat intelligence feeds, into the router's denylist
+*/
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::denylist::{Denylist, Network};
+use crate::error::{Error, Report};
+
+/// Requests taking longer are abandoned, the feed keeping its previous networks
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The feeds and how often they are fetched
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    pub feeds: Vec<Feed>,
+    /// seconds between fetches of each feed
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+    60 * 60
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+    /// HTTP(S) URL of the list
+    pub url: String,
+    /// how the list is parsed, told from its content type or first character if unset
+    pub format: Option<Format>,
+}
+
+impl Feed {
+    /// The source of its networks in the [`Denylist`]
+    pub fn source(&self) -> String {
+        format!("feed:{}", self.url)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// an address or CIDR per line, ignoring `#` and `;` comments and whatever follows it on
+    /// the line, as in Spamhaus DROP or FireHOL lists
+    Plain,
+    /// an array of addresses or CIDRs, or of objects with one in their `cidr`, `network` or
+    /// `ip` field
+    Json,
+}
+
+/// Parses a list in `format`, failing on the first entry that is no address or CIDR
+pub fn parse(list: &str, format: Format) -> Result<Vec<Network>, String> {
+    match format {
+        Format::Plain => list
+            .lines()
+            .map(|line| line.split(['#', ';']).next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.split_whitespace().next().unwrap_or_default().parse())
+            .collect(),
+        Format::Json => {
+            let entries: Vec<serde_json::Value> =
+                serde_json::from_str(list).map_err(|e| e.to_string())?;
+            entries
+                .iter()
+                .map(|entry| {
+                    let network = match entry {
+                        serde_json::Value::Object(fields) => ["cidr", "network", "ip"]
+                            .iter()
+                            .find_map(|field| fields.get(*field)?.as_str()),
+                        entry => entry.as_str(),
+                    };
+                    network
+                        .ok_or_else(|| format!("expected an address or CIDR, got {entry}"))?
+                        .parse()
+                })
+                .collect()
+        }
+    }
+}
+
+/// Fetches every feed of `config` right away and then every interval, replacing its networks in
+/// `denylist` once it was fetched and parsed as a whole
+///
+/// A feed that can't be fetched or parsed keeps its previous networks, with a warning.
+pub async fn refresh(config: Config, denylist: Denylist) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("not fetching blocklists: {}", e);
+            return;
+        }
+    };
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        for feed in &config.feeds {
+            match fetch(&client, feed).await {
+                Ok(networks) => {
+                    tracing::debug!("blocklist {} has {} networks", feed.url, networks.len());
+                    denylist.set(&feed.source(), networks);
+                }
+                Err(e) => tracing::warn!("keeping the previous blocklist: {}", Report(&e)),
+            }
+        }
+    }
+}
+
+/// The networks of `feed`
+async fn fetch(client: &reqwest::Client, feed: &Feed) -> Result<Vec<Network>, Error> {
+    let fetch_error = |source: Box<dyn std::error::Error + Send + Sync>| Error::BlocklistFetch {
+        url: feed.url.clone(),
+        source,
+    };
+    let response = client
+        .get(&feed.url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| fetch_error(e.into()))?;
+    let json = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("json"));
+    let list = response.text().await.map_err(|e| fetch_error(e.into()))?;
+    let format = feed.format.unwrap_or({
+        match json || list.trim_start().starts_with('[') {
+            true => Format::Json,
+            false => Format::Plain,
+        }
+    });
+    parse(&list, format).map_err(|e| fetch_error(e.into()))
+}
@@ -0,0 +1,112 @@
+/*
+* chaos.rs injects faults into the packets a router forwards, so clients, backends and alerting
+* can be tested against a misbehaving router
+*/
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+
+use crate::packet::{Identity, MessageType};
+
+/// Faults injected into every packet a router forwards, see
+/// [`RouterBuilder::chaos`](crate::router::RouterBuilder::chaos)
+///
+/// Shares are between 0 and 1, and the faults add up: a packet that isn't dropped may be
+/// duplicated, and both copies delayed and reordered.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Chaos {
+    /// share of the packets dropped
+    pub drop: f64,
+    /// share of the packets sent twice
+    pub duplicate: f64,
+    /// share of the packets held back by up to `reorder_ms` more, so later ones overtake them
+    pub reorder: f64,
+    pub reorder_ms: u64,
+    /// delay added to every packet
+    pub latency_ms: u64,
+    /// random delay of up to this much added on top of the latency
+    pub jitter_ms: u64,
+}
+
+/// What becomes of a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fate {
+    Drop,
+    /// sent this many times after the delay
+    Send {
+        copies: usize,
+        delay: Duration,
+    },
+}
+
+impl Chaos {
+    /// Draws the fate of the next packet
+    pub(crate) fn fate(&self) -> Fate {
+        if chance(self.drop) {
+            return Fate::Drop;
+        }
+        let copies = match chance(self.duplicate) {
+            true => 2,
+            false => 1,
+        };
+        let mut delay = Duration::from_millis(self.latency_ms + up_to(self.jitter_ms));
+        if chance(self.reorder) {
+            delay += Duration::from_millis(up_to(self.reorder_ms).max(1));
+        }
+        Fate::Send { copies, delay }
+    }
+}
+
+fn chance(share: f64) -> bool {
+    share > 0.0 && (OsRng.next_u32() as f64 / u32::MAX as f64) < share
+}
+
+fn up_to(ms: u64) -> u64 {
+    match ms {
+        0 => 0,
+        ms => OsRng.next_u64() % (ms + 1),
+    }
+}
+
+/// A packet held back, with what it takes to forward it later
+pub(crate) struct Delayed {
+    pub(crate) message: MessageType,
+    pub(crate) source: SocketAddr,
+    pub(crate) destination: SocketAddr,
+    pub(crate) identity: Identity,
+    pub(crate) backend: SocketAddr,
+    pub(crate) copies: usize,
+    pub(crate) packet: Vec<u8>,
+}
+
+/// The packets held back, by when they are due and then in the order they were held back
+#[derive(Default)]
+pub(crate) struct DelayQueue {
+    packets: BTreeMap<(Instant, u64), Delayed>,
+    held: u64,
+}
+
+impl DelayQueue {
+    pub(crate) fn push(&mut self, due: Instant, packet: Delayed) {
+        self.held += 1;
+        self.packets.insert((due, self.held), packet);
+    }
+
+    /// When the next packet is due
+    pub(crate) fn next(&self) -> Option<Instant> {
+        self.packets.first_key_value().map(|((due, _), _)| *due)
+    }
+
+    /// Takes the packets due by `now`, the earliest first
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<Delayed> {
+        let later = self.packets.split_off(&(now, u64::MAX));
+        std::mem::replace(&mut self.packets, later)
+            .into_values()
+            .collect()
+    }
+}
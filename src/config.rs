@@ -1,39 +1,1399 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
 use std::sync::{OnceLock, RwLock};
 
-use config::File;
+use config::{Environment, File, FileFormat};
+use notify::Event;
 use serde::Deserialize;
-use wireguard_router::Peer;
+use wireguard_router::discovery::PeerSet;
+use wireguard_router::error::{Error, Report};
+use wireguard_router::router::Horizon;
+use wireguard_router::{Peer, PeerConfig};
+
+pub const PATH: &str = "config.toml";
+/// Config path standing for stdin
+pub const STDIN: &str = "-";
+
+/// Prefix of environment variables overriding config values, e.g. `WG_ROUTER_ROUTER__MAX_SESSIONS`
+const ENV_PREFIX: &str = "WG_ROUTER";
+/// Listen addresses, separated by commas
+pub const LISTEN_ENV: &str = "WG_ROUTER_LISTEN";
+/// Disables reloading the config, if set to a true value
+pub const NO_WATCH_ENV: &str = "WG_ROUTER_NO_WATCH";
+/// Peers in addition to the config file's, as a JSON array or CSV lines of `address,pubkey[,proxy[,name]]`
+pub const PEERS_ENV: &str = "WG_ROUTER_PEERS";
+/// File with peers in the format of [`PEERS_ENV`], e.g. a mounted secret
+pub const PEERS_FILE_ENV: &str = "WG_ROUTER_PEERS_FILE";
+/// URL of a config document layered over the config file, see [`Remote`]
+pub const URL_ENV: &str = "WG_ROUTER_CONFIG_URL";
+/// Seconds between checks of [`URL_ENV`] and [`PEER_SYNC_URL_ENV`] for changes, 60 by default
+pub const URL_INTERVAL_ENV: &str = "WG_ROUTER_CONFIG_INTERVAL_SECS";
+/// Endpoint of a control plane serving peers in the protocol of [`PeerSync`]
+pub const PEER_SYNC_URL_ENV: &str = "WG_ROUTER_PEER_SYNC_URL";
+/// Bearer token authenticating to [`PEER_SYNC_URL_ENV`]
+pub const PEER_SYNC_TOKEN_ENV: &str = "WG_ROUTER_PEER_SYNC_TOKEN";
+/// Base64 ed25519 public key which config payloads must be signed with, if set
+pub const KEY_ENV: &str = "WG_ROUTER_CONFIG_KEY";
+/// Appended to the path or URL of a payload to get that of its detached signature
+#[cfg(feature = "signed-config")]
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+static CONFIG: OnceLock<RwLock<Config>> = OnceLock::new();
+/// The config file set with [`set_path`], if not [`PATH`]
+static FILE: OnceLock<PathBuf> = OnceLock::new();
+/// The config read from stdin, which replaces the file
+static STDIN_CONFIG: OnceLock<(String, FileFormat)> = OnceLock::new();
+/// `key=value` pairs from the command line, overriding every other source
+static OVERRIDES: OnceLock<Vec<(String, String)>> = OnceLock::new();
+/// The last accepted document of the [`Remote`] config
+#[cfg(feature = "remote-config")]
+static REMOTE: RwLock<Option<(String, FileFormat)>> = RwLock::new(None);
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub peers: Vec<Peer>,
+    #[serde(default)]
+    pub router: RouterConfig,
+    /// User to switch to once the listeners are bound, only read on startup
+    pub user: Option<String>,
+    /// Group to switch to once the listeners are bound, defaults to the user's primary group
+    pub group: Option<String>,
+    /// Confines the router to reading the config and routing once it is initialized
+    #[cfg(feature = "sandbox")]
+    #[serde(default)]
+    pub sandbox: bool,
+    #[cfg(feature = "wasm-plugin")]
+    pub wasm_policy: Option<WasmPolicyConfig>,
+    /// Peers whose backends are the healthy instances of a Consul service, only read on startup
+    #[cfg(feature = "consul")]
+    #[serde(default)]
+    pub consul: Vec<wireguard_router::discovery::consul::Service>,
+    /// Peers whose backends are the targets of an SRV record, only read on startup
+    #[cfg(feature = "dns")]
+    #[serde(default)]
+    pub dns: Vec<wireguard_router::discovery::dns::Service>,
+    /// Registers labeled containers of the local Docker daemon as backends, only read on startup
+    #[cfg(feature = "docker")]
+    pub docker: Option<wireguard_router::discovery::docker::Daemon>,
+    /// Key prefixes in etcd holding further peers, only read on startup
+    #[cfg(feature = "etcd")]
+    #[serde(default)]
+    pub etcd: Vec<wireguard_router::discovery::etcd::Prefix>,
+    /// Peers whose backends are the ready endpoints of a Service, only read on startup
+    #[cfg(feature = "kubernetes")]
+    #[serde(default)]
+    pub kubernetes: Vec<wireguard_router::discovery::kubernetes::Service>,
+    /// Lua script defining routing hooks, see `wireguard_router::policy::lua`
+    #[cfg(feature = "lua")]
+    pub lua_script: Option<std::path::PathBuf>,
+    /// Wall time budget per Lua hook call
+    #[cfg(feature = "lua")]
+    #[serde(default = "default_lua_budget_ms")]
+    pub lua_budget_ms: u64,
+    /// HTTP admin API, only read on startup
+    #[cfg(feature = "admin")]
+    pub admin: Option<AdminConfig>,
+    /// Sibling router whose sessions are taken over before routing, only read on startup
+    #[cfg(feature = "admin")]
+    pub warm_start: Option<WarmStartConfig>,
+    /// SNMP agent, only read on startup
+    #[cfg(feature = "snmp")]
+    pub snmp: Option<wireguard_router::snmp::Config>,
+    /// MQTT broker stats and backend health are published to, only read on startup
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<wireguard_router::mqtt::Config>,
+    /// Blocklist feeds refreshed into the denylist, only read on startup
+    #[cfg(feature = "blocklists")]
+    pub blocklists: Option<wireguard_router::blocklist::Config>,
+    /// CrowdSec Local API whose bans are enforced, only read on startup
+    #[cfg(feature = "crowdsec")]
+    pub crowdsec: Option<wireguard_router::crowdsec::Config>,
+    /// Outbound WireGuard tunnels peers are carried in, named by their `tunnel`, only read on startup
+    #[cfg(feature = "tunnel")]
+    #[serde(default)]
+    pub tunnels: Vec<wireguard_router::tunnel::Config>,
+    /// QUIC links to other routers, for peers with `quic = true`, only read on startup
+    #[cfg(feature = "quic")]
+    pub quic: Option<wireguard_router::quic::Config>,
+    /// Where the cumulative counters are checkpointed, only read on startup
+    pub counters: Option<CountersConfig>,
+    /// Peers reachable only through one local address, see `wireguard_router::router::Horizon`,
+    /// only read on startup
+    #[serde(default)]
+    pub listeners: Vec<Horizon>,
+    /// Where initiations matching no peer are routed, only read on startup
+    pub honeypot: Option<wireguard_router::router::Honeypot>,
+    /// Anomaly detectors, only read on startup
+    #[cfg(feature = "alarms")]
+    pub alarms: Option<wireguard_router::alarm::Config>,
+    /// Where session and handshake events are exported to, only read on startup
+    #[cfg(feature = "export")]
+    pub export: Option<wireguard_router::export::Config>,
+    /// Routes clients back to their last backend, only read on startup
+    pub affinity: Option<AffinityConfig>,
+    /// When backends are considered down after failed sends, only read on startup
+    pub health: Option<wireguard_router::health::Thresholds>,
+    /// Ejects backends whose handshake loss stands out in their pool, only read on startup
+    pub outliers: Option<wireguard_router::health::OutlierDetection>,
+    /// A periodic summary line in the log, only read on startup
+    pub stats: Option<StatsConfig>,
+    /// A syslog server the log is sent to besides stderr, only read on startup
+    pub syslog: Option<SyslogConfig>,
+    /// Faults injected into the packets forwarded, refused unless the router is started with
+    /// `--chaos`, only read on startup
+    pub chaos: Option<wireguard_router::chaos::Chaos>,
+    /// Hex BLAKE2s hash of the [`effective`] config, identifying the revision in use
+    #[serde(skip)]
+    pub checksum: String,
+}
+
+/// Tunables of the router, unset values keep the library defaults
+///
+/// These are only read on startup.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RouterConfig {
+    pub buffer_size: Option<usize>,
+    /// Longest datagram routed, longer ones being dropped before they are parsed
+    pub max_datagram_size: Option<usize>,
+    /// Panics handling datagrams after which the router exits, it drops them and routes on if unset
+    pub max_panics: Option<u64>,
+    /// Datagrams read at once, handshakes among them being handled first
+    pub batch_size: Option<usize>,
+    /// What becomes of datagrams read while the batch is full, per kind of message
+    pub backpressure: Option<wireguard_router::router::BackpressurePolicy>,
+    /// Shares the transport data of a batch fairly between sessions, see
+    /// `wireguard_router::router::RouterBuilder::fair_queuing`
+    #[serde(default)]
+    pub fair_queuing: bool,
+    pub max_sessions: Option<usize>,
+    /// Idle time after which sessions that carried transport data are forgotten
+    pub session_timeout_secs: Option<u64>,
+    /// Idle time after which sessions that carried no transport data yet are forgotten
+    pub handshake_timeout_secs: Option<u64>,
+    /// Handshakes of a client's tunnel within 2 minutes beyond which it rekeys excessively
+    pub rekey_threshold: Option<usize>,
+    /// Sessions whose recent events are kept for the admin API, 0 turning timelines off
+    pub timelines: Option<usize>,
+    /// Approximate memory the sessions, timelines, affinity and queues are kept below, see
+    /// `wireguard_router::router::RouterBuilder::memory_limit`
+    pub memory_limit_mb: Option<usize>,
+    pub unmatched_data: Option<wireguard_router::router::UnmatchedData>,
+    /// Sends to each backend through a socket of its own, see
+    /// `wireguard_router::transport::Listeners::connect_backends`
+    #[serde(default)]
+    pub connect_backends: bool,
+    /// The port sent to backends from, see `wireguard_router::transport::SourcePort`
+    #[serde(default)]
+    pub source_port: wireguard_router::transport::SourcePort,
+    /// Sockets opened with `source_port = "per_session"` at most, see
+    /// `wireguard_router::transport::Listeners::max_session_sockets`
+    pub max_session_sockets: Option<usize>,
+    /// fwmark of every datagram sent, see `wireguard_router::transport::Listeners::fwmark`
+    pub fwmark: Option<u32>,
+    /// Network namespace entered before binding, see `wireguard_router::transport::enter_netns`
+    pub netns: Option<String>,
+    /// Device every socket is bound to, e.g. a VRF, see
+    /// `wireguard_router::transport::Listeners::bind_to_device`
+    pub bind_device: Option<String>,
+    /// How a session picks among the backends of a peer, replaced by `lua_script` and `wasm_policy`
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Clients routed to one backend only, see `wireguard_router::router::Router::pins`
+    #[serde(default)]
+    pub pins: Vec<Pin>,
+    /// Addresses and CIDRs whose datagrams are dropped, see
+    /// `wireguard_router::router::Router::denylist`
+    #[serde(default)]
+    pub deny: Vec<wireguard_router::denylist::Network>,
+    /// IANA time zone the cron expressions of maintenance windows and schedule rules are read in,
+    /// e.g. `Europe/Berlin`, the local time zone if unset
+    pub timezone: Option<chrono_tz::Tz>,
+    /// ICMP port unreachable messages per second answering datagrams that are no WireGuard or
+    /// for no known backend, none are sent if unset
+    #[cfg(feature = "icmp")]
+    pub icmp_unreachable_per_sec: Option<u32>,
 }
 
-pub fn settings() -> &'static RwLock<Config> {
-    static CONFIG: OnceLock<RwLock<Config>> = OnceLock::new();
-    CONFIG.get_or_init(|| {
-        let settings = load();
+/// A client pinned to a backend from the start
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin {
+    pub client: std::net::IpAddr,
+    pub backend: std::net::SocketAddr,
+}
+
+/// The built-in routing policies
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// the first backend in config order, see `wireguard_router::policy::FirstMatch`
+    #[default]
+    FirstMatch,
+    /// the backend with the lowest recent handshake RTT, see `wireguard_router::policy::LowestLatency`
+    LowestLatency,
+    /// the backend with the fewest sessions, see `wireguard_router::policy::LeastSessions`
+    LeastSessions,
+    /// whichever backend responds first to the initiation forwarded to all of them, see
+    /// `wireguard_router::router::RouterBuilder::race_initiations`
+    FastestResponder,
+}
 
-        RwLock::new(settings)
+#[cfg(feature = "admin")]
+#[derive(Deserialize, Debug, Clone)]
+pub struct AdminConfig {
+    /// e.g. `127.0.0.1:51338`, the API is unauthenticated without `credentials` so this
+    /// shouldn't be public then, though it only serves the endpoints reading
+    pub listen: std::net::SocketAddr,
+    /// Which dimensions the series of `/metrics` are broken down by
+    #[serde(default)]
+    pub labels: MetricLabels,
+    /// Bearer token `POST /inject` requires, which is disabled without one
+    pub inject_token: Option<String>,
+    /// Bearer token `GET /sessions/export` requires, e.g. from the routers warm-starting from this
+    /// one, which is open without one
+    pub export_token: Option<String>,
+    /// Serves CPU profiles at `GET /debug/pprof/profile`, which needs `credentials` and a Unix
+    /// build with the `profiling` feature
+    #[serde(default)]
+    pub profiling: bool,
+    /// Bearer tokens every request then requires one of, each allowing the endpoints of its role
+    #[serde(default)]
+    pub credentials: Vec<Credential>,
+    /// Requests each credential, or each address without credentials, may make
+    pub rate_limit: Option<RateLimit>,
+    /// File every request changing the router is appended to, as a line of JSON
+    pub audit_log: Option<PathBuf>,
+}
+
+/// A token bucket of admin API requests
+#[cfg(feature = "admin")]
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// requests per second in the long run
+    #[serde(default = "default_admin_requests_per_sec")]
+    pub requests_per_sec: f64,
+    /// requests made at once after a pause
+    #[serde(default = "default_admin_burst")]
+    pub burst: u32,
+}
+
+#[cfg(feature = "admin")]
+fn default_admin_requests_per_sec() -> f64 {
+    10.0
+}
+
+#[cfg(feature = "admin")]
+fn default_admin_burst() -> u32 {
+    20
+}
+
+/// A named bearer token of the admin API
+#[cfg(feature = "admin")]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Credential {
+    /// who uses the token, e.g. `grafana`, named in logs
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+}
+
+/// What a [`Credential`] allows, each role allowing what the ones before it do
+#[cfg(feature = "admin")]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// reading metrics, sessions and status, and explaining packets
+    ReadOnly,
+    /// also collecting garbage, pinning clients and injecting packets
+    SessionAdmin,
+    /// also pausing new tunnels to the peers
+    PeerAdmin,
+}
+
+#[cfg(feature = "admin")]
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::ReadOnly => "read_only",
+            Role::SessionAdmin => "session_admin",
+            Role::PeerAdmin => "peer_admin",
+        })
+    }
+}
+
+/// The admin API of a sibling router, e.g. behind the same ECMP route, whose sessions a router
+/// takes over on startup, see `crate::sync`
+#[cfg(feature = "admin")]
+#[derive(Deserialize, Debug, Clone)]
+pub struct WarmStartConfig {
+    /// base URL of the sibling's admin API, e.g. `http://10.0.0.2:51338`
+    pub url: String,
+    /// the sibling's `export_token`
+    pub token: Option<String>,
+}
+
+/// Label dimensions of `/metrics`, bounding how many series it reports on large fleets
+#[cfg(feature = "admin")]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct MetricLabels {
+    pub backends: BackendLabels,
+    /// reports the rates of the busiest `top_clients` client addresses, by bytes per second
+    pub clients: bool,
+    pub top_clients: usize,
+}
+
+#[cfg(feature = "admin")]
+impl Default for MetricLabels {
+    fn default() -> Self {
+        MetricLabels {
+            backends: BackendLabels::Backend,
+            clients: false,
+            top_clients: 10,
+        }
+    }
+}
+
+/// How the series about backends are labelled
+#[cfg(feature = "admin")]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendLabels {
+    /// a series per backend address
+    #[default]
+    Backend,
+    /// a series per peer name, adding up its backends, e.g. discovered ones
+    Peer,
+    /// no series per backend
+    None,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CountersConfig {
+    /// file the counters are restored from on startup and written to
+    pub path: PathBuf,
+    #[serde(default = "default_checkpoint_interval_secs")]
+    pub interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AffinityConfig {
+    /// how long a client is routed back after its last new session
+    #[serde(default = "default_affinity_ttl_secs")]
+    pub ttl_secs: u64,
+    /// file the table is restored from on startup and written to, it is lost on restart without
+    pub path: Option<PathBuf>,
+    #[serde(default = "default_checkpoint_interval_secs")]
+    pub interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StatsConfig {
+    /// seconds between summaries, each covering the traffic since the last one
+    #[serde(default = "default_checkpoint_interval_secs")]
+    pub interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SyslogConfig {
+    /// `udp://host:port`, `tcp://host:port` or `unix:///dev/log`
+    pub address: String,
+    #[serde(default)]
+    pub facility: Facility,
+    /// APP-NAME of the messages
+    #[serde(default = "default_syslog_app_name")]
+    pub app_name: String,
+}
+
+/// Syslog facilities, by their code
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    #[default]
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+fn default_syslog_app_name() -> String {
+    "wireguard-router".to_string()
+}
+
+fn default_checkpoint_interval_secs() -> u64 {
+    60
+}
+
+fn default_affinity_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+/// A WebAssembly module deciding routing, see `wireguard_router::policy::wasm`
+#[cfg(feature = "wasm-plugin")]
+#[derive(Deserialize, Debug, Clone)]
+pub struct WasmPolicyConfig {
+    pub path: std::path::PathBuf,
+    /// wall time budget per call into the module
+    #[serde(default = "default_wasm_budget_ms")]
+    pub budget_ms: u64,
+}
+
+#[cfg(feature = "wasm-plugin")]
+fn default_wasm_budget_ms() -> u64 {
+    2
+}
+
+#[cfg(feature = "lua")]
+fn default_lua_budget_ms() -> u64 {
+    10
+}
+
+/// The configured features a sandboxed router can't run: they resolve names, load TLS roots,
+/// read files beyond the config or make syscalls outside the allowlist once started
+#[cfg(feature = "sandbox")]
+#[allow(unused_variables)] // built without any of them
+fn sandbox_conflicts(config: &Config) -> Vec<&'static str> {
+    let features: &[(bool, &str)] = &[
+        #[cfg(feature = "consul")]
+        (!config.consul.is_empty(), "[[consul]]"),
+        #[cfg(feature = "dns")]
+        (!config.dns.is_empty(), "[[dns]]"),
+        #[cfg(feature = "docker")]
+        (config.docker.is_some(), "[docker]"),
+        #[cfg(feature = "etcd")]
+        (!config.etcd.is_empty(), "[[etcd]]"),
+        #[cfg(feature = "kubernetes")]
+        (!config.kubernetes.is_empty(), "[[kubernetes]]"),
+        #[cfg(feature = "blocklists")]
+        (config.blocklists.is_some(), "[blocklists]"),
+        #[cfg(feature = "crowdsec")]
+        (config.crowdsec.is_some(), "[crowdsec]"),
+        #[cfg(feature = "alarms")]
+        (
+            config.alarms.as_ref().is_some_and(|a| a.webhook.is_some()),
+            "an [alarms] webhook",
+        ),
+        #[cfg(feature = "export")]
+        (config.export.is_some(), "[export]"),
+        #[cfg(feature = "mqtt")]
+        (config.mqtt.is_some(), "[mqtt]"),
+        #[cfg(feature = "quic")]
+        (config.quic.is_some(), "[quic]"),
+        #[cfg(feature = "tunnel")]
+        (!config.tunnels.is_empty(), "[[tunnels]]"),
+        #[cfg(feature = "admin")]
+        (config.warm_start.is_some(), "[warm_start]"),
+        #[cfg(feature = "admin")]
+        (
+            config.admin.as_ref().is_some_and(|admin| admin.profiling),
+            "admin profiling",
+        ),
+    ];
+    features
+        .iter()
+        .filter(|(configured, _)| *configured)
+        .map(|(_, feature)| *feature)
+        .collect()
+}
+
+/// Uses the config file at `path` instead of [`PATH`], or reads the config from stdin once for [`STDIN`]
+///
+/// Must be called before [`init`].
+pub fn set_path(path: &Path) -> Result<(), Error> {
+    if path != Path::new(STDIN) {
+        let _ = FILE.set(path.to_path_buf());
+        return Ok(());
+    }
+    let config = std::io::read_to_string(std::io::stdin()).map_err(|source| Error::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let format = match config.trim_start().starts_with('{') {
+        true => FileFormat::Json,
+        false => FileFormat::Toml,
+    };
+    let _ = STDIN_CONFIG.set((config, format));
+    Ok(())
+}
+
+/// Overrides config values with `key=value` pairs from the command line, must be called before [`init`]
+pub fn set_overrides(overrides: Vec<(String, String)>) {
+    let _ = OVERRIDES.set(overrides);
+}
+
+/// The directory of config fragments merged into the config file at `path`, e.g. `config.d` for `config.toml`
+pub fn include_dir(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or(path.as_os_str());
+    let mut dir = stem.to_owned();
+    dir.push(".d");
+    path.with_file_name(dir)
+}
+
+/// The config file, unless the config was read from stdin
+pub fn path() -> Option<&'static Path> {
+    match STDIN_CONFIG.get() {
+        Some(_) => None,
+        None => Some(FILE.get().map_or(Path::new(PATH), PathBuf::as_path)),
+    }
+}
+
+/// Loads the config for the first time, must be called before [`settings`]
+pub fn init() -> Result<(), Error> {
+    let config = load()?;
+    tracing::info!("loaded config {}", config.checksum);
+    if CONFIG.set(RwLock::new(config)).is_err() {
+        return Err(Error::InvalidConfig(
+            "config was already loaded".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The current config, an error until [`init`] loaded it
+pub fn settings() -> Result<&'static RwLock<Config>, Error> {
+    CONFIG
+        .get()
+        .ok_or_else(|| Error::InvalidConfig("config was not loaded yet".to_string()))
+}
+
+/// Reloads the config, keeping the current one if the new one is invalid, and returns its peers
+fn refresh() -> Result<Vec<Peer>, Error> {
+    let config = load()?;
+    tracing::info!("loaded config {}", config.checksum);
+    let peers = config.peers.clone();
+    *settings()?.write().unwrap() = config;
+    Ok(peers)
+}
+
+/// Loads the config from its current sources without making it the current one
+pub fn load() -> Result<Config, Error> {
+    let Some(path) = path() else {
+        return load_sources(Path::new(STDIN), false);
+    };
+    // without a config file, e.g. in containers, everything comes from the environment
+    load_sources(path, path.exists())
+}
+
+/// Loads the config at `path` without making it the current one
+pub fn load_from(path: &Path) -> Result<Config, Error> {
+    load_sources(path, true)
+}
+
+/// Loads the config at `path` if `read_file`, overridden and extended by the other sources
+///
+/// Later sources override values of earlier ones, tables being merged key by key:
+/// 1. the config file, or stdin
+/// 2. the fragments of its [`include_dir`], in the order of their file names
+/// 3. the remote document, with the `remote-config` feature
+/// 4. `WG_ROUTER_` environment variables
+/// 5. `--set key=value` on the command line
+///
+/// Peers add up instead: those of the fragments, [`PEERS_ENV`] and [`PEERS_FILE_ENV`] follow the merged ones.
+fn load_sources(path: &Path, read_file: bool) -> Result<Config, Error> {
+    merge(path, read_file).map(|(_, config)| config)
+}
+
+/// The effective config as JSON, with the values every source contributed, see [`load_sources`]
+pub fn effective() -> Result<serde_json::Value, Error> {
+    let (effective, _) = match path() {
+        Some(path) => merge(path, path.exists())?,
+        None => merge(Path::new(STDIN), false)?,
+    };
+    Ok(effective)
+}
+
+/// Merges the sources of [`load_sources`], returning the [`effective`] config along with the config it makes up
+fn merge(path: &Path, read_file: bool) -> Result<(serde_json::Value, Config), Error> {
+    // a key that can't be used fails every load, not only those of signed files
+    config_key()?;
+    let env = std::env::vars()
+        .filter(|(key, _)| {
+            ![
+                LISTEN_ENV,
+                NO_WATCH_ENV,
+                PEERS_ENV,
+                PEERS_FILE_ENV,
+                URL_ENV,
+                URL_INTERVAL_ENV,
+                PEER_SYNC_URL_ENV,
+                PEER_SYNC_TOKEN_ENV,
+                KEY_ENV,
+            ]
+            .contains(&key.as_str())
+        })
+        .collect();
+    let mut builder = config::Config::builder();
+    let load_error = |source| Error::ConfigLoad {
+        path: path.to_path_buf(),
+        source: Box::new(source),
+    };
+    let mut fragment_peers = Vec::new();
+    let mut documents = Vec::new();
+    if read_file {
+        builder = add_file(builder, path, &mut documents)?;
+        for fragment in fragments(path)? {
+            let mut values = add_file(config::Config::builder(), &fragment, &mut documents)?
+                .build()
+                .and_then(|values| values.try_deserialize::<config::Map<String, config::Value>>())
+                .map_err(|source| Error::ConfigLoad {
+                    path: fragment.clone(),
+                    source: Box::new(source),
+                })?;
+            if let Some(peers) = values.remove("peers") {
+                fragment_peers.extend(peers.try_deserialize::<Vec<Peer>>().map_err(|source| {
+                    locate(&documents[documents.len() - 1..]).unwrap_or(Error::ConfigLoad {
+                        path: fragment.clone(),
+                        source: Box::new(source),
+                    })
+                })?);
+            }
+            builder = builder.add_source(Fragment(values));
+        }
+    }
+    if let Some((config, format)) = STDIN_CONFIG.get() {
+        builder = builder.add_source(File::from_str(config, *format));
+        documents.push((PathBuf::from("stdin"), config.clone(), *format));
+    }
+    #[cfg(feature = "remote-config")]
+    if let Some((document, format)) = REMOTE.read().unwrap().as_ref() {
+        builder = builder.add_source(File::from_str(document, *format));
+        if let Ok(url) = std::env::var(URL_ENV) {
+            documents.push((PathBuf::from(url), document.clone(), *format));
+        }
+    }
+    builder = builder.add_source(
+        Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator("_")
+            .separator("__")
+            .try_parsing(true)
+            .source(Some(env)),
+    );
+    for (key, value) in OVERRIDES.get().into_iter().flatten() {
+        // typed like environment variables, which deserializing can't convert back for the effective config
+        let value = match value.parse::<i64>() {
+            Ok(number) => config::Value::from(number),
+            Err(_) => match value.parse::<bool>() {
+                Ok(flag) => config::Value::from(flag),
+                Err(_) => config::Value::from(value.as_str()),
+            },
+        };
+        builder = builder
+            .set_override(key.as_str(), value)
+            .map_err(load_error)?;
+    }
+    let merged = builder.build().map_err(load_error)?;
+    let mut effective: serde_json::Value = merged.clone().try_deserialize().map_err(load_error)?;
+    let mut config = merged
+        .try_deserialize::<Config>()
+        .map_err(|source| locate(&documents).unwrap_or_else(|| load_error(source)))?;
+    config.peers.extend(fragment_peers);
+
+    if let Ok(peers) = std::env::var(PEERS_ENV) {
+        config.peers.extend(
+            parse_peers(&peers).map_err(|e| Error::InvalidConfig(format!("{PEERS_ENV}: {e}")))?,
+        );
+    }
+    if let Some(path) = peers_file() {
+        let peers = match signed_payload(&path)? {
+            Some(peers) => peers,
+            None => std::fs::read_to_string(&path).map_err(|source| Error::ReadFile {
+                path: path.clone(),
+                source,
+            })?,
+        };
+        config.peers.extend(
+            parse_peers(&peers)
+                .map_err(|e| Error::InvalidConfig(format!("{}: {}", path.display(), e)))?,
+        );
+    }
+
+    // only configured peers, discovered backends of one service legitimately share a pubkey,
+    // as do peers that no horizon reaches both of
+    let mut shared_addresses = Vec::new();
+    let locals = config.listeners.iter().map(|horizon| Some(horizon.address));
+    for local in std::iter::once(None).chain(locals) {
+        let reachable: Vec<Peer> = config
+            .peers
+            .iter()
+            .filter(|peer| Horizon::reaches(&config.listeners, local, peer))
+            .cloned()
+            .collect();
+        for (first, second) in wireguard_router::check_peers(&reachable)
+            .map_err(|e| Error::InvalidConfig(e.to_string()))?
+        {
+            shared_addresses.push((first.to_string(), second.to_string()));
+        }
+    }
+    shared_addresses.sort();
+    shared_addresses.dedup();
+    for (first, second) in shared_addresses {
+        tracing::warn!("peers {} and {} have the same address", first, second);
+    }
+
+    #[cfg(feature = "tunnel")]
+    let tunnels: Vec<&str> = config.tunnels.iter().map(|t| t.name.as_str()).collect();
+    #[cfg(not(feature = "tunnel"))]
+    let tunnels: Vec<&str> = Vec::new();
+    for peer in &config.peers {
+        if let Some(tunnel) = &peer.tunnel
+            && !tunnels.contains(&tunnel.as_str())
+        {
+            return Err(Error::InvalidConfig(format!(
+                "peer {peer}: no tunnel {tunnel:?} among the [[tunnels]]"
+            )));
+        }
+        #[cfg(feature = "quic")]
+        let quic = config.quic.is_some();
+        #[cfg(not(feature = "quic"))]
+        let quic = false;
+        if peer.quic && !quic {
+            return Err(Error::InvalidConfig(format!(
+                "peer {peer}: carried over QUIC without a [quic] table"
+            )));
+        }
+    }
+
+    if config.router.buffer_size == Some(0) {
+        return Err(Error::InvalidConfig(
+            "router buffer_size must be positive".to_string(),
+        ));
+    }
+
+    #[cfg(feature = "sandbox")]
+    if config.sandbox {
+        let conflicts = sandbox_conflicts(&config);
+        if !conflicts.is_empty() {
+            return Err(Error::InvalidConfig(format!(
+                "sandbox = true can't be combined with {}, which need files, name resolution \
+                 or syscalls the sandbox denies",
+                conflicts.join(", ")
+            )));
+        }
+    }
+
+    #[cfg(feature = "admin")]
+    if let Some(admin) = &config.admin {
+        if let Some(rate_limit) = &admin.rate_limit
+            && (rate_limit.requests_per_sec.is_nan() || rate_limit.requests_per_sec <= 0.0)
+        {
+            return Err(Error::InvalidConfig(
+                "admin rate_limit: requests_per_sec must be positive".to_string(),
+            ));
+        }
+        #[cfg(not(all(unix, feature = "profiling")))]
+        if admin.profiling {
+            return Err(Error::InvalidConfig(
+                "admin profiling needs a Unix build with the profiling feature".to_string(),
+            ));
+        }
+        if admin.profiling && admin.credentials.is_empty() {
+            return Err(Error::InvalidConfig(
+                "admin profiling needs credentials, profiles can't be taken by whoever reaches \
+                 the API"
+                    .to_string(),
+            ));
+        }
+        for (i, credential) in admin.credentials.iter().enumerate() {
+            let earlier = &admin.credentials[..i];
+            if earlier.iter().any(|other| other.name == credential.name) {
+                return Err(Error::InvalidConfig(format!(
+                    "admin credential {:?} is named twice",
+                    credential.name
+                )));
+            }
+            if earlier.iter().any(|other| other.token == credential.token) {
+                return Err(Error::InvalidConfig(format!(
+                    "admin credential {:?} shares its token with another",
+                    credential.name
+                )));
+            }
+        }
+    }
+
+    effective["peers"] = serde_json::to_value(&config.peers)
+        .map_err(|e| Error::InvalidConfig(format!("peers: {e}")))?;
+    // objects serialize with sorted keys, so equal configs hash equally wherever their values come from
+    let json = serde_json::to_vec(&effective)
+        .map_err(|e| Error::InvalidConfig(format!("checksum: {e}")))?;
+    config.checksum = blake2s_simd::blake2s(&json).to_hex().to_string();
+    Ok((effective, config))
+}
+
+/// Adds the config file at `path` to `builder`, checking its signature if required
+///
+/// TOML and JSON files are added to `documents`, for [`locate`] to find errors in.
+fn add_file(
+    builder: config::ConfigBuilder<config::builder::DefaultState>,
+    path: &Path,
+    documents: &mut Vec<(PathBuf, String, FileFormat)>,
+) -> Result<config::ConfigBuilder<config::builder::DefaultState>, Error> {
+    let read = || {
+        std::fs::read_to_string(path).map_err(|source| Error::ReadFile {
+            path: path.to_path_buf(),
+            source,
+        })
+    };
+    let (contents, format) = match signed_payload(path)? {
+        Some(contents) => (contents, FileFormat::Toml),
+        None => match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => (read()?, FileFormat::Toml),
+            Some("json") => (read()?, FileFormat::Json),
+            // other formats the config crate supports can't be located in
+            _ => return Ok(builder.add_source(File::from(path))),
+        },
+    };
+    let builder = builder.add_source(File::from_str(&contents, format));
+    documents.push((path.to_path_buf(), contents, format));
+    Ok(builder)
+}
+
+/// Finds the first of `documents` that is an invalid config on its own, locating the error in it
+///
+/// The merged config loses where its values come from, so errors in it only name the key.
+fn locate(documents: &[(PathBuf, String, FileFormat)]) -> Option<Error> {
+    documents.iter().find_map(|(path, contents, format)| {
+        let (message, span) = match format {
+            FileFormat::Json => {
+                let e = serde_json::from_str::<Config>(contents).err()?;
+                // the position is just past the offending value, a string for most of them
+                let end = offset(contents, e.line(), e.column().saturating_sub(1));
+                let start = match contents[..end].strip_suffix('"') {
+                    Some(before) => before.rfind('"').unwrap_or(end - 1),
+                    None => end.saturating_sub(1),
+                };
+                // without the position, which the snippet shows
+                let message = e.to_string();
+                let message = match message.rsplit_once(" at line ") {
+                    Some((message, _)) => message.to_string(),
+                    None => message,
+                };
+                (message, start..end)
+            }
+            _ => {
+                let e = toml::from_str::<Config>(contents).err()?;
+                (e.message().to_string(), e.span()?)
+            }
+        };
+        let line_start = contents[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line = contents[line_start..]
+            .split('\n')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('\r');
+        let line_number = contents[..line_start].matches('\n').count() + 1;
+        let column = contents[line_start..span.start].chars().count() + 1;
+        let end = span.end.min(line_start + line.len()).max(span.start);
+        let width = contents[span.start..end].chars().count().max(1);
+        let gutter = " ".repeat(line_number.to_string().len());
+        Some(Error::ConfigAt {
+            path: path.clone(),
+            line: line_number,
+            column,
+            message,
+            snippet: format!(
+                "{gutter} |\n{line_number} | {line}\n{gutter} | {}{}",
+                " ".repeat(column - 1),
+                "^".repeat(width)
+            ),
+        })
     })
 }
 
-fn refresh() {
-    *settings().write().unwrap() = load();
+/// The byte offset of a 1-based `line` and 0-based `column` in `contents`
+fn offset(contents: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = contents
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    contents[line_start..]
+        .char_indices()
+        .nth(column)
+        .map_or(contents.len(), |(i, _)| line_start + i)
+}
+
+/// The TOML and JSON files of the [`include_dir`] of `path`, ordered by name
+fn fragments(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = include_dir(path);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(source) => return Err(Error::ReadFile { path: dir, source }),
+    };
+    let mut fragments = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|source| Error::ReadFile {
+                path: dir.clone(),
+                source,
+            })?
+            .path();
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if matches!(extension, Some("toml" | "json")) && path.is_file() {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+    Ok(fragments)
+}
+
+/// The values of a config fragment without its peers, which add up rather than replace each other
+#[derive(Clone, Debug)]
+struct Fragment(config::Map<String, config::Value>);
+
+impl config::Source for Fragment {
+    fn clone_into_box(&self) -> Box<dyn config::Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<config::Map<String, config::Value>, config::ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// The contents of `path` once its signature is verified, or `None` if no config key is set
+///
+/// Signed config files are always read as TOML.
+#[cfg(feature = "signed-config")]
+fn signed_payload(path: &Path) -> Result<Option<String>, Error> {
+    let Some(key) = config_key()? else {
+        return Ok(None);
+    };
+    let read =
+        |path: PathBuf| std::fs::read(&path).map_err(|source| Error::ReadFile { path, source });
+    let payload = read(path.to_path_buf())?;
+    let signature = read(signature_path(path))?;
+    verify(&key, &path.display().to_string(), &payload, &signature)?;
+    String::from_utf8(payload)
+        .map(Some)
+        .map_err(|e| Error::InvalidConfig(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(not(feature = "signed-config"))]
+fn signed_payload(_path: &Path) -> Result<Option<String>, Error> {
+    Ok(None)
+}
+
+/// Refuses a config key this build can't check signatures with, rather than accepting anything
+#[cfg(not(feature = "signed-config"))]
+fn config_key() -> Result<(), Error> {
+    match std::env::var_os(KEY_ENV) {
+        Some(_) => Err(Error::InvalidConfig(format!(
+            "{KEY_ENV} is set, but signatures are only checked with the signed-config feature"
+        ))),
+        None => Ok(()),
+    }
 }
 
-fn load() -> Config {
-    config::Config::builder()
-        .add_source(File::with_name("config.toml"))
+/// The key of [`KEY_ENV`], if set
+#[cfg(feature = "signed-config")]
+fn config_key() -> Result<Option<ed25519_dalek::VerifyingKey>, Error> {
+    use base64::Engine;
+
+    let Ok(key) = std::env::var(KEY_ENV) else {
+        return Ok(None);
+    };
+    let invalid = |e: String| Error::InvalidConfig(format!("{KEY_ENV}: {e}"));
+    let key: [u8; 32] = base64::engine::general_purpose::STANDARD
+        .decode(key.trim())
+        .map_err(|e| invalid(e.to_string()))?
+        .try_into()
+        .map_err(|key: Vec<u8>| {
+            invalid(format!("expected a 32 byte key, got {} bytes", key.len()))
+        })?;
+    ed25519_dalek::VerifyingKey::from_bytes(&key)
+        .map(Some)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+/// The detached signature of the config file at `path`
+#[cfg(feature = "signed-config")]
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(SIGNATURE_SUFFIX);
+    PathBuf::from(signature)
+}
+
+/// Checks that `signature`, in base64, is the signature of `payload` by `key`
+#[cfg(feature = "signed-config")]
+fn verify(
+    key: &ed25519_dalek::VerifyingKey,
+    what: &str,
+    payload: &[u8],
+    signature: &[u8],
+) -> Result<(), Error> {
+    use base64::Engine;
+
+    let invalid = |source: Box<dyn std::error::Error + Send + Sync>| Error::Signature {
+        what: what.to_string(),
+        source,
+    };
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(signature.trim_ascii())
+        .map_err(|e| invalid(e.into()))?;
+    let signature =
+        ed25519_dalek::Signature::from_slice(&signature).map_err(|e| invalid(e.into()))?;
+    key.verify_strict(payload, &signature)
+        .map_err(|_| invalid("not signed by the config key".into()))
+}
+
+/// The peers file named by [`PEERS_FILE_ENV`], if any
+pub fn peers_file() -> Option<PathBuf> {
+    std::env::var_os(PEERS_FILE_ENV).map(PathBuf::from)
+}
+
+/// Parses a JSON array of peers, or CSV lines of `address,pubkey[,proxy[,name]]`
+fn parse_peers(value: &str) -> Result<Vec<Peer>, String> {
+    let value = value.trim();
+    if value.starts_with('[') {
+        return serde_json::from_str(value).map_err(|e| e.to_string());
+    }
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split(',').map(str::trim);
+            let mut next = || fields.next().filter(|field| !field.is_empty());
+            let address = next().unwrap_or_default().to_string();
+            let pubkey = next()
+                .ok_or_else(|| format!("{line:?}: missing pubkey"))?
+                .to_string();
+            let config = PeerConfig {
+                address,
+                pubkey,
+                proxy: next().map(String::from),
+                name: next().map(String::from),
+                ..Default::default()
+            };
+            Peer::try_from(config).map_err(|e| format!("{line:?}: {}", e.with_field()))
+        })
+        .collect()
+}
+
+/// Reloads the config whenever the watcher reports a change, publishing the new peers to `peers`
+pub fn reload_on_change(events: Receiver<Result<Event, notify::Error>>, peers: PeerSet) {
+    // the watcher channel is blocking, so bridge it from a dedicated thread
+    std::thread::spawn(move || {
+        for event in events {
+            match event {
+                // reading the config ourselves triggers access events, ignore those
+                Ok(event) if event.kind.is_access() => {}
+                Ok(_) => {
+                    tracing::info!("config changed, reloading peers");
+                    match refresh() {
+                        Ok(configured) => peers.set_configured(configured),
+                        Err(e) => tracing::error!("keeping the previous config: {}", Report(&e)),
+                    }
+                }
+                Err(source) => {
+                    let err = Error::ConfigWatch {
+                        path: path().unwrap_or(Path::new(PATH)).to_path_buf(),
+                        source,
+                    };
+                    tracing::error!("{}", Report(&err));
+                }
+            }
+        }
+    });
+}
+
+/// A config document served over HTTP(S), e.g. by a provisioning service, overriding the config file
+///
+/// It is fetched again every interval, conditionally on its `ETag` and `Last-Modified`, and applied
+/// like a changed config file: an invalid document is rejected and the previous one kept.
+/// With a config key, the document's signature is fetched from its URL with `.sig` appended.
+#[cfg(feature = "remote-config")]
+pub struct Remote {
+    url: String,
+    interval: std::time::Duration,
+    client: reqwest::Client,
+    etag: Option<reqwest::header::HeaderValue>,
+    last_modified: Option<reqwest::header::HeaderValue>,
+}
+
+#[cfg(feature = "remote-config")]
+impl Remote {
+    /// The document named by [`URL_ENV`], if any
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        let Ok(url) = std::env::var(URL_ENV) else {
+            return Ok(None);
+        };
+        let client = http_client(&url)?;
+        Ok(Some(Remote {
+            url,
+            interval: poll_interval()?,
+            client,
+            etag: None,
+            last_modified: None,
+        }))
+    }
+
+    /// Fetches the document for [`init`], which can't start without it
+    pub async fn fetch_initial(&mut self) -> Result<(), Error> {
+        let document = self.fetch().await?.ok_or_else(|| Error::ConfigFetch {
+            url: self.url.clone(),
+            source: "not modified, though nothing was fetched yet".into(),
+        })?;
+        *REMOTE.write().unwrap() = Some(document);
+        Ok(())
+    }
+
+    /// Checks for a changed document every interval, publishing the new peers to `peers`
+    pub async fn poll(mut self, peers: PeerSet) {
+        let mut ticks = tokio::time::interval(self.interval);
+        // the first tick completes immediately, right after the initial fetch
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let document = match self.fetch().await {
+                Ok(Some(document)) => document,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::warn!("{}", Report(&e));
+                    continue;
+                }
+            };
+            tracing::info!("remote config changed, reloading peers");
+            let previous = REMOTE.write().unwrap().replace(document);
+            match refresh() {
+                Ok(configured) => peers.set_configured(configured),
+                Err(e) => {
+                    tracing::error!(
+                        "keeping the previous config, rejecting the document of {}: {}",
+                        self.url,
+                        Report(&e)
+                    );
+                    *REMOTE.write().unwrap() = previous;
+                }
+            }
+        }
+    }
+
+    /// The document if it changed since the last fetch, TOML unless served as JSON
+    async fn fetch(&mut self) -> Result<Option<(String, FileFormat)>, Error> {
+        use reqwest::header::{
+            CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        };
+
+        let fetch_error = |e: reqwest::Error| Error::ConfigFetch {
+            url: self.url.clone(),
+            source: e.into(),
+        };
+        let mut request = self.client.get(&self.url);
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(fetch_error)?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        let json = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.contains("json"));
+        let document = response.text().await.map_err(fetch_error)?;
+        #[cfg(feature = "signed-config")]
+        if let Some(key) = config_key()? {
+            let signature = self
+                .client
+                .get(format!("{}{}", self.url, SIGNATURE_SUFFIX))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(fetch_error)?
+                .bytes()
+                .await
+                .map_err(fetch_error)?;
+            verify(&key, &self.url, document.as_bytes(), &signature)?;
+        }
+        // only remembered once the whole document arrived
+        self.etag = etag;
+        self.last_modified = last_modified;
+        let format = match json {
+            true => FileFormat::Json,
+            false => FileFormat::Toml,
+        };
+        Ok(Some((document, format)))
+    }
+}
+
+/// The interval of [`URL_INTERVAL_ENV`]
+#[cfg(feature = "remote-config")]
+fn poll_interval() -> Result<std::time::Duration, Error> {
+    let secs = match std::env::var(URL_INTERVAL_ENV) {
+        Ok(secs) => secs
+            .parse()
+            .map_err(|e| Error::InvalidConfig(format!("{URL_INTERVAL_ENV}: {e}")))?,
+        Err(_) => 60,
+    };
+    Ok(std::time::Duration::from_secs(secs))
+}
+
+#[cfg(feature = "remote-config")]
+fn http_client(url: &str) -> Result<reqwest::Client, Error> {
+    reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
         .build()
-        .unwrap()
-        .try_deserialize::<Config>()
-        .unwrap()
+        .map_err(|e| Error::ConfigFetch {
+            url: url.to_string(),
+            source: e.into(),
+        })
 }
 
-fn show() {
-    println!(
-        " * Settings :: \n\x1b[31m{:?}\x1b[0m",
-        settings().read().unwrap()
-    );
+/// Version of the peer sync protocol spoken by [`PeerSync`]
+#[cfg(feature = "remote-config")]
+const PEER_SYNC_VERSION: u32 = 1;
+
+/// The peer list a control plane serves to [`PeerSync`]
+#[cfg(feature = "remote-config")]
+#[derive(Deserialize)]
+struct PeerList {
+    version: u32,
+    /// increases with every change of the list
+    revision: u64,
+    peers: Vec<Peer>,
+}
+
+/// Follows the peers a control plane serves, for those without Consul, etcd or Kubernetes
+///
+/// Every interval, the endpoint is asked for the peers with `GET <url>?revision=<last applied>`.
+/// It answers `304 Not Modified` if nothing changed since, or `200 OK` with a JSON body of
+/// `{"version": 1, "revision": 42, "peers": [...]}`, the peers as in `[[peers]]`. Lists with a
+/// revision below the applied one, e.g. from a lagging replica, are ignored, and invalid ones are
+/// rejected as a whole. With a config key, the body must be signed like a config file, the base64
+/// signature being sent in an `X-Signature` header.
+#[cfg(feature = "remote-config")]
+pub struct PeerSync {
+    url: String,
+    token: Option<String>,
+    interval: std::time::Duration,
+    client: reqwest::Client,
+    revision: Option<u64>,
+}
+
+#[cfg(feature = "remote-config")]
+impl PeerSync {
+    /// The endpoint named by [`PEER_SYNC_URL_ENV`], if any
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        let Ok(url) = std::env::var(PEER_SYNC_URL_ENV) else {
+            return Ok(None);
+        };
+        Ok(Some(PeerSync {
+            client: http_client(&url)?,
+            url,
+            token: std::env::var(PEER_SYNC_TOKEN_ENV).ok(),
+            interval: poll_interval()?,
+            revision: None,
+        }))
+    }
+
+    /// Checks for a new revision every interval, publishing its peers to `peers`
+    pub async fn poll(mut self, peers: PeerSet) {
+        let source = format!("sync:{}", self.url);
+        let mut ticks = tokio::time::interval(self.interval);
+        loop {
+            ticks.tick().await;
+            let list = match self.fetch().await {
+                Ok(Some(list)) => list,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::warn!("keeping the previous peers: {}", Report(&e));
+                    continue;
+                }
+            };
+            if self
+                .revision
+                .is_some_and(|revision| list.revision <= revision)
+            {
+                if self.revision != Some(list.revision) {
+                    tracing::warn!(
+                        "ignoring revision {} of {}, already at {}",
+                        list.revision,
+                        self.url,
+                        self.revision.unwrap_or_default()
+                    );
+                }
+                continue;
+            }
+            tracing::info!(
+                "{} has {} peers at revision {}",
+                source,
+                list.peers.len(),
+                list.revision
+            );
+            self.revision = Some(list.revision);
+            peers.set_discovered(&source, list.peers);
+        }
+    }
+
+    /// The peer list, unless the endpoint reports it unchanged since the applied revision
+    async fn fetch(&self) -> Result<Option<PeerList>, Error> {
+        let fetch_error = |source: Box<dyn std::error::Error + Send + Sync>| Error::ConfigFetch {
+            url: self.url.clone(),
+            source,
+        };
+        let mut request = self.client.get(&self.url);
+        if let Some(revision) = self.revision {
+            request = request.query(&[("revision", revision)]);
+        }
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| fetch_error(e.into()))?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
+        #[cfg(feature = "signed-config")]
+        let signature = response.headers().get("X-Signature").cloned();
+        let body = response.bytes().await.map_err(|e| fetch_error(e.into()))?;
+        #[cfg(feature = "signed-config")]
+        if let Some(key) = config_key()? {
+            let signature = signature.ok_or_else(|| Error::Signature {
+                what: self.url.clone(),
+                source: "no X-Signature header".into(),
+            })?;
+            verify(&key, &self.url, &body, signature.as_bytes())?;
+        }
+
+        let list: PeerList = serde_json::from_slice(&body).map_err(|e| fetch_error(e.into()))?;
+        if list.version != PEER_SYNC_VERSION {
+            return Err(fetch_error(
+                format!("unsupported peer sync version {}", list.version).into(),
+            ));
+        }
+        Ok(Some(list))
+    }
 }
@@ -0,0 +1,473 @@
+/*
+* crowdsec.rs enforces the IP bans of a CrowdSec Local API and reports handshake floods back to it
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::denylist::{Denylist, Network};
+use crate::error::{Error, Report};
+use crate::event::RouterEvent;
+use crate::packet::MessageType;
+
+/// The source of the bans in the [`Denylist`]
+pub const SOURCE: &str = "crowdsec";
+/// The scenario of the alerts pushed for handshake floods
+pub const FLOOD_SCENARIO: &str = "wireguard-router/handshake-flood";
+/// Requests taking longer are abandoned, and made again
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// the Local API, e.g. `http://127.0.0.1:8080`
+    pub url: String,
+    /// key of the bouncer registered with `cscli bouncers add`
+    pub api_key: String,
+    /// seconds between pulls of new and deleted decisions
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// credentials of the machine registered with `cscli machines add`, without which no
+    /// alerts are pushed
+    pub machine_id: Option<String>,
+    pub password: Option<String>,
+    /// initiations from one address within `flood_window_secs` beyond which it is reported
+    #[serde(default = "default_flood_initiations")]
+    pub flood_initiations: usize,
+    #[serde(default = "default_flood_window_secs")]
+    pub flood_window_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+fn default_flood_initiations() -> usize {
+    100
+}
+
+fn default_flood_window_secs() -> u64 {
+    10
+}
+
+impl Config {
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+}
+
+/// A decision of the Local API, of which the router enforces bans of addresses and ranges
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    /// `Ip` or `Range`, other scopes such as countries are ignored
+    pub scope: String,
+    pub value: String,
+    /// `ban`, other remediations such as captchas are ignored
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+impl Decision {
+    /// The network the decision bans, if it is a ban the router can enforce
+    pub fn banned(&self) -> Option<Network> {
+        let scoped = ["ip", "range"]
+            .iter()
+            .any(|scope| self.scope.eq_ignore_ascii_case(scope));
+        (scoped && self.kind.eq_ignore_ascii_case("ban"))
+            .then(|| self.value.parse().ok())
+            .flatten()
+    }
+}
+
+/// An answer of `GET /v1/decisions/stream`, the decisions since the last pull
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stream {
+    #[serde(default, deserialize_with = "nullable")]
+    pub new: Vec<Decision>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub deleted: Vec<Decision>,
+}
+
+/// The Local API answers `null` rather than an empty list
+fn nullable<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Decision>, D::Error> {
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// The bans in effect, kept up to date from the decision stream
+///
+/// A network banned by several decisions, e.g. of different scenarios, is lifted with the last.
+#[derive(Debug, Default)]
+pub struct Bans(HashMap<Network, usize>);
+
+impl Bans {
+    /// Applies the decisions of `stream`, replacing all bans if it is a `startup` pull
+    pub fn apply(&mut self, stream: Stream, startup: bool) {
+        if startup {
+            self.0.clear();
+        }
+        for network in stream.deleted.iter().filter_map(Decision::banned) {
+            if let Some(count) = self.0.get_mut(&network) {
+                *count -= 1;
+                if *count == 0 {
+                    self.0.remove(&network);
+                }
+            }
+        }
+        for network in stream.new.iter().filter_map(Decision::banned) {
+            *self.0.entry(network).or_default() += 1;
+        }
+    }
+
+    /// The banned networks, in order
+    pub fn networks(&self) -> Vec<Network> {
+        let mut networks: Vec<Network> = self.0.keys().copied().collect();
+        networks.sort();
+        networks
+    }
+}
+
+/// Pulls the decisions of the Local API every interval, replacing the bans in `denylist`
+///
+/// The first pull, and the first after a failed one, gets all decisions in effect rather than
+/// the changes, so no deletion is missed. Failed pulls keep the bans, with a warning.
+pub async fn bounce(config: Config, denylist: Denylist) {
+    let Some(client) = http_client() else {
+        return;
+    };
+    let mut bans = Bans::default();
+    let mut startup = true;
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        match pull(&client, &config, startup).await {
+            Ok(stream) => {
+                let (new, deleted) = (stream.new.len(), stream.deleted.len());
+                bans.apply(stream, startup);
+                if startup || new + deleted > 0 {
+                    tracing::debug!("crowdsec: {} new and {} deleted decisions", new, deleted);
+                    denylist.set(SOURCE, bans.networks());
+                }
+                startup = false;
+            }
+            Err(e) => {
+                if !startup {
+                    tracing::warn!("keeping the CrowdSec bans: {}", Report(&e));
+                }
+                startup = true;
+            }
+        }
+    }
+}
+
+async fn pull(client: &reqwest::Client, config: &Config, startup: bool) -> Result<Stream, Error> {
+    let url = config.endpoint("/v1/decisions/stream");
+    let request_error = |source: reqwest::Error| Error::CrowdSec {
+        url: url.clone(),
+        source: source.into(),
+    };
+    client
+        .get(&url)
+        .query(&[
+            ("startup", startup.to_string().as_str()),
+            ("scopes", "ip,range"),
+        ])
+        .header("X-Api-Key", &config.api_key)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(request_error)?
+        .json()
+        .await
+        .map_err(request_error)
+}
+
+/// A source that sent more initiations than the threshold within the window
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Flood {
+    pub source: IpAddr,
+    pub initiations: usize,
+    /// when the first and last initiation of the window were seen
+    pub start: SystemTime,
+    pub stop: SystemTime,
+}
+
+/// Counts the initiations of every source within a sliding window, to find floods
+///
+/// Time is passed in, so the detector can be driven without waiting.
+pub struct Floods {
+    threshold: usize,
+    window: Duration,
+    /// source -> its initiations, counted in buckets of a second from when the first was seen,
+    /// so a flood takes no more memory than a trickle
+    initiations: HashMap<IpAddr, VecDeque<(Instant, SystemTime, usize)>>,
+    /// sources reported within the last window, which aren't reported again until it passed
+    reported: HashMap<IpAddr, Instant>,
+}
+
+impl Floods {
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Floods {
+            threshold: threshold.max(1),
+            window,
+            initiations: HashMap::new(),
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Takes note of a routing outcome at `now`, returning a flood its initiation completes
+    ///
+    /// Initiations count whether they were routed or dropped, except for those of denylisted
+    /// sources, which are already dealt with.
+    pub fn observe(&mut self, event: &RouterEvent, now: Instant, at: SystemTime) -> Option<Flood> {
+        let source = match event {
+            RouterEvent::SessionCreated { client, .. } => client.ip(),
+            RouterEvent::Dropped {
+                message: Some(MessageType::HandshakeInitiation),
+                source,
+                ..
+            } => source.ip(),
+            _ => return None,
+        }
+        .to_canonical();
+        let window_start = now.checked_sub(self.window).unwrap_or(now);
+        let buckets = self.initiations.entry(source).or_default();
+        match buckets.back_mut() {
+            Some((seen, _, count)) if now.duration_since(*seen) < Duration::from_secs(1) => {
+                *count += 1
+            }
+            _ => buckets.push_back((now, at, 1)),
+        }
+        while buckets
+            .front()
+            .is_some_and(|(seen, _, _)| *seen < window_start)
+        {
+            buckets.pop_front();
+        }
+        let flood = Flood {
+            source,
+            initiations: buckets.iter().map(|(_, _, count)| count).sum(),
+            start: buckets.front().map_or(at, |(_, at, _)| *at),
+            stop: at,
+        };
+        if flood.initiations <= self.threshold
+            || self
+                .reported
+                .get(&source)
+                .is_some_and(|reported| *reported >= window_start)
+        {
+            return None;
+        }
+        self.reported.insert(source, now);
+        Some(flood)
+    }
+
+    /// Forgets the sources that sent nothing within the window at `now`
+    pub fn expire(&mut self, now: Instant) {
+        let window_start = now.checked_sub(self.window).unwrap_or(now);
+        self.initiations.retain(|_, buckets| {
+            buckets
+                .back()
+                .is_some_and(|(seen, _, _)| *seen >= window_start)
+        });
+        self.reported
+            .retain(|_, reported| *reported >= window_start);
+    }
+}
+
+/// The source of an [`Alert`]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AlertSource {
+    pub scope: &'static str,
+    pub value: String,
+    pub ip: String,
+}
+
+/// An alert as `POST /v1/alerts` takes it
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+    pub scenario: &'static str,
+    pub scenario_hash: &'static str,
+    pub scenario_version: &'static str,
+    pub message: String,
+    pub events_count: usize,
+    pub events: Vec<serde_json::Value>,
+    /// RFC 3339
+    pub start_at: String,
+    pub stop_at: String,
+    /// the threshold, as the bucket capacity of a CrowdSec scenario
+    pub capacity: usize,
+    /// the window, as a Go duration
+    pub leakspeed: String,
+    pub simulated: bool,
+    pub source: AlertSource,
+}
+
+impl Alert {
+    pub fn flood(flood: &Flood, threshold: usize, window: Duration) -> Alert {
+        let rfc3339 =
+            |at: SystemTime| DateTime::<Utc>::from(at).to_rfc3339_opts(SecondsFormat::Secs, true);
+        Alert {
+            scenario: FLOOD_SCENARIO,
+            scenario_hash: "",
+            scenario_version: "",
+            message: format!(
+                "{} sent {} WireGuard handshake initiations within {}s",
+                flood.source,
+                flood.initiations,
+                window.as_secs()
+            ),
+            events_count: flood.initiations,
+            events: Vec::new(),
+            start_at: rfc3339(flood.start),
+            stop_at: rfc3339(flood.stop),
+            capacity: threshold,
+            leakspeed: format!("{}s", window.as_secs()),
+            simulated: false,
+            source: AlertSource {
+                scope: "Ip",
+                value: flood.source.to_string(),
+                ip: flood.source.to_string(),
+            },
+        }
+    }
+}
+
+/// Pushes an alert to the Local API for every handshake flood among the router's `events`,
+/// until the router stops, if the config has machine credentials
+///
+/// The machine logs in once, and again when its token expired. Alerts that fail to be pushed
+/// are logged and not retried.
+pub async fn report(config: Config, mut events: broadcast::Receiver<RouterEvent>) {
+    let (Some(machine_id), Some(password)) = (config.machine_id.clone(), config.password.clone())
+    else {
+        return;
+    };
+    let Some(client) = http_client() else {
+        return;
+    };
+    let window = Duration::from_secs(config.flood_window_secs.max(1));
+    let mut floods = Floods::new(config.flood_initiations, window);
+    let mut expiry = tokio::time::interval(window);
+    let mut token = None;
+    loop {
+        let flood = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => floods.observe(&event, Instant::now(), SystemTime::now()),
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::debug!("flood detection missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = expiry.tick() => {
+                floods.expire(Instant::now());
+                continue;
+            }
+        };
+        let Some(flood) = flood else {
+            continue;
+        };
+        tracing::info!(
+            "{} sent {} initiations within {}s, reporting it to CrowdSec",
+            flood.source,
+            flood.initiations,
+            window.as_secs()
+        );
+        let alert = Alert::flood(&flood, config.flood_initiations, window);
+        // a token that expired is refused, after which the machine logs in again
+        for _ in 0..2 {
+            if token.is_none() {
+                match login(&client, &config, &machine_id, &password).await {
+                    Ok(new) => token = Some(new),
+                    Err(e) => {
+                        tracing::warn!("failed to report to CrowdSec: {}", Report(&e));
+                        break;
+                    }
+                }
+            }
+            match push(
+                &client,
+                &config,
+                token.as_deref().unwrap_or_default(),
+                &alert,
+            )
+            .await
+            {
+                Ok(()) => break,
+                Err(e) if e.status() == Some(reqwest::StatusCode::UNAUTHORIZED) => token = None,
+                Err(e) => {
+                    let e = Error::CrowdSec {
+                        url: config.endpoint("/v1/alerts"),
+                        source: e.into(),
+                    };
+                    tracing::warn!("failed to report to CrowdSec: {}", Report(&e));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Login {
+    token: String,
+}
+
+async fn login(
+    client: &reqwest::Client,
+    config: &Config,
+    machine_id: &str,
+    password: &str,
+) -> Result<String, Error> {
+    let url = config.endpoint("/v1/watchers/login");
+    let request_error = |source: reqwest::Error| Error::CrowdSec {
+        url: url.clone(),
+        source: source.into(),
+    };
+    let login: Login = client
+        .post(&url)
+        .json(&serde_json::json!({
+            "machine_id": machine_id,
+            "password": password,
+            "scenarios": [FLOOD_SCENARIO],
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(request_error)?
+        .json()
+        .await
+        .map_err(request_error)?;
+    Ok(login.token)
+}
+
+async fn push(
+    client: &reqwest::Client,
+    config: &Config,
+    token: &str,
+    alert: &Alert,
+) -> Result<(), reqwest::Error> {
+    client
+        .post(config.endpoint("/v1/alerts"))
+        .bearer_auth(token)
+        .json(&[alert])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map(|_| ())
+}
+
+fn http_client() -> Option<reqwest::Client> {
+    match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => Some(client),
+        Err(e) => {
+            tracing::error!("CrowdSec integration disabled: {}", e);
+            None
+        }
+    }
+}
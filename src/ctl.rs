@@ -0,0 +1,246 @@
+/*
+* ctl.rs implements the `ctl` subcommand, querying a running router through its admin API
+*/
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Subcommand;
+use wireguard_router::error::Error;
+
+use crate::admin::Format;
+use crate::config;
+
+/// How long to wait for the admin API, exports of large session tables taking a while
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Address of the admin API [default: the `[admin] listen` address of the config]
+    #[arg(long)]
+    admin: Option<SocketAddr>,
+    #[arg(long, default_value = config::PATH)]
+    config: PathBuf,
+    /// Token of an `[[admin.credentials]]` entry of the router, if it has any
+    #[arg(long, env = "WIREGUARD_ROUTER_ADMIN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    #[command(subcommand)]
+    command: Ctl,
+}
+
+#[derive(Subcommand, Debug)]
+enum Ctl {
+    /// Inspect the sessions of the router
+    #[command(subcommand)]
+    Sessions(Sessions),
+    /// Pause or resume new tunnels, established ones being routed on, or show whether they are
+    /// paused
+    Lockdown {
+        #[arg(value_enum)]
+        switch: Option<Switch>,
+    },
+    /// Pin clients to a backend, so neither the strategy nor failover moves them
+    #[command(subcommand)]
+    Pins(Pins),
+    /// Show how the router would route a packet, without sending it
+    Explain(DatagramArgs),
+    /// Route a crafted packet as if it was received, printing the events it caused
+    Inject {
+        #[command(flatten)]
+        datagram: DatagramArgs,
+        /// The `[admin] inject_token` of the router [default: the token of the credential given
+        /// to `ctl`]
+        #[arg(long, env = "WIREGUARD_ROUTER_INJECT_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct DatagramArgs {
+    /// Address the packet comes from
+    #[arg(long)]
+    source: SocketAddr,
+    /// Local address it is received on
+    #[arg(long)]
+    local: Option<IpAddr>,
+    /// The packet as hex or base64
+    #[arg(required_unless_present = "pubkey", conflicts_with = "pubkey")]
+    packet: Option<String>,
+    /// An initiation to the peer with this base64 key instead
+    #[arg(long)]
+    pubkey: Option<String>,
+}
+
+impl DatagramArgs {
+    fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "source": self.source,
+            "local": self.local,
+            "packet": self.packet,
+            "pubkey": self.pubkey,
+        })
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Subcommand, Debug)]
+enum Pins {
+    /// List the pinned clients
+    List,
+    /// Pin a client to a backend, e.g. ahead of its first handshake
+    Add { client: IpAddr, backend: SocketAddr },
+    /// Pin the client of a session to the session's backend
+    Session { index: String },
+    /// Let a client be routed like any other again
+    Remove { client: IpAddr },
+}
+
+#[derive(Subcommand, Debug)]
+enum Sessions {
+    /// Write every session with its counters to a file, as a point-in-time snapshot
+    Export {
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        /// File to write, - for stdout
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Forget the idle sessions and those of removed peers now, printing what was evicted and why
+    Gc,
+}
+
+pub async fn run(args: Args) -> Result<(), Error> {
+    let admin = match args.admin {
+        Some(admin) => admin,
+        None => admin_address(&args.config)?,
+    };
+    let token = args.token.as_deref();
+    match args.command {
+        Ctl::Sessions(Sessions::Export { format, output }) => {
+            let format = match format {
+                Format::Json => "json",
+                Format::Csv => "csv",
+            };
+            let url = format!("http://{admin}/sessions/export?format={format}");
+            let export = get(&url, token).await?;
+            if output.as_os_str() == "-" {
+                print!("{export}");
+                return Ok(());
+            }
+            std::fs::write(&output, export).map_err(|source| Error::WriteFile {
+                path: output,
+                source,
+            })
+        }
+        Ctl::Lockdown { switch } => {
+            let url = format!("http://{admin}/lockdown");
+            let state = match switch {
+                None => get(&url, token).await?,
+                Some(Switch::On) => post(&format!("{url}?active=true"), token).await?,
+                Some(Switch::Off) => post(&format!("{url}?active=false"), token).await?,
+            };
+            println!("{state}");
+            Ok(())
+        }
+        Ctl::Pins(command) => {
+            let url = format!("http://{admin}/pins");
+            let pins = match command {
+                Pins::List => get(&url, token).await?,
+                Pins::Add { client, backend } => {
+                    post(&format!("{url}?client={client}&backend={backend}"), token).await?
+                }
+                Pins::Session { index } => post(&format!("{url}?index={index}"), token).await?,
+                Pins::Remove { client } => {
+                    let url = format!("{url}?client={client}");
+                    request(reqwest::Method::DELETE, &url, None, token).await?
+                }
+            };
+            println!("{pins}");
+            Ok(())
+        }
+        Ctl::Explain(datagram) => {
+            let url = format!("http://{admin}/explain");
+            let body = Some(datagram.body());
+            println!(
+                "{}",
+                request(reqwest::Method::POST, &url, body, token).await?
+            );
+            Ok(())
+        }
+        Ctl::Inject {
+            datagram,
+            token: inject_token,
+        } => {
+            let url = format!("http://{admin}/inject");
+            let body = Some(datagram.body());
+            let token = inject_token.as_deref().or(token);
+            let events = request(reqwest::Method::POST, &url, body, token).await?;
+            println!("{events}");
+            Ok(())
+        }
+        Ctl::Sessions(Sessions::Gc) => {
+            let report = post(&format!("http://{admin}/sessions/gc"), token).await?;
+            println!("{report}");
+            Ok(())
+        }
+    }
+}
+
+/// Where the config's admin API listens, through loopback if it listens on all addresses
+fn admin_address(path: &std::path::Path) -> Result<SocketAddr, Error> {
+    let Some(admin) = config::load_from(path)?.admin else {
+        return Err(Error::InvalidConfig(format!(
+            "{} has no [admin] table, pass --admin",
+            path.display()
+        )));
+    };
+    let mut addr = admin.listen;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    Ok(addr)
+}
+
+async fn get(url: &str, token: Option<&str>) -> Result<String, Error> {
+    request(reqwest::Method::GET, url, None, token).await
+}
+
+async fn post(url: &str, token: Option<&str>) -> Result<String, Error> {
+    request(reqwest::Method::POST, url, None, token).await
+}
+
+pub(crate) async fn request(
+    method: reqwest::Method,
+    url: &str,
+    body: Option<serde_json::Value>,
+    token: Option<&str>,
+) -> Result<String, Error> {
+    let fetch = async {
+        let request = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?
+            .request(method, url);
+        let request = match &body {
+            Some(body) => request.json(body),
+            None => request,
+        };
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        request.send().await?.error_for_status()?.text().await
+    };
+    fetch.await.map_err(|source| Error::AdminRequest {
+        url: url.to_string(),
+        source: source.into(),
+    })
+}
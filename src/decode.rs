@@ -0,0 +1,169 @@
+/*
+* decode.rs implements the `decode` subcommand, printing the WireGuard headers of packets
+*/
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use wireguard_router::error::Error;
+use wireguard_router::packet::{HandshakeInitiation, WireguardPacket};
+use wireguard_router::{Peer, pcap};
+
+use crate::config;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// A packet as hex or base64
+    #[arg(required_unless_present = "pcap", conflicts_with = "pcap")]
+    packet: Option<String>,
+    /// Decode all UDP datagrams of a pcap capture instead
+    #[arg(long)]
+    pcap: Option<PathBuf>,
+    /// Config whose peers handshake initiations are matched against
+    #[arg(long, default_value = config::PATH)]
+    config: PathBuf,
+}
+
+pub fn run(args: Args) -> Result<(), Error> {
+    // matching is optional, so a missing default config is not an error
+    let peers = if args.config.exists() {
+        config::load_from(&args.config)?.peers
+    } else {
+        Vec::new()
+    };
+
+    match args.pcap {
+        Some(path) => {
+            let file = File::open(&path).map_err(|source| pcap_error(&path, source))?;
+            let reader = pcap::Reader::new(BufReader::new(file))
+                .map_err(|source| pcap_error(&path, source))?;
+            for (i, datagram) in reader.enumerate() {
+                let datagram = datagram.map_err(|source| pcap_error(&path, source))?;
+                println!(
+                    "#{} {}.{:06} {} -> {}",
+                    i + 1,
+                    datagram.timestamp.as_secs(),
+                    datagram.timestamp.subsec_micros(),
+                    datagram.source,
+                    datagram.destination
+                );
+                print_packet(&datagram.payload, &peers);
+            }
+        }
+        None => {
+            let packet = args.packet.unwrap_or_default();
+            let bytes = parse_bytes(&packet).ok_or_else(|| {
+                Error::InvalidInput(format!("{:?} is neither hex nor base64", packet))
+            })?;
+            print_packet(&bytes, &peers);
+        }
+    }
+    Ok(())
+}
+
+fn pcap_error(path: &Path, source: std::io::Error) -> Error {
+    Error::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// Hex, with optional whitespace and colons as produced by packet dumps, or base64
+pub(crate) fn parse_bytes(input: &str) -> Option<Vec<u8>> {
+    let digits: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+    if digits.len().is_multiple_of(2) && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+            .collect();
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(input.trim())
+        .ok()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn print_packet(data: &[u8], peers: &[Peer]) {
+    let packet = match WireguardPacket::parse(data) {
+        Ok(packet) => packet,
+        Err(e) => {
+            println!("  not a WireGuard message ({} bytes): {}", data.len(), e);
+            return;
+        }
+    };
+    println!(
+        "  type: {:?} ({}), {} bytes",
+        packet.message_type(),
+        packet.message_type().code(),
+        data.len()
+    );
+    match packet {
+        WireguardPacket::HandshakeInitiation(initiation) => {
+            println!(
+                "  sender: {} ({})",
+                initiation.sender(),
+                initiation.sender_index()
+            );
+            println!("  mac1: {}", hex(initiation.mac1()));
+            println!("  mac2: {}", hex(initiation.mac2()));
+            let covered = &data[..HandshakeInitiation::MAC1_OFFSET];
+            let matches: Vec<(&Peer, &[u8; 32])> = peers
+                .iter()
+                .filter_map(|p| Some((p, p.matching_key(covered, initiation.mac1())?)))
+                .collect();
+            if matches.is_empty() {
+                println!("  matches no configured peer");
+            }
+            for (peer, key) in matches {
+                match peer.other_keys.is_empty() {
+                    true => println!("  matches peer: {}", peer),
+                    false => println!(
+                        "  matches peer: {} with key {}",
+                        peer,
+                        base64::engine::general_purpose::STANDARD.encode(key)
+                    ),
+                }
+            }
+        }
+        WireguardPacket::HandshakeResponse(response) => {
+            println!(
+                "  sender: {} ({})",
+                response.sender(),
+                response.sender_index()
+            );
+            println!(
+                "  receiver: {} ({})",
+                response.receiver(),
+                response.receiver_index()
+            );
+            println!("  mac1: {}", hex(response.mac1()));
+            println!("  mac2: {}", hex(response.mac2()));
+        }
+        WireguardPacket::CookieReply(reply) => {
+            println!(
+                "  receiver: {} ({})",
+                reply.receiver(),
+                reply.receiver_index()
+            );
+        }
+        WireguardPacket::TransportData(header, payload) => {
+            println!(
+                "  receiver: {} ({})",
+                header.receiver(),
+                header.receiver_index()
+            );
+            println!("  counter: {}", header.counter());
+            println!("  payload: {} bytes", payload.len());
+        }
+        _ => {}
+    }
+}
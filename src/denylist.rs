@@ -0,0 +1,189 @@
+/*
+* denylist.rs holds the networks whose datagrams the router drops, gathered from several sources
+*/
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+/// An IP network, e.g. `192.0.2.0/24`, or a single address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Network {
+    /// the network address, with the bits beyond the prefix cleared
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// The network of `address` with a `prefix` long mask, `None` if the prefix is too long
+    ///
+    /// IPv4-mapped IPv6 addresses are taken as IPv4, as the router sees clients in that form.
+    pub fn new(address: IpAddr, prefix: u8) -> Option<Self> {
+        let address = match address {
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_some() && prefix >= 96 => {
+                return Network::new(IpAddr::V4(ip.to_ipv4_mapped()?), prefix - 96);
+            }
+            ip => ip,
+        };
+        let bits = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        (prefix <= bits).then(|| Network {
+            address: mask(address, prefix),
+            prefix,
+        })
+    }
+
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.address.is_ipv4() && mask(ip, self.prefix) == self.address
+    }
+}
+
+fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from_bits(ip.to_bits() & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & mask))
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network {value:?}, expected an address or CIDR");
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => {
+                let address: IpAddr = address.parse().map_err(|_| invalid())?;
+                (address, prefix.parse().map_err(|_| invalid())?)
+            }
+            None => {
+                let address: IpAddr = value.parse().map_err(|_| invalid())?;
+                let prefix = if address.is_ipv4() { 32 } else { 128 };
+                (address, prefix)
+            }
+        };
+        Network::new(address, prefix).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Network {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Default)]
+struct Lists {
+    /// source -> its networks
+    sources: HashMap<String, Vec<Network>>,
+    /// the networks of all sources, by prefix length, so a lookup masks once per length
+    merged: Vec<(u8, HashSet<IpAddr>)>,
+}
+
+impl Lists {
+    fn merge(&mut self) {
+        let mut merged: HashMap<u8, HashSet<IpAddr>> = HashMap::new();
+        for network in self.sources.values().flatten() {
+            merged
+                .entry(network.prefix)
+                .or_default()
+                .insert(network.address);
+        }
+        // the shortest prefixes cover the most addresses, so hits are found early
+        let mut merged: Vec<_> = merged.into_iter().collect();
+        merged.sort_by_key(|(prefix, _)| *prefix);
+        self.merged = merged;
+    }
+}
+
+/// The networks clients are refused from, see [`Router::denylist`](crate::router::Router::denylist)
+///
+/// Each source, e.g. the config or a blocklist feed, has its own networks, which it replaces
+/// as a whole: a lookup sees either all of a source's previous networks or all of its new ones.
+#[derive(Clone, Default)]
+pub struct Denylist(Arc<RwLock<Lists>>);
+
+impl Denylist {
+    /// Replaces the networks of `source` by `networks`, removing the source if there are none
+    pub fn set(&self, source: &str, networks: Vec<Network>) {
+        let mut lists = self.0.write().unwrap();
+        match networks.is_empty() {
+            true => lists.sources.remove(source),
+            false => lists.sources.insert(source.to_string(), networks),
+        };
+        lists.merge();
+    }
+
+    /// Whether `ip` is within a network of any source
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let lists = self.0.read().unwrap();
+        if lists.merged.is_empty() {
+            return false;
+        }
+        let ip = ip.to_canonical();
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        lists
+            .merged
+            .iter()
+            .filter(|(prefix, _)| *prefix <= bits)
+            .any(|(prefix, addresses)| addresses.contains(&mask(ip, *prefix)))
+    }
+
+    /// The distinct networks of all sources
+    pub fn len(&self) -> usize {
+        let lists = self.0.read().unwrap();
+        lists
+            .merged
+            .iter()
+            .map(|(_, addresses)| addresses.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().merged.is_empty()
+    }
+
+    /// The sources and how many networks each has, in the order of their names
+    pub fn sources(&self) -> Vec<(String, usize)> {
+        let lists = self.0.read().unwrap();
+        let names: BTreeSet<&String> = lists.sources.keys().collect();
+        names
+            .into_iter()
+            .map(|name| (name.clone(), lists.sources[name].len()))
+            .collect()
+    }
+}
@@ -0,0 +1,146 @@
+/*
+* discovery.rs merges the peers found at runtime, e.g. in service registries, with the configured ones
+*/
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, de};
+use tokio::sync::watch;
+
+use crate::schedule::{Rule, Window};
+use crate::{Peer, PeerConfig, PubKeys};
+
+#[cfg(feature = "consul")]
+pub mod consul;
+#[cfg(feature = "dns")]
+pub mod dns;
+#[cfg(feature = "docker")]
+pub mod docker;
+#[cfg(feature = "etcd")]
+pub mod etcd;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+
+/// The peer list a [`Router`](crate::router::Router) follows, the configured peers
+/// followed by those of every discovery source
+#[derive(Clone)]
+pub struct PeerSet {
+    inner: Arc<Mutex<Sources>>,
+}
+
+struct Sources {
+    configured: Vec<Peer>,
+    /// source -> peers it found
+    discovered: BTreeMap<String, Vec<Peer>>,
+    tx: watch::Sender<Vec<Peer>>,
+}
+
+impl PeerSet {
+    pub fn new(configured: Vec<Peer>) -> (Self, watch::Receiver<Vec<Peer>>) {
+        let (tx, rx) = watch::channel(configured.clone());
+        let sources = Sources {
+            configured,
+            discovered: BTreeMap::new(),
+            tx,
+        };
+        let set = PeerSet {
+            inner: Arc::new(Mutex::new(sources)),
+        };
+        (set, rx)
+    }
+
+    /// Replaces the configured peers, e.g. after a config reload
+    pub fn set_configured(&self, peers: Vec<Peer>) {
+        let mut sources = self.inner.lock().unwrap();
+        sources.configured = peers;
+        sources.publish();
+    }
+
+    /// Replaces the peers found by `source`
+    pub fn set_discovered(&self, source: &str, peers: Vec<Peer>) {
+        let mut sources = self.inner.lock().unwrap();
+        sources.discovered.insert(source.to_string(), peers);
+        sources.publish();
+    }
+}
+
+impl Sources {
+    fn publish(&self) {
+        let peers = self
+            .configured
+            .iter()
+            .chain(self.discovered.values().flatten())
+            .cloned()
+            .collect();
+        self.tx.send_replace(peers);
+    }
+}
+
+/// The parts of a peer shared by all backends a discovery source finds, which only differ in address
+///
+/// It is configured like a peer entry without the address.
+#[derive(Clone, Debug)]
+pub struct Template(Peer);
+
+impl Template {
+    pub fn peer(&self, address: SocketAddr) -> Peer {
+        Peer {
+            address,
+            ..self.0.clone()
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Fields {
+            pubkey: PubKeys,
+            proxy: Option<String>,
+            name: Option<String>,
+            max_sessions: Option<usize>,
+            #[serde(default)]
+            maintenance: Vec<Window>,
+            #[serde(default)]
+            primary: bool,
+            weight: Option<u32>,
+            #[serde(default)]
+            schedule: Vec<Rule>,
+            fwmark: Option<u32>,
+            interface: Option<String>,
+            source_address: Option<String>,
+            tunnel: Option<String>,
+            #[serde(default)]
+            quic: bool,
+        }
+
+        let fields = Fields::deserialize(deserializer)?;
+        let (pubkey, other_pubkeys) = fields.pubkey.split();
+        let config = PeerConfig {
+            // replaced by the discovered addresses
+            address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).to_string(),
+            pubkey,
+            proxy: fields.proxy,
+            name: fields.name,
+            max_sessions: fields.max_sessions,
+            other_pubkeys,
+            maintenance: fields.maintenance,
+            primary: fields.primary,
+            weight: fields.weight,
+            schedule: fields.schedule,
+            fwmark: fields.fwmark,
+            interface: fields.interface,
+            source_address: fields.source_address,
+            tunnel: fields.tunnel,
+            quic: fields.quic,
+        };
+        Peer::try_from(config)
+            .map(Template)
+            .map_err(|e| de::Error::custom(e.with_field()))
+    }
+}
@@ -0,0 +1,147 @@
+/*
+* consul.rs keeps the backends of a peer in sync with the healthy instances of a Consul service
+*/
+
+use std::time::Duration;
+
+use reqwest::Client;
+use serde::Deserialize;
+
+use super::{PeerSet, Template};
+
+/// How long Consul may hold a blocking query before answering without changes
+const WAIT: Duration = Duration::from_secs(300);
+/// Pause after a failed query, so an unreachable agent isn't hammered
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A peer whose backends are the instances of a Consul service passing their health checks
+#[derive(Deserialize, Debug, Clone)]
+pub struct Service {
+    pub service: String,
+    /// HTTP address of the Consul agent, defaults to `CONSUL_HTTP_ADDR` or the local agent
+    pub address: Option<String>,
+    /// only instances carrying this tag
+    pub tag: Option<String>,
+    pub datacenter: Option<String>,
+    /// ACL token, defaults to `CONSUL_HTTP_TOKEN`
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub peer: Template,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Entry {
+    node: Node,
+    service: Instance,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Instance {
+    address: String,
+    port: u16,
+}
+
+/// Follows the healthy instances of `service` through blocking queries, publishing a peer per instance to `peers`
+///
+/// Failed queries are logged and retried, so this only returns if no HTTP client can be created.
+pub async fn discover(service: Service, peers: PeerSet) {
+    let agent = service
+        .address
+        .clone()
+        .or_else(|| std::env::var("CONSUL_HTTP_ADDR").ok())
+        .unwrap_or_else(|| "127.0.0.1:8500".to_string());
+    let agent = match agent.contains("://") {
+        true => agent,
+        false => format!("http://{agent}"),
+    };
+    let url = format!(
+        "{}/v1/health/service/{}",
+        agent.trim_end_matches('/'),
+        service.service
+    );
+    let token = service
+        .token
+        .clone()
+        .or_else(|| std::env::var("CONSUL_HTTP_TOKEN").ok());
+    let source = format!("consul:{}", service.service);
+    // Consul answers up to a sixteenth of the wait late, anything beyond means a dead connection
+    let client = match Client::builder().timeout(WAIT * 2).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("cannot query {}: {}", source, e);
+            return;
+        }
+    };
+
+    let mut index = 0;
+    let mut published = None;
+    loop {
+        let mut query = vec![
+            ("passing", "true".to_string()),
+            ("index", index.to_string()),
+            ("wait", format!("{}s", WAIT.as_secs())),
+        ];
+        query.extend(service.tag.clone().map(|tag| ("tag", tag)));
+        query.extend(service.datacenter.clone().map(|dc| ("dc", dc)));
+        let mut request = client.get(&url).query(&query);
+        if let Some(token) = &token {
+            request = request.header("X-Consul-Token", token);
+        }
+
+        let (next, entries) = match query_instances(request).await {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::warn!("querying {} failed: {}", source, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        // the index only ever decreases if Consul's state was reset
+        index = if next < index { 0 } else { next };
+        if index == 0 {
+            // without an index every query returns at once
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+
+        let mut addresses = Vec::new();
+        for entry in entries {
+            // instances without an address of their own share their node's
+            let host = match entry.service.address.is_empty() {
+                true => entry.node.address,
+                false => entry.service.address,
+            };
+            match tokio::net::lookup_host((host.as_str(), entry.service.port)).await {
+                Ok(resolved) => addresses.extend(resolved),
+                Err(e) => tracing::warn!("{} instance {} does not resolve: {}", source, host, e),
+            }
+        }
+        addresses.sort();
+        addresses.dedup();
+        if published.as_ref() != Some(&addresses) {
+            tracing::info!("{} has {} healthy instances", source, addresses.len());
+            let found = addresses.iter().map(|&a| service.peer.peer(a)).collect();
+            peers.set_discovered(&source, found);
+            published = Some(addresses);
+        }
+    }
+}
+
+/// Runs a blocking query, returning the index to block on next and the instances
+async fn query_instances(request: reqwest::RequestBuilder) -> reqwest::Result<(u64, Vec<Entry>)> {
+    let response = request.send().await?.error_for_status()?;
+    let index = response
+        .headers()
+        .get("X-Consul-Index")
+        .and_then(|index| index.to_str().ok())
+        .and_then(|index| index.parse().ok())
+        .unwrap_or(0);
+    Ok((index, response.json().await?))
+}
@@ -0,0 +1,127 @@
+/*
+* dns.rs keeps the backends of a peer in sync with the targets of a DNS SRV record
+*/
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hickory_resolver::TokioResolver;
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+
+use super::{PeerSet, Template};
+
+/// Bounds on how long resolved records are used, whatever their TTL
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+const MAX_REFRESH: Duration = Duration::from_secs(300);
+/// Pause after a failed lookup, so an unreachable resolver isn't hammered
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A peer whose backends are the targets of an SRV record
+#[derive(Deserialize, Debug, Clone)]
+pub struct Service {
+    /// e.g. `_wireguard._udp.example.com`
+    pub srv: String,
+    #[serde(flatten)]
+    pub peer: Template,
+}
+
+/// A resolved address of an SRV target
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Target {
+    priority: u16,
+    weight: u16,
+    address: SocketAddr,
+}
+
+/// Resolves `service` whenever its records expire, publishing a peer per target address to `peers`
+///
+/// The peers are in the order RFC 2782 has clients try targets: by priority, and randomly by weight
+/// among equal priorities. That order is only drawn again when the records change, so with the
+/// default policy every session goes to the first target until then.
+/// Failed lookups are logged and retried, so this only returns if the system resolver config can't be read.
+pub async fn discover(service: Service, peers: PeerSet) {
+    let source = format!("dns:{}", service.srv);
+    let resolver = match TokioResolver::builder_tokio() {
+        Ok(builder) => builder.build(),
+        Err(e) => {
+            tracing::error!("cannot resolve {}: {}", source, e);
+            return;
+        }
+    };
+
+    let mut published = None;
+    loop {
+        let (mut targets, valid_until) = match resolver.srv_lookup(service.srv.as_str()).await {
+            Ok(lookup) => {
+                let mut targets = Vec::new();
+                for srv in lookup.iter() {
+                    // a target of "." means the service is decidedly not available
+                    if srv.target().is_root() {
+                        continue;
+                    }
+                    match resolver.lookup_ip(srv.target().clone()).await {
+                        Ok(ips) => targets.extend(ips.iter().map(|ip| Target {
+                            priority: srv.priority(),
+                            weight: srv.weight(),
+                            address: SocketAddr::new(ip, srv.port()),
+                        })),
+                        Err(e) => {
+                            tracing::warn!(
+                                "{} target {} does not resolve: {}",
+                                source,
+                                srv.target(),
+                                e
+                            )
+                        }
+                    }
+                }
+                (targets, lookup.as_lookup().valid_until())
+            }
+            Err(e) if e.is_no_records_found() => (Vec::new(), Instant::now() + MIN_REFRESH),
+            Err(e) => {
+                tracing::warn!("looking up {} failed: {}", source, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        targets.sort();
+        targets.dedup();
+        if published.as_ref() != Some(&targets) {
+            tracing::info!("{} has {} targets", source, targets.len());
+            let found = order(&targets)
+                .into_iter()
+                .map(|address| service.peer.peer(address))
+                .collect();
+            peers.set_discovered(&source, found);
+            published = Some(targets);
+        }
+
+        let ttl = valid_until.saturating_duration_since(Instant::now());
+        tokio::time::sleep(ttl.clamp(MIN_REFRESH, MAX_REFRESH)).await;
+    }
+}
+
+/// Orders the addresses of `targets`, sorted by priority and weight, as described in RFC 2782
+fn order(targets: &[Target]) -> Vec<SocketAddr> {
+    let mut ordered = Vec::with_capacity(targets.len());
+    for group in targets.chunk_by(|a, b| a.priority == b.priority) {
+        // zero weights come first, which gives them a small chance of being picked
+        let mut group = group.to_vec();
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|target| u32::from(target.weight)).sum();
+            let pick = OsRng.next_u32() % (total + 1);
+            let mut sum = 0;
+            let next = group
+                .iter()
+                .position(|target| {
+                    sum += u32::from(target.weight);
+                    sum >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(next).address);
+        }
+    }
+    ordered
+}
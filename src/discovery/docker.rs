@@ -0,0 +1,180 @@
+/*
+* docker.rs registers the running containers of the local Docker daemon that are labeled with a pubkey as backends
+*/
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bollard::Docker;
+use bollard::models::ContainerSummary;
+use bollard::query_parameters::{EventsOptions, ListContainersOptions};
+use futures::StreamExt;
+use serde::Deserialize;
+
+use super::PeerSet;
+use crate::{Peer, PeerConfig};
+
+/// Label holding the public key of the WireGuard server in a container, which makes it a backend
+pub const PUBKEY_LABEL: &str = "wireguard-router.pubkey";
+/// Label holding the UDP port the server listens on inside the container
+pub const PORT_LABEL: &str = "wireguard-router.port";
+/// Label naming the backend in logs, defaults to the container name
+pub const NAME_LABEL: &str = "wireguard-router.name";
+
+const DEFAULT_PORT: u16 = 51820;
+/// Pause before listing again after the daemon failed, so it isn't hammered
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const SOURCE: &str = "docker";
+
+/// Discovery of labeled containers on the daemon of `DOCKER_HOST`, or the local socket
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Daemon {
+    /// network whose address of a container is used, defaults to the first one with an address
+    pub network: Option<String>,
+}
+
+/// Follows the labeled containers as they start and stop, publishing a peer per container to `peers`
+///
+/// Failures are logged and retried, so this only returns if no daemon connection can be set up.
+pub async fn discover(daemon: Daemon, peers: PeerSet) {
+    let docker = match Docker::connect_with_local_defaults() {
+        Ok(docker) => docker,
+        Err(e) => {
+            tracing::error!("cannot connect to the docker daemon: {}", e);
+            return;
+        }
+    };
+    let filters = |mut filters: HashMap<String, Vec<String>>| {
+        filters.insert("label".to_string(), vec![PUBKEY_LABEL.to_string()]);
+        Some(filters)
+    };
+
+    loop {
+        // replay the events from before the listing, so no change in between is missed
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut events = docker.events(Some(EventsOptions {
+            since: Some(since.to_string()),
+            filters: filters(HashMap::from([
+                ("type".to_string(), vec!["container".to_string()]),
+                (
+                    "event".to_string(),
+                    ["start", "die", "pause", "unpause"]
+                        .map(String::from)
+                        .to_vec(),
+                ),
+            ])),
+            ..Default::default()
+        }));
+        let list = ListContainersOptions {
+            filters: filters(HashMap::from([(
+                "status".to_string(),
+                vec!["running".to_string()],
+            )])),
+            ..Default::default()
+        };
+
+        loop {
+            match docker.list_containers(Some(list.clone())).await {
+                Ok(containers) => {
+                    let found: Vec<Peer> = containers
+                        .iter()
+                        .filter_map(|container| backend(container, &daemon))
+                        .collect();
+                    tracing::info!("{} has {} labeled containers", SOURCE, found.len());
+                    peers.set_discovered(SOURCE, found);
+                }
+                Err(e) => {
+                    tracing::warn!("listing containers failed: {}", e);
+                    break;
+                }
+            }
+            // containers are listed again on every change, there are few enough of them
+            match events.next().await {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    tracing::warn!("following docker events failed: {}", e);
+                    break;
+                }
+                None => {
+                    tracing::warn!("docker events ended");
+                    break;
+                }
+            }
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// The backend of a labeled container, if its labels are valid and it has an address
+fn backend(container: &ContainerSummary, daemon: &Daemon) -> Option<Peer> {
+    let labels = container.labels.as_ref()?;
+    let container_name = container
+        .names
+        .iter()
+        .flatten()
+        .next()
+        .map(|name| name.trim_start_matches('/').to_string())
+        .or_else(|| container.id.clone())
+        .unwrap_or_default();
+
+    let port = match labels.get(PORT_LABEL) {
+        Some(port) => match port.parse() {
+            Ok(port) => port,
+            Err(e) => {
+                tracing::warn!(
+                    "container {}: invalid {}: {}",
+                    container_name,
+                    PORT_LABEL,
+                    e
+                );
+                return None;
+            }
+        },
+        None => DEFAULT_PORT,
+    };
+    let networks = container
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.networks.as_ref());
+    let mut endpoints: Vec<_> = networks
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| {
+            daemon
+                .network
+                .as_ref()
+                .is_none_or(|network| network == *name)
+        })
+        .collect();
+    endpoints.sort_by_key(|(name, _)| name.as_str());
+    let ip = endpoints.into_iter().find_map(|(_, endpoint)| {
+        [&endpoint.ip_address, &endpoint.global_ipv6_address]
+            .into_iter()
+            .flatten()
+            .find_map(|ip| ip.parse::<IpAddr>().ok())
+    });
+    let Some(ip) = ip else {
+        tracing::warn!("container {} has no address to route to", container_name);
+        return None;
+    };
+
+    let config = PeerConfig {
+        address: SocketAddr::new(ip, port).to_string(),
+        pubkey: labels.get(PUBKEY_LABEL)?.clone(),
+        proxy: None,
+        name: Some(
+            labels
+                .get(NAME_LABEL)
+                .cloned()
+                .unwrap_or(container_name.clone()),
+        ),
+        ..Default::default()
+    };
+    Peer::try_from(config)
+        .inspect_err(|e| tracing::warn!("container {}: {}", container_name, e.with_field()))
+        .ok()
+}
@@ -0,0 +1,235 @@
+/*
+* etcd.rs keeps peers in sync with the JSON values under a key prefix in etcd, through its HTTP gateway
+*/
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use reqwest::Client;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+
+use super::PeerSet;
+use crate::Peer;
+
+/// Pause before listing again after the watch failed, so an unreachable cluster isn't hammered
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Peers stored as JSON under a key prefix, one key per peer
+#[derive(Deserialize, Debug, Clone)]
+pub struct Prefix {
+    /// e.g. `/wireguard-router/peers/`
+    pub prefix: String,
+    /// HTTP address of an etcd member, defaults to the first of `ETCDCTL_ENDPOINTS` or the local member
+    pub endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RangeResponse {
+    header: Header,
+    #[serde(default)]
+    kvs: Vec<KeyValue>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    #[serde(deserialize_with = "int64")]
+    revision: i64,
+}
+
+#[derive(Deserialize)]
+struct KeyValue {
+    key: String,
+    #[serde(default)]
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct WatchMessage {
+    result: Option<WatchResult>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct WatchResult {
+    #[serde(default)]
+    events: Vec<WatchEvent>,
+    /// set if the revision to watch from was compacted, among others
+    #[serde(default)]
+    canceled: bool,
+}
+
+#[derive(Deserialize)]
+struct WatchEvent {
+    /// absent for puts, the default of the enum
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    kv: KeyValue,
+}
+
+/// The gateway renders 64 bit integers as strings
+fn int64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Lists the peers under `prefix` and watches it for changes, publishing them to `peers`
+///
+/// Values that aren't valid peers are logged and keep the previous value of their key, like an
+/// invalid config file keeps the previous config. Failures are retried, so this never returns.
+pub async fn discover(prefix: Prefix, peers: PeerSet) {
+    let endpoint = prefix
+        .endpoint
+        .clone()
+        .or_else(|| {
+            let endpoints = std::env::var("ETCDCTL_ENDPOINTS").ok()?;
+            endpoints.split(',').next().map(str::to_string)
+        })
+        .unwrap_or_else(|| "127.0.0.1:2379".to_string());
+    let endpoint = match endpoint.contains("://") {
+        true => endpoint,
+        false => format!("http://{endpoint}"),
+    };
+    let source = format!("etcd:{}", prefix.prefix);
+    let client = Client::new();
+
+    loop {
+        match follow(
+            &client,
+            endpoint.trim_end_matches('/'),
+            &prefix.prefix,
+            &peers,
+            &source,
+        )
+        .await
+        {
+            Ok(()) => tracing::warn!("watching {} ended, listing again", source),
+            Err(e) => tracing::warn!("watching {} failed: {}", source, e),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// Lists the peers under `prefix`, then applies the changes after that revision until the watch ends
+async fn follow(
+    client: &Client,
+    endpoint: &str,
+    prefix: &str,
+    peers: &PeerSet,
+    source: &str,
+) -> Result<(), BoxError> {
+    let key = STANDARD.encode(prefix);
+    let range_end = STANDARD.encode(range_end(prefix.as_bytes()));
+
+    let range = json!({ "key": key, "range_end": range_end });
+    let list: RangeResponse = client
+        .post(format!("{endpoint}/v3/kv/range"))
+        .json(&range)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let mut found = BTreeMap::new();
+    for kv in list.kvs {
+        put(&mut found, kv, source);
+    }
+    publish(peers, source, &found);
+
+    let watch = json!({
+        "create_request": {
+            "key": key,
+            "range_end": range_end,
+            "start_revision": (list.header.revision + 1).to_string(),
+        }
+    });
+    let mut response = client
+        .post(format!("{endpoint}/v3/watch"))
+        .json(&watch)
+        .send()
+        .await?
+        .error_for_status()?;
+    // the gateway streams one JSON message per watch response
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        let mut messages =
+            serde_json::Deserializer::from_slice(&buffer).into_iter::<WatchMessage>();
+        let mut changed = false;
+        for message in messages.by_ref() {
+            let message = match message {
+                Ok(message) => message,
+                // the rest of the message is in the next chunk
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(error) = message.error {
+                return Err(error.to_string().into());
+            }
+            let Some(result) = message.result else {
+                continue;
+            };
+            if result.canceled {
+                return Err("the watch was canceled".into());
+            }
+            for event in result.events {
+                changed = true;
+                match event.kind.as_deref() {
+                    Some("DELETE") => {
+                        found.remove(&decode(&event.kv.key));
+                    }
+                    _ => put(&mut found, event.kv, source),
+                }
+            }
+        }
+        let consumed = messages.byte_offset();
+        buffer.drain(..consumed);
+        if changed {
+            publish(peers, source, &found);
+        }
+    }
+    Ok(())
+}
+
+/// Parses the peer stored in `kv`, keeping the key's previous peer if it is invalid
+fn put(found: &mut BTreeMap<String, Peer>, kv: KeyValue, source: &str) {
+    let key = decode(&kv.key);
+    let peer = STANDARD
+        .decode(&kv.value)
+        .map_err(|e| e.to_string())
+        .and_then(|value| serde_json::from_slice(&value).map_err(|e| e.to_string()));
+    match peer {
+        Ok(peer) => {
+            found.insert(key, peer);
+        }
+        Err(e) => tracing::warn!("ignoring invalid peer {} in {}: {}", key, source, e),
+    }
+}
+
+fn publish(peers: &PeerSet, source: &str, found: &BTreeMap<String, Peer>) {
+    tracing::info!("{} has {} peers", source, found.len());
+    peers.set_discovered(source, found.values().cloned().collect());
+}
+
+fn decode(key: &str) -> String {
+    let key = STANDARD.decode(key).unwrap_or_default();
+    String::from_utf8_lossy(&key).into_owned()
+}
+
+/// The end of the key range covering every key starting with `prefix`
+fn range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // every key
+    vec![0]
+}
@@ -0,0 +1,109 @@
+/*
+* kubernetes.rs keeps the backends of a peer in sync with the ready endpoints of a Kubernetes Service
+*/
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use futures::StreamExt;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::runtime::WatchStreamExt;
+use kube::runtime::watcher::{self, Event, watcher};
+use kube::{Api, Client, ResourceExt};
+use serde::Deserialize;
+
+use super::{PeerSet, Template};
+
+/// A peer whose backends are the ready endpoints of a Service
+#[derive(Deserialize, Debug, Clone)]
+pub struct Service {
+    pub service: String,
+    /// defaults to the namespace of the client's kube config or service account
+    pub namespace: Option<String>,
+    /// name of the WireGuard port of the Service, defaults to its first UDP port
+    pub port: Option<String>,
+    #[serde(flatten)]
+    pub peer: Template,
+}
+
+/// Watches the EndpointSlices of `service`, publishing a peer per ready endpoint to `peers`
+///
+/// API errors are logged and retried with backoff, so this only returns if the watch ends.
+pub async fn discover(client: Client, service: Service, peers: PeerSet) {
+    let namespace = service
+        .namespace
+        .clone()
+        .unwrap_or_else(|| client.default_namespace().to_string());
+    let source = format!("kubernetes:{}/{}", namespace, service.service);
+    let api: Api<EndpointSlice> = Api::namespaced(client, &namespace);
+    let config = watcher::Config::default()
+        .labels(&format!("kubernetes.io/service-name={}", service.service));
+
+    // slice name -> ready endpoints, relisted slices are collected apart and swapped in at once
+    let mut slices = HashMap::new();
+    let mut relisted = HashMap::new();
+    let mut published = None;
+    let mut events = std::pin::pin!(watcher(api, config).default_backoff());
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(Event::Init) => {
+                relisted.clear();
+                continue;
+            }
+            Ok(Event::InitApply(slice)) => {
+                relisted.insert(slice.name_any(), endpoints(&slice, service.port.as_deref()));
+                continue;
+            }
+            Ok(Event::InitDone) => slices = std::mem::take(&mut relisted),
+            Ok(Event::Apply(slice)) => {
+                slices.insert(slice.name_any(), endpoints(&slice, service.port.as_deref()));
+            }
+            Ok(Event::Delete(slice)) => {
+                slices.remove(&slice.name_any());
+            }
+            Err(e) => {
+                tracing::warn!("watching {} failed: {}", source, e);
+                continue;
+            }
+        }
+
+        let mut addresses: Vec<SocketAddr> = slices.values().flatten().copied().collect();
+        addresses.sort();
+        addresses.dedup();
+        if published.as_ref() != Some(&addresses) {
+            tracing::info!("{} has {} ready endpoints", source, addresses.len());
+            let found = addresses.iter().map(|&a| service.peer.peer(a)).collect();
+            peers.set_discovered(&source, found);
+            published = Some(addresses);
+        }
+    }
+}
+
+/// The addresses of the ready endpoints of `slice` at the WireGuard port
+fn endpoints(slice: &EndpointSlice, port: Option<&str>) -> Vec<SocketAddr> {
+    let port = slice.ports.iter().flatten().find(|p| match port {
+        Some(name) => p.name.as_deref() == Some(name),
+        None => p.protocol.as_deref() == Some("UDP"),
+    });
+    let Some(port) = port
+        .and_then(|p| p.port)
+        .and_then(|p| u16::try_from(p).ok())
+    else {
+        return Vec::new();
+    };
+    slice
+        .endpoints
+        .iter()
+        // an unknown readiness is to be treated as ready
+        .filter(|endpoint| {
+            endpoint
+                .conditions
+                .as_ref()
+                .and_then(|c| c.ready)
+                .unwrap_or(true)
+        })
+        .flat_map(|endpoint| &endpoint.addresses)
+        .filter_map(|address| address.parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+}
@@ -0,0 +1,182 @@
+/*
+* doctor.rs implements the `doctor` subcommand, checking a deployment for common setup problems
+*/
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base64::Engine;
+use wireguard_router::error::{Error, Report};
+use wireguard_router::probe::{self, Outcome};
+use wireguard_router::socks::Association;
+use wireguard_router::transport::{self, Listeners};
+use x25519_dalek::StaticSecret;
+
+use crate::config;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Addresses the router listens on [default: 0.0.0.0:51337 [::]:51337]
+    listen: Vec<SocketAddr>,
+    #[arg(long, default_value = config::PATH)]
+    config: PathBuf,
+    /// File with a base64 private key the backends accept, to probe them with real handshakes
+    #[arg(long)]
+    private_key_file: Option<PathBuf>,
+    /// How long to wait for each probe, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    timeout_ms: u64,
+}
+
+/// Prints check results and counts the failures
+#[derive(Default)]
+struct Checks {
+    failed: usize,
+}
+
+impl Checks {
+    fn pass(&self, check: impl Display, detail: impl Display) {
+        println!("pass  {}: {}", check, detail);
+    }
+
+    fn warn(&self, check: impl Display, detail: impl Display) {
+        println!("warn  {}: {}", check, detail);
+    }
+
+    fn fail(&mut self, check: impl Display, detail: impl Display) {
+        println!("FAIL  {}: {}", check, detail);
+        self.failed += 1;
+    }
+}
+
+pub async fn run(args: Args) -> Result<(), Error> {
+    let mut report = Checks::default();
+    let wait = Duration::from_millis(args.timeout_ms);
+
+    let defaulted = args.listen.is_empty();
+    let addrs = crate::listen_addrs(args.listen);
+    let listen = addrs
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    match Listeners::bind(&addrs) {
+        Ok(_) => report.pass(format!("bind {}", listen), "ok"),
+        // the router falls back to the v4 default like this too
+        Err(e) if defaulted && transport::ipv6_unavailable(&e) => {
+            match Listeners::bind(&addrs[..1]) {
+                Ok(_) => report.pass(format!("bind {}", addrs[0]), "ok, IPv6 is unavailable"),
+                Err(e) => report.fail(format!("bind {}", addrs[0]), e),
+            }
+        }
+        Err(e) => report.fail(format!("bind {}", listen), e),
+    }
+
+    let key = match &args.private_key_file {
+        Some(path) => match read_private_key(path) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                report.fail("private key", Report(&e));
+                None
+            }
+        },
+        None => None,
+    };
+
+    let config = match config::load_from(&args.config) {
+        Ok(config) => {
+            report.pass(
+                "config",
+                format!("{} peers in {}", config.peers.len(), args.config.display()),
+            );
+            config
+        }
+        Err(e) => {
+            report.fail("config", Report(&e));
+            return Err(Error::ChecksFailed(report.failed));
+        }
+    };
+    if config.peers.is_empty() {
+        report.warn(
+            "peers",
+            "no peers configured, every handshake will be dropped",
+        );
+    }
+
+    for peer in &config.peers {
+        // the config only loads keys of the right length, but an all-zero key is never valid
+        if peer.pub_key == [0; 32] {
+            report.fail(format!("{} key", peer), "the public key is all zeros");
+        } else {
+            report.pass(format!("{} key", peer), "32 bytes");
+        }
+
+        if let Some(proxy) = peer.proxy {
+            match Association::connect(proxy, wait).await {
+                Ok(association) => report.pass(
+                    format!("{} proxy", peer),
+                    format!(
+                        "associated with {}, relaying via {}",
+                        proxy, association.relay
+                    ),
+                ),
+                Err(source) => report.fail(
+                    format!("{} proxy", peer),
+                    Report(&Error::Proxy { proxy, source }),
+                ),
+            }
+            // probes would need to be relayed as well
+            continue;
+        }
+
+        match probe::reachability(peer.address, wait).await {
+            Ok(Outcome::Silent) => report.pass(
+                format!("{} reachable", peer),
+                format!("no ICMP error within {}ms", args.timeout_ms),
+            ),
+            Ok(Outcome::Answered(_)) => report.warn(
+                format!("{} reachable", peer),
+                "something answered a non-WireGuard datagram, is this a WireGuard server?",
+            ),
+            Err(e) => {
+                report.fail(format!("{} reachable", peer), e);
+                continue;
+            }
+        }
+
+        if let Some(key) = &key {
+            match probe::handshake(key, peer, wait).await {
+                Ok(rtt) => report.pass(
+                    format!("{} handshake", peer),
+                    format!("response after {:?}", rtt),
+                ),
+                Err(e) => report.fail(format!("{} handshake", peer), e),
+            }
+        }
+    }
+
+    match report.failed {
+        0 => Ok(()),
+        failed => Err(Error::ChecksFailed(failed)),
+    }
+}
+
+fn read_private_key(path: &PathBuf) -> Result<StaticSecret, Error> {
+    let encoded = std::fs::read_to_string(path).map_err(|source| Error::ReadFile {
+        path: path.clone(),
+        source,
+    })?;
+    let key: [u8; 32] = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "{} does not hold a base64 32 byte key",
+                path.display()
+            ))
+        })?;
+    Ok(StaticSecret::from(key))
+}
@@ -1,9 +1,231 @@
+use std::fmt;
+use std::io;
+use std::net::{AddrParseError, SocketAddr};
+use std::path::PathBuf;
+
 use thiserror::Error;
 
-#[derive(Clone, Error, Debug)]
+#[derive(Error, Debug)]
 pub enum Error {
     #[error("Packet too short")]
     PacketTooShort,
+    #[error("Packet too long")]
+    PacketTooLong,
     #[error("Invalid Packet")]
     InvalidPacket,
+    #[cfg(feature = "watch")]
+    #[error("failed to load config from {path}")]
+    ConfigLoad {
+        path: PathBuf,
+        #[source]
+        source: Box<config::ConfigError>,
+    },
+    /// A config value that can't be used, located in the file it comes from
+    #[error("{}:{line}:{column}: {message}\n{snippet}", path.display())]
+    ConfigAt {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+        /// the offending line, with the value underlined
+        snippet: String,
+    },
+    #[error("failed to fetch config from {url}")]
+    ConfigFetch {
+        url: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("failed to fetch blocklist from {url}")]
+    BlocklistFetch {
+        url: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("CrowdSec request to {url} failed")]
+    CrowdSec {
+        url: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("admin API request to {url} failed")]
+    AdminRequest {
+        url: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("invalid signature of {what}")]
+    Signature {
+        what: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("{0} checks failed")]
+    ChecksFailed(usize),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("failed to read {path}")]
+    ReadFile {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to write {path}")]
+    WriteFile {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[cfg(feature = "watch")]
+    #[error("failed to watch {path} for changes")]
+    ConfigWatch {
+        path: PathBuf,
+        #[source]
+        source: notify::Error,
+    },
+    #[error("invalid listen address {value:?}")]
+    ListenAddress {
+        value: String,
+        #[source]
+        source: AddrParseError,
+    },
+    #[error("failed to start the async runtime")]
+    Runtime(#[source] io::Error),
+    #[error("failed to bind listener")]
+    Bind(#[source] io::Error),
+    #[error("failed to enter network namespace {name}")]
+    Netns {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to open raw ICMP sockets, which need CAP_NET_RAW")]
+    IcmpSocket(#[source] io::Error),
+    #[error("failed to drop privileges to {target}")]
+    DropPrivileges {
+        target: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to receive packet")]
+    Recv(#[source] io::Error),
+    #[error("handling packets panicked {0} times")]
+    Panicked(u64),
+    #[error("failed to send packet to {}", describe(.addr, .peer))]
+    Send {
+        addr: SocketAddr,
+        /// the peer owning `addr`, if it is a backend
+        peer: Option<String>,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to associate with SOCKS5 proxy {proxy}")]
+    Proxy {
+        proxy: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to sandbox {layer} access")]
+    Sandbox {
+        layer: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("failed to start {kind} discovery")]
+    Discovery {
+        kind: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("windows service failed")]
+    Service(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("failed to load routing policy from {path}")]
+    Policy {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+fn describe(addr: &SocketAddr, peer: &Option<String>) -> String {
+    match peer {
+        Some(peer) => format!("peer {peer} at {addr}"),
+        None => addr.to_string(),
+    }
+}
+
+impl From<crate::packet::ParseError> for Error {
+    fn from(err: crate::packet::ParseError) -> Self {
+        match err {
+            crate::packet::ParseError::TooShort => Error::PacketTooShort,
+            crate::packet::ParseError::TooLong => Error::PacketTooLong,
+            crate::packet::ParseError::Invalid => Error::InvalidPacket,
+        }
+    }
+}
+
+/// Renders an error followed by its chain of sources, e.g. for log messages
+pub struct Report<'a>(pub &'a dyn std::error::Error);
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(err) = source {
+            write!(f, ": {}", err)?;
+            source = err.source();
+        }
+        Ok(())
+    }
+}
+
+/// Why a [`PeerConfig`](crate::PeerConfig) could not be turned into a [`Peer`](crate::Peer)
+#[derive(Clone, Error, Debug, PartialEq, Eq)]
+pub enum PeerError {
+    #[error("invalid address {value:?}")]
+    InvalidAddress {
+        value: String,
+        source: AddrParseError,
+    },
+    #[error("invalid base64: {0}")]
+    InvalidPubKeyEncoding(base64::DecodeError),
+    #[error("expected a 32 byte key, got {0} bytes")]
+    InvalidPubKeyLength(usize),
+    #[error("invalid proxy address {value:?}")]
+    InvalidProxy {
+        value: String,
+        source: AddrParseError,
+    },
+    #[error("invalid source address {value:?}")]
+    InvalidSourceAddress {
+        value: String,
+        source: AddrParseError,
+    },
+    #[error("peers {first} and {second} share the pubkey {pubkey}")]
+    DuplicatePubKey {
+        pubkey: String,
+        first: String,
+        second: String,
+    },
+}
+
+impl PeerError {
+    /// The config field the error originates from
+    pub fn field(&self) -> &'static str {
+        match self {
+            PeerError::InvalidAddress { .. } => "address",
+            PeerError::InvalidPubKeyEncoding(_)
+            | PeerError::InvalidPubKeyLength(_)
+            | PeerError::DuplicatePubKey { .. } => "pubkey",
+            PeerError::InvalidProxy { .. } => "proxy",
+            PeerError::InvalidSourceAddress { .. } => "source_address",
+        }
+    }
+
+    /// Renders the error and its sources prefixed with the offending field
+    pub fn with_field(&self) -> String {
+        format!("invalid field `{}`: {}", self.field(), Report(self))
+    }
 }
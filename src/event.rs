@@ -0,0 +1,128 @@
+/*
+* event.rs contains the outcomes a router reports to its subscribers
+*/
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::packet::{Identity, MessageType, ParseError};
+
+/// Something that happened while routing a packet, see [`Router::subscribe`](crate::router::Router::subscribe)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouterEvent {
+    /// an initiation was routed to a newly selected backend
+    SessionCreated {
+        client: SocketAddr,
+        backend: SocketAddr,
+        client_index: Identity,
+    },
+    /// the backend answered the initiation of `client_index`
+    SessionEstablished {
+        client: SocketAddr,
+        backend: SocketAddr,
+        client_index: Identity,
+        backend_index: Identity,
+    },
+    Forwarded {
+        message: MessageType,
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    /// the tunnel of `client` to `backend` started more handshakes within
+    /// [`REKEY_WINDOW`](crate::router::REKEY_WINDOW) than the rekey threshold allows
+    ExcessiveRekeys {
+        client: SocketAddr,
+        backend: SocketAddr,
+        handshakes: usize,
+    },
+    /// sends to the backend failed repeatedly, new sessions avoid it for a while
+    BackendDown { backend: SocketAddr },
+    /// a send to a backend that was down succeeded
+    BackendUp { backend: SocketAddr },
+    Dropped {
+        /// `None` if the packet could not be parsed
+        message: Option<MessageType>,
+        source: SocketAddr,
+        reason: DropReason,
+    },
+}
+
+/// Why a packet was not forwarded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// a datagram from a SOCKS5 relay lacked a valid encapsulation header
+    InvalidRelayHeader,
+    /// the source is within a network of the denylist
+    Denylisted,
+    /// the datagram is longer than the maximum datagram size
+    Oversized,
+    /// the datagram didn't fit the receive buffer, so only its start was read
+    Truncated,
+    /// the datagram does not look like WireGuard traffic
+    NotWireguard,
+    Invalid(ParseError),
+    /// no configured peer matches the mac1 of an initiation
+    UnknownBackend,
+    /// the router already tracks its maximum number of sessions
+    SessionLimit,
+    /// the router holds its maximum memory even after evicting sessions
+    MemoryLimit,
+    /// every backend matching an initiation already has its maximum number of sessions
+    PeerSessionLimit,
+    /// every backend matching an initiation is within a maintenance window
+    Maintenance,
+    /// the routing policy selected none of the candidate backends
+    RejectedByPolicy,
+    /// the initiation would start a new tunnel while the router is in lockdown
+    Lockdown,
+    /// the response came from a backend the initiation was raced to after another one's
+    LostRace,
+    /// the routing policy vetoed forwarding the packet
+    Vetoed(String),
+    /// chaos mode dropped the packet on purpose
+    Chaos,
+    /// no session uses the receiver index of the packet
+    NoSession,
+    /// the packet was read while the batch was full, and its kind is shed under backpressure
+    QueueFull,
+    /// the destination is proxied, but there is no association with its proxy
+    NoProxyAssociation {
+        proxy: SocketAddr,
+    },
+    SendFailed {
+        destination: SocketAddr,
+        kind: io::ErrorKind,
+    },
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DropReason::InvalidRelayHeader => f.write_str("invalid SOCKS5 relay header"),
+            DropReason::Denylisted => f.write_str("source denylisted"),
+            DropReason::Oversized => f.write_str("datagram too large"),
+            DropReason::Truncated => f.write_str("datagram truncated by the receive buffer"),
+            DropReason::NotWireguard => f.write_str("not a WireGuard packet"),
+            DropReason::Invalid(err) => write!(f, "{}", err),
+            DropReason::UnknownBackend => f.write_str("unknown backend"),
+            DropReason::SessionLimit => f.write_str("session limit reached"),
+            DropReason::MemoryLimit => f.write_str("memory limit reached"),
+            DropReason::PeerSessionLimit => f.write_str("session limit of the backend reached"),
+            DropReason::Maintenance => f.write_str("backend in maintenance"),
+            DropReason::RejectedByPolicy => f.write_str("rejected by policy"),
+            DropReason::Lockdown => f.write_str("new tunnels paused by lockdown"),
+            DropReason::LostRace => f.write_str("another backend responded first"),
+            DropReason::Chaos => f.write_str("dropped by chaos mode"),
+            DropReason::Vetoed(reason) => write!(f, "vetoed by policy: {}", reason),
+            DropReason::NoSession => f.write_str("no matching session"),
+            DropReason::QueueFull => f.write_str("queue full"),
+            DropReason::NoProxyAssociation { proxy } => {
+                write!(f, "no association with SOCKS5 proxy {}", proxy)
+            }
+            DropReason::SendFailed { destination, kind } => {
+                write!(f, "failed to send to {}: {}", destination, kind)
+            }
+        }
+    }
+}
@@ -0,0 +1,345 @@
+/*
+* export.rs ships session and handshake events in batches to Kafka or ClickHouse, for analytics
+* over a longer time than the router keeps
+*/
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+use crate::event::RouterEvent;
+use crate::packet::{Identity, MessageType};
+
+/// Requests taking longer are abandoned, and their batch is sent again
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where events are exported to, and how they are batched
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub sink: Sink,
+    /// records sent in one request at most
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// seconds after which records are sent even if there are fewer than `batch_size`
+    #[serde(default = "default_flush_secs")]
+    pub flush_secs: u64,
+    /// records held while the sink is slow or down, beyond which the oldest are dropped
+    #[serde(default = "default_queue")]
+    pub queue: usize,
+    /// tells the routers of a fleet apart, e.g. their host name
+    pub instance: Option<String>,
+}
+
+fn default_batch_size() -> usize {
+    1000
+}
+
+fn default_flush_secs() -> u64 {
+    5
+}
+
+fn default_queue() -> usize {
+    100_000
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sink {
+    /// a topic of Kafka, produced to through a REST proxy speaking the v2 API, e.g. Confluent's
+    /// REST Proxy or Redpanda's HTTP Proxy
+    Kafka { url: String, topic: String },
+    /// a table of ClickHouse, inserted into through its HTTP interface
+    Clickhouse {
+        url: String,
+        table: String,
+        user: Option<String>,
+        password: Option<String>,
+    },
+}
+
+/// What happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    SessionCreated,
+    SessionEstablished,
+    ExcessiveRekeys,
+    /// a handshake message was not forwarded
+    HandshakeDropped,
+}
+
+/// One exported event, a row of the ClickHouse table or the value of a Kafka message
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Record {
+    /// milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub instance: Option<String>,
+    pub kind: Kind,
+    pub client: SocketAddr,
+    pub backend: Option<SocketAddr>,
+    pub client_index: Option<Identity>,
+    pub backend_index: Option<Identity>,
+    /// handshakes of an excessively rekeying tunnel
+    pub handshakes: Option<u64>,
+    /// `initiation` or `response`, of a dropped handshake message
+    pub message: Option<&'static str>,
+    /// why a handshake message was dropped
+    pub reason: Option<String>,
+}
+
+impl Record {
+    /// The record of `event` at `timestamp_ms`, if it is a session or handshake event
+    pub fn from_event(event: &RouterEvent, timestamp_ms: u64) -> Option<Record> {
+        let record = Record {
+            timestamp_ms,
+            instance: None,
+            kind: Kind::SessionCreated,
+            client: SocketAddr::from(([0, 0, 0, 0], 0)),
+            backend: None,
+            client_index: None,
+            backend_index: None,
+            handshakes: None,
+            message: None,
+            reason: None,
+        };
+        match event {
+            RouterEvent::SessionCreated {
+                client,
+                backend,
+                client_index,
+            } => Some(Record {
+                client: *client,
+                backend: Some(*backend),
+                client_index: Some(*client_index),
+                ..record
+            }),
+            RouterEvent::SessionEstablished {
+                client,
+                backend,
+                client_index,
+                backend_index,
+            } => Some(Record {
+                kind: Kind::SessionEstablished,
+                client: *client,
+                backend: Some(*backend),
+                client_index: Some(*client_index),
+                backend_index: Some(*backend_index),
+                ..record
+            }),
+            RouterEvent::ExcessiveRekeys {
+                client,
+                backend,
+                handshakes,
+            } => Some(Record {
+                kind: Kind::ExcessiveRekeys,
+                client: *client,
+                backend: Some(*backend),
+                handshakes: Some(*handshakes as u64),
+                ..record
+            }),
+            RouterEvent::Dropped {
+                message: Some(message),
+                source,
+                reason,
+            } => {
+                let message = match message {
+                    MessageType::HandshakeInitiation => "initiation",
+                    MessageType::HandshakeResponse => "response",
+                    _ => return None,
+                };
+                Some(Record {
+                    kind: Kind::HandshakeDropped,
+                    client: *source,
+                    message: Some(message),
+                    reason: Some(reason.to_string()),
+                    ..record
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Sink {
+    /// The request sending `records` with `client`
+    pub fn request(&self, client: &reqwest::Client, records: &[Record]) -> reqwest::RequestBuilder {
+        match self {
+            Sink::Kafka { url, topic } => {
+                // keyed by client, so the events of one client stay in order on one partition
+                let records: Vec<_> = records
+                    .iter()
+                    .map(|record| {
+                        serde_json::json!({ "key": record.client.ip().to_string(), "value": record })
+                    })
+                    .collect();
+                client
+                    .post(format!("{}/topics/{}", url.trim_end_matches('/'), topic))
+                    .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                    .body(serde_json::json!({ "records": records }).to_string())
+            }
+            Sink::Clickhouse {
+                url,
+                table,
+                user,
+                password,
+            } => {
+                let request = client
+                    .post(url)
+                    .query(&[("query", format!("INSERT INTO {table} FORMAT JSONEachRow"))])
+                    .body(json_lines(records));
+                match user {
+                    Some(user) => request.basic_auth(user, password.as_ref()),
+                    None => request,
+                }
+            }
+        }
+    }
+}
+
+/// `records` as JSON objects, one per line
+pub fn json_lines(records: &[Record]) -> String {
+    records
+        .iter()
+        .filter_map(|record| serde_json::to_string(record).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// Records waiting to be sent, dropping the oldest beyond a capacity
+#[derive(Debug)]
+pub struct Queue {
+    records: VecDeque<Record>,
+    capacity: usize,
+    /// records dropped since the last [`take_dropped`](Self::take_dropped)
+    dropped: u64,
+}
+
+impl Queue {
+    pub fn new(capacity: usize) -> Self {
+        Queue {
+            records: VecDeque::new(),
+            capacity: capacity.max(1),
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: Record) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        self.records.push_back(record);
+    }
+
+    /// Takes up to `size` of the oldest records
+    pub fn batch(&mut self, size: usize) -> Vec<Record> {
+        let size = size.min(self.records.len());
+        self.records.drain(..size).collect()
+    }
+
+    /// Puts a batch that failed to be sent back in front, as far as there is room
+    pub fn requeue(&mut self, batch: Vec<Record>) {
+        let room = self.capacity - self.records.len();
+        let skipped = batch.len().saturating_sub(room);
+        self.dropped += skipped as u64;
+        for record in batch.into_iter().skip(skipped).rev() {
+            self.records.push_front(record);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+/// Exports the router's `events` to the sink of `config` until the router stops
+///
+/// One request is in flight at a time. Events keep being queued meanwhile, and the oldest are
+/// dropped once `queue` are waiting, so a slow or unreachable sink never holds up routing.
+/// Failed batches are sent again.
+pub async fn export(config: Config, mut events: broadcast::Receiver<RouterEvent>) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("event export is disabled: {}", e);
+            return;
+        }
+    };
+    let batch_size = config.batch_size.max(1);
+    let mut queue = Queue::new(config.queue);
+    let mut missed = 0;
+    let mut in_flight: Option<JoinHandle<(Vec<Record>, reqwest::Result<()>)>> = None;
+    let mut flush = tokio::time::interval(Duration::from_secs(config.flush_secs.max(1)));
+    let mut closed = false;
+    loop {
+        let mut due = queue.len() >= batch_size;
+        tokio::select! {
+            event = events.recv(), if !closed => match event {
+                Ok(event) => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |since| since.as_millis() as u64);
+                    if let Some(mut record) = Record::from_event(&event, now) {
+                        record.instance = config.instance.clone();
+                        queue.push(record);
+                    }
+                }
+                Err(RecvError::Lagged(count)) => missed += count,
+                // sends what is left before returning
+                Err(RecvError::Closed) => closed = true,
+            },
+            sent = async { in_flight.as_mut().unwrap().await }, if in_flight.is_some() => {
+                in_flight = None;
+                match sent {
+                    Ok((_, Ok(()))) => {}
+                    Ok((batch, Err(e))) => {
+                        tracing::warn!("failed to export {} events: {}", batch.len(), e);
+                        queue.requeue(batch);
+                    }
+                    Err(e) => tracing::warn!("event export request failed: {}", e),
+                }
+            }
+            _ = flush.tick() => {
+                due = true;
+                let dropped = queue.take_dropped();
+                if dropped > 0 || missed > 0 {
+                    tracing::warn!(
+                        "event export fell behind, dropping {} queued events and missing {} \
+                         from the router",
+                        dropped,
+                        missed
+                    );
+                    missed = 0;
+                }
+            }
+        }
+        if closed && in_flight.is_none() && queue.is_empty() {
+            return;
+        }
+        if in_flight.is_none() && (due || closed) && !queue.is_empty() {
+            let batch = queue.batch(batch_size);
+            let request = config.sink.request(&client, &batch);
+            in_flight = Some(tokio::spawn(async move {
+                let result = request
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map(|_| ());
+                (batch, result)
+            }));
+        }
+    }
+}
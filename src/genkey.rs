@@ -0,0 +1,121 @@
+/*
+* genkey.rs implements the `genkey` subcommand, generating the X25519 keypairs of health probes
+* and outbound tunnels like `wg genkey` and `wg pubkey`, so the router host needs no `wg` binary
+*/
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use wireguard_router::error::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::decode;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Print the public key of the private key read from stdin, or --private-key-file, instead
+    #[arg(long)]
+    public: bool,
+    /// File with the private key to print the public key of, as base64 or hex
+    #[arg(long, requires = "public")]
+    private_key_file: Option<PathBuf>,
+    /// Write the new private key to this file, readable only by its owner, and print its
+    /// public key instead
+    #[arg(short, long, conflicts_with = "public")]
+    output: Option<PathBuf>,
+    /// Encoding of the keys printed and written
+    #[arg(long, value_enum, default_value_t = Format::Base64)]
+    format: Format,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Base64,
+    Hex,
+}
+
+impl Format {
+    fn encode(self, key: &[u8; 32]) -> String {
+        match self {
+            Format::Base64 => base64::engine::general_purpose::STANDARD.encode(key),
+            Format::Hex => decode::hex(key),
+        }
+    }
+}
+
+pub fn run(args: Args) -> Result<(), Error> {
+    if args.public {
+        let private = match &args.private_key_file {
+            Some(path) => read_key(path)?,
+            None => {
+                let mut encoded = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut encoded)
+                    .map_err(|source| Error::ReadFile {
+                        path: PathBuf::from("<stdin>"),
+                        source,
+                    })?;
+                parse_key(&encoded).ok_or_else(|| {
+                    Error::InvalidInput("stdin does not hold a 32 byte key".into())
+                })?
+            }
+        };
+        println!("{}", args.format.encode(&public_key(private)));
+        return Ok(());
+    }
+
+    let private = private_key();
+    match &args.output {
+        Some(path) => {
+            write_key(path, &args.format.encode(&private))?;
+            println!("{}", args.format.encode(&public_key(private)));
+        }
+        None => println!("{}", args.format.encode(&private)),
+    }
+    Ok(())
+}
+
+/// A random private key, clamped like those of `wg genkey`
+fn private_key() -> [u8; 32] {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    key[0] &= 248;
+    key[31] = (key[31] & 127) | 64;
+    key
+}
+
+fn public_key(private: [u8; 32]) -> [u8; 32] {
+    PublicKey::from(&StaticSecret::from(private)).to_bytes()
+}
+
+fn parse_key(encoded: &str) -> Option<[u8; 32]> {
+    decode::parse_bytes(encoded.trim())?.try_into().ok()
+}
+
+fn read_key(path: &Path) -> Result<[u8; 32], Error> {
+    let encoded = std::fs::read_to_string(path).map_err(|source| Error::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse_key(&encoded).ok_or_else(|| {
+        Error::InvalidInput(format!("{} does not hold a 32 byte key", path.display()))
+    })
+}
+
+/// Writes `encoded` to a new file at `path`, never replacing an existing key
+fn write_key(path: &Path, encoded: &str) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{encoded}"))
+        .map_err(|source| Error::WriteFile {
+            path: path.to_path_buf(),
+            source,
+        })
+}
@@ -0,0 +1,297 @@
+/*
+* health.rs tracks which backends the router failed to reach, so new sessions avoid them
+*/
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+
+/// When a backend is considered down, when it is tried again and when it is back up
+///
+/// Raising `up_after` and `min_down_secs` keeps a flapping backend out of rotation until it
+/// stayed reachable for a while, rather than moving new sessions back and forth.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Thresholds {
+    /// consecutive sends to the backend that failed for good
+    pub down_after: u32,
+    /// after which a down backend may take a new session again, to see if it recovered
+    pub retry_after_secs: u64,
+    /// consecutive successful sends to a down backend that bring it back up
+    pub up_after: u32,
+    /// a down backend stays down at least this long, however many sends to it succeed
+    pub min_down_secs: u64,
+    /// over which a backend that came back up ramps from no share of the new sessions to its full
+    /// share, so they don't all land on it at once
+    pub slow_start_secs: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            down_after: 5,
+            retry_after_secs: 30,
+            up_after: 1,
+            min_down_secs: 0,
+            slow_start_secs: 0,
+        }
+    }
+}
+
+/// When a backend is ejected from its pool, the backends sharing its pubkey, because far more
+/// of its handshakes go unanswered than those of the others, see
+/// [`Metrics::handshake_loss`](crate::metrics::Metrics::handshake_loss)
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct OutlierDetection {
+    /// how often the pools are compared
+    pub interval_secs: u64,
+    /// initiations forwarded to a backend in the last minute for its loss to be judged
+    pub min_handshakes: u64,
+    /// standard deviations of the other backends' loss by which a backend's loss must exceed
+    /// their mean
+    pub stdev_factor: f64,
+    /// and at least this much, so that pools with hardly any loss eject no one
+    pub min_excess: f64,
+    /// share of a pool that is ejected at the same time at most, though one backend always may be
+    pub max_ejection_percent: u32,
+    /// how long an ejected backend takes no new sessions, its unanswered handshakes counting
+    /// against it for a minute
+    pub ejection_secs: u64,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            interval_secs: 10,
+            min_handshakes: 10,
+            stdev_factor: 1.9,
+            min_excess: 0.2,
+            max_ejection_percent: 10,
+            ejection_secs: 60,
+        }
+    }
+}
+
+impl OutlierDetection {
+    /// The backends among `pool`, with their loss, that are outliers compared to the others
+    ///
+    /// At most `room` of them are returned, the worst first.
+    pub fn outliers(&self, pool: &[(SocketAddr, f64)], room: usize) -> Vec<SocketAddr> {
+        let mut outliers: Vec<(SocketAddr, f64)> = pool
+            .iter()
+            .filter(|(backend, loss)| {
+                let others: Vec<f64> = pool
+                    .iter()
+                    .filter(|(other, _)| other != backend)
+                    .map(|(_, loss)| *loss)
+                    .collect();
+                if others.is_empty() {
+                    return false;
+                }
+                let mean = others.iter().sum::<f64>() / others.len() as f64;
+                let variance =
+                    others.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / others.len() as f64;
+                *loss > mean + (self.stdev_factor * variance.sqrt()).max(self.min_excess)
+            })
+            .copied()
+            .collect();
+        outliers.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        outliers.truncate(room);
+        outliers.into_iter().map(|(backend, _)| backend).collect()
+    }
+}
+
+/// Where a backend is in the health state machine
+///
+/// Backends start out up, are failing after a failed send, down after
+/// [`Thresholds::down_after`] failures in a row and recovering once a send to them succeeds
+/// again, until [`Thresholds::up_after`] successes in a row bring them back up. Independently,
+/// a backend that is reachable but answers too few handshakes may be ejected as an outlier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BackendState {
+    Up,
+    Failing,
+    Down,
+    Recovering,
+    Ejected,
+}
+
+impl BackendState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BackendState::Up => "up",
+            BackendState::Failing => "failing",
+            BackendState::Down => "down",
+            BackendState::Recovering => "recovering",
+            BackendState::Ejected => "ejected",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// consecutive failed sends
+    failures: u32,
+    /// consecutive successful sends since the backend was marked down
+    successes: u32,
+    /// when the backend was marked down
+    marked: Option<Instant>,
+    /// when the backend was marked down, or last tried again
+    down_since: Option<Instant>,
+}
+
+impl State {
+    fn state(&self) -> BackendState {
+        match (self.down_since, self.successes) {
+            (None, _) => BackendState::Failing,
+            (Some(_), 0) => BackendState::Down,
+            (Some(_), _) => BackendState::Recovering,
+        }
+    }
+}
+
+/// The health of the backends, as judged from sending to them
+#[derive(Debug)]
+pub struct Health {
+    thresholds: Thresholds,
+    backends: Mutex<HashMap<SocketAddr, State>>,
+    /// when backends in their slow start came back up
+    ramping: Mutex<HashMap<SocketAddr, Instant>>,
+    /// backends ejected as outliers, until when
+    ejected: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Health {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Health {
+            thresholds,
+            backends: Default::default(),
+            ramping: Default::default(),
+            ejected: Default::default(),
+        }
+    }
+
+    /// Records a failed send to `backend`, returning whether this marked it down
+    pub fn failed(&self, backend: SocketAddr) -> bool {
+        let mut backends = self.backends.lock().unwrap();
+        let state = backends.entry(backend).or_default();
+        state.failures = state.failures.saturating_add(1);
+        state.successes = 0;
+        if state.failures < self.thresholds.down_after.max(1) {
+            return false;
+        }
+        // a retry that failed starts the wait over
+        let now = Instant::now();
+        let marked = state.down_since.is_none();
+        state.down_since = Some(now);
+        state.marked.get_or_insert(now);
+        marked
+    }
+
+    /// Records a successful send to `backend`, returning whether this brought it back up
+    pub fn succeeded(&self, backend: SocketAddr) -> bool {
+        let mut backends = self.backends.lock().unwrap();
+        let Some(state) = backends.get_mut(&backend) else {
+            return false;
+        };
+        let Some(marked) = state.marked else {
+            backends.remove(&backend);
+            return false;
+        };
+        state.failures = 0;
+        state.successes = state.successes.saturating_add(1);
+        let min_down = Duration::from_secs(self.thresholds.min_down_secs);
+        if state.successes < self.thresholds.up_after.max(1) || marked.elapsed() < min_down {
+            // the sessions already trying it go on, new ones wait for the next retry
+            state.down_since = Some(Instant::now());
+            return false;
+        }
+        backends.remove(&backend);
+        if self.thresholds.slow_start_secs > 0 {
+            let mut ramping = self.ramping.lock().unwrap();
+            ramping.insert(backend, Instant::now());
+        }
+        true
+    }
+
+    /// Whether `backend` may take a new session, which is always the case but during its slow
+    /// start, where the odds grow from 0 to 1 over [`Thresholds::slow_start_secs`]
+    pub fn admits(&self, backend: SocketAddr) -> bool {
+        let mut ramping = self.ramping.lock().unwrap();
+        let Some(up_since) = ramping.get(&backend) else {
+            return true;
+        };
+        let slow_start = Duration::from_secs(self.thresholds.slow_start_secs);
+        let share = up_since.elapsed().as_secs_f64() / slow_start.as_secs_f64();
+        if share >= 1.0 {
+            ramping.remove(&backend);
+            return true;
+        }
+        (OsRng.next_u32() as f64 / u32::MAX as f64) < share
+    }
+
+    /// Whether new sessions should avoid `backend`, which is the case while it is down, unless
+    /// it wasn't tried for [`Thresholds::retry_after_secs`]
+    pub fn is_down(&self, backend: SocketAddr) -> bool {
+        let retry_after = Duration::from_secs(self.thresholds.retry_after_secs);
+        let backends = self.backends.lock().unwrap();
+        backends
+            .get(&backend)
+            .and_then(|state| state.down_since)
+            .is_some_and(|since| since.elapsed() < retry_after)
+    }
+
+    /// Keeps new sessions away from `backend` for `duration`, as it is an outlier
+    pub fn eject(&self, backend: SocketAddr, duration: Duration) {
+        let mut ejected = self.ejected.lock().unwrap();
+        ejected.insert(backend, Instant::now() + duration);
+    }
+
+    /// Whether `backend` was ejected as an outlier and is still cooling down
+    pub fn is_ejected(&self, backend: SocketAddr) -> bool {
+        let mut ejected = self.ejected.lock().unwrap();
+        match ejected.get(&backend) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                ejected.remove(&backend);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The state of every backend that is not up
+    pub fn states(&self) -> HashMap<SocketAddr, BackendState> {
+        let now = Instant::now();
+        let mut states: HashMap<SocketAddr, BackendState> = self
+            .ejected
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(backend, _)| (*backend, BackendState::Ejected))
+            .collect();
+        let backends = self.backends.lock().unwrap();
+        states.extend(
+            backends
+                .iter()
+                .map(|(backend, state)| (*backend, state.state())),
+        );
+        states
+    }
+
+    /// The backends currently marked down, including those recovering
+    pub fn down(&self) -> Vec<SocketAddr> {
+        let backends = self.backends.lock().unwrap();
+        backends
+            .iter()
+            .filter(|(_, state)| state.down_since.is_some())
+            .map(|(backend, _)| *backend)
+            .collect()
+    }
+}
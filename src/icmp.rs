@@ -0,0 +1,225 @@
+/*
+* icmp.rs answers rejected datagrams with ICMP port unreachable, so misconfigured clients fail fast
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::event::{DropReason, RouterEvent};
+use crate::utils::checksum;
+
+/// A client is sent at most one message per interval, however many datagrams it sends
+pub const PER_CLIENT_INTERVAL: Duration = Duration::from_secs(1);
+/// Clients remembered for [`PER_CLIENT_INTERVAL`], beyond which the oldest are forgotten early
+const MAX_CLIENTS: usize = 4096;
+
+/// Sends ICMP port unreachable messages through raw sockets, which need `CAP_NET_RAW`
+///
+/// Since the source of a UDP datagram is easily spoofed, the messages are rate limited in total
+/// and per client, so the router can't be used to reflect traffic at a third party.
+pub struct Unreachable {
+    v4: Option<Socket>,
+    v6: Option<Socket>,
+    /// the addresses of the listeners, whose ports the messages refer to
+    listeners: Vec<SocketAddr>,
+    limiter: Mutex<Limiter>,
+}
+
+struct Limiter {
+    per_second: u32,
+    tokens: f64,
+    refilled: Instant,
+    /// client -> when it was last sent a message
+    clients: HashMap<IpAddr, Instant>,
+}
+
+impl Limiter {
+    fn allow(&mut self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let rate = f64::from(self.per_second);
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        if let Some(last) = self.clients.get(&client)
+            && now.duration_since(*last) < PER_CLIENT_INTERVAL
+        {
+            return false;
+        }
+        if self.clients.len() >= MAX_CLIENTS {
+            self.clients
+                .retain(|_, last| now.duration_since(*last) < PER_CLIENT_INTERVAL);
+            if self.clients.len() >= MAX_CLIENTS {
+                return false;
+            }
+        }
+        self.clients.insert(client, now);
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+impl Unreachable {
+    /// Opens the raw sockets, at most `per_second` messages are sent in total
+    ///
+    /// `listeners` are the addresses the router receives on. Only one address family needs to
+    /// be supported, clients of the other are then not answered.
+    pub fn open(listeners: Vec<SocketAddr>, per_second: u32) -> io::Result<Self> {
+        let open = |domain, protocol| -> io::Result<Socket> {
+            let socket = Socket::new(domain, Type::RAW, Some(protocol))?;
+            socket.set_nonblocking(true)?;
+            // nothing is read, so don't queue every ICMP message the host receives
+            socket.set_recv_buffer_size(0)?;
+            Ok(socket)
+        };
+        let v4 = open(Domain::IPV4, Protocol::ICMPV4);
+        let v6 = open(Domain::IPV6, Protocol::ICMPV6);
+        let (v4, v6) = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => (v4.ok(), v6.ok()),
+        };
+        Ok(Unreachable {
+            v4,
+            v6,
+            listeners,
+            limiter: Mutex::new(Limiter {
+                per_second,
+                tokens: f64::from(per_second),
+                refilled: Instant::now(),
+                clients: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Answers the datagrams the router dropped for not being WireGuard or for no known backend
+    ///
+    /// Runs until the router stops. Events missed by falling behind are not answered.
+    pub async fn respond(self, mut events: broadcast::Receiver<RouterEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(RouterEvent::Dropped {
+                    source,
+                    reason: DropReason::NotWireguard | DropReason::UnknownBackend,
+                    ..
+                }) => {
+                    if let Err(e) = self.reject(source) {
+                        tracing::debug!("failed to send ICMP unreachable to {}: {}", source, e);
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// Tells `client` that nothing listens on the port it sent to, unless the rate limit is reached
+    pub fn reject(&self, client: SocketAddr) -> io::Result<()> {
+        let Some(listener) = self.listener_for(client) else {
+            return Ok(());
+        };
+        let socket = match client.ip() {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        };
+        let Some(socket) = socket else {
+            return Ok(());
+        };
+        if !self.limiter.lock().unwrap().allow(client.ip()) {
+            return Ok(());
+        }
+        let local = match listener.ip() {
+            ip if ip.is_unspecified() => local_ip(client)?,
+            ip => ip.to_canonical(),
+        };
+        let message = match (client.ip(), local) {
+            (IpAddr::V4(client_ip), IpAddr::V4(local)) => {
+                unreachable_v4(client_ip, client.port(), local, listener.port())
+            }
+            (IpAddr::V6(client_ip), IpAddr::V6(local)) => {
+                unreachable_v6(client_ip, client.port(), local, listener.port())
+            }
+            _ => return Ok(()),
+        };
+        let destination = SockAddr::from(SocketAddr::new(client.ip(), 0));
+        match socket.send_to(&message, &destination) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// The listener `client` sent to, preferring one of its address family as the transport does
+    fn listener_for(&self, client: SocketAddr) -> Option<SocketAddr> {
+        let same_family = self
+            .listeners
+            .iter()
+            .find(|listener| listener.is_ipv4() == client.is_ipv4());
+        match (same_family, client) {
+            (Some(listener), _) => Some(*listener),
+            // v4 clients of a dual-stack v6 listener
+            (None, SocketAddr::V4(_)) => self.listeners.first().copied(),
+            (None, SocketAddr::V6(_)) => None,
+        }
+    }
+}
+
+/// The address the host sends to `client` from, which is the one the client sent to unless it is routed asymmetrically
+fn local_ip(client: SocketAddr) -> io::Result<IpAddr> {
+    let unspecified: IpAddr = match client {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.connect(client)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Length of the UDP header quoted after the IP header, the payload is left out
+const UDP_HEADER: usize = 8;
+
+fn udp_header(source_port: u16, destination_port: u16) -> [u8; UDP_HEADER] {
+    let mut header = [0; UDP_HEADER];
+    header[..2].copy_from_slice(&source_port.to_be_bytes());
+    header[2..4].copy_from_slice(&destination_port.to_be_bytes());
+    header[4..6].copy_from_slice(&(UDP_HEADER as u16).to_be_bytes());
+    header
+}
+
+/// ICMP destination unreachable, port unreachable, quoting the headers of a datagram from `client` to `local`
+fn unreachable_v4(client: Ipv4Addr, client_port: u16, local: Ipv4Addr, port: u16) -> Vec<u8> {
+    let mut quoted = [0u8; 20];
+    quoted[0] = 0x45; // version 4, 5 words of header
+    quoted[2..4].copy_from_slice(&((20 + UDP_HEADER) as u16).to_be_bytes());
+    quoted[8] = 64; // TTL
+    quoted[9] = 17; // UDP
+    quoted[12..16].copy_from_slice(&client.octets());
+    quoted[16..20].copy_from_slice(&local.octets());
+    let header_checksum = checksum(&quoted);
+    quoted[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+    let mut message = vec![3, 3, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&quoted);
+    message.extend_from_slice(&udp_header(client_port, port));
+    let checksum = checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/// ICMPv6 destination unreachable, port unreachable, whose checksum the kernel fills in
+fn unreachable_v6(client: Ipv6Addr, client_port: u16, local: Ipv6Addr, port: u16) -> Vec<u8> {
+    let mut message = vec![1, 4, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&[0x60, 0, 0, 0]); // version 6, no traffic class or flow label
+    message.extend_from_slice(&(UDP_HEADER as u16).to_be_bytes());
+    message.extend_from_slice(&[17, 64]); // UDP, hop limit
+    message.extend_from_slice(&client.octets());
+    message.extend_from_slice(&local.octets());
+    message.extend_from_slice(&udp_header(client_port, port));
+    message
+}
@@ -0,0 +1,93 @@
+/*
+* js.rs exposes packet classification to JavaScript through wasm-bindgen
+*
+* Meant for browser tooling decoding captured traffic, build it without the router:
+*
+*   cargo rustc --lib --release --target wasm32-unknown-unknown --crate-type cdylib \
+*       --no-default-features --features js
+*   wasm-bindgen --target web target/wasm32-unknown-unknown/release/wireguard_router.wasm --out-dir pkg
+*/
+
+use base64::Engine;
+use wasm_bindgen::prelude::*;
+
+use crate::packet::{HandshakeInitiation, WireguardPacket};
+use crate::{Peer, utils};
+
+/// The header fields of a parsed message, indices absent from a message type are undefined
+#[wasm_bindgen]
+pub struct Header {
+    /// the WireGuard message type, 1 to 4
+    #[wasm_bindgen(readonly, js_name = messageType)]
+    pub message_type: u8,
+    #[wasm_bindgen(readonly, js_name = senderIndex)]
+    pub sender_index: Option<u32>,
+    #[wasm_bindgen(readonly, js_name = receiverIndex)]
+    pub receiver_index: Option<u32>,
+    /// the nonce counter of transport data
+    #[wasm_bindgen(readonly)]
+    pub counter: Option<u64>,
+    /// the number of bytes following the header of transport data
+    #[wasm_bindgen(readonly, js_name = payloadLength)]
+    pub payload_len: Option<usize>,
+}
+
+#[wasm_bindgen(js_name = isWireguardPacket)]
+pub fn is_wg_packet(packet: &[u8]) -> bool {
+    crate::packet::is_wg_packet(packet)
+}
+
+#[wasm_bindgen]
+pub fn parse(packet: &[u8]) -> Result<Header, JsError> {
+    let mut header = Header {
+        message_type: 0,
+        sender_index: None,
+        receiver_index: None,
+        counter: None,
+        payload_len: None,
+    };
+    let parsed = WireguardPacket::parse(packet)?;
+    header.message_type = parsed.message_type().code();
+    match parsed {
+        WireguardPacket::HandshakeInitiation(packet) => {
+            header.sender_index = Some(packet.sender_index());
+        }
+        WireguardPacket::HandshakeResponse(packet) => {
+            header.sender_index = Some(packet.sender_index());
+            header.receiver_index = Some(packet.receiver_index());
+        }
+        WireguardPacket::CookieReply(packet) => {
+            header.receiver_index = Some(packet.receiver_index());
+        }
+        WireguardPacket::TransportData(data, payload) => {
+            header.receiver_index = Some(data.receiver_index());
+            header.counter = Some(data.counter());
+            header.payload_len = Some(payload.len());
+        }
+        _ => {}
+    }
+    Ok(header)
+}
+
+/// Whether `packet` is a handshake initiation addressed to the base64 encoded `pubkey`
+#[wasm_bindgen(js_name = initiationMatches)]
+pub fn initiation_matches(pubkey: &str, packet: &[u8]) -> Result<bool, JsError> {
+    let key: [u8; 32] = base64::engine::general_purpose::STANDARD
+        .decode(pubkey)?
+        .try_into()
+        .map_err(|key: Vec<u8>| {
+            JsError::new(&format!("expected a 32 byte key, got {}", key.len()))
+        })?;
+    // the address is irrelevant for matching
+    let peer = Peer::new(([0, 0, 0, 0], 0).into(), key);
+    Ok(match WireguardPacket::parse(packet) {
+        Ok(WireguardPacket::HandshakeInitiation(initiation)) => {
+            let mac = utils::mac(
+                &peer.precomputed_hash_label_mac1,
+                &packet[..HandshakeInitiation::MAC1_OFFSET],
+            );
+            initiation.mac1() == &mac
+        }
+        _ => false,
+    })
+}
@@ -1,21 +1,195 @@
 use core::fmt;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 
 use base64::Engine;
+use error::PeerError;
+use schedule::{Rule, Window};
 use serde::{
-    Deserialize,
-    de::{self, MapAccess, SeqAccess, Visitor},
+    Deserialize, Serialize,
+    de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    ser::SerializeStruct,
 };
 
+#[cfg(feature = "runtime")]
+pub mod affinity;
+#[cfg(feature = "alarms")]
+pub mod alarm;
+#[cfg(feature = "blocklists")]
+pub mod blocklist;
+#[cfg(feature = "runtime")]
+pub mod chaos;
+#[cfg(feature = "crowdsec")]
+pub mod crowdsec;
+#[cfg(feature = "runtime")]
+pub mod denylist;
+#[cfg(feature = "runtime")]
+pub mod discovery;
+pub mod error;
+#[cfg(feature = "runtime")]
+pub mod event;
+#[cfg(feature = "export")]
+pub mod export;
+#[cfg(feature = "runtime")]
+pub mod health;
+#[cfg(feature = "icmp")]
+pub mod icmp;
+#[cfg(feature = "js")]
+pub mod js;
+#[cfg(feature = "runtime")]
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod packet;
+pub mod pcap;
+pub mod policy;
+#[cfg(feature = "doctor")]
+pub mod probe;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(feature = "runtime")]
+pub mod router;
+pub mod schedule;
+#[cfg(feature = "snmp")]
+pub mod snmp;
+#[cfg(feature = "runtime")]
+pub mod socks;
+#[cfg(feature = "runtime")]
+pub mod state;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "runtime")]
+pub mod timeline;
+#[cfg(feature = "runtime")]
+pub mod transport;
+#[cfg(feature = "tunnel")]
+pub mod tunnel;
 pub mod utils;
 
-const LABEL_MAC1: &'static str = "mac1----";
+const LABEL_MAC1: &str = "mac1----";
 
 #[derive(Clone, Debug)]
 pub struct Peer {
-    pub pub_key: [u8; 32],                     // TODO: is this the right length?
+    pub pub_key: [u8; 32], // curve25519 public keys are 32 bytes
     pub precomputed_hash_label_mac1: [u8; 32], // used as key for mac1 function
     pub address: SocketAddr,
+    /// SOCKS5 proxy the backend is only reachable through, if any
+    pub proxy: Option<SocketAddr>,
+    /// human readable name used in logs
+    pub name: Option<String>,
+    /// sessions the backend takes at most, further initiations for it are dropped
+    pub max_sessions: Option<usize>,
+    /// keys the backend is also addressed with, e.g. its old key while the key is rotated
+    pub other_keys: Vec<PeerKey>,
+    /// recurring windows in which the backend is drained, taking no new sessions
+    pub maintenance: Vec<Window>,
+    /// takes all new sessions for its keys while it is up, the other backends only standing in
+    pub primary: bool,
+    /// share of the new sessions for its keys, relative to the weights of the other backends
+    pub weight: Option<u32>,
+    /// recurring windows in which the backend takes other settings, the first open one applying
+    pub schedule: Vec<Rule>,
+    /// fwmark of the datagrams forwarded to the backend, e.g. to route them through an uplink
+    pub fwmark: Option<u32>,
+    /// device the datagrams forwarded to the backend are sent through, e.g. an uplink or tunnel
+    pub interface: Option<String>,
+    /// local address the datagrams forwarded to the backend are sent from
+    pub source: Option<IpAddr>,
+    /// name of the WireGuard tunnel the datagrams forwarded to the backend are carried in
+    pub tunnel: Option<String>,
+    /// whether the datagrams forwarded to the backend, another router, are carried in a QUIC link
+    pub quic: bool,
+}
+
+/// A further public key of a [`Peer`], along with the mac1 key derived from it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerKey {
+    pub pub_key: [u8; 32],
+    pub precomputed_hash_label_mac1: [u8; 32],
+}
+
+impl PeerKey {
+    pub fn new(pub_key: [u8; 32]) -> Self {
+        PeerKey {
+            pub_key,
+            precomputed_hash_label_mac1: mac1_key(&pub_key),
+        }
+    }
+}
+
+fn mac1_key(pub_key: &[u8; 32]) -> [u8; 32] {
+    blake2s_simd::Params::new()
+        .to_state()
+        .update(LABEL_MAC1.as_bytes())
+        .update(pub_key.as_slice())
+        .finalize()
+        .as_array()
+        .to_owned()
+}
+
+/// `pubkey` in the config, either one key or a list of keys the same backend is addressed with
+///
+/// Every key is checked right away, so that errors are located at the value.
+pub(crate) struct PubKeys(Vec<String>);
+
+impl<'de> Deserialize<'de> for PubKeys {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct PubKeysVisitor;
+
+        impl<'de> Visitor<'de> for PubKeysVisitor {
+            type Value = Vec<String>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a base64 public key or a list of them")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Vec<String>, E> {
+                Ok(vec![value.to_string()])
+            }
+
+            fn visit_seq<V>(self, mut seq: V) -> Result<Vec<String>, V::Error>
+            where
+                V: SeqAccess<'de>,
+            {
+                let mut keys = Vec::new();
+                while let Some(key) = seq.next_element()? {
+                    keys.push(key);
+                }
+                Ok(keys)
+            }
+        }
+
+        let keys = deserializer.deserialize_any(PubKeysVisitor)?;
+        if keys.is_empty() {
+            return Err(de::Error::custom("invalid field `pubkey`: no key given"));
+        }
+        for key in &keys {
+            parse_pubkey(key).map_err(|e| de::Error::custom(e.with_field()))?;
+        }
+        Ok(PubKeys(keys))
+    }
+}
+
+impl PubKeys {
+    /// The first key and the others
+    pub(crate) fn split(self) -> (String, Vec<String>) {
+        let mut keys = self.0.into_iter();
+        (keys.next().unwrap_or_default(), keys.collect())
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.address),
+            None => write!(f, "{}", self.address),
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for Peer {
@@ -28,6 +202,20 @@ impl<'de> Deserialize<'de> for Peer {
         enum Field {
             PubKey,
             Address,
+            Proxy,
+            Name,
+            #[serde(rename = "max_sessions")]
+            MaxSessions,
+            Maintenance,
+            Primary,
+            Weight,
+            Schedule,
+            Fwmark,
+            Interface,
+            #[serde(rename = "source_address")]
+            SourceAddress,
+            Tunnel,
+            Quic,
         }
 
         struct PeerVisitor;
@@ -46,10 +234,39 @@ impl<'de> Deserialize<'de> for Peer {
                 let address = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-                let pubkey = seq
-                    .next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-                Ok(Peer::build(address, pubkey))
+                let (pubkey, other_pubkeys) = seq
+                    .next_element::<PubKeys>()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?
+                    .split();
+                let proxy = seq.next_element()?.flatten();
+                let name = seq.next_element()?.flatten();
+                let max_sessions = seq.next_element()?.flatten();
+                let maintenance = seq.next_element()?.unwrap_or_default();
+                let primary = seq.next_element()?.unwrap_or_default();
+                let weight = seq.next_element()?.flatten();
+                let schedule = seq.next_element()?.unwrap_or_default();
+                let fwmark = seq.next_element()?.flatten();
+                let interface = seq.next_element()?.flatten();
+                let source_address = seq.next_element()?.flatten();
+                let tunnel = seq.next_element()?.flatten();
+                let quic = seq.next_element()?.unwrap_or_default();
+                build(PeerConfig {
+                    address,
+                    pubkey,
+                    proxy,
+                    name,
+                    max_sessions,
+                    other_pubkeys,
+                    maintenance,
+                    primary,
+                    weight,
+                    schedule,
+                    fwmark,
+                    interface,
+                    source_address,
+                    tunnel,
+                    quic,
+                })
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<Peer, V::Error>
@@ -58,52 +275,479 @@ impl<'de> Deserialize<'de> for Peer {
             {
                 let mut address = None;
                 let mut pubkey = None;
+                let mut proxy = None;
+                let mut name = None;
+                let mut max_sessions = None;
+                let mut maintenance = None;
+                let mut primary = None;
+                let mut weight = None;
+                let mut schedule = None;
+                let mut fwmark = None;
+                let mut interface = None;
+                let mut source_address = None;
+                let mut tunnel = None;
+                let mut quic = None;
                 while let Some(key) = map.next_key()? {
                     match key {
                         Field::PubKey => {
                             if pubkey.is_some() {
                                 return Err(de::Error::duplicate_field("pubkey"));
                             }
-                            pubkey = Some(map.next_value()?);
+                            pubkey = Some(map.next_value::<PubKeys>()?.split());
                         }
                         Field::Address => {
                             if address.is_some() {
                                 return Err(de::Error::duplicate_field("address"));
                             }
-                            address = Some(map.next_value()?);
+                            address = Some(map.next_value_seed(Checked(parse_address))?);
+                        }
+                        Field::Proxy => {
+                            if proxy.is_some() {
+                                return Err(de::Error::duplicate_field("proxy"));
+                            }
+                            proxy = Some(map.next_value_seed(Checked(parse_proxy))?);
+                        }
+                        Field::Name => {
+                            if name.is_some() {
+                                return Err(de::Error::duplicate_field("name"));
+                            }
+                            name = Some(map.next_value()?);
+                        }
+                        Field::MaxSessions => {
+                            if max_sessions.is_some() {
+                                return Err(de::Error::duplicate_field("max_sessions"));
+                            }
+                            max_sessions = Some(map.next_value()?);
+                        }
+                        Field::Maintenance => {
+                            if maintenance.is_some() {
+                                return Err(de::Error::duplicate_field("maintenance"));
+                            }
+                            maintenance = Some(map.next_value()?);
+                        }
+                        Field::Primary => {
+                            if primary.is_some() {
+                                return Err(de::Error::duplicate_field("primary"));
+                            }
+                            primary = Some(map.next_value()?);
+                        }
+                        Field::Weight => {
+                            if weight.is_some() {
+                                return Err(de::Error::duplicate_field("weight"));
+                            }
+                            weight = Some(map.next_value()?);
+                        }
+                        Field::Schedule => {
+                            if schedule.is_some() {
+                                return Err(de::Error::duplicate_field("schedule"));
+                            }
+                            schedule = Some(map.next_value()?);
+                        }
+                        Field::Fwmark => {
+                            if fwmark.is_some() {
+                                return Err(de::Error::duplicate_field("fwmark"));
+                            }
+                            fwmark = Some(map.next_value()?);
+                        }
+                        Field::Interface => {
+                            if interface.is_some() {
+                                return Err(de::Error::duplicate_field("interface"));
+                            }
+                            interface = Some(map.next_value()?);
+                        }
+                        Field::SourceAddress => {
+                            if source_address.is_some() {
+                                return Err(de::Error::duplicate_field("source_address"));
+                            }
+                            source_address =
+                                Some(map.next_value_seed(Checked(parse_source_address))?);
+                        }
+                        Field::Tunnel => {
+                            if tunnel.is_some() {
+                                return Err(de::Error::duplicate_field("tunnel"));
+                            }
+                            tunnel = Some(map.next_value()?);
+                        }
+                        Field::Quic => {
+                            if quic.is_some() {
+                                return Err(de::Error::duplicate_field("quic"));
+                            }
+                            quic = Some(map.next_value()?);
                         }
                     }
                 }
                 let address = address.ok_or_else(|| de::Error::missing_field("address"))?;
-                let pubkey = pubkey.ok_or_else(|| de::Error::missing_field("pubkey"))?;
-                Ok(Peer::build(address, pubkey))
+                let (pubkey, other_pubkeys) =
+                    pubkey.ok_or_else(|| de::Error::missing_field("pubkey"))?;
+                build(PeerConfig {
+                    address,
+                    pubkey,
+                    proxy,
+                    name,
+                    max_sessions,
+                    other_pubkeys,
+                    maintenance: maintenance.unwrap_or_default(),
+                    primary: primary.unwrap_or_default(),
+                    weight,
+                    schedule: schedule.unwrap_or_default(),
+                    fwmark,
+                    interface,
+                    source_address,
+                    tunnel,
+                    quic: quic.unwrap_or_default(),
+                })
             }
         }
-        const FIELDS: &[&str] = &["address", "pubkey"];
+
+        /// Deserializes a field, checking it right away so that errors are located at its value
+        struct Checked<T>(fn(&str) -> Result<T, PeerError>);
+
+        impl<'de, T> DeserializeSeed<'de> for Checked<T> {
+            type Value = String;
+
+            fn deserialize<D>(self, deserializer: D) -> Result<String, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                let value = String::deserialize(deserializer)?;
+                (self.0)(&value).map_err(|e| de::Error::custom(e.with_field()))?;
+                Ok(value)
+            }
+        }
+
+        /// Converts the collected fields, naming the peer and field in errors
+        fn build<E: de::Error>(config: PeerConfig) -> Result<Peer, E> {
+            let name = config.name.clone();
+            Peer::try_from(config).map_err(|e| match name {
+                Some(name) => de::Error::custom(format!("peer {:?}: {}", name, e.with_field())),
+                None => de::Error::custom(e.with_field()),
+            })
+        }
+
+        const FIELDS: &[&str] = &[
+            "address",
+            "pubkey",
+            "proxy",
+            "name",
+            "max_sessions",
+            "maintenance",
+            "primary",
+            "weight",
+            "schedule",
+            "fwmark",
+            "interface",
+            "source_address",
+            "tunnel",
+            "quic",
+        ];
         deserializer.deserialize_struct("Peer", FIELDS, PeerVisitor)
     }
 }
 
-impl Peer {
-    pub fn build(address: String, pub_key: String) -> Self {
-        let address = address.parse::<std::net::SocketAddr>().unwrap();
-        let pub_key: [u8; 32] = base64::engine::general_purpose::STANDARD
-            .decode(pub_key)
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let hash = blake2s_simd::Params::new()
-            .to_state()
-            .update(LABEL_MAC1.as_bytes())
-            .update(pub_key.as_slice())
-            .finalize()
-            .as_array()
-            .to_owned();
+/// Writes the config form read by the [`Deserialize`] impl, the mac1 hash is derived again on load
+impl Serialize for Peer {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let config = PeerConfig::from(self);
+        let mut state = serializer.serialize_struct("Peer", 14)?;
+        state.serialize_field("address", &config.address)?;
+        if config.other_pubkeys.is_empty() {
+            state.serialize_field("pubkey", &config.pubkey)?;
+        } else {
+            let pubkeys: Vec<&String> = std::iter::once(&config.pubkey)
+                .chain(&config.other_pubkeys)
+                .collect();
+            state.serialize_field("pubkey", &pubkeys)?;
+        }
+        match &config.proxy {
+            Some(proxy) => state.serialize_field("proxy", proxy)?,
+            None => state.skip_field("proxy")?,
+        }
+        match &config.name {
+            Some(name) => state.serialize_field("name", name)?,
+            None => state.skip_field("name")?,
+        }
+        match &config.max_sessions {
+            Some(max_sessions) => state.serialize_field("max_sessions", max_sessions)?,
+            None => state.skip_field("max_sessions")?,
+        }
+        if config.maintenance.is_empty() {
+            state.skip_field("maintenance")?;
+        } else {
+            state.serialize_field("maintenance", &config.maintenance)?;
+        }
+        match config.primary {
+            true => state.serialize_field("primary", &true)?,
+            false => state.skip_field("primary")?,
+        }
+        match &config.weight {
+            Some(weight) => state.serialize_field("weight", weight)?,
+            None => state.skip_field("weight")?,
+        }
+        if config.schedule.is_empty() {
+            state.skip_field("schedule")?;
+        } else {
+            state.serialize_field("schedule", &config.schedule)?;
+        }
+        match &config.fwmark {
+            Some(fwmark) => state.serialize_field("fwmark", fwmark)?,
+            None => state.skip_field("fwmark")?,
+        }
+        match &config.interface {
+            Some(interface) => state.serialize_field("interface", interface)?,
+            None => state.skip_field("interface")?,
+        }
+        match &config.source_address {
+            Some(source_address) => state.serialize_field("source_address", source_address)?,
+            None => state.skip_field("source_address")?,
+        }
+        match &config.tunnel {
+            Some(tunnel) => state.serialize_field("tunnel", tunnel)?,
+            None => state.skip_field("tunnel")?,
+        }
+        match config.quic {
+            true => state.serialize_field("quic", &true)?,
+            false => state.skip_field("quic")?,
+        }
+        state.end()
+    }
+}
+
+/// The textual form of a [`Peer`] as it appears in the config
+#[derive(Clone, Debug, Default)]
+pub struct PeerConfig {
+    pub address: String,
+    pub pubkey: String,
+    pub proxy: Option<String>,
+    pub name: Option<String>,
+    pub max_sessions: Option<usize>,
+    /// keys besides `pubkey` routing to the same backend
+    pub other_pubkeys: Vec<String>,
+    pub maintenance: Vec<Window>,
+    pub primary: bool,
+    pub weight: Option<u32>,
+    pub schedule: Vec<Rule>,
+    pub fwmark: Option<u32>,
+    pub interface: Option<String>,
+    pub source_address: Option<String>,
+    pub tunnel: Option<String>,
+    pub quic: bool,
+}
+
+impl TryFrom<PeerConfig> for Peer {
+    type Error = PeerError;
+
+    fn try_from(config: PeerConfig) -> Result<Self, Self::Error> {
+        let address = parse_address(&config.address)?;
+        let pub_key = parse_pubkey(&config.pubkey)?;
+        let proxy = config.proxy.as_deref().map(parse_proxy).transpose()?;
+        let source = config
+            .source_address
+            .as_deref()
+            .map(parse_source_address)
+            .transpose()?;
+        let other_keys = config
+            .other_pubkeys
+            .iter()
+            .map(|key| parse_pubkey(key).map(PeerKey::new))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Peer::new(address, pub_key)
+            .with_proxy(proxy)
+            .with_name(config.name)
+            .with_max_sessions(config.max_sessions)
+            .with_other_keys(other_keys)
+            .with_maintenance(config.maintenance)
+            .with_primary(config.primary)
+            .with_weight(config.weight)
+            .with_schedule(config.schedule)
+            .with_fwmark(config.fwmark)
+            .with_interface(config.interface)
+            .with_source(source)
+            .with_tunnel(config.tunnel)
+            .with_quic(config.quic))
+    }
+}
+
+fn parse_address(value: &str) -> Result<SocketAddr, PeerError> {
+    value.parse().map_err(|source| PeerError::InvalidAddress {
+        value: value.to_string(),
+        source,
+    })
+}
+
+fn parse_pubkey(value: &str) -> Result<[u8; 32], PeerError> {
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(PeerError::InvalidPubKeyEncoding)?;
+    decoded
+        .try_into()
+        .map_err(|decoded: Vec<u8>| PeerError::InvalidPubKeyLength(decoded.len()))
+}
 
+fn parse_proxy(value: &str) -> Result<SocketAddr, PeerError> {
+    value.parse().map_err(|source| PeerError::InvalidProxy {
+        value: value.to_string(),
+        source,
+    })
+}
+
+fn parse_source_address(value: &str) -> Result<IpAddr, PeerError> {
+    value
+        .parse()
+        .map_err(|source| PeerError::InvalidSourceAddress {
+            value: value.to_string(),
+            source,
+        })
+}
+
+impl From<&Peer> for PeerConfig {
+    fn from(peer: &Peer) -> Self {
+        PeerConfig {
+            address: peer.address.to_string(),
+            pubkey: base64::engine::general_purpose::STANDARD.encode(peer.pub_key),
+            proxy: peer.proxy.map(|proxy| proxy.to_string()),
+            name: peer.name.clone(),
+            max_sessions: peer.max_sessions,
+            other_pubkeys: peer
+                .other_keys
+                .iter()
+                .map(|key| base64::engine::general_purpose::STANDARD.encode(key.pub_key))
+                .collect(),
+            maintenance: peer.maintenance.clone(),
+            primary: peer.primary,
+            weight: peer.weight,
+            schedule: peer.schedule.clone(),
+            fwmark: peer.fwmark,
+            interface: peer.interface.clone(),
+            source_address: peer.source.map(|source| source.to_string()),
+            tunnel: peer.tunnel.clone(),
+            quic: peer.quic,
+        }
+    }
+}
+
+impl Peer {
+    pub fn new(address: SocketAddr, pub_key: [u8; 32]) -> Self {
         Peer {
             pub_key,
-            precomputed_hash_label_mac1: hash,
+            precomputed_hash_label_mac1: mac1_key(&pub_key),
             address,
+            proxy: None,
+            name: None,
+            max_sessions: None,
+            other_keys: Vec::new(),
+            maintenance: Vec::new(),
+            primary: false,
+            weight: None,
+            schedule: Vec::new(),
+            fwmark: None,
+            interface: None,
+            source: None,
+            tunnel: None,
+            quic: false,
+        }
+    }
+
+    pub fn with_proxy(self, proxy: Option<SocketAddr>) -> Self {
+        Peer { proxy, ..self }
+    }
+
+    pub fn with_name(self, name: Option<String>) -> Self {
+        Peer { name, ..self }
+    }
+
+    pub fn with_max_sessions(self, max_sessions: Option<usize>) -> Self {
+        Peer {
+            max_sessions,
+            ..self
+        }
+    }
+
+    pub fn with_other_keys(self, other_keys: Vec<PeerKey>) -> Self {
+        Peer { other_keys, ..self }
+    }
+
+    pub fn with_maintenance(self, maintenance: Vec<Window>) -> Self {
+        Peer {
+            maintenance,
+            ..self
+        }
+    }
+
+    pub fn with_primary(self, primary: bool) -> Self {
+        Peer { primary, ..self }
+    }
+
+    pub fn with_weight(self, weight: Option<u32>) -> Self {
+        Peer { weight, ..self }
+    }
+
+    pub fn with_schedule(self, schedule: Vec<Rule>) -> Self {
+        Peer { schedule, ..self }
+    }
+
+    pub fn with_fwmark(self, fwmark: Option<u32>) -> Self {
+        Peer { fwmark, ..self }
+    }
+
+    pub fn with_interface(self, interface: Option<String>) -> Self {
+        Peer { interface, ..self }
+    }
+
+    pub fn with_source(self, source: Option<IpAddr>) -> Self {
+        Peer { source, ..self }
+    }
+
+    pub fn with_tunnel(self, tunnel: Option<String>) -> Self {
+        Peer { tunnel, ..self }
+    }
+
+    pub fn with_quic(self, quic: bool) -> Self {
+        Peer { quic, ..self }
+    }
+
+    /// The public key an initiation is addressed to if it is one of this peer's, by its `mac1`
+    ///
+    /// `covered` is the part of the initiation the mac1 is computed over.
+    pub fn matching_key(&self, covered: &[u8], mac1: &[u8; 16]) -> Option<&[u8; 32]> {
+        std::iter::once((&self.pub_key, &self.precomputed_hash_label_mac1))
+            .chain(
+                self.other_keys
+                    .iter()
+                    .map(|key| (&key.pub_key, &key.precomputed_hash_label_mac1)),
+            )
+            .find(|(_, mac1_key)| &utils::mac(mac1_key.as_slice(), covered) == mac1)
+            .map(|(pub_key, _)| pub_key)
+    }
+}
+
+/// Checks that no two of `peers` share a public key
+///
+/// Handshakes are matched to backends by the mac1 computed from their public key, so sessions
+/// would be split between such peers at random. Peers sharing an address are allowed, they may
+/// differ by proxy, but likely a mistake, so their pairs are returned for a warning.
+pub fn check_peers(peers: &[Peer]) -> Result<Vec<(&Peer, &Peer)>, PeerError> {
+    let mut pubkeys = HashMap::new();
+    let mut addresses = HashMap::new();
+    let mut shared_addresses = Vec::new();
+    for peer in peers {
+        let keys =
+            std::iter::once(peer.pub_key).chain(peer.other_keys.iter().map(|key| key.pub_key));
+        for key in keys {
+            if let Some(first) = pubkeys.insert(key, peer) {
+                return Err(PeerError::DuplicatePubKey {
+                    pubkey: base64::engine::general_purpose::STANDARD.encode(key),
+                    first: first.to_string(),
+                    second: peer.to_string(),
+                });
+            }
+        }
+        if let Some(first) = addresses.insert(peer.address, peer) {
+            shared_addresses.push((first, peer));
         }
     }
+    Ok(shared_addresses)
 }
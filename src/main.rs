@@ -1,44 +1,849 @@
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
-use std::env;
-use std::path::Path;
-use std::sync::mpsc::channel;
+use clap::{Parser, Subcommand};
+use notify::{Event, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, channel};
 use std::time::Duration;
-use tokio::net::UdpSocket;
+use tracing_subscriber::EnvFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use wireguard_router::affinity::{Affinity, AffinityTable};
+use wireguard_router::discovery::PeerSet;
+use wireguard_router::error::{Error, Report};
+use wireguard_router::metrics::{Checkpoint, Metrics};
+use wireguard_router::policy::{LeastSessions, LowestLatency};
+use wireguard_router::router::{DEFAULT_SESSION_TIMEOUT, Router, RouterBuilder};
+use wireguard_router::transport::{self, Listeners, PacketTransport};
 
-use crate::router::Router;
+use crate::config::Strategy;
 
+#[cfg(feature = "admin")]
+mod admin;
 pub mod config;
-pub mod error;
-pub mod router;
-pub mod state;
+#[cfg(feature = "admin")]
+mod ctl;
+mod decode;
+#[cfg(feature = "doctor")]
+mod doctor;
+#[cfg(feature = "doctor")]
+mod genkey;
+mod persist;
+#[cfg(unix)]
+mod privileges;
+mod replay;
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+mod sandbox;
+#[cfg(windows)]
+mod service;
+mod stats;
+#[cfg(feature = "admin")]
+mod sync;
+mod syslog;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(
+    feature = "mimalloc",
+    not(all(feature = "jemalloc", not(target_env = "msvc")))
+))]
+#[global_allocator]
+static ALLOCATOR: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+const DEFAULT_PORT: u16 = 51337;
+
+/// Routes WireGuard handshakes to backends by the public key they are addressed to
+#[derive(Parser, Debug)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// Options of the router itself, also taken by the Windows service
+#[derive(clap::Args, Clone, Debug)]
+struct RunArgs {
+    /// Addresses to listen on, at most one per address family [default: 0.0.0.0:51337 [::]:51337]
+    #[arg(env = config::LISTEN_ENV, value_delimiter = ',')]
+    listen: Vec<SocketAddr>,
+    /// Config file, or - to read it once from stdin [default: config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Override a config value, taking precedence over every other source, e.g. router.max_sessions=100
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    overrides: Vec<(String, String)>,
+    /// Print the config merged from all sources as JSON and exit
+    #[arg(long)]
+    print_effective_config: bool,
+    /// Load the config once and never reload it, e.g. on a read-only filesystem
+    #[arg(long, env = config::NO_WATCH_ENV, value_parser = clap::builder::BoolishValueParser::new())]
+    no_watch: bool,
+    /// Inject the faults of the [chaos] config table into forwarded packets, for testing only
+    #[arg(long)]
+    chaos: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the WireGuard headers of a packet or a pcap capture
+    Decode(decode::Args),
+    /// Check the config, the listen addresses and the reachability of all backends
+    #[cfg(feature = "doctor")]
+    Doctor(doctor::Args),
+    /// Generate a private key, or print the public key of one, without the `wg` binary
+    #[cfg(feature = "doctor")]
+    Genkey(genkey::Args),
+    /// Route the datagrams of a pcap capture offline and print the decisions and sessions
+    ///
+    /// The capture's timestamps are printed but don't drive the router's clock: datagrams are
+    /// routed back to back, so sessions don't expire and rate and rekey windows don't apply as
+    /// they did live.
+    Replay(replay::Args),
+    /// Query a running router through its admin API
+    #[cfg(feature = "admin")]
+    Ctl(ctl::Args),
+    /// Run as a Windows service, started by the service control manager
+    #[cfg(windows)]
+    Service(RunArgs),
+}
+
+fn parse_override(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {value:?}"))
+}
+
+fn main() -> ExitCode {
+    let journal = journal();
+    let stderr = journal
+        .is_none()
+        .then(|| tracing_subscriber::fmt::layer().without_time());
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().without_time())
+        .with(syslog::layer())
+        .with(journal)
+        .with(stderr)
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
         .init();
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "0.0.0.0:51337".to_string());
 
-    let socket = UdpSocket::bind(&addr).await?;
-    tracing::info!("Listening on: {}", socket.local_addr()?);
+    let cli = Cli::parse();
+    let result = match cli.command {
+        // the service starts a runtime of its own
+        #[cfg(windows)]
+        Some(Command::Service(args)) => service::run(args),
+        command => runtime(command.is_none().then_some(&cli.run))
+            .and_then(|runtime| runtime.block_on(dispatch(command, cli.run))),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("{}", Report(&e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn dispatch(command: Option<Command>, run_args: RunArgs) -> Result<(), Error> {
+    match command {
+        Some(Command::Decode(args)) => decode::run(args),
+        #[cfg(feature = "doctor")]
+        Some(Command::Doctor(args)) => doctor::run(args).await,
+        #[cfg(feature = "doctor")]
+        Some(Command::Genkey(args)) => genkey::run(args),
+        Some(Command::Replay(args)) => replay::run(args).await,
+        #[cfg(feature = "admin")]
+        Some(Command::Ctl(args)) => ctl::run(args).await,
+        #[cfg(windows)]
+        Some(Command::Service(_)) => unreachable!("the service is run outside of the runtime"),
+        None => run(run_args).await,
+    }
+}
+
+/// The multi-threaded runtime, whose threads each confine themselves as they start if the router
+/// run with `run` is sandboxed
+#[cfg_attr(
+    not(all(target_os = "linux", feature = "sandbox")),
+    allow(unused_variables)
+)]
+fn runtime(run: Option<&RunArgs>) -> Result<tokio::runtime::Runtime, Error> {
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    if let Some(args) = run
+        && let Some(sandbox) = sandbox_paths(args)?
+    {
+        let sandbox = sandbox.install();
+        runtime.on_thread_start(move || sandbox.confine_thread());
+    }
+    runtime.build().map_err(Error::Runtime)
+}
+
+/// The files the router reads and writes once started, if the config sets `sandbox = true`
+///
+/// This is read ahead of [`run`], which reads the config again with the remote config layered
+/// over it, so the threads of the runtime are confined before any task runs.
+#[cfg(all(target_os = "linux", feature = "sandbox"))]
+fn sandbox_paths(args: &RunArgs) -> Result<Option<sandbox::Sandbox>, Error> {
+    if args.print_effective_config {
+        return Ok(None);
+    }
+    if let Some(path) = &args.config {
+        config::set_path(path)?;
+    }
+    config::set_overrides(args.overrides.clone());
+    let config = config::load()?;
+    if !config.sandbox {
+        return Ok(None);
+    }
+    let read = [config::path().map(Path::to_path_buf), config::peers_file()]
+        .into_iter()
+        .flatten()
+        .collect();
+    let write = [
+        config.counters.map(|counters| counters.path),
+        config.affinity.and_then(|affinity| affinity.path),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    Ok(Some(sandbox::Sandbox { read, write }))
+}
+
+#[cfg(all(unix, feature = "systemd"))]
+fn journal() -> Option<tracing_journald::Layer> {
+    systemd::journal()
+}
 
-    let (tx, rx) = channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(
-        tx,
-        notify::Config::default().with_poll_interval(Duration::from_secs(2)),
-    )
-    .unwrap();
+#[cfg(not(all(unix, feature = "systemd")))]
+fn journal() -> Option<tracing_subscriber::layer::Identity> {
+    None
+}
 
-    watcher
-        .watch(Path::new("config.toml"), RecursiveMode::NonRecursive)
-        .unwrap();
+/// Watches the config with the platform's native backend, or by polling where that fails
+///
+/// The native backends differ between platforms, e.g. in whether single files can be watched.
+fn watch_config(path: &Path, tx: Sender<notify::Result<Event>>) -> Result<Box<dyn Watcher>, Error> {
+    let watch_error = |source| Error::ConfigWatch {
+        path: path.to_path_buf(),
+        source,
+    };
+    let settings = notify::Config::default().with_poll_interval(Duration::from_secs(2));
+    let include_dir = config::include_dir(path);
+    let watch = |watcher: &mut dyn Watcher| {
+        watcher.watch(path, RecursiveMode::NonRecursive)?;
+        if include_dir.is_dir() {
+            watcher.watch(&include_dir, RecursiveMode::NonRecursive)?;
+        }
+        // the signature may be replaced after the config
+        #[cfg(feature = "signed-config")]
+        if std::env::var_os(config::KEY_ENV).is_some() {
+            watcher.watch(&config::signature_path(path), RecursiveMode::NonRecursive)?;
+        }
+        Ok(())
+    };
 
-    let router = Router::new(socket);
-    router.run(rx).await?;
+    let native = RecommendedWatcher::new(tx.clone(), settings).and_then(|mut watcher| {
+        watch(&mut watcher)?;
+        Ok(watcher)
+    });
+    match native {
+        Ok(watcher) => Ok(Box::new(watcher)),
+        Err(e) => {
+            tracing::warn!("native config watching failed, polling instead: {}", e);
+            let mut watcher = PollWatcher::new(tx, settings).map_err(watch_error)?;
+            watch(&mut watcher).map_err(watch_error)?;
+            Ok(Box::new(watcher))
+        }
+    }
+}
 
+/// Follows the Services of the `[[kubernetes]]` config entries
+#[cfg(feature = "kubernetes")]
+async fn discover_kubernetes(peers: &PeerSet) -> Result<(), Error> {
+    let services = config::settings()?.read().unwrap().kubernetes.clone();
+    if services.is_empty() {
+        return Ok(());
+    }
+    let client = kube::Client::try_default()
+        .await
+        .map_err(|e| Error::Discovery {
+            kind: "kubernetes",
+            source: e.into(),
+        })?;
+    for service in services {
+        tokio::spawn(wireguard_router::discovery::kubernetes::discover(
+            client.clone(),
+            service,
+            peers.clone(),
+        ));
+    }
     Ok(())
 }
+
+/// The given listen addresses, at most one per address family, defaulting to both, the v4 one
+/// first
+fn listen_addrs(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    if !addrs.is_empty() {
+        return addrs;
+    }
+    vec![
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)),
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, DEFAULT_PORT)),
+    ]
+}
+
+/// The sockets passed by systemd if socket activated, otherwise ones bound to `addrs`
+fn listeners(addrs: Vec<SocketAddr>, device: Option<&str>) -> Result<Listeners, Error> {
+    #[cfg(all(unix, feature = "systemd"))]
+    {
+        let sockets = systemd::listen_sockets().map_err(Error::Bind)?;
+        if !sockets.is_empty() {
+            if device.is_some() {
+                return Err(Error::Bind(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "socket activation can't be combined with bind_device",
+                )));
+            }
+            if !addrs.is_empty() {
+                tracing::warn!("socket activated, ignoring the listen addresses");
+            }
+            return Listeners::from_std(sockets).map_err(Error::Bind);
+        }
+    }
+    let defaulted = addrs.is_empty();
+    let addrs = listen_addrs(addrs);
+    let bind = |addrs: &[SocketAddr]| match device {
+        Some(device) => Listeners::bind_to_device(addrs, device),
+        None => Listeners::bind(addrs),
+    };
+    match bind(&addrs) {
+        // the default addresses shouldn't keep hosts without IPv6 from starting
+        Err(e) if defaulted && transport::ipv6_unavailable(&e) => {
+            tracing::warn!("IPv6 is unavailable ({}), listening on IPv4 only", e);
+            bind(&addrs[..1])
+        }
+        result => result,
+    }
+    .map_err(Error::Bind)
+}
+
+/// Applies the routing settings of `config` to `router`, as shared by `run` and `replay`
+fn configure<T: PacketTransport>(
+    mut router: RouterBuilder<T>,
+    config: &config::Config,
+    metrics: &Arc<Metrics>,
+) -> Result<RouterBuilder<T>, Error> {
+    if let Some(health) = config.health {
+        router = router.health(health);
+    }
+    if let Some(outliers) = config.outliers {
+        router = router.outlier_detection(outliers);
+    }
+    if let Some(honeypot) = config.honeypot.clone() {
+        router = router.honeypot(honeypot);
+    }
+    if !config.listeners.is_empty() {
+        router = router.horizons(config.listeners.clone());
+    }
+    if let Some(buffer_size) = config.router.buffer_size {
+        router = router.buffer_size(buffer_size);
+    }
+    if let Some(size) = config.router.max_datagram_size {
+        router = router.max_datagram_size(size);
+    }
+    if let Some(max_panics) = config.router.max_panics {
+        router = router.max_panics(max_panics);
+    }
+    if let Some(batch_size) = config.router.batch_size {
+        router = router.batch_size(batch_size);
+    }
+    if let Some(backpressure) = config.router.backpressure {
+        router = router.backpressure(backpressure);
+    }
+    router = router.fair_queuing(config.router.fair_queuing);
+    if let Some(max_sessions) = config.router.max_sessions {
+        router = router.max_sessions(max_sessions);
+    }
+    if let Some(secs) = config.router.session_timeout_secs {
+        router = router.session_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = config.router.handshake_timeout_secs {
+        router = router.handshake_timeout(Duration::from_secs(secs));
+    }
+    if let Some(threshold) = config.router.rekey_threshold {
+        router = router.rekey_threshold(threshold);
+    }
+    if let Some(sessions) = config.router.timelines {
+        router = router.timelines(sessions);
+    }
+    if let Some(mb) = config.router.memory_limit_mb {
+        router = router.memory_limit(mb * 1024 * 1024);
+    }
+    if let Some(unmatched_data) = config.router.unmatched_data {
+        router = router.unmatched_data(unmatched_data);
+    }
+    if let Some(timezone) = config.router.timezone {
+        router = router.timezone(timezone);
+    }
+    for pin in &config.router.pins {
+        router = router.pin(pin.client, pin.backend);
+    }
+    if !config.router.deny.is_empty() {
+        router = router.deny(config.router.deny.clone());
+    }
+    match config.router.strategy {
+        Strategy::FirstMatch => {}
+        Strategy::LowestLatency => {
+            router = router.policy(LowestLatency::new(metrics.clone()));
+        }
+        Strategy::LeastSessions => router = router.policy(LeastSessions),
+        Strategy::FastestResponder => router = router.race_initiations(true),
+    }
+    #[cfg(feature = "lua")]
+    if let Some(script) = config.lua_script.clone() {
+        let budget = Duration::from_millis(config.lua_budget_ms);
+        let policy =
+            wireguard_router::policy::lua::LuaPolicy::load(&script, budget).map_err(|e| {
+                Error::Policy {
+                    path: script.clone(),
+                    source: e.into(),
+                }
+            })?;
+        tracing::info!("loaded lua routing hooks from {}", script.display());
+        router = router.policy(policy);
+    }
+    #[cfg(feature = "wasm-plugin")]
+    if let Some(plugin) = config.wasm_policy.clone() {
+        #[cfg(feature = "lua")]
+        if config.lua_script.is_some() {
+            return Err(Error::InvalidConfig(
+                "only one of lua_script and wasm_policy can be configured".to_string(),
+            ));
+        }
+        let budget = Duration::from_millis(plugin.budget_ms);
+        let policy = wireguard_router::policy::wasm::WasmPolicy::load(&plugin.path, budget)
+            .map_err(|e| Error::Policy {
+                path: plugin.path.clone(),
+                source: e.into(),
+            })?;
+        tracing::info!("loaded wasm routing policy from {}", plugin.path.display());
+        router = router.policy(policy);
+    }
+    Ok(router)
+}
+
+async fn run(args: RunArgs) -> Result<(), Error> {
+    if let Some(path) = &args.config {
+        config::set_path(path)?;
+    }
+    config::set_overrides(args.overrides);
+    #[cfg(feature = "remote-config")]
+    let remote = match config::Remote::from_env()? {
+        Some(mut remote) => {
+            remote.fetch_initial().await?;
+            Some(remote)
+        }
+        None => None,
+    };
+    if args.print_effective_config {
+        let effective = config::effective()?;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&effective).unwrap_or_default()
+        );
+        return Ok(());
+    }
+    config::init()?;
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    let sandbox = match (
+        config::settings()?.read().unwrap().sandbox,
+        sandbox::Sandbox::get(),
+    ) {
+        (true, Some(sandbox)) => Some(sandbox),
+        (false, None) => None,
+        _ => {
+            return Err(Error::InvalidConfig(
+                "sandbox can't be switched by the remote config".to_string(),
+            ));
+        }
+    };
+    if let Some(syslog) = config::settings()?.read().unwrap().syslog.clone() {
+        syslog::start(&syslog)?;
+    }
+
+    let settings = config::settings()?.read().unwrap().router.clone();
+    if let Some(name) = &settings.netns {
+        transport::enter_netns(name).map_err(|source| Error::Netns {
+            name: name.clone(),
+            source,
+        })?;
+    }
+    let mut listeners = listeners(args.listen, settings.bind_device.as_deref())?;
+    if settings.connect_backends {
+        listeners = listeners.connect_backends();
+    }
+    if let Some(mark) = settings.fwmark {
+        listeners = listeners.fwmark(mark).map_err(Error::Bind)?;
+    }
+    let idle = settings
+        .session_timeout_secs
+        .map_or(DEFAULT_SESSION_TIMEOUT, Duration::from_secs);
+    listeners = listeners
+        .source_port(settings.source_port, idle)
+        .map_err(Error::Bind)?;
+    if let Some(limit) = settings.max_session_sockets {
+        listeners = listeners.max_session_sockets(limit);
+    }
+    #[cfg(feature = "tunnel")]
+    {
+        let tunnels = config::settings()?.read().unwrap().tunnels.clone();
+        let mut opened = Vec::with_capacity(tunnels.len());
+        for tunnel in &tunnels {
+            opened.push(wireguard_router::tunnel::Tunnel::open(tunnel).await?);
+            tracing::info!("tunnel {} to {}", tunnel.name, tunnel.endpoint);
+        }
+        listeners = listeners.tunnels(opened);
+    }
+    #[cfg(feature = "quic")]
+    if let Some(quic) = config::settings()?.read().unwrap().quic.clone() {
+        let links = wireguard_router::quic::Links::open(&quic)?;
+        if let Some(listen) = quic.listen {
+            tracing::info!("accepting QUIC links on {}", listen);
+        }
+        listeners = listeners.quic(links);
+    }
+    let listen_addresses = listeners
+        .v4
+        .iter()
+        .chain(listeners.v6.iter())
+        .map(|socket| socket.local_addr())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::Bind)?;
+    for address in &listen_addresses {
+        tracing::info!("Listening on: {}", address);
+    }
+
+    #[cfg(feature = "admin")]
+    let admin = config::settings()?.read().unwrap().admin.clone();
+    #[cfg(feature = "admin")]
+    let admin = match admin {
+        Some(settings) => {
+            let listener = admin::bind(settings.listen).await?;
+            // before privileges are dropped, like the listener
+            let audit = admin::Audit::open(settings.audit_log.as_deref())?;
+            Some((listener, audit, settings))
+        }
+        None => None,
+    };
+    #[cfg(feature = "snmp")]
+    let snmp = config::settings()?.read().unwrap().snmp.clone();
+    #[cfg(feature = "snmp")]
+    let snmp = match snmp {
+        Some(settings) => Some(wireguard_router::snmp::Agent::bind(settings).await?),
+        None => None,
+    };
+
+    // raw sockets need privileges too
+    #[cfg(feature = "icmp")]
+    let icmp = config::settings()?
+        .read()
+        .unwrap()
+        .router
+        .icmp_unreachable_per_sec;
+    #[cfg(feature = "icmp")]
+    let icmp = match icmp {
+        Some(per_second) => {
+            let locals = listen_addresses.clone();
+            let unreachable = wireguard_router::icmp::Unreachable::open(locals, per_second)
+                .map_err(Error::IcmpSocket)?;
+            tracing::info!("answering rejected datagrams with ICMP port unreachable");
+            Some(unreachable)
+        }
+        None => None,
+    };
+
+    #[cfg(unix)]
+    {
+        let config = config::settings()?.read().unwrap();
+        privileges::drop(config.user.as_deref(), config.group.as_deref())?;
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    #[cfg(feature = "admin")]
+    if admin
+        .as_ref()
+        .is_some_and(|(_, _, settings)| settings.labels.clients)
+    {
+        metrics.track_clients();
+    }
+    let mut router = Router::builder(listeners).metrics(metrics.clone());
+    let mut checkpoints = Vec::new();
+    let counters = config::settings()?.read().unwrap().counters.clone();
+    if let Some(counters) = counters {
+        if let Some(checkpoint) = persist::restore::<Checkpoint>(&counters.path, "counters")? {
+            metrics.restore(&checkpoint);
+        }
+        let metrics = metrics.clone();
+        checkpoints.push(persist::Checkpoint::new(
+            counters.path,
+            Duration::from_secs(counters.interval_secs),
+            move || metrics.checkpoint(),
+        ));
+    }
+    let affinity = config::settings()?.read().unwrap().affinity.clone();
+    if let Some(affinity) = affinity {
+        let table = Arc::new(AffinityTable::new(Duration::from_secs(affinity.ttl_secs)));
+        if let Some(path) = affinity.path {
+            if let Some(entries) = persist::restore::<Vec<Affinity>>(&path, "client affinity")? {
+                table.restore(&entries);
+            }
+            let table = table.clone();
+            checkpoints.push(persist::Checkpoint::new(
+                path,
+                Duration::from_secs(affinity.interval_secs),
+                move || table.entries(),
+            ));
+        }
+        router = router.affinity(table);
+    }
+    let chaos = config::settings()?.read().unwrap().chaos;
+    match chaos {
+        Some(_) if !args.chaos => {
+            return Err(Error::InvalidConfig(
+                "[chaos] drops and delays packets on purpose, start with --chaos to allow it"
+                    .to_string(),
+            ));
+        }
+        Some(chaos) => {
+            let shares = [chaos.drop, chaos.duplicate, chaos.reorder];
+            if !shares.iter().all(|share| (0.0..=1.0).contains(share)) {
+                return Err(Error::InvalidConfig(
+                    "chaos drop, duplicate and reorder must be between 0 and 1".to_string(),
+                ));
+            }
+            tracing::warn!(
+                "CHAOS MODE: dropping {}%, duplicating {}% and reordering {}% of the packets, \
+                 delaying them by {}ms plus up to {}ms, never use this on real traffic",
+                chaos.drop * 100.0,
+                chaos.duplicate * 100.0,
+                chaos.reorder * 100.0,
+                chaos.latency_ms,
+                chaos.jitter_ms
+            );
+            router = router.chaos(chaos);
+        }
+        None if args.chaos => tracing::warn!("--chaos given without a [chaos] config table"),
+        None => {}
+    }
+    router = configure(router, &config::settings()?.read().unwrap(), &metrics)?;
+
+    let (peers, peers_rx) = PeerSet::new(config::settings()?.read().unwrap().peers.clone());
+    #[cfg(feature = "remote-config")]
+    if let Some(remote) = remote.filter(|_| !args.no_watch) {
+        tokio::spawn(remote.poll(peers.clone()));
+    }
+    #[cfg(feature = "remote-config")]
+    if let Some(sync) = config::PeerSync::from_env()? {
+        tokio::spawn(sync.poll(peers.clone()));
+    }
+    #[cfg(feature = "consul")]
+    for service in config::settings()?.read().unwrap().consul.clone() {
+        tokio::spawn(wireguard_router::discovery::consul::discover(
+            service,
+            peers.clone(),
+        ));
+    }
+    #[cfg(feature = "dns")]
+    for service in config::settings()?.read().unwrap().dns.clone() {
+        tokio::spawn(wireguard_router::discovery::dns::discover(
+            service,
+            peers.clone(),
+        ));
+    }
+    #[cfg(feature = "docker")]
+    if let Some(daemon) = config::settings()?.read().unwrap().docker.clone() {
+        tokio::spawn(wireguard_router::discovery::docker::discover(
+            daemon,
+            peers.clone(),
+        ));
+    }
+    #[cfg(feature = "etcd")]
+    for prefix in config::settings()?.read().unwrap().etcd.clone() {
+        tokio::spawn(wireguard_router::discovery::etcd::discover(
+            prefix,
+            peers.clone(),
+        ));
+    }
+    #[cfg(feature = "kubernetes")]
+    discover_kubernetes(&peers).await?;
+    if !checkpoints.is_empty() {
+        tokio::spawn(persist::run(checkpoints));
+    }
+
+    // the runtime's threads are confined already, the config watcher inherits the restriction
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    if let Some(sandbox) = sandbox {
+        sandbox.confine()?;
+    }
+
+    // without a config file everything comes from the environment, which can't change
+    let _watcher = match config::path() {
+        _ if args.no_watch => {
+            tracing::info!("not watching the config for changes");
+            None
+        }
+        None => {
+            tracing::info!("config read from stdin, not watching it");
+            None
+        }
+        Some(path) if path.exists() => {
+            let (tx, rx) = channel();
+            let watcher = watch_config(path, tx)?;
+            config::reload_on_change(rx, peers.clone());
+            Some(watcher)
+        }
+        Some(path) => {
+            tracing::info!("no {} found, configured by the environment", path.display());
+            None
+        }
+    };
+
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    if sandbox.is_some() {
+        sandbox::restrict_syscalls()?;
+    }
+
+    #[cfg(all(unix, feature = "systemd"))]
+    {
+        if let Some(interval) = systemd::watchdog_interval() {
+            router = router.heartbeat(interval, systemd::watchdog);
+        }
+        systemd::ready();
+    }
+    let router = router.build();
+    #[cfg(feature = "alarms")]
+    if let Some(alarms) = config::settings()?.read().unwrap().alarms.clone() {
+        tokio::spawn(wireguard_router::alarm::watch(
+            alarms,
+            router.subscribe(),
+            router.session_table(),
+        ));
+    }
+    #[cfg(feature = "blocklists")]
+    if let Some(blocklists) = config::settings()?.read().unwrap().blocklists.clone() {
+        tokio::spawn(wireguard_router::blocklist::refresh(
+            blocklists,
+            router.denylist(),
+        ));
+    }
+    #[cfg(feature = "crowdsec")]
+    if let Some(crowdsec) = config::settings()?.read().unwrap().crowdsec.clone() {
+        if crowdsec.machine_id.is_some() != crowdsec.password.is_some() {
+            return Err(Error::InvalidConfig(
+                "crowdsec machine_id and password must be set together".to_string(),
+            ));
+        }
+        tokio::spawn(wireguard_router::crowdsec::report(
+            crowdsec.clone(),
+            router.subscribe(),
+        ));
+        tokio::spawn(wireguard_router::crowdsec::bounce(
+            crowdsec,
+            router.denylist(),
+        ));
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt) = config::settings()?.read().unwrap().mqtt.clone() {
+        let options = mqtt.options()?;
+        let sources = wireguard_router::mqtt::Sources {
+            metrics: router.metrics(),
+            sessions: router.session_table(),
+            health: router.health(),
+            peers: peers_rx.clone(),
+            events: router.subscribe(),
+        };
+        tokio::spawn(wireguard_router::mqtt::publish(mqtt, options, sources));
+    }
+    #[cfg(feature = "export")]
+    if let Some(export) = config::settings()?.read().unwrap().export.clone() {
+        tokio::spawn(wireguard_router::export::export(export, router.subscribe()));
+    }
+    let summary = config::settings()?.read().unwrap().stats.clone();
+    if let Some(summary) = summary {
+        let sources = stats::Sources {
+            metrics: metrics.clone(),
+            sessions: router.session_table(),
+            health: router.health(),
+            peers: peers_rx.clone(),
+            events: router.subscribe(),
+        };
+        tokio::spawn(stats::log(
+            Duration::from_secs(summary.interval_secs),
+            sources,
+        ));
+    }
+    #[cfg(feature = "admin")]
+    if let Some((listener, audit, settings)) = admin {
+        let status = admin::Status {
+            listeners: listen_addresses,
+            health: router.health(),
+            events: router.subscribe(),
+            gc: router.gc_trigger(),
+            lockdown: router.lockdown(),
+            pins: router.pins(),
+            memory: router.memory_meter(),
+            explainer: router.explainer(),
+            injection: settings
+                .inject_token
+                .map(|token| (router.injector(), token)),
+            export_token: settings.export_token,
+            credentials: settings.credentials,
+            rate_limit: settings.rate_limit,
+            audit,
+            #[cfg(all(unix, feature = "profiling"))]
+            profiling: settings.profiling,
+        };
+        tokio::spawn(admin::serve(
+            listener,
+            metrics,
+            router.session_table(),
+            settings.labels,
+            status,
+        ));
+    }
+    #[cfg(feature = "snmp")]
+    if let Some(agent) = snmp {
+        tokio::spawn(agent.serve(wireguard_router::snmp::Sources {
+            metrics: router.metrics(),
+            sessions: router.session_table(),
+            health: router.health(),
+            peers: peers_rx.clone(),
+        }));
+    }
+    #[cfg(feature = "icmp")]
+    if let Some(unreachable) = icmp {
+        tokio::spawn(unreachable.respond(router.subscribe()));
+    }
+    #[cfg(feature = "admin")]
+    let warm_start = config::settings()?.read().unwrap().warm_start.clone();
+    #[cfg(feature = "admin")]
+    if let Some(warm_start) = warm_start {
+        let peers = peers_rx.borrow().clone();
+        match sync::warm_start(&warm_start, &router.session_table(), &peers).await {
+            Ok(imported) => tracing::info!(
+                "took over {} sessions from the router at {}",
+                imported,
+                warm_start.url
+            ),
+            Err(e) => tracing::warn!("starting without the sibling's sessions: {}", Report(&e)),
+        }
+    }
+    router.run(peers_rx).await
+}
@@ -0,0 +1,577 @@
+/*
+* metrics.rs contains the counters a router keeps about the traffic it routes
+*/
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::packet::MessageType;
+
+/// The span [`Rates`] are averaged over
+pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+const BUCKETS: usize = RATE_WINDOW.as_secs() as usize;
+/// Weight of a new handshake RTT in the moving average of a backend
+const RTT_WEIGHT: f64 = 0.2;
+/// Upper bounds of the buckets received datagrams are counted in by size, in bytes
+///
+/// Besides the sizes of the handshake messages, they tell keepalives (32) from data, and data
+/// close to common MTUs, which may be fragmented on the way.
+pub const SIZE_BUCKETS: [usize; 10] = [32, 64, 92, 148, 256, 512, 1024, 1280, 1420, 1500];
+/// The message types in the order [`Metrics::received_by_type`] counts them
+const MESSAGE_TYPES: [MessageType; 4] = [
+    MessageType::HandshakeInitiation,
+    MessageType::HandshakeResponse,
+    MessageType::CookieReply,
+    MessageType::TransportData,
+];
+
+/// Counters shared between a [`Router`](crate::router::Router) and whoever reports on it
+#[derive(Debug, Default)]
+pub struct Metrics {
+    received: AtomicU64,
+    /// by the index of their type in [`MESSAGE_TYPES`]
+    received_types: [AtomicU64; 4],
+    /// by the index of their bucket in [`SIZE_BUCKETS`], the last one counting larger datagrams
+    received_sizes: [AtomicU64; SIZE_BUCKETS.len() + 1],
+    received_bytes: AtomicU64,
+    forwarded: AtomicU64,
+    dropped: AtomicU64,
+    sessions_created: AtomicU64,
+    sessions_expired: AtomicU64,
+    sessions_purged: AtomicU64,
+    excessive_rekeys: AtomicU64,
+    honeypot_initiations: AtomicU64,
+    locked_out: AtomicU64,
+    denied: AtomicU64,
+    oversized: AtomicU64,
+    truncated: AtomicU64,
+    panics: AtomicU64,
+    races_lost: AtomicU64,
+    sessions_shed: AtomicU64,
+    sessions_limited: AtomicU64,
+    unmatched_data: AtomicU64,
+    queue_dropped_handshakes: AtomicU64,
+    queue_dropped_data: AtomicU64,
+    queue_blocked: AtomicU64,
+    /// whether traffic is also counted per client, see [`Metrics::track_clients`]
+    clients: AtomicBool,
+    windows: Mutex<Windows>,
+}
+
+/// The values of all [`Metrics`] counters at one point in time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Snapshot {
+    /// datagrams read from the transport
+    pub received: u64,
+    /// packets sent on to a client or backend
+    pub forwarded: u64,
+    /// packets discarded, e.g. because they matched no session
+    pub dropped: u64,
+    pub sessions_created: u64,
+    pub sessions_expired: u64,
+    /// sessions forgotten because their backend went down or was removed, so their clients are
+    /// routed anew
+    pub sessions_purged: u64,
+    /// times a client's tunnel started rekeying far more often than every 2 minutes
+    pub excessive_rekeys: u64,
+    /// initiations matching no peer that were routed to the honeypot
+    pub honeypot_initiations: u64,
+    /// initiations of new tunnels dropped during a lockdown
+    pub locked_out: u64,
+    /// datagrams dropped as their source is denylisted
+    pub denied: u64,
+    /// datagrams dropped as they are longer than the maximum datagram size
+    pub oversized: u64,
+    /// datagrams dropped as they didn't fit the receive buffer, so only their start was read
+    pub truncated: u64,
+    /// datagrams dropped as handling them panicked
+    pub panics: u64,
+    /// handshake responses dropped as another backend the initiation was raced to responded first
+    pub races_lost: u64,
+    /// sessions evicted to keep the router within its memory limit
+    pub sessions_shed: u64,
+    /// initiations dropped because the router or their backend reached its session limit
+    pub sessions_limited: u64,
+    /// transport data whose receiver index matched no session, whether dropped or forwarded
+    pub unmatched_data: u64,
+    /// handshake messages dropped under backpressure
+    pub queue_dropped_handshakes: u64,
+    /// transport data dropped under backpressure
+    pub queue_dropped_data: u64,
+    /// times reading ahead stopped at a full batch, leaving datagrams in the socket buffer
+    pub queue_blocked: u64,
+}
+
+/// The sizes of the datagrams received, in the form of a Prometheus histogram
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// each of [`SIZE_BUCKETS`] with the datagrams at most that large
+    pub buckets: Vec<(usize, u64)>,
+    /// all datagrams, including those larger than the last bucket
+    pub count: u64,
+    /// their bytes
+    pub sum: u64,
+}
+
+/// Recent throughput, averaged over the last [`RATE_WINDOW`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rates {
+    /// initiations forwarded to backends
+    pub handshakes_per_min: f64,
+    /// packets forwarded in either direction
+    pub packets_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+/// Everything forwarded to and from a backend since the counters started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Totals {
+    /// initiations forwarded to the backend
+    pub handshakes: u64,
+    /// of those, the ones the backend answered
+    pub answered: u64,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// The cumulative counters of [`Metrics`], to carry them over a restart with [`Metrics::restore`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Checkpoint {
+    pub counters: Snapshot,
+    pub backends: HashMap<SocketAddr, Totals>,
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    total: Traffic,
+    backends: HashMap<SocketAddr, Traffic>,
+    /// unlike the windows, these are never pruned
+    totals: HashMap<SocketAddr, Totals>,
+    /// moving average of the time from forwarding an initiation to the backend's response
+    rtts: HashMap<SocketAddr, Duration>,
+    /// responses routed from the backend, to tell how many of its initiations went unanswered
+    responses: HashMap<SocketAddr, Window>,
+    /// sends to the backend that failed for good
+    send_failures: HashMap<SocketAddr, u64>,
+    /// by client address, only if tracked
+    clients: HashMap<IpAddr, Traffic>,
+    /// the configured names of the backends, to break metrics down by peer
+    names: HashMap<SocketAddr, String>,
+}
+
+/// What was forwarded to and from a backend, or all of them
+#[derive(Debug, Default)]
+struct Traffic {
+    handshakes: Window,
+    packets: Window,
+    bytes: Window,
+}
+
+impl Traffic {
+    fn add(&mut self, now: Instant, handshake: bool, bytes: usize) {
+        self.handshakes.add(now, u64::from(handshake));
+        self.packets.add(now, 1);
+        self.bytes.add(now, bytes as u64);
+    }
+
+    fn rates(&mut self, now: Instant) -> Rates {
+        let window = RATE_WINDOW.as_secs_f64();
+        Rates {
+            handshakes_per_min: self.handshakes.sum(now) as f64 * 60.0 / window,
+            packets_per_sec: self.packets.sum(now) as f64 / window,
+            bytes_per_sec: self.bytes.sum(now) as f64 / window,
+        }
+    }
+}
+
+/// Counts of the last [`RATE_WINDOW`] in one second buckets, the oldest being dropped as time passes
+#[derive(Debug)]
+struct Window {
+    epoch: Instant,
+    /// seconds since `epoch` of the newest bucket
+    second: u64,
+    buckets: [u64; BUCKETS],
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window {
+            epoch: Instant::now(),
+            second: 0,
+            buckets: [0; BUCKETS],
+        }
+    }
+}
+
+impl Window {
+    fn add(&mut self, now: Instant, count: u64) {
+        self.advance(now);
+        self.buckets[self.second as usize % BUCKETS] += count;
+    }
+
+    fn sum(&mut self, now: Instant) -> u64 {
+        self.advance(now);
+        self.buckets.iter().sum()
+    }
+
+    /// Clears the buckets of the seconds that passed since the newest one
+    fn advance(&mut self, now: Instant) {
+        let second = now.saturating_duration_since(self.epoch).as_secs();
+        if second <= self.second {
+            return;
+        }
+        if second - self.second >= BUCKETS as u64 {
+            self.buckets = [0; BUCKETS];
+        } else {
+            for passed in self.second + 1..=second {
+                self.buckets[passed as usize % BUCKETS] = 0;
+            }
+        }
+        self.second = second;
+    }
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            received: self.received.load(Ordering::Relaxed),
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            sessions_created: self.sessions_created.load(Ordering::Relaxed),
+            sessions_expired: self.sessions_expired.load(Ordering::Relaxed),
+            sessions_purged: self.sessions_purged.load(Ordering::Relaxed),
+            excessive_rekeys: self.excessive_rekeys.load(Ordering::Relaxed),
+            honeypot_initiations: self.honeypot_initiations.load(Ordering::Relaxed),
+            locked_out: self.locked_out.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+            truncated: self.truncated.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            races_lost: self.races_lost.load(Ordering::Relaxed),
+            sessions_shed: self.sessions_shed.load(Ordering::Relaxed),
+            sessions_limited: self.sessions_limited.load(Ordering::Relaxed),
+            unmatched_data: self.unmatched_data.load(Ordering::Relaxed),
+            queue_dropped_handshakes: self.queue_dropped_handshakes.load(Ordering::Relaxed),
+            queue_dropped_data: self.queue_dropped_data.load(Ordering::Relaxed),
+            queue_blocked: self.queue_blocked.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the size of a received datagram, once it is unwrapped from a relay's header
+    pub(crate) fn received_size(&self, size: usize) {
+        let bucket = SIZE_BUCKETS.partition_point(|bound| *bound < size);
+        self.received_sizes[bucket].fetch_add(1, Ordering::Relaxed);
+        self.received_bytes
+            .fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received_message(&self, message: MessageType) {
+        let index = MESSAGE_TYPES.iter().position(|m| *m == message);
+        if let Some(index) = index {
+            self.received_types[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Datagrams received that parsed as WireGuard messages, by their type
+    pub fn received_by_type(&self) -> [(MessageType, u64); 4] {
+        std::array::from_fn(|i| {
+            let count = self.received_types[i].load(Ordering::Relaxed);
+            (MESSAGE_TYPES[i], count)
+        })
+    }
+
+    /// The sizes of all datagrams received, whether they were WireGuard messages or not
+    pub fn received_sizes(&self) -> SizeHistogram {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(SIZE_BUCKETS.len());
+        for (bound, counter) in SIZE_BUCKETS.iter().zip(&self.received_sizes) {
+            count += counter.load(Ordering::Relaxed);
+            buckets.push((*bound, count));
+        }
+        count += self.received_sizes[SIZE_BUCKETS.len()].load(Ordering::Relaxed);
+        SizeHistogram {
+            buckets,
+            count,
+            sum: self.received_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Rates of all traffic the router forwarded
+    pub fn rates(&self) -> Rates {
+        self.windows.lock().unwrap().total.rates(Instant::now())
+    }
+
+    /// Rates of the traffic forwarded to and from each backend within the last [`RATE_WINDOW`]
+    pub fn backend_rates(&self) -> HashMap<SocketAddr, Rates> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // backends are forgotten once their traffic left the window
+        windows
+            .backends
+            .retain(|_, traffic| traffic.packets.sum(now) > 0);
+        windows
+            .backends
+            .iter_mut()
+            .map(|(backend, traffic)| (*backend, traffic.rates(now)))
+            .collect()
+    }
+
+    /// Also keeps the rates of each client address, which takes memory for every active client
+    pub fn track_clients(&self) {
+        self.clients.store(true, Ordering::Relaxed);
+    }
+
+    /// Rates of the traffic forwarded to and from each client address within the last
+    /// [`RATE_WINDOW`], if [tracked](Self::track_clients)
+    pub fn client_rates(&self) -> HashMap<IpAddr, Rates> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        windows
+            .clients
+            .retain(|_, traffic| traffic.packets.sum(now) > 0);
+        windows
+            .clients
+            .iter_mut()
+            .map(|(client, traffic)| (*client, traffic.rates(now)))
+            .collect()
+    }
+
+    /// The configured names of the backends that have one
+    pub fn peer_names(&self) -> HashMap<SocketAddr, String> {
+        self.windows.lock().unwrap().names.clone()
+    }
+
+    pub(crate) fn set_peer_names(&self, names: HashMap<SocketAddr, String>) {
+        self.windows.lock().unwrap().names = names;
+    }
+
+    /// Traffic forwarded to and from each backend ever seen
+    pub fn backend_totals(&self) -> HashMap<SocketAddr, Totals> {
+        self.windows.lock().unwrap().totals.clone()
+    }
+
+    /// The cumulative counters, which unlike rates are worth keeping over a restart
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            counters: self.snapshot(),
+            backends: self.backend_totals(),
+        }
+    }
+
+    /// Adds the counters of `checkpoint`, e.g. taken before the last restart
+    pub fn restore(&self, checkpoint: &Checkpoint) {
+        let counters = &checkpoint.counters;
+        for (counter, value) in [
+            (&self.received, counters.received),
+            (&self.forwarded, counters.forwarded),
+            (&self.dropped, counters.dropped),
+            (&self.sessions_created, counters.sessions_created),
+            (&self.sessions_expired, counters.sessions_expired),
+            (&self.sessions_purged, counters.sessions_purged),
+            (&self.excessive_rekeys, counters.excessive_rekeys),
+            (&self.honeypot_initiations, counters.honeypot_initiations),
+            (&self.locked_out, counters.locked_out),
+            (&self.denied, counters.denied),
+            (&self.oversized, counters.oversized),
+            (&self.truncated, counters.truncated),
+            (&self.panics, counters.panics),
+            (&self.races_lost, counters.races_lost),
+            (&self.sessions_shed, counters.sessions_shed),
+            (&self.sessions_limited, counters.sessions_limited),
+            (&self.unmatched_data, counters.unmatched_data),
+            (
+                &self.queue_dropped_handshakes,
+                counters.queue_dropped_handshakes,
+            ),
+            (&self.queue_dropped_data, counters.queue_dropped_data),
+            (&self.queue_blocked, counters.queue_blocked),
+        ] {
+            counter.fetch_add(value, Ordering::Relaxed);
+        }
+        let mut windows = self.windows.lock().unwrap();
+        for (backend, restored) in &checkpoint.backends {
+            let totals = windows.totals.entry(*backend).or_default();
+            totals.handshakes += restored.handshakes;
+            totals.answered += restored.answered;
+            totals.packets += restored.packets;
+            totals.bytes += restored.bytes;
+        }
+    }
+
+    /// Recent handshake round trip times of the backends that answered an initiation
+    ///
+    /// These are measured passively, from forwarding an initiation to routing its response, so
+    /// they include the backend's processing.
+    pub fn handshake_rtts(&self) -> HashMap<SocketAddr, Duration> {
+        self.windows.lock().unwrap().rtts.clone()
+    }
+
+    /// Estimated share of the initiations forwarded to each backend over the last [`RATE_WINDOW`]
+    /// that were never answered
+    ///
+    /// This correlates initiations with the responses routed back, without probing. A backend
+    /// close to 1 is down or unreachable, while a slow one still answers and shows in
+    /// [`Metrics::handshake_rtts`] instead. Initiations forwarded within the last round trip are
+    /// still awaiting their response, so few handshakes overstate the loss. Backends without
+    /// initiations in the window are left out.
+    pub fn handshake_loss(&self) -> HashMap<SocketAddr, f64> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let Windows {
+            backends,
+            responses,
+            ..
+        } = &mut *windows;
+        backends
+            .iter_mut()
+            .filter_map(|(backend, traffic)| {
+                let sent = traffic.handshakes.sum(now);
+                let answered = responses.get_mut(backend).map_or(0, |r| r.sum(now));
+                (sent > 0).then(|| (*backend, 1.0 - answered.min(sent) as f64 / sent as f64))
+            })
+            .collect()
+    }
+
+    pub(crate) fn handshake_rtt(&self, backend: SocketAddr, rtt: Duration) {
+        let mut windows = self.windows.lock().unwrap();
+        windows
+            .responses
+            .entry(backend)
+            .or_default()
+            .add(Instant::now(), 1);
+        windows.totals.entry(backend).or_default().answered += 1;
+        windows
+            .rtts
+            .entry(backend)
+            .and_modify(|average| {
+                *average = average.mul_f64(1.0 - RTT_WEIGHT) + rtt.mul_f64(RTT_WEIGHT)
+            })
+            .or_insert(rtt);
+    }
+
+    /// Counts a `message` of `bytes` forwarded to or from `backend`
+    pub(crate) fn forwarded(
+        &self,
+        backend: SocketAddr,
+        client: IpAddr,
+        message: MessageType,
+        bytes: usize,
+    ) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let handshake = message == MessageType::HandshakeInitiation;
+        let mut windows = self.windows.lock().unwrap();
+        windows.total.add(now, handshake, bytes);
+        if self.clients.load(Ordering::Relaxed) {
+            windows
+                .clients
+                .entry(client)
+                .or_default()
+                .add(now, handshake, bytes);
+        }
+        windows
+            .backends
+            .entry(backend)
+            .or_default()
+            .add(now, handshake, bytes);
+        let totals = windows.totals.entry(backend).or_default();
+        totals.handshakes += u64::from(handshake);
+        totals.packets += 1;
+        totals.bytes += bytes as u64;
+    }
+
+    /// Sends to each backend that failed with an error that was not transient
+    pub fn backend_send_failures(&self) -> HashMap<SocketAddr, u64> {
+        self.windows.lock().unwrap().send_failures.clone()
+    }
+
+    pub(crate) fn send_failed(&self, backend: SocketAddr) {
+        let mut windows = self.windows.lock().unwrap();
+        *windows.send_failures.entry(backend).or_default() += 1;
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn session_created(&self) {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sessions_expired(&self, count: u64) {
+        self.sessions_expired.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sessions_purged(&self, count: u64) {
+        self.sessions_purged.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn excessive_rekey(&self) {
+        self.excessive_rekeys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn honeypot_initiation(&self) {
+        self.honeypot_initiations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn locked_out(&self) {
+        self.locked_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn denied(&self) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn oversized(&self) {
+        self.oversized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn truncated(&self) {
+        self.truncated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn panicked(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sessions_shed(&self, count: u64) {
+        self.sessions_shed.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn race_lost(&self) {
+        self.races_lost.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn session_limited(&self) {
+        self.sessions_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn unmatched_data(&self) {
+        self.unmatched_data.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a datagram dropped under backpressure, `bulk` if it is transport data
+    pub(crate) fn queue_dropped(&self, bulk: bool) {
+        let counter = match bulk {
+            true => &self.queue_dropped_data,
+            false => &self.queue_dropped_handshakes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn queue_blocked(&self) {
+        self.queue_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+}
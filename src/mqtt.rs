@@ -0,0 +1,273 @@
+/*
+* mqtt.rs publishes periodic stats and backend health transitions to an MQTT broker, for edge
+* deployments whose telemetry goes through one
+*/
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+use crate::Peer;
+use crate::error::Error;
+use crate::event::RouterEvent;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::router::SessionTable;
+
+/// Publishes waiting for the connection, further ones are dropped rather than wait for the broker
+const QUEUE: usize = 64;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// `mqtt://host:port`, or `mqtts://host:port` for TLS with the system's roots
+    pub broker: String,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// topics are published under it, e.g. `site-1/wireguard-router`
+    pub topic_prefix: String,
+    /// seconds between stats
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_client_id() -> String {
+    "wireguard-router".to_string()
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+impl Config {
+    /// The connection options, failing on a broker address that can't be connected to
+    pub fn options(&self) -> Result<MqttOptions, Error> {
+        let invalid =
+            |reason: &str| Error::InvalidConfig(format!("mqtt broker {:?}: {reason}", self.broker));
+        let (scheme, address) = self
+            .broker
+            .split_once("://")
+            .ok_or_else(|| invalid("expected mqtt:// or mqtts://"))?;
+        let (transport, default_port) = match scheme {
+            "mqtt" => (Transport::Tcp, 1883),
+            "mqtts" => (Transport::tls_with_config(tls_config()?.into()), 8883),
+            _ => return Err(invalid("expected mqtt:// or mqtts://")),
+        };
+        let address = address.trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse().map_err(|_| invalid("invalid port"))?;
+                (host, port)
+            }
+            _ => (address, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+        if self.topic_prefix.is_empty() || self.topic_prefix.contains(['#', '+']) {
+            return Err(Error::InvalidConfig(
+                "mqtt topic_prefix must be a non-empty topic without wildcards".to_string(),
+            ));
+        }
+
+        let mut options = MqttOptions::new(&self.client_id, host, port);
+        options.set_transport(transport);
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.clone().unwrap_or_default());
+        }
+        // subscribers tell a router that went away from one that stopped publishing
+        options.set_last_will(LastWill::new(
+            self.topic("status"),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        Ok(options)
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}/{}", self.topic_prefix.trim_end_matches('/'), suffix)
+    }
+}
+
+/// TLS with the system's roots and ring, set explicitly as rustls can't choose a default provider
+/// once other features compile in another one
+fn tls_config() -> Result<rustls::ClientConfig, Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) =
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    if added == 0 {
+        tracing::warn!("no system root certificates found for the mqtt broker");
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::InvalidConfig(format!("mqtt TLS config: {e}")))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(config)
+}
+
+/// What is published on
+pub struct Sources {
+    pub metrics: Arc<Metrics>,
+    pub sessions: SessionTable,
+    pub health: Arc<Health>,
+    pub peers: watch::Receiver<Vec<Peer>>,
+    pub events: broadcast::Receiver<RouterEvent>,
+}
+
+/// The payload of `<topic_prefix>/stats`, retained so new subscribers see the latest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub received: u64,
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub sessions_created: u64,
+    /// sessions clients initiated that are still tracked
+    pub sessions: usize,
+    pub backends: usize,
+    pub backends_down: usize,
+}
+
+impl Stats {
+    pub async fn collect(sources: &Sources) -> Stats {
+        let counters = sources.metrics.snapshot();
+        let backends = sources.peers.borrow().len();
+        Stats {
+            timestamp_ms: now_ms(),
+            received: counters.received,
+            forwarded: counters.forwarded,
+            dropped: counters.dropped,
+            sessions_created: counters.sessions_created,
+            sessions: sources.sessions.count().await,
+            backends,
+            backends_down: sources.health.down().len(),
+        }
+    }
+}
+
+/// The payload of `<topic_prefix>/backends/<address>`, retained so it holds the backend's state
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Transition {
+    pub timestamp_ms: u64,
+    pub backend: std::net::SocketAddr,
+    /// `up` or `down`
+    pub state: &'static str,
+}
+
+impl Transition {
+    /// The transition `event` reports, if it is a change of a backend's health
+    pub fn from_event(event: &RouterEvent, timestamp_ms: u64) -> Option<Transition> {
+        let (backend, state) = match event {
+            RouterEvent::BackendDown { backend } => (*backend, "down"),
+            RouterEvent::BackendUp { backend } => (*backend, "up"),
+            _ => return None,
+        };
+        Some(Transition {
+            timestamp_ms,
+            backend,
+            state,
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Publishes to the broker of `config`, connecting with its [`options`](Config::options), until
+/// the router stops
+///
+/// - `<topic_prefix>/status`: `online` once connected, `offline` by the broker once the
+///   connection is lost, retained
+/// - `<topic_prefix>/stats`: a [`Stats`] every `interval_secs`, retained
+/// - `<topic_prefix>/backends/<address>`: a [`Transition`] whenever a backend goes down or comes
+///   back up, retained and published at least once
+///
+/// The connection is kept up in the background and reestablished after failures, while
+/// publishes made in the meantime are dropped once 64 are waiting.
+pub async fn publish(config: Config, options: MqttOptions, sources: Sources) {
+    let (client, mut eventloop) = AsyncClient::new(options, QUEUE);
+    let status = config.topic("status");
+    let connection = {
+        let client = client.clone();
+        let status = status.clone();
+        tokio::spawn(async move {
+            let mut failing = false;
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        if failing {
+                            tracing::info!("connected to the MQTT broker again");
+                        }
+                        failing = false;
+                        let _ = client.try_publish(&status, QoS::AtLeastOnce, true, "online");
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+                    Ok(_) => {}
+                    Err(e) => {
+                        if !failing {
+                            tracing::warn!("lost the connection to the MQTT broker: {}", e);
+                        }
+                        failing = true;
+                        // polling again reconnects, not in a busy loop while the broker is down
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        })
+    };
+
+    let mut sources = sources;
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut dropping = false;
+    loop {
+        let (topic, payload, qos) = tokio::select! {
+            event = sources.events.recv() => match event {
+                Ok(event) => match Transition::from_event(&event, now_ms()) {
+                    Some(transition) => (
+                        config.topic(&format!("backends/{}", transition.backend)),
+                        serde_json::to_vec(&transition),
+                        QoS::AtLeastOnce,
+                    ),
+                    None => continue,
+                },
+                // transitions are rare, the stats still catch up on missed ones
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            _ = ticks.tick() => {
+                let stats = Stats::collect(&sources).await;
+                (config.topic("stats"), serde_json::to_vec(&stats), QoS::AtMostOnce)
+            }
+        };
+        let Ok(payload) = payload else {
+            continue;
+        };
+        match client.try_publish(topic, qos, true, payload) {
+            Ok(()) => dropping = false,
+            Err(e) if !dropping => {
+                dropping = true;
+                tracing::warn!("dropping MQTT publishes until the broker catches up: {}", e);
+            }
+            Err(_) => {}
+        }
+    }
+    // a clean disconnect doesn't trigger the last will
+    let _ = client.try_publish(&status, QoS::AtLeastOnce, true, "offline");
+    let _ = client.try_disconnect();
+    let _ = tokio::time::timeout(Duration::from_secs(5), connection).await;
+}
@@ -0,0 +1,5 @@
+/*
+* packet.rs re-exports the no_std WireGuard message parser from the wireguard-router-packet crate
+*/
+
+pub use wireguard_router_packet::*;
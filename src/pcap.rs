@@ -0,0 +1,202 @@
+/*
+* pcap.rs reads UDP datagrams from classic pcap captures
+*
+* Only what is needed to extract WireGuard traffic is supported: the classic file format
+* (not pcapng) with Ethernet, Linux cooked, BSD loopback or raw IP link types,
+* IPv4 and IPv6 without extension headers, and unfragmented UDP.
+*/
+
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// Records longer than this are refused whatever the snapshot length claims, so a corrupt
+/// capture can't make the reader allocate gigabytes
+const MAX_RECORD: u32 = 256 * 1024;
+
+/// A UDP datagram captured in a pcap file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    /// capture time since the unix epoch
+    pub timestamp: Duration,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// Iterates over the UDP datagrams of a pcap capture, skipping all other packets
+pub struct Reader<R> {
+    inner: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: u32,
+    /// the longest record the capture may hold
+    max_record: u32,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the file header, failing for anything but a classic pcap file
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        inner.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let (big_endian, nanos) = match magic {
+            0xa1b2c3d4 => (false, false),
+            0xa1b23c4d => (false, true),
+            0xd4c3b2a1 => (true, false),
+            0x4d3cb2a1 => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a pcap file, pcapng is not supported",
+                ));
+            }
+        };
+        let mut reader = Reader {
+            inner,
+            big_endian,
+            nanos,
+            link_type: 0,
+            max_record: MAX_RECORD,
+        };
+        reader.link_type = reader.u32(&header[20..24]) & 0x0fff_ffff;
+        // some writers leave the snapshot length 0
+        let snaplen = reader.u32(&header[16..20]);
+        if snaplen != 0 {
+            reader.max_record = snaplen.min(MAX_RECORD);
+        }
+        Ok(reader)
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// Reads the next record, `None` at the end of the file
+    fn record(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0u8; 16];
+        match self.inner.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let secs = self.u32(&header[0..4]) as u64;
+        let fraction = self.u32(&header[4..8]);
+        let timestamp = if self.nanos {
+            Duration::new(secs, fraction)
+        } else {
+            Duration::new(secs, 0) + Duration::from_micros(fraction as u64)
+        };
+        let len = self.u32(&header[8..12]);
+        if len > self.max_record {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "record of {len} bytes exceeds the capture's limit of {}",
+                    self.max_record
+                ),
+            ));
+        }
+        let mut data = vec![0u8; len as usize];
+        self.inner.read_exact(&mut data)?;
+        Ok(Some((timestamp, data)))
+    }
+
+    /// The network layer packet of a link layer frame
+    fn network<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        match self.link_type {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(frame),
+            LINKTYPE_NULL => frame.get(4..),
+            LINKTYPE_LINUX_SLL => frame.get(16..),
+            LINKTYPE_LINUX_SLL2 => frame.get(20..),
+            LINKTYPE_ETHERNET => {
+                let mut offset = 12;
+                // skip VLAN tags
+                while matches!(frame.get(offset..offset + 2)?, [0x81, 0x00] | [0x88, 0xa8]) {
+                    offset += 4;
+                }
+                frame.get(offset + 2..)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Extracts source, destination and payload of a UDP over IP packet
+pub(crate) fn udp(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (source, destination, segment) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = ((packet[0] & 0x0f) as usize) * 4;
+            let total_len = u16::from_be_bytes(packet.get(2..4)?.try_into().ok()?) as usize;
+            let fragmented = u16::from_be_bytes(packet.get(6..8)?.try_into().ok()?) & 0x3fff != 0;
+            if *packet.get(9)? != 17 || fragmented {
+                return None;
+            }
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv4Addr::from(source)),
+                IpAddr::from(Ipv4Addr::from(destination)),
+                packet.get(header_len..total_len.min(packet.len()))?,
+            )
+        }
+        6 => {
+            if *packet.get(6)? != 17 {
+                return None;
+            }
+            let payload_len = u16::from_be_bytes(packet.get(4..6)?.try_into().ok()?) as usize;
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv6Addr::from(source)),
+                IpAddr::from(Ipv6Addr::from(destination)),
+                packet.get(40..(40 + payload_len).min(packet.len()))?,
+            )
+        }
+        _ => return None,
+    };
+    let source_port = u16::from_be_bytes(segment.get(0..2)?.try_into().ok()?);
+    let destination_port = u16::from_be_bytes(segment.get(2..4)?.try_into().ok()?);
+    let udp_len = u16::from_be_bytes(segment.get(4..6)?.try_into().ok()?) as usize;
+    Some((
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(destination, destination_port),
+        segment.get(8..udp_len.clamp(8, segment.len()))?,
+    ))
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Datagram>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (timestamp, frame) = match self.record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            let Some((source, destination, payload)) = self.network(&frame).and_then(udp) else {
+                continue;
+            };
+            return Some(Ok(Datagram {
+                timestamp,
+                source,
+                destination,
+                payload: payload.to_vec(),
+            }));
+        }
+    }
+}
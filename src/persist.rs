@@ -0,0 +1,127 @@
+/*
+* persist.rs checkpoints state to disk that should survive restarts, like the counters and client affinity
+*/
+
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use wireguard_router::error::{Error, Report};
+
+/// State written to `path` every `interval`
+pub struct Checkpoint {
+    pub path: PathBuf,
+    pub interval: Duration,
+    /// serializes the state at the time of the checkpoint
+    pub state: Box<dyn Fn() -> serde_json::Result<Vec<u8>> + Send>,
+}
+
+impl Checkpoint {
+    pub fn new<T: Serialize>(
+        path: PathBuf,
+        interval: Duration,
+        state: impl Fn() -> T + Send + 'static,
+    ) -> Self {
+        Checkpoint {
+            path,
+            interval: interval.max(Duration::from_secs(1)),
+            state: Box::new(move || serde_json::to_vec(&state())),
+        }
+    }
+
+    fn save(&self) {
+        let written = (self.state)()
+            .map_err(io::Error::other)
+            .and_then(|json| write(&self.path, &json));
+        if let Err(source) = written {
+            let err = Error::WriteFile {
+                path: self.path.clone(),
+                source,
+            };
+            tracing::error!("{}", Report(&err));
+        }
+    }
+}
+
+/// The state last checkpointed at `path`, or `None` before the first checkpoint
+///
+/// An unreadable checkpoint fails startup rather than silently starting over.
+pub fn restore<T: DeserializeOwned>(path: &Path, what: &str) -> Result<Option<T>, Error> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            tracing::info!("no {} checkpointed at {} yet", what, path.display());
+            return Ok(None);
+        }
+        Err(source) => {
+            return Err(Error::ReadFile {
+                path: path.to_path_buf(),
+                source,
+            });
+        }
+    };
+    let state = serde_json::from_str(&contents).map_err(|e| {
+        Error::InvalidInput(format!("{} checkpoint {}: {}", what, path.display(), e))
+    })?;
+    tracing::info!("restored {} from {}", what, path.display());
+    Ok(Some(state))
+}
+
+/// Writes every checkpoint at its interval, and all of them a last time on SIGTERM or Ctrl-C before exiting
+pub async fn run(checkpoints: Vec<Checkpoint>) {
+    let mut due: Vec<Instant> = checkpoints
+        .iter()
+        .map(|checkpoint| Instant::now() + checkpoint.interval)
+        .collect();
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    // created once, as signals arriving between two listeners would be lost
+    let terminated = terminated();
+    tokio::pin!(terminated);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let now = Instant::now();
+                for (checkpoint, due) in checkpoints.iter().zip(&mut due) {
+                    if *due <= now {
+                        checkpoint.save();
+                        *due = now + checkpoint.interval;
+                    }
+                }
+            }
+            _ = &mut terminated => {
+                checkpoints.iter().for_each(Checkpoint::save);
+                std::process::exit(0);
+            }
+        }
+    }
+}
+
+/// Replaces the file at `path` atomically, so a crash mid-write leaves the previous checkpoint
+fn write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+}
+
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,178 @@
+/*
+* policy.rs lets embedders customize which backend a session is routed to
+*/
+
+use std::net::SocketAddr;
+#[cfg(feature = "runtime")]
+use std::sync::Arc;
+
+#[cfg(feature = "runtime")]
+use rand_core::{OsRng, RngCore};
+
+use crate::Peer;
+#[cfg(feature = "runtime")]
+use crate::metrics::Metrics;
+use crate::packet::Identity;
+use crate::packet::MessageType;
+
+#[cfg(feature = "lua")]
+pub mod lua;
+#[cfg(feature = "wasm-plugin")]
+pub mod wasm;
+
+/// A handshake initiation that did not belong to a known session yet
+pub struct Initiation<'a> {
+    /// the client the initiation came from
+    pub source: SocketAddr,
+    /// the sender index chosen by the client
+    pub sender: Identity,
+    pub packet: &'a [u8],
+    /// the number of sessions clients initiated with a candidate, counted in the session table
+    /// before the policy was called, 0 for other backends
+    pub backend_sessions: &'a (dyn Fn(SocketAddr) -> usize + Sync),
+}
+
+/// A packet about to be forwarded
+pub struct Forward<'a> {
+    pub message: MessageType,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    /// the sender index for handshake initiations and responses, the receiver index otherwise
+    pub identity: Identity,
+    pub packet: &'a [u8],
+}
+
+/// A change in the lifecycle of a session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// an initiation was routed to a backend
+    Created {
+        client: SocketAddr,
+        backend: SocketAddr,
+        /// the sender index chosen by the client
+        client_index: Identity,
+    },
+    /// the backend answered with a handshake response
+    Established {
+        client: SocketAddr,
+        backend: SocketAddr,
+        client_index: Identity,
+        /// the sender index chosen by the backend
+        backend_index: Identity,
+    },
+}
+
+/// The outcome of [`RoutingPolicy::check_forward`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Forward,
+    /// forward the packet, attaching a note to the router's log output
+    Annotate(String),
+    /// drop the packet for the given reason
+    Drop(String),
+}
+
+/// Decides where new sessions go and whether packets may be forwarded.
+///
+/// The router only consults the policy after it did the protocol work: a policy never
+/// sees initiations whose mac1 matches no peer, or packets for unknown sessions.
+pub trait RoutingPolicy: Send + Sync {
+    /// Picks the backend for a new session among the peers whose mac1 matched the initiation.
+    ///
+    /// `candidates` is never empty and in config order. Returning `None` drops the initiation.
+    fn select<'a>(&self, initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer>;
+
+    /// Whether [`select`](Self::select) may take long enough, e.g. to run a script, that the
+    /// router calls it on a blocking thread rather than holding up the packet loop
+    fn blocks(&self) -> bool {
+        false
+    }
+
+    /// Vetoes or annotates a packet about to be forwarded
+    fn check_forward(&self, _forward: &Forward) -> Verdict {
+        Verdict::Forward
+    }
+
+    /// Observes session lifecycle changes
+    fn on_session(&self, _event: &SessionEvent) {}
+}
+
+/// Routes every session to the first matching peer and forwards everything
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirstMatch;
+
+impl RoutingPolicy for FirstMatch {
+    fn select<'a>(&self, _initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        candidates.first().copied()
+    }
+}
+
+/// Routes sessions to the backend with the fewest sessions, the first in config order among equals
+///
+/// Unlike taking turns, this keeps backends balanced when some sessions last much longer than others.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeastSessions;
+
+impl RoutingPolicy for LeastSessions {
+    fn select<'a>(&self, initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        if candidates.len() < 2 {
+            return candidates.first().copied();
+        }
+        candidates
+            .iter()
+            .min_by_key(|peer| (initiation.backend_sessions)(peer.address))
+            .copied()
+    }
+}
+
+/// Routes sessions to the backend with the lowest recent handshake RTT, see [`Metrics::handshake_rtts`]
+///
+/// Backends without a measurement yet are preferred, so every backend gets measured. To keep
+/// backends of similar latency from all sessions converging on one of them, each RTT is scaled
+/// by a random factor between 1 and 1 + `jitter` for every selection.
+#[cfg(feature = "runtime")]
+#[derive(Clone, Debug)]
+pub struct LowestLatency {
+    metrics: Arc<Metrics>,
+    jitter: f64,
+}
+
+#[cfg(feature = "runtime")]
+impl LowestLatency {
+    /// The jitter of [`new`](Self::new), spreading sessions among backends within 20% of the fastest
+    pub const DEFAULT_JITTER: f64 = 0.2;
+
+    /// Selects by the RTTs measured into `metrics`, which must be the router's
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        LowestLatency {
+            metrics,
+            jitter: Self::DEFAULT_JITTER,
+        }
+    }
+
+    pub fn with_jitter(self, jitter: f64) -> Self {
+        LowestLatency {
+            jitter: jitter.max(0.0),
+            ..self
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl RoutingPolicy for LowestLatency {
+    fn select<'a>(&self, _initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        if candidates.len() < 2 {
+            return candidates.first().copied();
+        }
+        let rtts = self.metrics.handshake_rtts();
+        candidates
+            .iter()
+            .map(|peer| {
+                let rtt = rtts.get(&peer.address).map_or(0.0, |rtt| rtt.as_secs_f64());
+                let factor = 1.0 + self.jitter * (OsRng.next_u32() as f64 / u32::MAX as f64);
+                (rtt * factor, *peer)
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, peer)| peer)
+    }
+}
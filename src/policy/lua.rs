@@ -0,0 +1,292 @@
+/*
+* lua.rs invokes operator supplied Lua hooks on handshake routing and session lifecycle events
+*
+* The script may define any of these global functions:
+*
+*   on_initiation(info)
+*     info = { source = "ip:port", sender = <u32>, candidates = { { address, pubkey, proxy, sessions }, ... } }
+*     sessions is the number of live sessions with the candidate in the router's session table
+*     return nil to keep the default choice, false to drop the initiation,
+*     or the (1-based) index of the candidate to route to
+*
+*   on_session_created(event)      event = { client, backend, client_index }
+*   on_session_established(event)  event = { client, backend, client_index, backend_index }
+*
+* Every hook call gets a budget of wall time, hooks running longer are aborted with an error.
+*
+* Scripts can log through log.trace/debug/info/warn/error(msg) and read the policy's counters
+* with counters(), which returns { initiations, dropped, sessions_created, sessions_established,
+* backend_sessions_created = { [backend] = count } }. Counters are cumulative, sessions that
+* ended are still counted.
+*/
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use mlua::{Function, HookTriggers, Lua, Table, Value};
+
+use super::{FirstMatch, Initiation, RoutingPolicy, SessionEvent};
+use crate::Peer;
+
+#[derive(Default)]
+struct Counters {
+    initiations: u64,
+    dropped: u64,
+    sessions_created: u64,
+    sessions_established: u64,
+    /// sessions created per backend
+    backend_sessions_created: HashMap<SocketAddr, u64>,
+}
+
+/// Instructions run between checks of the time budget
+const BUDGET_CHECK_INSTRUCTIONS: u32 = 1000;
+
+/// When the running hook call runs out of its budget, kept as the app data of the Lua state
+struct Deadline(Option<Instant>);
+
+/// A [`RoutingPolicy`] delegating to hooks defined in a Lua script.
+///
+/// Every hook call gets `budget` of wall time. Hooks that are not defined, that raise an error or
+/// that run out of time fall back to the [`FirstMatch`] behavior.
+pub struct LuaPolicy {
+    lua: Mutex<Lua>,
+    counters: Arc<Mutex<Counters>>,
+    budget: Duration,
+}
+
+impl LuaPolicy {
+    pub fn load(path: &Path, budget: Duration) -> mlua::Result<Self> {
+        let source = std::fs::read_to_string(path).map_err(mlua::Error::external)?;
+        Self::from_source(&source, &path.display().to_string(), budget)
+    }
+
+    pub fn from_source(source: &str, name: &str, budget: Duration) -> mlua::Result<Self> {
+        let lua = Lua::new();
+        let counters: Arc<Mutex<Counters>> = Default::default();
+
+        lua.set_app_data(Deadline(None));
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(BUDGET_CHECK_INSTRUCTIONS),
+            |lua, _| match lua
+                .app_data_ref::<Deadline>()
+                .and_then(|deadline| deadline.0)
+            {
+                Some(deadline) if Instant::now() > deadline => {
+                    Err(mlua::Error::runtime("hook ran out of its time budget"))
+                }
+                _ => Ok(()),
+            },
+        );
+
+        let log = lua.create_table()?;
+        log.set(
+            "trace",
+            lua.create_function(|_, msg: String| {
+                tracing::trace!(target: "lua", "{}", msg);
+                Ok(())
+            })?,
+        )?;
+        log.set(
+            "debug",
+            lua.create_function(|_, msg: String| {
+                tracing::debug!(target: "lua", "{}", msg);
+                Ok(())
+            })?,
+        )?;
+        log.set(
+            "info",
+            lua.create_function(|_, msg: String| {
+                tracing::info!(target: "lua", "{}", msg);
+                Ok(())
+            })?,
+        )?;
+        log.set(
+            "warn",
+            lua.create_function(|_, msg: String| {
+                tracing::warn!(target: "lua", "{}", msg);
+                Ok(())
+            })?,
+        )?;
+        log.set(
+            "error",
+            lua.create_function(|_, msg: String| {
+                tracing::error!(target: "lua", "{}", msg);
+                Ok(())
+            })?,
+        )?;
+        lua.globals().set("log", log)?;
+
+        let shared = counters.clone();
+        lua.globals().set(
+            "counters",
+            lua.create_function(move |lua, ()| {
+                let counters = shared.lock().unwrap();
+                let table = lua.create_table()?;
+                table.set("initiations", counters.initiations)?;
+                table.set("dropped", counters.dropped)?;
+                table.set("sessions_created", counters.sessions_created)?;
+                table.set("sessions_established", counters.sessions_established)?;
+                let created = lua.create_table()?;
+                for (backend, count) in &counters.backend_sessions_created {
+                    created.set(backend.to_string(), *count)?;
+                }
+                table.set("backend_sessions_created", created)?;
+                Ok(table)
+            })?,
+        )?;
+
+        lua.load(source).set_name(name).exec()?;
+
+        Ok(LuaPolicy {
+            lua: Mutex::new(lua),
+            counters,
+            budget,
+        })
+    }
+
+    /// Calls the global hook `name` within the budget, returning `None` if the script doesn't
+    /// define it
+    fn call_hook<'lua>(
+        &self,
+        lua: &'lua Lua,
+        name: &str,
+        arg: Table<'lua>,
+    ) -> mlua::Result<Option<Value<'lua>>> {
+        let Some(hook) = lua.globals().get::<_, Option<Function>>(name)? else {
+            return Ok(None);
+        };
+        lua.set_app_data(Deadline(Some(Instant::now() + self.budget)));
+        let result = hook.call(arg).map(Some);
+        lua.set_app_data(Deadline(None));
+        result
+    }
+
+    fn initiation_info<'lua>(
+        lua: &'lua Lua,
+        initiation: &Initiation,
+        candidates: &[&Peer],
+    ) -> mlua::Result<Table<'lua>> {
+        let info = lua.create_table()?;
+        info.set("source", initiation.source.to_string())?;
+        info.set("sender", initiation.sender.as_u32())?;
+        let list = lua.create_table()?;
+        for (i, peer) in candidates.iter().enumerate() {
+            let candidate = lua.create_table()?;
+            candidate.set("address", peer.address.to_string())?;
+            candidate.set(
+                "pubkey",
+                base64::engine::general_purpose::STANDARD.encode(peer.pub_key),
+            )?;
+            candidate.set("proxy", peer.proxy.map(|p| p.to_string()))?;
+            candidate.set("sessions", (initiation.backend_sessions)(peer.address))?;
+            list.set(i + 1, candidate)?;
+        }
+        info.set("candidates", list)?;
+        Ok(info)
+    }
+}
+
+impl RoutingPolicy for LuaPolicy {
+    fn blocks(&self) -> bool {
+        true
+    }
+
+    fn select<'a>(&self, initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        self.counters.lock().unwrap().initiations += 1;
+
+        let lua = self.lua.lock().unwrap();
+        let result = Self::initiation_info(&lua, initiation, candidates)
+            .and_then(|info| self.call_hook(&lua, "on_initiation", info));
+        let selected = match result {
+            Ok(None | Some(Value::Nil)) => FirstMatch.select(initiation, candidates),
+            Ok(Some(Value::Boolean(false))) => None,
+            Ok(Some(Value::Integer(index))) => match index
+                .checked_sub(1)
+                .and_then(|i| usize::try_from(i).ok())
+                .and_then(|i| candidates.get(i))
+            {
+                Some(peer) => Some(*peer),
+                None => {
+                    tracing::warn!(
+                        "lua on_initiation selected out of range candidate {}",
+                        index
+                    );
+                    FirstMatch.select(initiation, candidates)
+                }
+            },
+            Ok(Some(other)) => {
+                tracing::warn!(
+                    "lua on_initiation returned unexpected {}, using first match",
+                    other.type_name()
+                );
+                FirstMatch.select(initiation, candidates)
+            }
+            Err(e) => {
+                tracing::warn!("lua on_initiation failed, using first match: {}", e);
+                FirstMatch.select(initiation, candidates)
+            }
+        };
+
+        if selected.is_none() {
+            self.counters.lock().unwrap().dropped += 1;
+        }
+        selected
+    }
+
+    fn on_session(&self, event: &SessionEvent) {
+        let hook = {
+            let mut counters = self.counters.lock().unwrap();
+            match event {
+                SessionEvent::Created { backend, .. } => {
+                    counters.sessions_created += 1;
+                    *counters
+                        .backend_sessions_created
+                        .entry(*backend)
+                        .or_default() += 1;
+                    "on_session_created"
+                }
+                SessionEvent::Established { .. } => {
+                    counters.sessions_established += 1;
+                    "on_session_established"
+                }
+            }
+        };
+
+        let lua = self.lua.lock().unwrap();
+        let result = session_table(&lua, event).and_then(|table| self.call_hook(&lua, hook, table));
+        if let Err(e) = result {
+            tracing::warn!("lua {} failed: {}", hook, e);
+        }
+    }
+}
+
+fn session_table<'lua>(lua: &'lua Lua, event: &SessionEvent) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    match event {
+        SessionEvent::Created {
+            client,
+            backend,
+            client_index,
+        } => {
+            table.set("client", client.to_string())?;
+            table.set("backend", backend.to_string())?;
+            table.set("client_index", client_index.as_u32())?;
+        }
+        SessionEvent::Established {
+            client,
+            backend,
+            client_index,
+            backend_index,
+        } => {
+            table.set("client", client.to_string())?;
+            table.set("backend", backend.to_string())?;
+            table.set("client_index", client_index.as_u32())?;
+            table.set("backend_index", backend_index.as_u32())?;
+        }
+    }
+    Ok(table)
+}
@@ -0,0 +1,242 @@
+/*
+* wasm.rs runs routing decisions in an operator supplied WebAssembly module
+*
+* The module may export any of the following functions, missing ones fall back to FirstMatch:
+*
+*   select(src_hi: i64, src_lo: i64, src_port: i32, sender: i32, candidates: i32) -> i32
+*     returns the index of the chosen candidate, or a negative value to drop the initiation
+*
+*   check_forward(type: i32, src_hi: i64, src_lo: i64, src_port: i32,
+*                 dst_hi: i64, dst_lo: i64, dst_port: i32, identity: i32, len: i32) -> i32
+*     returns 0 to forward the packet, anything else to drop it
+*
+* Addresses are passed as the two halves of their 128-bit v6 (or v4-mapped) representation,
+* identities as the little-endian u32 of their 4 bytes. While `select` runs, candidates can be
+* inspected through the `wg_router` imports `candidate_ip_hi(i)`, `candidate_ip_lo(i)` and
+* `candidate_port(i)`.
+*/
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+use std::time::Duration;
+
+use wasmtime::{Caller, Config, Engine, Instance, Linker, Module, Store, TypedFunc};
+
+use super::{FirstMatch, Forward, Initiation, RoutingPolicy, Verdict};
+use crate::Peer;
+
+/// how often the engine epoch advances while a call runs, the granularity of the time budget
+const EPOCH_TICK: Duration = Duration::from_millis(1);
+
+type SelectFn = TypedFunc<(i64, i64, i32, i32, i32), i32>;
+type CheckForwardFn = TypedFunc<(i32, i64, i64, i32, i64, i64, i32, i32, i32), i32>;
+
+struct Plugin {
+    store: Store<Vec<SocketAddr>>,
+    select: Option<SelectFn>,
+    check_forward: Option<CheckForwardFn>,
+}
+
+/// A [`RoutingPolicy`] evaluated by a WebAssembly module.
+///
+/// Every call gets `budget` of wall time; calls that trap or run out of time
+/// are logged and fall back to the [`FirstMatch`] behavior.
+pub struct WasmPolicy {
+    plugin: Mutex<Plugin>,
+    budget_ticks: u64,
+    ticker: Ticker,
+}
+
+/// Advances the epoch of an engine every [`EPOCH_TICK`] while a call is in flight, and is
+/// parked otherwise, so an idle policy costs no wakeups
+struct Ticker {
+    calls: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+    thread: Thread,
+}
+
+impl Ticker {
+    fn start(engine: Engine) -> Self {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (running, stopping) = (calls.clone(), stopped.clone());
+        let thread = std::thread::spawn(move || {
+            while !stopping.load(Ordering::Relaxed) {
+                if running.load(Ordering::Relaxed) == 0 {
+                    std::thread::park();
+                    continue;
+                }
+                engine.increment_epoch();
+                std::thread::sleep(EPOCH_TICK);
+            }
+        });
+        Ticker {
+            calls,
+            stopped,
+            thread: thread.thread().clone(),
+        }
+    }
+
+    /// Ticks until the returned guard is dropped
+    fn call(&self) -> Call<'_> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.thread.unpark();
+        Call(self)
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+}
+
+/// A call in flight, see [`Ticker::call`]
+struct Call<'a>(&'a Ticker);
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        self.0.calls.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl WasmPolicy {
+    pub fn load(path: &Path, budget: Duration) -> wasmtime::Result<Self> {
+        let mut config = Config::new();
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::from_file(&engine, path)?;
+
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap(
+            "wg_router",
+            "candidate_ip_hi",
+            |caller: Caller<'_, Vec<SocketAddr>>, i: i32| {
+                candidate(&caller, i).map_or(0, |addr| split_ip(addr.ip()).0)
+            },
+        )?;
+        linker.func_wrap(
+            "wg_router",
+            "candidate_ip_lo",
+            |caller: Caller<'_, Vec<SocketAddr>>, i: i32| {
+                candidate(&caller, i).map_or(0, |addr| split_ip(addr.ip()).1)
+            },
+        )?;
+        linker.func_wrap(
+            "wg_router",
+            "candidate_port",
+            |caller: Caller<'_, Vec<SocketAddr>>, i: i32| {
+                candidate(&caller, i).map_or(-1, |addr| addr.port() as i32)
+            },
+        )?;
+
+        let mut store = Store::new(&engine, Vec::new());
+        store.set_epoch_deadline(u64::MAX);
+        let instance: Instance = linker.instantiate(&mut store, &module)?;
+        let select = instance.get_typed_func(&mut store, "select").ok();
+        let check_forward = instance.get_typed_func(&mut store, "check_forward").ok();
+
+        Ok(WasmPolicy {
+            plugin: Mutex::new(Plugin {
+                store,
+                select,
+                check_forward,
+            }),
+            budget_ticks: (budget.as_millis() as u64).max(1),
+            ticker: Ticker::start(engine),
+        })
+    }
+}
+
+impl RoutingPolicy for WasmPolicy {
+    fn blocks(&self) -> bool {
+        true
+    }
+
+    fn select<'a>(&self, initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        let mut plugin = self.plugin.lock().unwrap();
+        let Some(select) = plugin.select.clone() else {
+            return FirstMatch.select(initiation, candidates);
+        };
+
+        let (src_hi, src_lo) = split_ip(initiation.source.ip());
+        let args = (
+            src_hi,
+            src_lo,
+            initiation.source.port() as i32,
+            initiation.sender.as_u32() as i32,
+            candidates.len() as i32,
+        );
+        *plugin.store.data_mut() = candidates.iter().map(|p| p.address).collect();
+        plugin.store.set_epoch_deadline(self.budget_ticks);
+        let call = self.ticker.call();
+        let result = select.call(&mut plugin.store, args);
+        drop(call);
+        match result {
+            Ok(index) if index < 0 => None,
+            Ok(index) => match candidates.get(index as usize) {
+                Some(peer) => Some(peer),
+                None => {
+                    tracing::warn!("wasm policy selected out of range candidate {}", index);
+                    FirstMatch.select(initiation, candidates)
+                }
+            },
+            Err(e) => {
+                tracing::warn!("wasm policy select failed, using first match: {:?}", e);
+                FirstMatch.select(initiation, candidates)
+            }
+        }
+    }
+
+    fn check_forward(&self, forward: &Forward) -> Verdict {
+        let mut plugin = self.plugin.lock().unwrap();
+        let Some(check_forward) = plugin.check_forward.clone() else {
+            return Verdict::Forward;
+        };
+
+        let (src_hi, src_lo) = split_ip(forward.source.ip());
+        let (dst_hi, dst_lo) = split_ip(forward.destination.ip());
+        let args = (
+            forward.message.code() as i32,
+            src_hi,
+            src_lo,
+            forward.source.port() as i32,
+            dst_hi,
+            dst_lo,
+            forward.destination.port() as i32,
+            forward.identity.as_u32() as i32,
+            forward.packet.len() as i32,
+        );
+        plugin.store.set_epoch_deadline(self.budget_ticks);
+        let call = self.ticker.call();
+        let result = check_forward.call(&mut plugin.store, args);
+        drop(call);
+        match result {
+            Ok(0) => Verdict::Forward,
+            Ok(code) => Verdict::Drop(format!("wasm policy returned {code}")),
+            Err(e) => {
+                tracing::warn!("wasm policy check_forward failed, forwarding: {:?}", e);
+                Verdict::Forward
+            }
+        }
+    }
+}
+
+fn candidate(caller: &Caller<'_, Vec<SocketAddr>>, i: i32) -> Option<SocketAddr> {
+    usize::try_from(i)
+        .ok()
+        .and_then(|i| caller.data().get(i).copied())
+}
+
+/// Splits an address into the two halves of its v6 (or v4-mapped) representation
+fn split_ip(ip: IpAddr) -> (i64, i64) {
+    let bits = match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().to_bits(),
+        IpAddr::V6(ip) => ip.to_bits(),
+    };
+    ((bits >> 64) as i64, bits as i64)
+}
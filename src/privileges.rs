@@ -0,0 +1,53 @@
+/*
+* privileges.rs drops root once the listeners are bound, so the packet path never runs as root
+*/
+
+use nix::unistd::{self, Gid, Group, Uid, User};
+use wireguard_router::error::Error;
+
+/// Switches to `user` and `group`, or the primary group of `user` if no group is given
+pub fn drop(user: Option<&str>, group: Option<&str>) -> Result<(), Error> {
+    if user.is_none() && group.is_none() {
+        if Uid::effective().is_root() {
+            tracing::warn!("running as root, consider setting user and group in the config");
+        }
+        return Ok(());
+    }
+
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?.gid,
+        None => user.as_ref().map_or_else(Gid::current, |user| user.gid),
+    };
+
+    let error = |target: String| {
+        move |e: nix::Error| Error::DropPrivileges {
+            target,
+            source: e.into(),
+        }
+    };
+    // supplementary groups go first, changing them needs the privileges dropped below
+    unistd::setgroups(&[gid]).map_err(error(format!("groups [{}]", gid)))?;
+    unistd::setgid(gid).map_err(error(format!("group {}", gid)))?;
+    if let Some(user) = user {
+        unistd::setuid(user.uid).map_err(error(format!("user {}", user.name)))?;
+        tracing::info!("running as user {} and group {}", user.name, gid);
+    } else {
+        tracing::info!("running as group {}", gid);
+    }
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<User, Error> {
+    User::from_name(name)
+        .ok()
+        .flatten()
+        .ok_or_else(|| Error::InvalidConfig(format!("unknown user {:?}", name)))
+}
+
+fn lookup_group(name: &str) -> Result<Group, Error> {
+    Group::from_name(name)
+        .ok()
+        .flatten()
+        .ok_or_else(|| Error::InvalidConfig(format!("unknown group {:?}", name)))
+}
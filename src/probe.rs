@@ -0,0 +1,190 @@
+/*
+* probe.rs checks backends from the outside, by UDP reachability and by WireGuard handshakes
+*
+* A handshake probe sends a genuine handshake initiation (Noise_IKpsk2 without preshared key)
+* from a key the backend must have configured as a peer. Any handshake response to it shows the
+* backend is up and accepts the key, the response itself is not decrypted.
+*/
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand_core::{OsRng, RngCore};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::Peer;
+use crate::packet::{HandshakeInitiation, Identity, WireguardPacket};
+use crate::utils;
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+
+/// What a probe found out about a backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// the backend answered, after the given round trip time
+    Answered(Duration),
+    /// no answer, but no ICMP error either
+    Silent,
+}
+
+/// Socket connected to `addr`, so ICMP errors are reported on it
+async fn connected(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Sends a datagram no WireGuard implementation answers and waits for an ICMP rejection
+///
+/// Fails with [`io::ErrorKind::ConnectionRefused`] if nothing listens on the port.
+pub async fn reachability(addr: SocketAddr, wait: Duration) -> io::Result<Outcome> {
+    let socket = connected(addr).await?;
+    let started = Instant::now();
+    socket.send(&[0]).await?;
+    let mut buf = [0u8; 64];
+    match timeout(wait, socket.recv(&mut buf)).await {
+        Ok(Ok(_)) => Ok(Outcome::Answered(started.elapsed())),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Ok(Outcome::Silent),
+    }
+}
+
+/// Initiates a handshake with `backend` as `local` and waits for the response
+///
+/// Fails with [`io::ErrorKind::TimedOut`] if the backend does not respond within `wait`.
+pub async fn handshake(
+    local: &StaticSecret,
+    backend: &Peer,
+    wait: Duration,
+) -> io::Result<Duration> {
+    let mut sender = [0u8; 4];
+    OsRng.fill_bytes(&mut sender);
+    let sender = Identity::from(sender);
+    let packet = initiation(local, backend, sender);
+
+    let socket = connected(backend.address).await?;
+    let started = Instant::now();
+    socket.send(&packet).await?;
+    let mut buf = [0u8; 256];
+    let deadline = started + wait;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let size = timeout(remaining, socket.recv(&mut buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no handshake response"))??;
+        if let Ok(WireguardPacket::HandshakeResponse(response)) =
+            WireguardPacket::parse(&buf[..size])
+            && response.receiver() == sender
+        {
+            return Ok(started.elapsed());
+        }
+    }
+}
+
+/// Builds a handshake initiation from `local` to `backend`, following the WireGuard paper
+pub fn initiation(
+    local: &StaticSecret,
+    backend: &Peer,
+    sender: Identity,
+) -> [u8; HandshakeInitiation::SIZE] {
+    let remote = PublicKey::from(backend.pub_key);
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+
+    let mut chain = utils::hash(CONSTRUCTION);
+    let mut hash = hash2(&chain, IDENTIFIER);
+    hash = hash2(&hash, remote.as_bytes());
+
+    chain = kdf1(&chain, ephemeral_public.as_bytes());
+    hash = hash2(&hash, ephemeral_public.as_bytes());
+
+    let (next, key) = kdf2(&chain, ephemeral.diffie_hellman(&remote).as_bytes());
+    chain = next;
+    let encrypted_static = seal(&key, PublicKey::from(local).as_bytes(), &hash);
+    hash = hash2(&hash, &encrypted_static);
+
+    let (_, key) = kdf2(&chain, local.diffie_hellman(&remote).as_bytes());
+    let encrypted_timestamp = seal(&key, &tai64n(), &hash);
+
+    let mut packet = [0u8; HandshakeInitiation::SIZE];
+    packet[0] = 0x01;
+    packet[4..8].copy_from_slice(&sender.0);
+    packet[8..40].copy_from_slice(ephemeral_public.as_bytes());
+    packet[40..88].copy_from_slice(&encrypted_static);
+    packet[88..116].copy_from_slice(&encrypted_timestamp);
+    let mac1 = utils::mac(
+        &backend.precomputed_hash_label_mac1,
+        &packet[..HandshakeInitiation::MAC1_OFFSET],
+    );
+    packet[116..132].copy_from_slice(&mac1);
+    packet
+}
+
+fn hash2(a: &[u8], b: &[u8]) -> [u8; 32] {
+    blake2s_simd::Params::new()
+        .to_state()
+        .update(a)
+        .update(b)
+        .finalize()
+        .as_array()
+        .to_owned()
+}
+
+/// HMAC-BLAKE2s
+fn hmac(key: &[u8; 32], parts: &[&[u8]]) -> [u8; 32] {
+    let mut padded = [0u8; 64];
+    padded[..32].copy_from_slice(key);
+    let mut inner = blake2s_simd::Params::new().to_state();
+    inner.update(&padded.map(|b| b ^ 0x36));
+    for part in parts {
+        inner.update(part);
+    }
+    let inner = inner.finalize();
+    hash2(&padded.map(|b| b ^ 0x5c), inner.as_bytes())
+}
+
+fn kdf1(key: &[u8; 32], input: &[u8]) -> [u8; 32] {
+    let prk = hmac(key, &[input]);
+    hmac(&prk, &[&[1]])
+}
+
+fn kdf2(key: &[u8; 32], input: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let prk = hmac(key, &[input]);
+    let first = hmac(&prk, &[&[1]]);
+    let second = hmac(&prk, &[&first, &[2]]);
+    (first, second)
+}
+
+/// ChaCha20Poly1305 with a zero nonce, every handshake key is only used once
+fn seal(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .encrypt(
+            &Nonce::from([0; 12]),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("plaintext is far below the ChaCha20Poly1305 limit")
+}
+
+fn tai64n() -> [u8; 12] {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut timestamp = [0u8; 12];
+    timestamp[..8].copy_from_slice(&(0x4000_0000_0000_000a + now.as_secs()).to_be_bytes());
+    timestamp[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    timestamp
+}
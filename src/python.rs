@@ -0,0 +1,151 @@
+/*
+* python.rs exposes peers, mac1 computation and packet parsing to Python
+*
+* Built as the `wireguard_router` extension module with maturin, see pyproject.toml:
+*
+*   peer = wireguard_router.Peer("10.0.0.1:51820", "<base64 pubkey>", name="ctf")
+*   peer.matches(packet)                     # whether the initiation is addressed to peer
+*   wireguard_router.mac(key, data)          # keyed-Blake2s, 16 bytes
+*   wireguard_router.parse(packet)           # dict of header fields, raises ValueError
+*   wireguard_router.is_wg_packet(packet)
+*/
+
+// the version the module is built against, for embedding it in an interpreter and for tests
+#[doc(hidden)]
+pub use pyo3;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::packet::{HandshakeInitiation, WireguardPacket};
+use crate::{Peer, PeerConfig, utils};
+
+/// A backend as configured in the router, see [`Peer`]
+#[pyclass(name = "Peer", frozen)]
+pub struct PyPeer(Peer);
+
+#[pymethods]
+impl PyPeer {
+    #[new]
+    #[pyo3(signature = (address, pubkey, proxy = None, name = None))]
+    fn new(
+        address: String,
+        pubkey: String,
+        proxy: Option<String>,
+        name: Option<String>,
+    ) -> PyResult<Self> {
+        Peer::try_from(PeerConfig {
+            address,
+            pubkey,
+            proxy,
+            name,
+            ..Default::default()
+        })
+        .map(PyPeer)
+        .map_err(|e| PyValueError::new_err(e.with_field()))
+    }
+
+    #[getter]
+    fn address(&self) -> String {
+        self.0.address.to_string()
+    }
+
+    #[getter]
+    fn pubkey(&self) -> String {
+        PeerConfig::from(&self.0).pubkey
+    }
+
+    #[getter]
+    fn proxy(&self) -> Option<String> {
+        self.0.proxy.map(|proxy| proxy.to_string())
+    }
+
+    #[getter]
+    fn name(&self) -> Option<String> {
+        self.0.name.clone()
+    }
+
+    /// The mac1 an initiation addressed to this peer carries
+    fn mac1<'py>(&self, py: Python<'py>, packet: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        let covered = packet
+            .get(..HandshakeInitiation::MAC1_OFFSET)
+            .ok_or_else(|| {
+                PyValueError::new_err("packet is shorter than a handshake initiation")
+            })?;
+        let mac = utils::mac(&self.0.precomputed_hash_label_mac1, covered);
+        Ok(PyBytes::new(py, &mac))
+    }
+
+    /// Whether `packet` is a handshake initiation addressed to this peer
+    fn matches(&self, packet: &[u8]) -> bool {
+        match WireguardPacket::parse(packet) {
+            Ok(WireguardPacket::HandshakeInitiation(initiation)) => self
+                .0
+                .matching_key(
+                    &packet[..HandshakeInitiation::MAC1_OFFSET],
+                    initiation.mac1(),
+                )
+                .is_some(),
+            _ => false,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Peer({})", self.0)
+    }
+}
+
+/// Keyed-Blake2s(key, data, 16), the mac function of WireGuard
+#[pyfunction]
+fn mac<'py>(py: Python<'py>, key: &[u8], data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    if key.len() > 32 {
+        return Err(PyValueError::new_err("key must be at most 32 bytes"));
+    }
+    Ok(PyBytes::new(py, &utils::mac(key, data)))
+}
+
+#[pyfunction]
+fn is_wg_packet(packet: &[u8]) -> bool {
+    crate::packet::is_wg_packet(packet)
+}
+
+/// The header fields of `packet` by name, indices as integers
+#[pyfunction]
+fn parse<'py>(py: Python<'py>, packet: &[u8]) -> PyResult<Bound<'py, PyDict>> {
+    let parsed =
+        WireguardPacket::parse(packet).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let fields = PyDict::new(py);
+    fields.set_item("type", parsed.message_type().code())?;
+    match parsed {
+        WireguardPacket::HandshakeInitiation(packet) => {
+            fields.set_item("sender", packet.sender_index())?;
+            fields.set_item("mac1", PyBytes::new(py, packet.mac1()))?;
+            fields.set_item("mac2", PyBytes::new(py, packet.mac2()))?;
+        }
+        WireguardPacket::HandshakeResponse(packet) => {
+            fields.set_item("sender", packet.sender_index())?;
+            fields.set_item("receiver", packet.receiver_index())?;
+            fields.set_item("mac1", PyBytes::new(py, packet.mac1()))?;
+            fields.set_item("mac2", PyBytes::new(py, packet.mac2()))?;
+        }
+        WireguardPacket::CookieReply(packet) => {
+            fields.set_item("receiver", packet.receiver_index())?;
+        }
+        WireguardPacket::TransportData(header, payload) => {
+            fields.set_item("receiver", header.receiver_index())?;
+            fields.set_item("counter", header.counter())?;
+            fields.set_item("payload_len", payload.len())?;
+        }
+        _ => {}
+    }
+    Ok(fields)
+}
+
+#[pymodule]
+pub fn wireguard_router(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPeer>()?;
+    m.add_function(wrap_pyfunction!(mac, m)?)?;
+    m.add_function(wrap_pyfunction!(is_wg_packet, m)?)?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    Ok(())
+}
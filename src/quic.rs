@@ -0,0 +1,269 @@
+/*
+* quic.rs chains routers over QUIC: the datagrams one router forwards to another are carried as
+* QUIC datagrams of a connection between the two, which is authenticated by mutual TLS and
+* congestion controlled, and gets through middleboxes that throttle or drop plain UDP
+*
+* A link carries datagrams without further framing, so each end sees the other as a single
+* client or backend at the address of the connection, like routers chained over plain UDP.
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{ClientConfig, Connection, Endpoint, ServerConfig, TransportConfig};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::error::Error;
+
+/// The ALPN protocol links negotiate, so other QUIC services aren't mistaken for a router
+const ALPN: &[u8] = b"wireguard-router";
+
+/// A datagram received through a link, as passed on to the router
+type Received = (SocketAddr, Vec<u8>);
+
+/// The `[quic]` table of the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// where links of other routers are accepted, none if this router only connects to others
+    pub listen: Option<SocketAddr>,
+    /// PEM certificate chain of this router
+    pub cert_file: PathBuf,
+    /// PEM private key of the certificate
+    pub key_file: PathBuf,
+    /// PEM certificates of the CA issuing the certificates of all routers
+    pub ca_file: PathBuf,
+    /// name the certificates of routers connected to have to be issued for, their IP address
+    /// if unset
+    pub server_name: Option<String>,
+    /// interval of the keepalives holding links open, and NAT mappings along them
+    #[serde(default = "default_keepalive_secs")]
+    pub keepalive_secs: u64,
+}
+
+fn default_keepalive_secs() -> u64 {
+    10
+}
+
+/// The QUIC links of a router to other routers, those it connected to and those it accepted, see
+/// [`Listeners::quic`](crate::transport::Listeners::quic)
+pub struct Links {
+    endpoint: Endpoint,
+    client: ClientConfig,
+    server_name: Option<String>,
+    /// remote address -> its connection, none while it is being connected to
+    connections: Mutex<HashMap<SocketAddr, Option<Connection>>>,
+    /// where received datagrams are passed on to
+    sender: OnceLock<mpsc::Sender<Received>>,
+}
+
+impl Links {
+    /// Reads the certificates and binds the endpoint, accepting links if `listen` is set
+    pub fn open(config: &Config) -> Result<Self, Error> {
+        let tls = |e: rustls::Error| Error::InvalidInput(format!("QUIC TLS config: {e}"));
+        let certs = read_certs(&config.cert_file)?;
+        let key = PrivateKeyDer::from_pem_file(&config.key_file)
+            .map_err(|e| Error::InvalidInput(format!("{}: {e}", config.key_file.display())))?;
+        let mut roots = rustls::RootCertStore::empty();
+        for ca in read_certs(&config.ca_file)? {
+            roots.add(ca).map_err(tls)?;
+        }
+        let roots = Arc::new(roots);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(Duration::from_secs(config.keepalive_secs)));
+        let transport = Arc::new(transport);
+
+        let mut client = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls)?
+            .with_root_certificates(roots.clone())
+            .with_client_auth_cert(certs.clone(), key.clone_key())
+            .map_err(tls)?;
+        client.alpn_protocols = vec![ALPN.to_vec()];
+        let client = QuicClientConfig::try_from(client)
+            .map_err(|e| Error::InvalidInput(format!("QUIC TLS config: {e}")))?;
+        let mut client = ClientConfig::new(Arc::new(client));
+        client.transport_config(transport.clone());
+
+        let endpoint = match config.listen {
+            Some(listen) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+                    .build()
+                    .map_err(|e| Error::InvalidInput(format!("QUIC TLS config: {e}")))?;
+                let mut server = rustls::ServerConfig::builder_with_provider(provider)
+                    .with_protocol_versions(&[&rustls::version::TLS13])
+                    .map_err(tls)?
+                    .with_client_cert_verifier(verifier)
+                    .with_single_cert(certs, key)
+                    .map_err(tls)?;
+                server.alpn_protocols = vec![ALPN.to_vec()];
+                let server = QuicServerConfig::try_from(server)
+                    .map_err(|e| Error::InvalidInput(format!("QUIC TLS config: {e}")))?;
+                let mut server = ServerConfig::with_crypto(Arc::new(server));
+                server.transport_config(transport);
+                Endpoint::server(server, listen)
+            }
+            None => Endpoint::client((Ipv6Addr::UNSPECIFIED, 0).into()),
+        }
+        .map_err(Error::Bind)?;
+
+        Ok(Links {
+            endpoint,
+            client,
+            server_name: config.server_name.clone(),
+            connections: Mutex::new(HashMap::new()),
+            sender: OnceLock::new(),
+        })
+    }
+
+    /// The address links are accepted on, or connected from
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Passes the datagrams received through links on to `sender`, accepting links if the
+    /// endpoint listens
+    pub(crate) fn start(self: &Arc<Self>, sender: mpsc::Sender<Received>) {
+        let _ = self.sender.set(sender);
+        let links = self.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = links.endpoint.accept().await {
+                let links = links.clone();
+                tokio::spawn(async move {
+                    let remote = incoming.remote_address();
+                    match incoming.await {
+                        Ok(connection) => links.serve(connection).await,
+                        Err(e) => tracing::warn!("QUIC link from {} failed: {}", remote, e),
+                    }
+                });
+            }
+        });
+    }
+
+    /// The connection to `remote`, if there is one
+    pub(crate) fn connection(&self, remote: SocketAddr) -> Option<Connection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&remote)
+            .cloned()
+            .flatten()
+    }
+
+    /// Sends `buf` to `remote` through its link, which is connected to first if there is none
+    ///
+    /// Datagrams sent while the link is being connected to are dropped, WireGuard retrying its
+    /// handshakes.
+    pub(crate) fn send(self: &Arc<Self>, buf: &[u8], remote: SocketAddr) -> io::Result<usize> {
+        let connection = {
+            let mut connections = self.connections.lock().unwrap();
+            match connections.get(&remote) {
+                Some(Some(connection)) => Some(connection.clone()),
+                Some(None) => None,
+                None => {
+                    connections.insert(remote, None);
+                    tokio::spawn(self.clone().connect(remote));
+                    None
+                }
+            }
+        };
+        let Some(connection) = connection else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("QUIC link to {remote} is being connected"),
+            ));
+        };
+        send(&connection, buf)
+    }
+
+    async fn connect(self: Arc<Self>, remote: SocketAddr) {
+        let server_name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => remote.ip().to_string(),
+        };
+        let connecting = self
+            .endpoint
+            .connect_with(self.client.clone(), remote, &server_name)
+            .map_err(|e| e.to_string());
+        let connected = match connecting {
+            Ok(connecting) => connecting.await.map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match connected {
+            Ok(connection) => self.serve(connection).await,
+            Err(e) => {
+                tracing::warn!("QUIC link to {} failed: {}", remote, e);
+                self.connections.lock().unwrap().remove(&remote);
+            }
+        }
+    }
+
+    /// Passes on the datagrams received through `connection` until it is closed
+    async fn serve(&self, connection: Connection) {
+        let remote = canonical(connection.remote_address());
+        tracing::info!("QUIC link with {} established", remote);
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(remote, Some(connection.clone()));
+        let error = loop {
+            match connection.read_datagram().await {
+                Ok(datagram) => {
+                    let Some(sender) = self.sender.get() else {
+                        continue;
+                    };
+                    if sender.send((remote, datagram.to_vec())).await.is_err() {
+                        connection.close(0u32.into(), b"router stopped");
+                        break None;
+                    }
+                }
+                Err(e) => break Some(e),
+            }
+        };
+        let mut connections = self.connections.lock().unwrap();
+        // unless it was replaced by a newer link already
+        if let Some(Some(current)) = connections.get(&remote)
+            && current.stable_id() == connection.stable_id()
+        {
+            connections.remove(&remote);
+        }
+        if let Some(e) = error {
+            tracing::info!("QUIC link with {} closed: {}", remote, e);
+        }
+    }
+}
+
+/// Sends `buf` as a datagram of `connection`
+pub(crate) fn send(connection: &Connection, buf: &[u8]) -> io::Result<usize> {
+    connection.send_datagram(buf.to_vec().into()).map_err(|e| {
+        io::Error::other(format!("QUIC link to {}: {e}", connection.remote_address()))
+    })?;
+    Ok(buf.len())
+}
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::InvalidInput(format!("{}: {e}", path.display())))?;
+    if certs.is_empty() {
+        return Err(Error::InvalidInput(format!(
+            "{} holds no PEM certificate",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
@@ -0,0 +1,169 @@
+/*
+* replay.rs implements the `replay` subcommand, routing the datagrams of a pcap capture through a
+* router configured like a running one, to reproduce incidents offline
+*
+* The router sends through an in-memory transport, so nothing reaches the network. Datagrams are
+* replayed back to back on the router's own clock rather than the capture's timestamps: gaps in
+* the capture aren't waited for, sessions never time out, and decisions depending on time, like
+* rekey and rate windows, may differ from those made live.
+*/
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use wireguard_router::error::Error;
+use wireguard_router::event::RouterEvent;
+use wireguard_router::metrics::Metrics;
+use wireguard_router::pcap;
+use wireguard_router::router::{Router, SessionQuery};
+use wireguard_router::transport::mock::MockTransport;
+
+use crate::config;
+
+/// How many datagrams the router sent are remembered, to recognize them in the capture
+const MAX_SENT: usize = 4096;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// The pcap capture to replay
+    pcap: PathBuf,
+    /// Config whose peers and routing settings the datagrams are routed with
+    #[arg(long, default_value = config::PATH)]
+    config: PathBuf,
+}
+
+pub async fn run(args: Args) -> Result<(), Error> {
+    let config = config::load_from(&args.config)?;
+    let transport = MockTransport::new();
+    let metrics = Arc::new(Metrics::default());
+    let builder = Router::builder(transport.clone()).metrics(metrics.clone());
+    let mut router = crate::configure(builder, &config, &metrics)?.build();
+    router.set_peers(config.peers.clone()).await;
+    let mut events = router.subscribe();
+
+    let file = File::open(&args.pcap).map_err(|source| pcap_error(&args.pcap, source))?;
+    let reader =
+        pcap::Reader::new(BufReader::new(file)).map_err(|source| pcap_error(&args.pcap, source))?;
+    // a capture on the router's host holds what it sent too, which the replay sends again
+    let mut sent: VecDeque<(SocketAddr, Vec<u8>)> = VecDeque::new();
+    let (mut replayed, mut skipped) = (0, 0);
+    for (i, datagram) in reader.enumerate() {
+        let datagram = datagram.map_err(|source| pcap_error(&args.pcap, source))?;
+        let resent = sent.iter().position(|(destination, payload)| {
+            *destination == datagram.destination && *payload == datagram.payload
+        });
+        if let Some(resent) = resent {
+            sent.remove(resent);
+            skipped += 1;
+            continue;
+        }
+        println!(
+            "#{} {}.{:06} {} -> {}",
+            i + 1,
+            datagram.timestamp.as_secs(),
+            datagram.timestamp.subsec_micros(),
+            datagram.source,
+            datagram.destination
+        );
+        router
+            .process_packet_at(
+                datagram.source,
+                Some(datagram.destination.ip()),
+                &datagram.payload,
+            )
+            .await;
+        while let Ok(event) = events.try_recv() {
+            println!("  {}", describe(&event));
+        }
+        sent.extend(transport.take_sent());
+        if sent.len() > MAX_SENT {
+            sent.drain(..sent.len() - MAX_SENT);
+        }
+        replayed += 1;
+    }
+
+    let sessions = router
+        .session_table()
+        .sessions(&SessionQuery::default(), usize::MAX)
+        .await
+        .sessions;
+    println!(
+        "replayed {} datagrams, skipped {} the router sent, {} sessions:",
+        replayed,
+        skipped,
+        sessions.len()
+    );
+    for session in sessions {
+        let backend_index = session
+            .backend_index
+            .map_or_else(|| "unanswered".to_string(), |index| index.to_string());
+        println!(
+            "  {} {} -> {} backend index {}, {} packets, {} bytes",
+            session.client_index,
+            session.client,
+            session.backend,
+            backend_index,
+            session.packets,
+            session.bytes
+        );
+    }
+    Ok(())
+}
+
+fn pcap_error(path: &Path, source: std::io::Error) -> Error {
+    Error::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn describe(event: &RouterEvent) -> String {
+    match event {
+        RouterEvent::SessionCreated {
+            client,
+            backend,
+            client_index,
+        } => format!(
+            "session {} created: {} -> {}",
+            client_index, client, backend
+        ),
+        RouterEvent::SessionEstablished {
+            client,
+            backend,
+            client_index,
+            backend_index,
+        } => format!(
+            "session {} established with backend index {}: {} -> {}",
+            client_index, backend_index, client, backend
+        ),
+        RouterEvent::Forwarded {
+            message,
+            source,
+            destination,
+        } => format!("forwarded {:?}: {} -> {}", message, source, destination),
+        RouterEvent::ExcessiveRekeys {
+            client,
+            backend,
+            handshakes,
+        } => format!(
+            "excessive rekeys: {} handshakes of {} with {}",
+            handshakes, client, backend
+        ),
+        RouterEvent::BackendDown { backend } => format!("backend {} down", backend),
+        RouterEvent::BackendUp { backend } => format!("backend {} up", backend),
+        RouterEvent::Dropped {
+            message: Some(message),
+            source,
+            reason,
+        } => format!("dropped {:?} from {}: {}", message, source, reason),
+        RouterEvent::Dropped {
+            message: None,
+            source,
+            reason,
+        } => format!("dropped datagram from {}: {}", source, reason),
+    }
+}
@@ -1,229 +1,3164 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io;
-use std::sync::mpsc::Receiver;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
-use notify::Event;
-use rkyv::rancor::Failure;
-use rkyv::{Archive, Deserialize, Portable};
-use tokio::net::UdpSocket;
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
 use tokio::select;
-use tokio::sync::Mutex;
-use tracing::debug;
-use wireguard_router::utils;
-use wireguard_router::{Peer, utils::is_wg_packet};
-use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
-
-use crate::state::Identity;
-
-#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
-#[repr(C)]
-pub struct HandshakeInitiation {
-    r#type: u8,
-    reserved: [u8; 3],
-    sender: Identity,
-    ephemeral: [u8; 32],
-    r#static: [u8; 48],
-    timestamp: [u8; 28],
-    mac1: [u8; 16],
-    mac2: [u8; 16],
-}
-#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
-#[repr(C)]
-pub struct HandshakeResponse {
-    r#type: u8,
-    reserved: [u8; 3],
-    sender: Identity,
-    receiver: Identity,
-    ephemeral: [u8; 32],
-    empty: [u8; 16],
-    mac1: [u8; 16],
-    mac2: [u8; 16],
-}
-#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
-#[repr(C)]
-pub struct CookieReply {
-    r#type: u8,
-    reserved: [u8; 3],
-    receiver: Identity,
-    nonce: [u8; 24],
-    cookie: [u8; 32],
-}
-#[derive(FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq)]
-#[repr(C)]
-pub struct TransportDataHeader {
-    r#type: u8,
-    reserved: [u8; 3],
-    receiver: Identity,
-    counter: [u8; 8],
-}
-
-pub enum WireguardPacket<'a> {
-    HandshakeInitiation(&'a HandshakeInitiation),
-    HandshakeResponse(&'a HandshakeResponse),
-    CookieReply(&'a CookieReply),
-    TransportData((&'a TransportDataHeader, &'a [u8], usize)),
-}
-
-impl<'a> TryFrom<(&'a [u8], usize)> for WireguardPacket<'a> {
-    type Error = crate::error::Error;
-
-    fn try_from((data, size): (&'a [u8], usize)) -> Result<Self, Self::Error> {
-        match (data[0], size) {
-            (0x01, 148) => Ok(WireguardPacket::HandshakeInitiation(
-                HandshakeInitiation::ref_from_bytes(&data[..148]).unwrap(),
-            )),
-            (0x02, 92) => Ok(WireguardPacket::HandshakeResponse(
-                HandshakeResponse::ref_from_bytes(&data[..92]).unwrap(),
-            )),
-            (0x03, 64) => Ok(WireguardPacket::CookieReply(
-                CookieReply::ref_from_bytes(&data[..64]).unwrap(),
-            )),
-            (0x04, 32..usize::MAX) => Ok(WireguardPacket::TransportData((
-                // cast header
-                TransportDataHeader::ref_from_bytes(&data[..16]).unwrap(),
-                // rest of the packet (data)
-                &data[16..size],
-                // size of data (packet size - header)
-                size - 16,
-            ))),
-            _ => Err(crate::error::Error::InvalidPacket),
-        }
-    }
-}
-
-pub struct Router {
-    socket: UdpSocket,
-    to_process: Option<(usize, SocketAddr)>,
-    /// Identity -> (From, To)
-    sessions: Arc<Mutex<HashMap<Identity, (SocketAddr, SocketAddr)>>>,
-}
-
-impl Router {
-    pub fn new(socket: UdpSocket) -> Self {
+use tokio::sync::{Mutex, broadcast, mpsc, oneshot, watch};
+use tokio::time::MissedTickBehavior;
+use tracing::field::{Empty, display};
+use tracing::{Span, debug};
+
+use crate::affinity::AffinityTable;
+use crate::chaos::{Chaos, DelayQueue, Delayed, Fate};
+use crate::denylist::{Denylist, Network};
+use crate::error::{Error, Report};
+use crate::event::{DropReason, RouterEvent};
+use crate::health::{BackendState, Health, OutlierDetection, Thresholds};
+use crate::metrics::Metrics;
+use crate::packet::{HandshakeInitiation, Identity, MessageType, WireguardPacket};
+use crate::policy::{FirstMatch, Forward, Initiation, RoutingPolicy, SessionEvent, Verdict};
+use crate::schedule::Window;
+use crate::socks::{self, Association};
+use crate::timeline::{DEFAULT_TIMELINES, SessionTimeline, TimelineEvent, Timelines};
+use crate::transport::{Egress, PacketTransport};
+use crate::{Peer, utils::is_wg_packet};
+
+/// Large enough for any UDP datagram, including SOCKS5 encapsulation
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 70;
+/// WireGuard rejects keys older than this, so idle sessions can not be resumed afterwards
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(180);
+/// WireGuard rekeys every 2 minutes, the handshakes of a client's tunnel are counted over this
+pub const REKEY_WINDOW: Duration = Duration::from_secs(120);
+/// Handshakes of a tunnel within [`REKEY_WINDOW`] beyond which it rekeys excessively, a healthy
+/// one starting one or two
+pub const DEFAULT_REKEY_THRESHOLD: usize = 6;
+/// Clients start over with a new initiation after 5 seconds without a response, so sessions
+/// that carried no transport data yet are forgotten after three of these
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+/// Events buffered per [`Router::subscribe`] receiver
+pub const EVENT_CAPACITY: usize = 1024;
+/// Datagrams read at once, see [`RouterBuilder::batch_size`]
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+/// Handshake messages are sent again this many times while sending fails with a transient error
+pub const SEND_RETRIES: u32 = 3;
+/// Delay before the first retry of a send, doubling with every further one
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(1);
+/// Reading ahead stops after this many batches worth of datagrams, even if datagrams are being
+/// dropped under backpressure, so the router gets to its timers and peer updates
+const READ_AHEAD_LIMIT: usize = 4;
+/// Bytes of transport data each session may forward per round of fair queuing, about one
+/// full-size packet, see [`RouterBuilder::fair_queuing`]
+const FAIR_QUEUING_QUANTUM: usize = 1500;
+/// With [`UnmatchedData::Log`], at most one unmatched packet is logged per interval
+pub const UNMATCHED_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// The source of the networks given to [`RouterBuilder::deny`], see [`Denylist`]
+pub const CONFIG_DENYLIST: &str = "config";
+/// Approximate bytes of a session by one of its indices, with its deadline, its share of the
+/// index by client and of its span in the subscriber, which are not measured
+const SESSION_BYTES: usize = size_of::<(Identity, Session)>()
+    + size_of::<(Instant, Identity)>()
+    + size_of::<Identity>()
+    + 128;
+
+/// What becomes of transport data whose receiver index matches no session
+///
+/// In the config this is `"drop"`, `"log"` or `{ forward = "<backend address>" }`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnmatchedData {
+    /// drop and count it
+    #[default]
+    Drop,
+    /// drop and count it, logging a sample of the packets along with how many were not logged
+    Log,
+    /// forward client packets to this backend, e.g. after a restart lost the sessions of the
+    /// tunnels the backend still holds
+    ///
+    /// Its replies are still dropped, as the router can't tell which client they are for, until
+    /// the client starts a new handshake.
+    Forward(SocketAddr),
+}
+
+/// Where initiations matching no peer are routed instead of being dropped, to learn who probes
+/// the router
+///
+/// The honeypot's responses reach the prober like those of any backend. Every such initiation
+/// is counted, and a sample of `sample_rate` of them is logged with all the router can read from
+/// them.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Honeypot {
+    pub address: SocketAddr,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+/// The peers reachable through one local address, e.g. that of an internal interface
+///
+/// Initiations received on `address` can only reach the peers named in `peers`. Those received
+/// on any other address can reach the peers that no horizon names, so the peers of a horizon
+/// are hidden from everywhere else, and peers of different horizons may share a pubkey.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Horizon {
+    pub address: IpAddr,
+    pub peers: Vec<String>,
+}
+
+impl Horizon {
+    /// Whether `peer` can be reached by initiations received on `local`, given all `horizons`
+    ///
+    /// Without the local address, e.g. as the transport can't tell, there is no horizon to match.
+    pub fn reaches(horizons: &[Horizon], local: Option<IpAddr>, peer: &Peer) -> bool {
+        let named = |horizon: &Horizon| {
+            peer.name
+                .as_ref()
+                .is_some_and(|name| horizon.peers.contains(name))
+        };
+        let local = local.map(|local| local.to_canonical());
+        let mut matching = horizons
+            .iter()
+            .filter(|horizon| Some(horizon.address.to_canonical()) == local)
+            .peekable();
+        match matching.peek() {
+            Some(_) => matching.any(named),
+            None => !horizons.iter().any(named),
+        }
+    }
+}
+
+/// What becomes of a datagram read while the batch is full, see [`RouterBuilder::backpressure`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// handle it after the batch and stop reading ahead, further datagrams wait in the socket
+    /// buffer, where the kernel drops the newest once it is full
+    #[default]
+    Block,
+    /// drop it and read on, in search of datagrams of the other kind
+    DropNewest,
+    /// drop the oldest datagram of its kind in the batch to make room for it and read on
+    DropOldest,
+}
+
+/// The [`Backpressure`] of handshake messages and of transport data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BackpressurePolicy {
+    pub handshake: Backpressure,
+    pub data: Backpressure,
+}
+
+impl BackpressurePolicy {
+    fn of(&self, bulk: bool) -> Backpressure {
+        match bulk {
+            true => self.data,
+            false => self.handshake,
+        }
+    }
+}
+
+/// The routing state of one sender index
+#[derive(Clone, Debug)]
+struct Session {
+    /// the peer that allocated the index
+    from: SocketAddr,
+    /// the peer packets sent with the index are routed to
+    to: SocketAddr,
+    /// the backend side of the session, either `from` or `to`
+    backend: SocketAddr,
+    created: Instant,
+    last_seen: Instant,
+    /// datagrams routed with this index and their bytes
+    packets: u64,
+    bytes: u64,
+    /// transport data was routed with either index of the session, shared by both
+    carried_data: Arc<AtomicBool>,
+    /// the index the backend answered the client's initiation with
+    answered: Option<Identity>,
+    /// the address packets with the index last came from, initially `to`
+    sender: SocketAddr,
+    /// the backends the client's initiation was raced between, the first to respond taking the
+    /// session, none unless [`RouterBuilder::race_initiations`] is on
+    raced: Vec<SocketAddr>,
+    /// when the client's tunnel to the backend started handshakes within [`REKEY_WINDOW`],
+    /// carried over from the tunnel's previous session
+    handshakes: VecDeque<Instant>,
+    /// covers the lifetime of the WireGuard session, shared by the indices of both sides
+    span: Span,
+}
+
+impl Session {
+    fn new(from: SocketAddr, to: SocketAddr, backend: SocketAddr, span: Span) -> Self {
+        let now = Instant::now();
+        Session {
+            from,
+            to,
+            backend,
+            created: now,
+            last_seen: now,
+            packets: 0,
+            bytes: 0,
+            carried_data: Default::default(),
+            answered: None,
+            sender: to,
+            raced: Vec::new(),
+            handshakes: VecDeque::new(),
+            span,
+        }
+    }
+
+    /// Whether the client allocated the index, rather than the backend
+    fn initiated(&self) -> bool {
+        self.to == self.backend
+    }
+
+    /// The client side of the session, either `from` or `to`
+    fn client(&self) -> SocketAddr {
+        match self.initiated() {
+            true => self.from,
+            false => self.to,
+        }
+    }
+
+    /// Counts a datagram of `bytes` routed with the index
+    fn touch(&mut self, bytes: usize) {
+        self.last_seen = Instant::now();
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+
+    /// The entry of the other side of the session, created once the backend responded
+    fn answer(&self, from: SocketAddr) -> Self {
+        Session {
+            carried_data: self.carried_data.clone(),
+            ..Session::new(from, self.from, from, self.span.clone())
+        }
+    }
+}
+
+/// The sessions by both their indices, indexed by client address as well, and their timelines
+#[derive(Default)]
+struct Sessions {
+    by_index: HashMap<Identity, Session>,
+    by_client: HashMap<IpAddr, HashSet<Identity>>,
+    /// when the indices are next checked for expiry, which is not moved as packets are routed
+    /// but once it is due, so expiry looks at the sessions due rather than all of them
+    ///
+    /// Indices of sessions forgotten otherwise are skipped when due.
+    deadlines: BTreeMap<Instant, Vec<Identity>>,
+    timelines: Timelines,
+    /// how many of the sessions clients initiated, which the session limit applies to
+    initiated: usize,
+    /// backend -> how many of them it took, which its `max_sessions` applies to
+    by_backend: HashMap<SocketAddr, usize>,
+}
+
+impl Deref for Sessions {
+    type Target = HashMap<Identity, Session>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.by_index
+    }
+}
+
+impl Sessions {
+    fn get_mut(&mut self, index: &Identity) -> Option<&mut Session> {
+        self.by_index.get_mut(index)
+    }
+
+    /// Adds or replaces the session of `index`, due for its first expiry check right away
+    fn insert(&mut self, index: Identity, session: Session) {
+        self.schedule(index, session.last_seen);
+        let client = session.client().ip();
+        if session.initiated() {
+            self.initiated += 1;
+            *self.by_backend.entry(session.backend).or_default() += 1;
+        }
+        if let Some(replaced) = self.by_index.insert(index, session) {
+            if replaced.initiated() {
+                self.initiated -= 1;
+                uncount(&mut self.by_backend, replaced.backend);
+            }
+            unindex(&mut self.by_client, replaced.client().ip(), &index);
+        }
+        self.by_client.entry(client).or_default().insert(index);
+    }
+
+    /// The number of sessions clients initiated, counting each once rather than by both its
+    /// indices
+    fn initiated(&self) -> usize {
+        self.initiated
+    }
+
+    /// The number of sessions clients initiated with `backend`
+    fn backend_sessions(&self, backend: SocketAddr) -> usize {
+        self.by_backend.get(&backend).copied().unwrap_or(0)
+    }
+
+    /// Counts a session clients initiated with `from` as taken by `to`, which won its race
+    fn reassign(&mut self, from: SocketAddr, to: SocketAddr) {
+        uncount(&mut self.by_backend, from);
+        *self.by_backend.entry(to).or_default() += 1;
+    }
+
+    /// Checks `index` for expiry once `deadline` passed
+    fn schedule(&mut self, index: Identity, deadline: Instant) {
+        self.deadlines.entry(deadline).or_default().push(index);
+    }
+
+    /// Takes the indices due for an expiry check by `now`
+    fn due(&mut self, now: Instant) -> Vec<Identity> {
+        let later = self.deadlines.split_off(&now);
+        let due = std::mem::replace(&mut self.deadlines, later);
+        due.into_values().flatten().collect()
+    }
+
+    fn remove(&mut self, index: &Identity) -> Option<Session> {
+        let session = self.by_index.remove(index)?;
+        if session.initiated() {
+            self.initiated -= 1;
+            uncount(&mut self.by_backend, session.backend);
+        }
+        unindex(&mut self.by_client, session.client().ip(), index);
+        Some(session)
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Identity, &mut Session) -> bool) {
+        let (by_client, initiated, by_backend) = (
+            &mut self.by_client,
+            &mut self.initiated,
+            &mut self.by_backend,
+        );
+        self.by_index.retain(|index, session| {
+            let kept = keep(index, session);
+            if !kept {
+                if session.initiated() {
+                    *initiated -= 1;
+                    uncount(by_backend, session.backend);
+                }
+                unindex(by_client, session.client().ip(), index);
+            }
+            kept
+        });
+    }
+
+    /// The indices of the sessions of `client`, both its own and those of its backends
+    fn of_client(&self, client: IpAddr) -> impl Iterator<Item = (&Identity, &Session)> {
+        let indices = self.by_client.get(&client).into_iter().flatten();
+        indices.filter_map(|index| self.by_index.get_key_value(index))
+    }
+}
+
+/// Awaits `future`, returning the payload of a panic while polling it as an error
+async fn catch_unwind<F: Future>(future: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    })
+    .await
+}
+
+/// Narrows `candidates` to one arm of their traffic split, drawn by [`Peer::weight`], if any of
+/// them is weighted
+///
+/// Backends without a weight are left out of the draw, taking sessions only when no weighted one
+/// is a candidate.
+fn split<'a>(candidates: &[&'a Peer]) -> Vec<&'a Peer> {
+    let total: u64 = candidates
+        .iter()
+        .filter_map(|p| p.weight)
+        .map(u64::from)
+        .sum();
+    if total == 0 {
+        return candidates.to_vec();
+    }
+    let mut draw = u64::from(OsRng.next_u32()) % total;
+    for peer in candidates {
+        let weight = u64::from(peer.weight.unwrap_or(0));
+        if draw < weight {
+            return vec![peer];
+        }
+        draw -= weight;
+    }
+    candidates.to_vec()
+}
+
+/// When the tunnel of `client` to `backend` started handshakes within [`REKEY_WINDOW`], taken
+/// from its latest session and including one starting now
+fn tunnel_handshakes(
+    sessions: &Sessions,
+    client: SocketAddr,
+    backend: SocketAddr,
+) -> VecDeque<Instant> {
+    let now = Instant::now();
+    let mut handshakes = sessions
+        .of_client(client.ip())
+        .map(|(_, session)| session)
+        .filter(|session| {
+            session.initiated() && session.from == client && session.backend == backend
+        })
+        .max_by_key(|session| session.created)
+        .map(|session| session.handshakes.clone())
+        .unwrap_or_default();
+    while handshakes
+        .front()
+        .is_some_and(|at| now.duration_since(*at) > REKEY_WINDOW)
+    {
+        handshakes.pop_front();
+    }
+    handshakes.push_back(now);
+    handshakes
+}
+
+/// Whether the client endpoint `client` established a session, so its initiations rekey a
+/// tunnel rather than starting a new one
+fn has_tunnel(sessions: &Sessions, client: SocketAddr) -> bool {
+    sessions.of_client(client.ip()).any(|(_, session)| {
+        session.initiated() && session.from == client && session.answered.is_some()
+    })
+}
+
+fn unindex(by_client: &mut HashMap<IpAddr, HashSet<Identity>>, client: IpAddr, index: &Identity) {
+    if let Some(indices) = by_client.get_mut(&client) {
+        indices.remove(index);
+        if indices.is_empty() {
+            by_client.remove(&client);
+        }
+    }
+}
+
+fn uncount(by_backend: &mut HashMap<SocketAddr, usize>, backend: SocketAddr) {
+    if let Some(count) = by_backend.get_mut(&backend) {
+        *count -= 1;
+        if *count == 0 {
+            by_backend.remove(&backend);
+        }
+    }
+}
+
+/// The number of sessions clients initiated with each of `candidates`
+fn session_counts(sessions: &Sessions, candidates: &[&Peer]) -> HashMap<SocketAddr, usize> {
+    candidates
+        .iter()
+        .map(|p| (p.address, sessions.backend_sessions(p.address)))
+        .collect()
+}
+
+/// A client endpoint with sessions to a backend, as listed by [`SessionTable::clients`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Client {
+    pub client: SocketAddr,
+    pub backend: SocketAddr,
+    /// sessions the client initiated, which overlap while it rekeys
+    pub sessions: usize,
+    /// time since a packet of these sessions was routed, in either direction
+    pub idle: Duration,
+}
+
+/// Which sessions [`SessionTable::sessions`] lists, all of them by default
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionQuery {
+    /// only the sessions of clients at this address, found without going through all sessions
+    pub client: Option<IpAddr>,
+    pub backend: Option<SocketAddr>,
+    /// only sessions created at least this long ago
+    pub min_age: Option<Duration>,
+    /// continues a listing after this client index, the [`SessionPage::next`] of the last page
+    pub after: Option<Identity>,
+}
+
+/// A session a client initiated, as listed by [`SessionTable::sessions`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub client: SocketAddr,
+    pub backend: SocketAddr,
+    pub client_index: Identity,
+    /// none until the backend responded
+    pub backend_index: Option<Identity>,
+    pub age: Duration,
+    /// time since a packet of the session was routed, in either direction
+    pub idle: Duration,
+    /// datagrams of the session routed so far, in both directions
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// One page of the sessions listed by [`SessionTable::sessions`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionPage {
+    /// ordered by client index
+    pub sessions: Vec<SessionInfo>,
+    /// where the next page starts, none if this is the last one
+    pub next: Option<Identity>,
+}
+
+/// Why garbage collection forgot a session, see [`GcTrigger`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EvictionReason {
+    /// no packet was routed for it within the session timeout
+    Idle,
+    /// it carried no transport data and no packet was routed for it within the handshake timeout
+    Unanswered,
+    /// its backend is no longer among the peers
+    RemovedPeer,
+}
+
+impl EvictionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionReason::Idle => "idle",
+            EvictionReason::Unanswered => "unanswered",
+            EvictionReason::RemovedPeer => "removed_peer",
+        }
+    }
+}
+
+/// A session a client initiated that garbage collection forgot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Eviction {
+    pub client: SocketAddr,
+    pub backend: SocketAddr,
+    pub client_index: Identity,
+    pub reason: EvictionReason,
+}
+
+/// What a garbage collection pass triggered with [`GcTrigger::run`] did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// the sessions clients initiated that were forgotten, each counted once
+    pub evicted: Vec<Eviction>,
+    /// the sessions clients initiated that are left
+    pub remaining: usize,
+    pub took: Duration,
+}
+
+/// A handle to run garbage collection of a [`Router`]'s sessions now, rather than waiting for
+/// its next scheduled pass
+#[derive(Clone)]
+pub struct GcTrigger(mpsc::Sender<oneshot::Sender<GcReport>>);
+
+impl GcTrigger {
+    /// Forgets the idle sessions, as the router does periodically, and the sessions of backends
+    /// no longer among the peers, which would otherwise be kept until they are idle
+    ///
+    /// Returns none if the router stopped running.
+    pub async fn run(&self) -> Option<GcReport> {
+        let (report_tx, report_rx) = oneshot::channel();
+        self.0.send(report_tx).await.ok()?;
+        report_rx.await.ok()
+    }
+}
+
+/// Approximate bytes held by the state of a [`Router`], see [`MemoryMeter`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub sessions: usize,
+    pub timelines: usize,
+    pub affinity: usize,
+    /// at most the receive buffers and the event queue hold, fixed on startup
+    pub queues: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.sessions + self.timelines + self.affinity + self.queues
+    }
+}
+
+/// A handle measuring the memory a [`Router`] holds while it runs
+#[derive(Clone)]
+pub struct MemoryMeter {
+    sessions: Arc<Mutex<Sessions>>,
+    affinity: Option<Arc<AffinityTable>>,
+    queues: usize,
+    limit: Option<usize>,
+}
+
+impl MemoryMeter {
+    pub async fn usage(&self) -> MemoryUsage {
+        let sessions = self.sessions.lock().await;
+        memory_usage(&sessions, self.affinity.as_deref(), self.queues)
+    }
+
+    /// The ceiling set with [`RouterBuilder::memory_limit`]
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+}
+
+fn memory_usage(
+    sessions: &Sessions,
+    affinity: Option<&AffinityTable>,
+    queues: usize,
+) -> MemoryUsage {
+    MemoryUsage {
+        sessions: sessions.len() * SESSION_BYTES,
+        timelines: sessions.timelines.footprint(),
+        affinity: affinity.map_or(0, AffinityTable::footprint),
+        queues,
+    }
+}
+
+/// A datagram to explain the routing of, see [`Explainer`], or to inject, see [`Injector`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    pub source: SocketAddr,
+    /// the local address it is received on, deciding the [`Horizon`] it is matched in
+    pub local: Option<IpAddr>,
+    pub packet: Vec<u8>,
+}
+
+/// Where a [`Router`] would send a datagram, see [`Explainer`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Forward(SocketAddr),
+    /// the initiation goes to all these backends, see [`RouterBuilder::race_initiations`]
+    Race(Vec<SocketAddr>),
+    Drop(DropReason),
+}
+
+/// The session a datagram's index belongs to, see [`Explanation::session`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionHit {
+    /// the sender index of an initiation, the receiver index of other messages
+    pub index: Identity,
+    pub client: SocketAddr,
+    pub backend: SocketAddr,
+}
+
+/// What the router knows of a backend matching an initiation, see [`Explanation::matched`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackendCheck {
+    pub backend: SocketAddr,
+    pub name: Option<String>,
+    /// sessions clients initiated with it, and the most it takes
+    pub sessions: usize,
+    pub max_sessions: Option<usize>,
+    /// whether it is within one of its maintenance windows
+    pub maintenance: bool,
+    pub state: BackendState,
+    pub primary: bool,
+    pub weight: Option<u32>,
+}
+
+/// How a [`Router`] would route a datagram, without routing it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Explanation {
+    /// none if the datagram is no WireGuard message
+    pub message: Option<MessageType>,
+    /// the session the datagram's index belongs to, none for an initiation starting one
+    pub session: Option<SessionHit>,
+    /// the backends whose key matches an initiation without a session, or the honeypot
+    pub matched: Vec<BackendCheck>,
+    /// those of them left to choose from once maintenance, session limits, health and
+    /// primaries narrowed them down
+    pub candidates: Vec<SocketAddr>,
+    /// the backend the client is pinned to, see [`Pins`]
+    pub pinned: Option<SocketAddr>,
+    /// whether new tunnels are paused, see [`Lockdown`]
+    pub lockdown: bool,
+    /// the sessions clients initiated, and the most the router tracks
+    pub sessions: usize,
+    pub max_sessions: Option<usize>,
+    pub decision: Decision,
+}
+
+type ExplainQuery = (Datagram, oneshot::Sender<Explanation>);
+
+/// SOCKS5 proxy -> its UDP association, none while it is being associated with
+type Associations = Arc<std::sync::RwLock<HashMap<SocketAddr, Option<Association>>>>;
+
+/// Associates with `proxy`, filling in its entry unless the proxy was removed meanwhile, or
+/// removing the entry to retry with the next peers if it failed
+async fn associate(associations: Associations, proxy: SocketAddr) {
+    let result = Association::connect(proxy, socks::HANDSHAKE_TIMEOUT).await;
+    let mut associations = associations.write().unwrap();
+    match result {
+        Ok(association) => {
+            tracing::info!(
+                "associated with SOCKS5 proxy {}, relaying via {}",
+                proxy,
+                association.relay
+            );
+            if let Some(entry) = associations.get_mut(&proxy) {
+                *entry = Some(association);
+            }
+        }
+        Err(source) => {
+            let err = Error::Proxy { proxy, source };
+            tracing::error!("{}", Report(&err));
+            associations.remove(&proxy);
+        }
+    }
+}
+
+/// A handle asking a [`Router`] how it would route a datagram, to debug a config without
+/// sending real traffic
+///
+/// Initiations are run past the routing policy as if they were real, so a stateful policy
+/// counts them. Sends the policy may veto are explained as forwarded.
+#[derive(Clone)]
+pub struct Explainer(mpsc::Sender<ExplainQuery>);
+
+impl Explainer {
+    /// How the router would route `request` now, none if it stopped running
+    pub async fn explain(&self, request: Datagram) -> Option<Explanation> {
+        let (explanation_tx, explanation_rx) = oneshot::channel();
+        self.0.send((request, explanation_tx)).await.ok()?;
+        explanation_rx.await.ok()
+    }
+}
+
+type InjectQuery = (Datagram, oneshot::Sender<Vec<RouterEvent>>);
+
+/// A handle feeding crafted datagrams to a [`Router`] as if they were received, e.g. to
+/// validate a staging deployment
+///
+/// Injected datagrams are routed like any other, creating sessions and sending to backends
+/// and clients.
+#[derive(Clone)]
+pub struct Injector(mpsc::Sender<InjectQuery>);
+
+impl Injector {
+    /// Routes `datagram` and returns the events it caused, none if the router stopped running
+    pub async fn inject(&self, datagram: Datagram) -> Option<Vec<RouterEvent>> {
+        let (events_tx, events_rx) = oneshot::channel();
+        self.0.send((datagram, events_tx)).await.ok()?;
+        events_rx.await.ok()
+    }
+}
+
+/// A switch pausing new tunnels through a [`Router`] while it runs, see [`Router::lockdown`]
+#[derive(Clone, Default)]
+pub struct Lockdown(Arc<AtomicBool>);
+
+impl Lockdown {
+    /// Starts or ends the lockdown, returning whether it was active before
+    pub fn set(&self, active: bool) -> bool {
+        self.0.swap(active, Ordering::Relaxed)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The backends clients are pinned to, see [`Router::pins`]
+#[derive(Clone, Default)]
+pub struct Pins(Arc<std::sync::RwLock<HashMap<IpAddr, SocketAddr>>>);
+
+impl Pins {
+    /// Pins `client` to `backend`, returning the backend it was pinned to before
+    pub fn pin(&self, client: IpAddr, backend: SocketAddr) -> Option<SocketAddr> {
+        let backend = SocketAddr::new(backend.ip().to_canonical(), backend.port());
+        self.0
+            .write()
+            .unwrap()
+            .insert(client.to_canonical(), backend)
+    }
+
+    /// Lets `client` be routed like any other again, returning the backend it was pinned to
+    pub fn unpin(&self, client: IpAddr) -> Option<SocketAddr> {
+        self.0.write().unwrap().remove(&client.to_canonical())
+    }
+
+    /// The backend `client` is pinned to
+    pub fn get(&self, client: IpAddr) -> Option<SocketAddr> {
+        self.0.read().unwrap().get(&client.to_canonical()).copied()
+    }
+
+    /// Every pinned client and its backend, in the order of the clients
+    pub fn list(&self) -> Vec<(IpAddr, SocketAddr)> {
+        let mut pins: Vec<(IpAddr, SocketAddr)> = self
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(client, backend)| (*client, *backend))
+            .collect();
+        pins.sort();
+        pins
+    }
+}
+
+/// A handle on the sessions of a [`Router`], to inspect them while it runs
+#[derive(Clone)]
+pub struct SessionTable {
+    sessions: Arc<Mutex<Sessions>>,
+    /// the limits imports stop at, see [`RouterBuilder::max_sessions`] and
+    /// [`RouterBuilder::memory_limit`]
+    max_sessions: Option<usize>,
+    memory: MemoryMeter,
+    honeypot: Option<SocketAddr>,
+}
+
+impl SessionTable {
+    /// The sessions clients initiated, counting each once rather than by both its indices
+    pub async fn count(&self) -> usize {
+        self.sessions.lock().await.initiated()
+    }
+
+    /// Up to `limit` sessions matching `query`, in the order of their client index
+    pub async fn sessions(&self, query: &SessionQuery, limit: usize) -> SessionPage {
+        let sessions = self.sessions.lock().await;
+        let candidates: Box<dyn Iterator<Item = (&Identity, &Session)>> = match query.client {
+            Some(client) => Box::new(sessions.of_client(client)),
+            None => Box::new(sessions.iter()),
+        };
+        let mut matching: Vec<(&Identity, &Session)> = candidates
+            .filter(|(_, session)| session.initiated())
+            .filter(|(_, session)| query.backend.is_none_or(|b| b == session.backend))
+            .filter(|(_, session)| {
+                query
+                    .min_age
+                    .is_none_or(|age| session.created.elapsed() >= age)
+            })
+            .filter(|(index, _)| {
+                query
+                    .after
+                    .is_none_or(|after| index.as_u32() > after.as_u32())
+            })
+            .collect();
+        matching.sort_unstable_by_key(|(index, _)| index.as_u32());
+        let limit = limit.max(1);
+        let next = match matching.len() > limit {
+            true => Some(*matching[limit - 1].0),
+            false => None,
+        };
+        matching.truncate(limit);
+        let sessions = matching
+            .into_iter()
+            .map(|(index, session)| {
+                let answer = session.answered.and_then(|answer| sessions.get(&answer));
+                let last_seen = answer.map_or(session.last_seen, |answer| {
+                    answer.last_seen.max(session.last_seen)
+                });
+                SessionInfo {
+                    client: session.from,
+                    backend: session.backend,
+                    client_index: *index,
+                    backend_index: session.answered,
+                    age: session.created.elapsed(),
+                    idle: last_seen.elapsed(),
+                    packets: session.packets + answer.map_or(0, |answer| answer.packets),
+                    bytes: session.bytes + answer.map_or(0, |answer| answer.bytes),
+                }
+            })
+            .collect();
+        SessionPage { sessions, next }
+    }
+
+    /// Adds the established sessions among `sessions`, e.g. those of another router the clients'
+    /// traffic is spread across, so their transport data is routed right away, returning how many
+    /// were added
+    ///
+    /// Sessions the backend didn't respond to yet are left out, as their clients retry the
+    /// handshake, and so are those with an index that is already known and those with a backend
+    /// that is neither among `peers` nor the honeypot. Importing stops at the session and memory
+    /// limits.
+    pub async fn import(&self, sessions: &[SessionInfo], peers: &[Peer]) -> usize {
+        let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let backends: HashSet<SocketAddr> = peers
+            .iter()
+            .map(|p| p.address)
+            .chain(self.honeypot)
+            .map(canonical)
+            .collect();
+        let mut table = self.sessions.lock().await;
+        let now = Instant::now();
+        let mut imported = 0;
+        for info in sessions {
+            let Some(backend_index) = info.backend_index else {
+                continue;
+            };
+            if table.contains_key(&info.client_index) || table.contains_key(&backend_index) {
+                continue;
+            }
+            let (client, backend) = (canonical(info.client), canonical(info.backend));
+            if !backends.contains(&backend) {
+                continue;
+            }
+            let memory = &self.memory;
+            let full = self
+                .max_sessions
+                .is_some_and(|max| table.initiated() >= max)
+                || memory.limit.is_some_and(|limit| {
+                    memory_usage(&table, memory.affinity.as_deref(), memory.queues).total() >= limit
+                });
+            if full {
+                break;
+            }
+            let span = tracing::info_span!(
+                parent: None,
+                "session",
+                client = %client,
+                client_index = %info.client_index,
+                backend = %backend,
+                peer = Empty,
+                pubkey = Empty,
+                backend_index = %backend_index,
+            );
+            let mut session = Session::new(client, backend, backend, span);
+            session.created = now.checked_sub(info.age).unwrap_or(now);
+            session.last_seen = now.checked_sub(info.idle).unwrap_or(now);
+            session.answered = Some(backend_index);
+            // beyond the initiation and response, the session carried data
+            session
+                .carried_data
+                .store(info.packets > 2, Ordering::Relaxed);
+            let answer = Session {
+                created: session.created,
+                last_seen: session.last_seen,
+                ..session.answer(backend)
+            };
+            table.insert(info.client_index, session);
+            table.insert(backend_index, answer);
+            table.timelines.start(info.client_index, client, backend);
+            table.timelines.alias(backend_index, info.client_index);
+            imported += 1;
+        }
+        imported
+    }
+
+    /// The client and backend of the session with either index `index`
+    pub async fn endpoints(&self, index: Identity) -> Option<(SocketAddr, SocketAddr)> {
+        let sessions = self.sessions.lock().await;
+        sessions
+            .get(&index)
+            .map(|session| (session.client(), session.backend))
+    }
+
+    /// The recent events of the session with either index `index`, kept for a while after it ended
+    pub async fn timeline(&self, index: Identity) -> Option<SessionTimeline> {
+        self.sessions.lock().await.timelines.get(index)
+    }
+
+    /// The timelines of the recent sessions of `client`, the oldest first
+    pub async fn timelines(&self, client: IpAddr) -> Vec<SessionTimeline> {
+        self.sessions.lock().await.timelines.of_client(client)
+    }
+
+    /// The clients currently mapped to `backend`, or to any backend, most recently active first
+    pub async fn clients(&self, backend: Option<SocketAddr>) -> Vec<Client> {
+        let sessions = self.sessions.lock().await;
+        let mut clients: HashMap<(SocketAddr, SocketAddr), Client> = HashMap::new();
+        for session in sessions.values() {
+            let initiated = session.initiated();
+            let client = session.client();
+            if backend.is_some_and(|backend| backend != session.backend) {
+                continue;
+            }
+            let idle = session.last_seen.elapsed();
+            let entry = clients.entry((client, session.backend)).or_insert(Client {
+                client,
+                backend: session.backend,
+                sessions: 0,
+                idle,
+            });
+            entry.sessions += usize::from(initiated);
+            entry.idle = entry.idle.min(idle);
+        }
+        let mut clients: Vec<Client> = clients.into_values().collect();
+        clients.sort_by_key(|client| client.idle);
+        clients
+    }
+}
+
+/// Routes WireGuard sessions between clients and backends over any [`PacketTransport`]
+pub struct Router<T> {
+    transport: T,
+    policy: Arc<dyn RoutingPolicy>,
+    metrics: Arc<Metrics>,
+    buffer_size: usize,
+    max_datagram_size: Option<usize>,
+    batch_size: usize,
+    backpressure: BackpressurePolicy,
+    max_sessions: Option<usize>,
+    session_timeout: Duration,
+    handshake_timeout: Duration,
+    rekey_threshold: usize,
+    race_initiations: bool,
+    fair_queuing: bool,
+    memory_limit: Option<usize>,
+    chaos: Option<Chaos>,
+    /// packets chaos holds back
+    delayed: std::sync::Mutex<DelayQueue>,
+    unmatched_data: UnmatchedData,
+    affinity: Option<Arc<AffinityTable>>,
+    horizons: Vec<Horizon>,
+    /// the honeypot as a peer, and the share of its initiations that is logged
+    honeypot: Option<(Peer, f64)>,
+    health: Arc<Health>,
+    outliers: Option<OutlierDetection>,
+    lockdown: Lockdown,
+    pins: Pins,
+    denylist: Denylist,
+    /// when an unmatched packet was last logged, and how many were not logged since
+    unmatched_log: std::sync::Mutex<(Option<Instant>, u64)>,
+    /// Identity -> Session
+    ///
+    /// Addresses are stored in canonical form, so a session may freely span address families.
+    sessions: Arc<Mutex<Sessions>>,
+    /// SOCKS5 proxy -> UDP association, none while it is being associated with
+    associations: Associations,
+    /// backend -> SOCKS5 proxy it is reached through
+    proxied: HashMap<SocketAddr, SocketAddr>,
+    /// backend -> configured peer name, for log context
+    names: HashMap<SocketAddr, String>,
+    /// the peers as configured, `peers` being them with the settings of their open schedule rules
+    configured: Vec<Peer>,
+    peers: Vec<Peer>,
+    /// backends within one of their maintenance windows, which take no new sessions
+    drained: HashSet<SocketAddr>,
+    /// backends taking the settings of one of their schedule rules
+    scheduled: HashSet<SocketAddr>,
+    /// the minute since the epoch `drained` and `peers` were last updated for
+    schedule_minute: Option<i64>,
+    /// the time zone cron expressions are read in, the local one if unset
+    timezone: Option<chrono_tz::Tz>,
+    events: broadcast::Sender<RouterEvent>,
+    /// garbage collection requested through a [`GcTrigger`], answered with its report
+    gc: (
+        mpsc::Sender<oneshot::Sender<GcReport>>,
+        mpsc::Receiver<oneshot::Sender<GcReport>>,
+    ),
+    /// routing decisions asked for through an [`Explainer`]
+    explain_requests: (mpsc::Sender<ExplainQuery>, mpsc::Receiver<ExplainQuery>),
+    /// datagrams fed through an [`Injector`]
+    injected: (mpsc::Sender<InjectQuery>, mpsc::Receiver<InjectQuery>),
+    heartbeat: Option<Heartbeat>,
+    /// datagrams whose handling panicked, and how many [`run`](Self::run) tolerates
+    panics: AtomicU64,
+    max_panics: Option<u64>,
+}
+
+/// A send that failed, after it was retried if the error was transient
+struct SendFailure {
+    reason: DropReason,
+    /// the error is no fault of the destination, e.g. the socket buffer was full
+    transient: bool,
+}
+
+/// Whether sending may succeed if it is simply tried again
+fn is_transient(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(nix::errno::Errno::ENOBUFS as i32) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::OutOfMemory
+    )
+}
+
+/// Datagrams read at once, as source, local address and contents, queued by priority
+#[derive(Default)]
+struct Batch {
+    handshakes: VecDeque<(SocketAddr, Option<IpAddr>, Vec<u8>)>,
+    /// transport data, which a retransmit recovers if it is lost
+    bulk: VecDeque<(SocketAddr, Option<IpAddr>, Vec<u8>)>,
+    /// read beyond a full batch when its kind blocks, it is handled last
+    overflow: Option<(SocketAddr, Option<IpAddr>, Vec<u8>)>,
+    /// transport data is shared fairly between sessions, see [`RouterBuilder::fair_queuing`]
+    fair: bool,
+}
+
+impl Batch {
+    fn new(fair: bool) -> Self {
+        Batch {
+            fair,
+            ..Default::default()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.handshakes.len() + self.bulk.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push(&mut self, bulk: bool, source: SocketAddr, local: Option<IpAddr>, data: Vec<u8>) {
+        self.queue(bulk).push_back((source, local, data));
+    }
+
+    /// The oldest datagram of a kind, of transport data that of the session with the most bytes
+    /// queued if it is shared fairly
+    fn pop_oldest(&mut self, bulk: bool) -> Option<(SocketAddr, Option<IpAddr>, Vec<u8>)> {
+        if !(bulk && self.fair) {
+            return self.queue(bulk).pop_front();
+        }
+        let mut queued: HashMap<Option<Identity>, usize> = HashMap::new();
+        for (_, _, data) in &self.bulk {
+            *queued.entry(receiver(data)).or_default() += data.len();
+        }
+        let (largest, _) = queued.into_iter().max_by_key(|(_, bytes)| *bytes)?;
+        let position = self
+            .bulk
+            .iter()
+            .position(|(_, _, data)| receiver(data) == largest)?;
+        self.bulk.remove(position)
+    }
+
+    fn queue(&mut self, bulk: bool) -> &mut VecDeque<(SocketAddr, Option<IpAddr>, Vec<u8>)> {
+        match bulk {
+            true => &mut self.bulk,
+            false => &mut self.handshakes,
+        }
+    }
+
+    /// The handshakes in the order they arrived, then the transport data, by deficit round-robin
+    /// across sessions if it is shared fairly
+    fn drain(self) -> impl Iterator<Item = (SocketAddr, Option<IpAddr>, Vec<u8>)> {
+        let bulk = match self.fair {
+            true => round_robin(self.bulk),
+            false => self.bulk,
+        };
+        self.handshakes.into_iter().chain(bulk).chain(self.overflow)
+    }
+}
+
+/// Orders transport data by deficit round-robin across the sessions it is for: each round, each
+/// session forwards its datagrams in the order they arrived while they fit its
+/// [`FAIR_QUEUING_QUANTUM`] and what it left over of earlier rounds
+///
+/// A session sending a few small datagrams thereby gets them all out in the first round,
+/// however much another one queued.
+fn round_robin(
+    bulk: VecDeque<(SocketAddr, Option<IpAddr>, Vec<u8>)>,
+) -> VecDeque<(SocketAddr, Option<IpAddr>, Vec<u8>)> {
+    let mut ordered = VecDeque::with_capacity(bulk.len());
+    // deficit and queue by session, in the order of their first datagram
+    let mut flows: Vec<(usize, VecDeque<_>)> = Vec::new();
+    let mut by_receiver: HashMap<Option<Identity>, usize> = HashMap::new();
+    for queued in bulk {
+        let flow = *by_receiver.entry(receiver(&queued.2)).or_insert_with(|| {
+            flows.push((0, VecDeque::new()));
+            flows.len() - 1
+        });
+        flows[flow].1.push_back(queued);
+    }
+    while !flows.is_empty() {
+        for (deficit, queue) in &mut flows {
+            *deficit += FAIR_QUEUING_QUANTUM;
+            while let Some((_, _, data)) = queue.front()
+                && data.len() <= *deficit
+            {
+                *deficit -= data.len();
+                ordered.extend(queue.pop_front());
+            }
+        }
+        // a session whose queue ran dry keeps no credit
+        flows.retain(|(_, queue)| !queue.is_empty());
+    }
+    ordered
+}
+
+/// The receiver index of transport data, which is wrapped in a SOCKS5 header if it came from a
+/// relay
+fn receiver(data: &[u8]) -> Option<Identity> {
+    let offset = match data.first() == Some(&MessageType::TransportData.code()) {
+        true => 0,
+        false => socks::unwrap(data).ok()?.1,
+    };
+    let receiver: [u8; 4] = data.get(offset + 4..offset + 8)?.try_into().ok()?;
+    Some(Identity(receiver))
+}
+
+/// Called periodically from the receive loop, proving it is not stuck
+type Heartbeat = (Duration, Box<dyn FnMut() + Send + Sync>);
+
+/// Configures a [`Router`], every parameter has a default suitable for the binary
+pub struct RouterBuilder<T> {
+    transport: T,
+    policy: Arc<dyn RoutingPolicy>,
+    metrics: Arc<Metrics>,
+    buffer_size: usize,
+    max_datagram_size: Option<usize>,
+    batch_size: usize,
+    backpressure: BackpressurePolicy,
+    max_sessions: Option<usize>,
+    session_timeout: Duration,
+    handshake_timeout: Duration,
+    rekey_threshold: usize,
+    timelines: usize,
+    race_initiations: bool,
+    fair_queuing: bool,
+    memory_limit: Option<usize>,
+    chaos: Option<Chaos>,
+    unmatched_data: UnmatchedData,
+    affinity: Option<Arc<AffinityTable>>,
+    pins: Pins,
+    denylist: Denylist,
+    horizons: Vec<Horizon>,
+    honeypot: Option<Honeypot>,
+    health: Thresholds,
+    outliers: Option<OutlierDetection>,
+    heartbeat: Option<Heartbeat>,
+    timezone: Option<chrono_tz::Tz>,
+    max_panics: Option<u64>,
+}
+
+impl<T: PacketTransport> RouterBuilder<T> {
+    /// Replaces the default [`FirstMatch`] backend selection
+    pub fn policy(mut self, policy: impl RoutingPolicy + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Counters to update, so the caller can report on them while the router runs
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Size of the receive buffer, datagrams longer than this are counted as truncated and dropped
+    ///
+    /// # Panics
+    ///
+    /// If `buffer_size` is 0, which would drop every datagram.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        assert!(buffer_size > 0, "the buffer size must be positive");
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Drops datagrams longer than `size` before they are parsed, counting them as oversized
+    ///
+    /// Unlike the buffer size this bounds what is routed, e.g. to the path MTU of the backends,
+    /// while datagrams up to the buffer size are still read whole and accounted for.
+    pub fn max_datagram_size(mut self, size: usize) -> Self {
+        self.max_datagram_size = Some(size);
+        self
+    }
+
+    /// Reads up to `batch_size` datagrams that are already waiting before handling them,
+    /// handshake messages first
+    ///
+    /// Under load, when datagrams queue up, handshakes are thereby not delayed or dropped behind
+    /// bulk transport data: a lost handshake costs its client a 5 second retry, a lost data packet
+    /// only a retransmit. A batch size of 1 handles every datagram in the order it arrived,
+    /// unless [`backpressure`](Self::backpressure) drops some.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Decides per kind of message what becomes of datagrams read while the batch is full,
+    /// by default reading stops until the batch is handled
+    pub fn backpressure(mut self, backpressure: BackpressurePolicy) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Forwards the transport data of a batch by deficit round-robin across sessions rather than
+    /// in the order it arrived, and makes room in a full batch at the expense of the session with
+    /// the most bytes queued
+    ///
+    /// Batches only fill while the router can't keep up, e.g. as its sends to a saturated path
+    /// block, so then one client's bulk transfer can't hold up the sessions sharing the router.
+    pub fn fair_queuing(mut self, fair: bool) -> Self {
+        self.fair_queuing = fair;
+        self
+    }
+
+    /// Drops initiations for new sessions while `max_sessions` are tracked, each counted once
+    /// although it is tracked by the indices of both the client and the backend
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// Forgets sessions that carried transport data once no packet was routed for them within
+    /// `timeout`
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = timeout;
+        self
+    }
+
+    /// Forgets sessions that carried no transport data yet, e.g. whose initiation went unanswered,
+    /// once no packet was routed for them within `timeout`
+    ///
+    /// This is cut to the session timeout if that is shorter.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Flags client tunnels that start more than `threshold` handshakes within [`REKEY_WINDOW`],
+    /// a sign of packet loss, a broken backend or abuse
+    pub fn rekey_threshold(mut self, threshold: usize) -> Self {
+        self.rekey_threshold = threshold;
+        self
+    }
+
+    /// Forwards the initiation of a new session to all the backends it may go to at once, the
+    /// session going to whichever responds first while the responses of the others are dropped
+    ///
+    /// This picks the currently fastest backend of a pool sharing a key, at the cost of the
+    /// others each doing a handshake for nothing. It replaces the policy's selection, but not
+    /// affinity.
+    pub fn race_initiations(mut self, race: bool) -> Self {
+        self.race_initiations = race;
+        self
+    }
+
+    /// Drops, duplicates, reorders and delays the packets forwarded as `chaos` says, to test how
+    /// clients, backends and monitoring cope with a bad network
+    ///
+    /// Never enable this on a router carrying real traffic.
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Keeps the approximate memory held by sessions, timelines, affinity and queues below
+    /// `bytes`, to stay predictable under a flood of initiations
+    ///
+    /// The queues take a fixed share of it, see [`MemoryUsage::queues`], and the rest is room
+    /// for the state. Once 80% of the room is used, garbage collection forgets sessions after
+    /// half their timeout. At the limit, a new session first evicts the sessions that carried no
+    /// data yet, then the longest idle ones, and then the oldest timelines, until a tenth of the
+    /// room is free, sparing pinned clients. Its initiation is dropped if that is not enough.
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Keeps the timelines of the latest `sessions` sessions, their handshakes, endpoint changes,
+    /// drops and end, 0 turning them off
+    ///
+    /// They are looked up with [`SessionTable::timeline`].
+    pub fn timelines(mut self, sessions: usize) -> Self {
+        self.timelines = sessions;
+        self
+    }
+
+    /// Handles transport data matching no session as `unmatched_data` says, instead of dropping it
+    pub fn unmatched_data(mut self, unmatched_data: UnmatchedData) -> Self {
+        self.unmatched_data = unmatched_data;
+        self
+    }
+
+    /// Routes clients back to the backend recorded in `affinity`, ahead of the policy, and records new sessions in it
+    pub fn affinity(mut self, affinity: Arc<AffinityTable>) -> Self {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    /// Pins `client` to `backend` from the start, see [`Router::pins`]
+    pub fn pin(self, client: IpAddr, backend: SocketAddr) -> Self {
+        self.pins.pin(client, backend);
+        self
+    }
+
+    /// Drops the datagrams of clients within `networks` from the start, see [`Router::denylist`]
+    ///
+    /// They are the networks of the `config` source, replacing those of an earlier call.
+    pub fn deny(self, networks: Vec<Network>) -> Self {
+        self.denylist.set(CONFIG_DENYLIST, networks);
+        self
+    }
+
+    /// Splits the peers by the local address initiations are received on, see [`Horizon`]
+    pub fn horizons(mut self, horizons: Vec<Horizon>) -> Self {
+        self.horizons = horizons;
+        self
+    }
+
+    /// Routes initiations matching no peer to a honeypot, see [`Honeypot`]
+    pub fn honeypot(mut self, honeypot: Honeypot) -> Self {
+        self.honeypot = Some(honeypot);
+        self
+    }
+
+    /// When backends are considered down after failed sends, and tried again
+    pub fn health(mut self, thresholds: Thresholds) -> Self {
+        self.health = thresholds;
+        self
+    }
+
+    /// Ejects backends whose handshakes go unanswered far more often than those of the other
+    /// backends with the same pubkey, see [`OutlierDetection`]
+    pub fn outlier_detection(mut self, detection: OutlierDetection) -> Self {
+        self.outliers = Some(detection);
+        self
+    }
+
+    /// Calls `beat` every `interval` from [`Router::run`], e.g. to feed a service manager watchdog
+    ///
+    /// The calls stop when the loop stalls, as they share its task.
+    pub fn heartbeat(
+        mut self,
+        interval: Duration,
+        beat: impl FnMut() + Send + Sync + 'static,
+    ) -> Self {
+        self.heartbeat = Some((interval, Box::new(beat)));
+        self
+    }
+
+    /// Reads the cron expressions of maintenance windows and schedule rules in `timezone` rather
+    /// than the local time zone
+    pub fn timezone(mut self, timezone: chrono_tz::Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Has [`Router::run`] fail once handling datagrams panicked `max_panics` times, rather than
+    /// dropping every such datagram and routing on
+    ///
+    /// A panic may leave state behind that breaks routing in subtler ways, so a service manager
+    /// restarting the router can be the safer choice.
+    pub fn max_panics(mut self, max_panics: u64) -> Self {
+        self.max_panics = Some(max_panics);
+        self
+    }
+
+    pub fn build(self) -> Router<T> {
         Router {
-            socket,
-            to_process: None,
-            sessions: Default::default(),
+            transport: self.transport,
+            policy: self.policy,
+            metrics: self.metrics,
+            buffer_size: self.buffer_size,
+            max_datagram_size: self.max_datagram_size,
+            batch_size: self.batch_size,
+            backpressure: self.backpressure,
+            max_sessions: self.max_sessions,
+            session_timeout: self.session_timeout,
+            handshake_timeout: self.handshake_timeout.min(self.session_timeout),
+            rekey_threshold: self.rekey_threshold,
+            race_initiations: self.race_initiations,
+            fair_queuing: self.fair_queuing,
+            memory_limit: self.memory_limit,
+            chaos: self.chaos,
+            delayed: Default::default(),
+            unmatched_data: self.unmatched_data,
+            affinity: self.affinity,
+            horizons: self.horizons,
+            honeypot: self.honeypot.map(|honeypot| {
+                let name = Some("honeypot".to_string());
+                (
+                    Peer::new(honeypot.address, [0; 32]).with_name(name),
+                    honeypot.sample_rate,
+                )
+            }),
+            health: Arc::new(Health::new(self.health)),
+            outliers: self.outliers,
+            lockdown: Lockdown::default(),
+            pins: self.pins,
+            denylist: self.denylist,
+            unmatched_log: Default::default(),
+            sessions: Arc::new(Mutex::new(Sessions {
+                timelines: Timelines::new(self.timelines),
+                ..Default::default()
+            })),
+            associations: Default::default(),
+            proxied: Default::default(),
+            names: Default::default(),
+            configured: Vec::new(),
+            peers: Vec::new(),
+            drained: Default::default(),
+            scheduled: Default::default(),
+            schedule_minute: None,
+            timezone: self.timezone,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            gc: mpsc::channel(1),
+            explain_requests: mpsc::channel(16),
+            injected: mpsc::channel(16),
+            heartbeat: self.heartbeat,
+            panics: AtomicU64::new(0),
+            max_panics: self.max_panics,
+        }
+    }
+}
+
+impl<T: PacketTransport> Router<T> {
+    pub fn new(transport: T) -> Self {
+        Self::builder(transport).build()
+    }
+
+    pub fn builder(transport: T) -> RouterBuilder<T> {
+        RouterBuilder {
+            transport,
+            policy: Arc::new(FirstMatch),
+            metrics: Default::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_datagram_size: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            backpressure: BackpressurePolicy::default(),
+            max_sessions: None,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            rekey_threshold: DEFAULT_REKEY_THRESHOLD,
+            timelines: DEFAULT_TIMELINES,
+            race_initiations: false,
+            fair_queuing: false,
+            memory_limit: None,
+            chaos: None,
+            unmatched_data: UnmatchedData::Drop,
+            affinity: None,
+            pins: Pins::default(),
+            denylist: Denylist::default(),
+            horizons: Vec::new(),
+            honeypot: None,
+            health: Thresholds::default(),
+            outliers: None,
+            heartbeat: None,
+            timezone: None,
+            max_panics: None,
+        }
+    }
+
+    /// The counters this router updates
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// The sessions this router tracks, which can be listed while it runs
+    pub fn session_table(&self) -> SessionTable {
+        SessionTable {
+            sessions: self.sessions.clone(),
+            max_sessions: self.max_sessions,
+            memory: self.memory_meter(),
+            honeypot: self.honeypot.as_ref().map(|(honeypot, _)| honeypot.address),
+        }
+    }
+
+    /// The health of the backends as judged from sending to them, which can be inspected while
+    /// the router runs
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    /// A switch that, while on, drops the initiations of new tunnels to freeze the client
+    /// population, e.g. during an incident or migration
+    ///
+    /// Transport data of established sessions is still routed, as are the initiations of client
+    /// endpoints with an established session, so their tunnels survive rekeying.
+    pub fn lockdown(&self) -> Lockdown {
+        self.lockdown.clone()
+    }
+
+    /// The backends clients are pinned to, which can be changed while the router runs
+    ///
+    /// The new sessions of a pinned client go to its backend whenever its key matches, even
+    /// with affinity, a policy or others of the pool to choose from, and its sessions are kept
+    /// as the backend goes down. They wait rather than fail over, e.g. to debug a client or
+    /// keep a customer on a dedicated backend.
+    pub fn pins(&self) -> Pins {
+        self.pins.clone()
+    }
+
+    /// The networks clients are refused from, which can be changed while the router runs
+    ///
+    /// Every datagram from within them is dropped, including the transport data of sessions
+    /// that were established before, e.g. to act on a threat intelligence feed. Datagrams of
+    /// backends are never dropped, so a feed listing one can't cut off its sessions.
+    pub fn denylist(&self) -> Denylist {
+        self.denylist.clone()
+    }
+
+    /// A handle measuring the memory the router holds while it runs
+    pub fn memory_meter(&self) -> MemoryMeter {
+        MemoryMeter {
+            sessions: self.sessions.clone(),
+            affinity: self.affinity.clone(),
+            queues: self.queue_bytes(),
+            limit: self.memory_limit,
+        }
+    }
+
+    /// At most the receive buffers and the event queue hold
+    fn queue_bytes(&self) -> usize {
+        (self.batch_size + 2) * self.buffer_size + EVENT_CAPACITY * size_of::<RouterEvent>()
+    }
+
+    fn memory_usage(&self, sessions: &Sessions) -> MemoryUsage {
+        memory_usage(sessions, self.affinity.as_deref(), self.queue_bytes())
+    }
+
+    /// Evicts sessions and then timelines until the memory held is below `target`, the sessions
+    /// that carried no data yet first as a flood of initiations leaves those, then the longest
+    /// idle, sparing pinned clients
+    fn shed_sessions(&self, sessions: &mut Sessions, target: usize) {
+        let mut victims: Vec<(bool, Instant, Identity)> = sessions
+            .iter()
+            .filter(|(_, session)| session.initiated())
+            .filter(|(_, session)| self.pins.get(session.from.ip()).is_none())
+            .map(|(index, session)| {
+                let carried_data = session.carried_data.load(Ordering::Relaxed);
+                (carried_data, session.last_seen, *index)
+            })
+            .collect();
+        victims.sort_unstable_by_key(|(carried_data, last_seen, _)| (*carried_data, *last_seen));
+        let mut evicted = 0;
+        for (_, _, index) in victims {
+            if self.memory_usage(sessions).total() < target {
+                break;
+            }
+            let Some(session) = sessions.remove(&index) else {
+                continue;
+            };
+            if let Some(answer) = session.answered {
+                sessions.remove(&answer);
+            }
+            debug!(parent: &session.span, "session evicted at the memory limit");
+            sessions.timelines.record(index, TimelineEvent::Evicted);
+            evicted += 1;
+        }
+        while self.memory_usage(sessions).total() >= target && sessions.timelines.forget_oldest() {}
+        if evicted > 0 {
+            tracing::warn!("evicted {} sessions at the memory limit", evicted);
+            self.metrics.sessions_shed(evicted);
+        }
+    }
+
+    /// A handle asking how the router would route a datagram while it runs
+    pub fn explainer(&self) -> Explainer {
+        Explainer(self.explain_requests.0.clone())
+    }
+
+    /// A handle feeding crafted datagrams to the router while it runs
+    pub fn injector(&self) -> Injector {
+        Injector(self.injected.0.clone())
+    }
+
+    /// A handle to run garbage collection of the sessions while the router runs
+    pub fn gc_trigger(&self) -> GcTrigger {
+        GcTrigger(self.gc.0.clone())
+    }
+
+    /// Receives an event for every routing outcome from now on
+    ///
+    /// Subscribers that fall behind by more than [`EVENT_CAPACITY`] events miss the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<RouterEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: impl FnOnce() -> RouterEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event());
+        }
+    }
+
+    fn drop_packet(&self, message: Option<MessageType>, source: SocketAddr, reason: DropReason) {
+        debug!("dropping {:?} from {}: {}", message, source, reason);
+        self.metrics.dropped();
+        self.emit(|| RouterEvent::Dropped {
+            message,
+            source,
+            reason,
+        });
+    }
+
+    /// Adds `event` to the timeline of the session with either index `index`
+    async fn record_timeline(&self, index: Identity, event: TimelineEvent) {
+        self.sessions.lock().await.timelines.record(index, event);
+    }
+
+    /// Annotates the current packet span with the session the packet belongs to, linking it to
+    /// the session's span if it `changed` the session
+    ///
+    /// Handshakes and the transport data confirming or moving a session are linked, the rest of
+    /// the transport data isn't, which would pile up a link per packet on the session's span.
+    fn record_session(&self, session: &Session, changed: bool) {
+        let span = Span::current();
+        span.record("backend", display(session.backend));
+        if let Some(name) = self.names.get(&session.backend) {
+            span.record("peer", name.as_str());
         }
+        if changed {
+            span.follows_from(&session.span);
+        }
+    }
+
+    /// Replaces the peers initiations are routed to
+    pub async fn set_peers(&mut self, peers: Vec<Peer>) {
+        self.names = peers
+            .iter()
+            .filter_map(|p| p.name.clone().map(|name| (p.address, name)))
+            .collect();
+        self.metrics.set_peer_names(self.names.clone());
+        self.transport.set_backend_egress(
+            peers
+                .iter()
+                .map(|p| {
+                    let egress = Egress {
+                        fwmark: p.fwmark,
+                        interface: p.interface.clone(),
+                        source: p.source,
+                        tunnel: p.tunnel.clone(),
+                        quic: p.quic,
+                    };
+                    (p.address, egress)
+                })
+                .filter(|(_, egress)| *egress != Egress::default())
+                .collect(),
+        );
+        self.refresh_proxies(&peers);
+        self.configured = peers;
+        self.schedule_minute = None;
+        self.update_schedules(chrono::Utc::now());
     }
 
-    async fn handle_packet(&self, size: usize, peer: SocketAddr, data: &[u8], peers: &[Peer]) {
-        if !is_wg_packet(size, &data) {
+    /// Drains the backends whose maintenance window contains `now` and restores the others, and
+    /// gives each backend the settings of its first schedule rule open at `now`, if any
+    ///
+    /// Windows have a resolution of a minute, so this does nothing if called again within the same minute.
+    fn update_schedules(&mut self, now: chrono::DateTime<chrono::Utc>) {
+        let minute = now.timestamp().div_euclid(60);
+        if self.schedule_minute == Some(minute) {
             return;
         }
+        self.schedule_minute = Some(minute);
+        let open = |window: &Window| match self.timezone {
+            Some(timezone) => window.contains(now.with_timezone(&timezone)),
+            None => window.contains(now.with_timezone(&chrono::Local)),
+        };
+        let drained: HashSet<SocketAddr> = self
+            .configured
+            .iter()
+            .filter(|p| p.maintenance.iter().any(open))
+            .map(|p| p.address)
+            .collect();
+        let mut scheduled = HashSet::new();
+        let mut peers = Vec::with_capacity(self.configured.len());
+        for peer in &self.configured {
+            let address = &peer.address;
+            match (self.drained.contains(address), drained.contains(address)) {
+                (false, true) => tracing::info!("backend {} entered maintenance, draining", peer),
+                (true, false) => tracing::info!("backend {} left maintenance", peer),
+                _ => {}
+            }
+            let rule = peer.schedule.iter().find(|rule| open(&rule.window));
+            match (self.scheduled.contains(address), rule) {
+                (false, Some(rule)) => tracing::info!(
+                    "backend {} follows its schedule from {}",
+                    peer,
+                    rule.window.schedule
+                ),
+                (true, None) => tracing::info!("backend {} is back to its own settings", peer),
+                _ => {}
+            }
+            match rule {
+                Some(rule) => {
+                    scheduled.insert(*address);
+                    peers.push(rule.apply(peer));
+                }
+                None => peers.push(peer.clone()),
+            }
+        }
+        self.drained = drained;
+        self.scheduled = scheduled;
+        self.peers = peers;
+    }
+
+    /// Associates with all proxies referenced by `peers` in the background, replacing ones
+    /// whose control connection was closed
+    ///
+    /// Datagrams to the backends behind a proxy are dropped until it is associated with, so a
+    /// proxy that doesn't answer never holds up routing.
+    fn refresh_proxies(&mut self, peers: &[Peer]) {
+        self.proxied = peers
+            .iter()
+            .filter_map(|p| p.proxy.map(|proxy| (p.address, proxy)))
+            .collect();
+
+        let proxied = &self.proxied;
+        let mut associations = self.associations.write().unwrap();
+        associations.retain(|proxy, association| {
+            association.as_ref().is_none_or(|a| !a.is_closed())
+                && proxied.values().any(|p| p == proxy)
+        });
+
+        for proxy in self.proxied.values() {
+            if associations.contains_key(proxy) {
+                continue;
+            }
+            associations.insert(*proxy, None);
+            tokio::spawn(associate(self.associations.clone(), *proxy));
+        }
+    }
+
+    /// Whether `source` is the relay of a SOCKS5 association, whose datagrams carry the address
+    /// of the backend they originate from
+    fn is_relay(&self, source: SocketAddr) -> bool {
+        self.associations
+            .read()
+            .unwrap()
+            .values()
+            .flatten()
+            .any(|a| a.relay == source)
+    }
+
+    /// How the datagram of `request` would be routed now, going through the same steps
+    async fn explain(&self, request: &Datagram) -> Explanation {
+        let sessions = self.sessions.lock().await;
+        let mut explanation = Explanation {
+            message: None,
+            session: None,
+            matched: Vec::new(),
+            candidates: Vec::new(),
+            pinned: None,
+            lockdown: self.lockdown.is_active(),
+            sessions: sessions.initiated(),
+            max_sessions: self.max_sessions,
+            decision: Decision::Drop(DropReason::NotWireguard),
+        };
+        let data = &request.packet[..];
+        if self.is_oversized(data.len()) {
+            explanation.decision = Decision::Drop(DropReason::Oversized);
+            return explanation;
+        }
+        if self.is_denied(request.source) {
+            explanation.decision = Decision::Drop(DropReason::Denylisted);
+            return explanation;
+        }
+        if !is_wg_packet(data.len(), data) {
+            return explanation;
+        }
+        let packet = match WireguardPacket::parse(data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                explanation.decision = Decision::Drop(DropReason::Invalid(err));
+                return explanation;
+            }
+        };
+        explanation.message = Some(packet.message_type());
+        let (index, initiation) = match packet {
+            WireguardPacket::HandshakeInitiation(packet) => (packet.sender(), Some(packet)),
+            WireguardPacket::HandshakeResponse(packet) => (packet.receiver(), None),
+            WireguardPacket::CookieReply(packet) => (packet.receiver(), None),
+            WireguardPacket::TransportData(header, _) => (header.receiver(), None),
+            _ => {
+                explanation.decision = Decision::Drop(DropReason::NotWireguard);
+                return explanation;
+            }
+        };
+        if let Some(session) = sessions.get(&index) {
+            explanation.session = Some(SessionHit {
+                index,
+                client: session.client(),
+                backend: session.backend,
+            });
+            explanation.decision = Decision::Forward(match initiation {
+                Some(_) => session.to,
+                None => session.from,
+            });
+            return explanation;
+        }
+        let Some(packet) = initiation else {
+            explanation.decision = match self.unmatched_data {
+                UnmatchedData::Forward(backend)
+                    if explanation.message == Some(MessageType::TransportData)
+                        && !self.peers.iter().any(|p| p.address == request.source) =>
+                {
+                    Decision::Forward(backend)
+                }
+                _ => Decision::Drop(DropReason::NoSession),
+            };
+            return explanation;
+        };
+        let covered = &data[..HandshakeInitiation::MAC1_OFFSET];
+        let mut matched = self.matching(request.local, covered, packet.mac1());
+        if matched.is_empty() {
+            matched.extend(self.honeypot.as_ref().map(|(honeypot, _)| honeypot));
+        }
+        explanation.pinned = self.pins.get(request.source.ip());
+        let matched = self.pinned(request.source, matched);
+        let states = self.health.states();
+        explanation.matched = matched
+            .iter()
+            .map(|p| BackendCheck {
+                backend: p.address,
+                name: p.name.clone(),
+                sessions: sessions.backend_sessions(p.address),
+                max_sessions: p.max_sessions,
+                maintenance: self.drained.contains(&p.address),
+                state: states.get(&p.address).copied().unwrap_or(BackendState::Up),
+                primary: p.primary,
+                weight: p.weight,
+            })
+            .collect();
+        let refused = if explanation.lockdown && !has_tunnel(&sessions, request.source) {
+            Some(DropReason::Lockdown)
+        } else if self
+            .max_sessions
+            .is_some_and(|max| sessions.initiated() >= max)
+        {
+            Some(DropReason::SessionLimit)
+        } else if matched.is_empty() {
+            Some(DropReason::UnknownBackend)
+        } else {
+            None
+        };
+        if let Some(reason) = refused {
+            explanation.decision = Decision::Drop(reason);
+            return explanation;
+        }
+        let candidates = match self.narrow(&sessions, matched) {
+            Ok(candidates) => candidates,
+            Err(reason) => {
+                explanation.decision = Decision::Drop(reason);
+                return explanation;
+            }
+        };
+        explanation.candidates = candidates.iter().map(|p| p.address).collect();
+        let raced = self.racing(request.source, &candidates);
+        if !raced.is_empty() {
+            explanation.decision = Decision::Race(raced.iter().map(|p| p.address).collect());
+            return explanation;
+        }
+        let counts = session_counts(&sessions, &candidates);
+        drop(sessions);
+        let chosen = self
+            .choose(counts, request.source, packet.sender(), data, &candidates)
+            .await;
+        explanation.decision = match chosen {
+            Some(backend) => Decision::Forward(backend.address),
+            None => Decision::Drop(DropReason::RejectedByPolicy),
+        };
+        explanation
+    }
+
+    /// Whether a datagram of `size` bytes exceeds `max_datagram_size`
+    fn is_oversized(&self, size: usize) -> bool {
+        self.max_datagram_size.is_some_and(|max| size > max)
+    }
+
+    /// Whether datagrams from `source` are refused by the denylist, which spares backends
+    fn is_denied(&self, source: SocketAddr) -> bool {
+        self.denylist.contains(source.ip())
+            && !self
+                .peers
+                .iter()
+                .any(|p| p.address.ip().to_canonical() == source.ip().to_canonical())
+    }
+
+    /// The peers whose key matches the `mac1` of an initiation received on `local`, among those
+    /// reachable from there
+    fn matching(&self, local: Option<IpAddr>, covered: &[u8], mac1: &[u8; 16]) -> Vec<&Peer> {
+        self.peers
+            .iter()
+            .filter(|p| p.matching_key(covered, mac1).is_some())
+            .filter(|p| Horizon::reaches(&self.horizons, local, p))
+            .collect()
+    }
+
+    /// The backend `client` is pinned to if it is among the `candidates`, or all of them
+    fn pinned<'a>(&self, client: SocketAddr, candidates: Vec<&'a Peer>) -> Vec<&'a Peer> {
+        let Some(backend) = self.pins.get(client.ip()) else {
+            return candidates;
+        };
+        match candidates.iter().find(|p| p.address == backend) {
+            Some(pinned) => vec![*pinned],
+            None => candidates,
+        }
+    }
+
+    /// Narrows the peers matching an initiation down to the backends that may take its session
+    fn narrow<'a>(
+        &self,
+        sessions: &Sessions,
+        candidates: Vec<&'a Peer>,
+    ) -> Result<Vec<&'a Peer>, DropReason> {
+        let candidates: Vec<&Peer> = candidates
+            .into_iter()
+            .filter(|p| !self.drained.contains(&p.address))
+            .collect();
+        if candidates.is_empty() {
+            return Err(DropReason::Maintenance);
+        }
+        // full backends are passed over, other backends of the same peer may take the session
+        let candidates: Vec<&Peer> = candidates
+            .into_iter()
+            .filter(|p| {
+                p.max_sessions
+                    .is_none_or(|max| sessions.backend_sessions(p.address) < max)
+            })
+            .collect();
+        if candidates.is_empty() {
+            return Err(DropReason::PeerSessionLimit);
+        }
+        // backends that couldn't be reached or were ejected are avoided, unless none other is left
+        let up: Vec<&Peer> = candidates
+            .iter()
+            .copied()
+            .filter(|p| !self.health.is_down(p.address))
+            .filter(|p| !self.health.is_ejected(p.address))
+            .collect();
+        let candidates = match up.is_empty() {
+            true => candidates,
+            false => up,
+        };
+        // backends that just came back up take a growing share of the new sessions
+        let admitted: Vec<&Peer> = candidates
+            .iter()
+            .copied()
+            .filter(|p| self.health.admits(p.address))
+            .collect();
+        let candidates = match admitted.is_empty() {
+            true => candidates,
+            false => admitted,
+        };
+        // while a primary is left, the other backends only stand in for it
+        let primaries: Vec<&Peer> = candidates.iter().copied().filter(|p| p.primary).collect();
+        Ok(match primaries.is_empty() {
+            true => candidates,
+            false => primaries,
+        })
+    }
+
+    /// Picks the backend among `candidates` for the initiation `data` of `sender`, none if the
+    /// policy rejects it
+    ///
+    /// The policy is told the sessions of the candidates as counted beforehand, see
+    /// [`session_counts`], so the session table isn't locked while it decides. Policies that
+    /// [block](RoutingPolicy::blocks) decide on a blocking thread instead of the packet loop.
+    async fn choose<'a>(
+        &self,
+        counts: HashMap<SocketAddr, usize>,
+        source: SocketAddr,
+        sender: Identity,
+        data: &[u8],
+        candidates: &[&'a Peer],
+    ) -> Option<&'a Peer> {
+        // the client's backend may still hold its state, e.g. after a restart of the router
+        let preferred = self
+            .affinity
+            .as_ref()
+            .and_then(|affinity| affinity.get(source.ip()))
+            .and_then(|backend| candidates.iter().find(|p| p.address == backend));
+        if let Some(backend) = preferred {
+            return Some(backend);
+        }
+        let candidates = split(candidates);
+        if !self.policy.blocks() {
+            let initiation = Initiation {
+                source,
+                sender,
+                packet: data,
+                backend_sessions: &|backend| counts.get(&backend).copied().unwrap_or(0),
+            };
+            return self.policy.select(&initiation, &candidates);
+        }
+        let policy = self.policy.clone();
+        let peers: Vec<Peer> = candidates.iter().map(|p| (*p).clone()).collect();
+        let packet = data.to_vec();
+        let chosen = tokio::task::spawn_blocking(move || {
+            let initiation = Initiation {
+                source,
+                sender,
+                packet: &packet,
+                backend_sessions: &|backend| counts.get(&backend).copied().unwrap_or(0),
+            };
+            let peers: Vec<&Peer> = peers.iter().collect();
+            policy
+                .select(&initiation, &peers)
+                .map(|backend| backend.address)
+        })
+        .await;
+        match chosen {
+            Ok(chosen) => {
+                chosen.and_then(|address| candidates.into_iter().find(|p| p.address == address))
+            }
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => None,
+        }
+    }
+
+    /// The backends among `candidates` to race the initiation of `source` between, none unless
+    /// there is a choice to make
+    fn racing<'a>(&self, source: SocketAddr, candidates: &[&'a Peer]) -> Vec<&'a Peer> {
+        if !self.race_initiations || candidates.len() < 2 {
+            return Vec::new();
+        }
+        let preferred = self
+            .affinity
+            .as_ref()
+            .and_then(|affinity| affinity.get(source.ip()))
+            .is_some_and(|backend| candidates.iter().any(|p| p.address == backend));
+        match preferred {
+            true => Vec::new(),
+            false => candidates.to_vec(),
+        }
+    }
+
+    /// Forwards `forward.packet` of the session with `backend` unless the policy vetoes it
+    async fn forward(&self, forward: Forward<'_>, backend: SocketAddr) {
+        match self.policy.check_forward(&forward) {
+            Verdict::Forward => {}
+            Verdict::Annotate(note) => tracing::trace!(
+                "forwarding {:?} from {} to {}: {}",
+                forward.message,
+                forward.source,
+                forward.destination,
+                note
+            ),
+            Verdict::Drop(reason) => {
+                let reason = DropReason::Vetoed(reason);
+                self.record_timeline(
+                    forward.identity,
+                    TimelineEvent::Dropped {
+                        message: forward.message,
+                        reason: reason.clone(),
+                    },
+                )
+                .await;
+                return self.drop_packet(Some(forward.message), forward.source, reason);
+            }
+        }
+        let Some(chaos) = &self.chaos else {
+            return self.transmit(&forward, backend).await;
+        };
+        match chaos.fate() {
+            Fate::Drop => {
+                self.record_timeline(
+                    forward.identity,
+                    TimelineEvent::Dropped {
+                        message: forward.message,
+                        reason: DropReason::Chaos,
+                    },
+                )
+                .await;
+                self.drop_packet(Some(forward.message), forward.source, DropReason::Chaos)
+            }
+            Fate::Send { copies, delay } if delay.is_zero() => {
+                for _ in 0..copies {
+                    self.transmit(&forward, backend).await;
+                }
+            }
+            Fate::Send { copies, delay } => {
+                let packet = Delayed {
+                    message: forward.message,
+                    source: forward.source,
+                    destination: forward.destination,
+                    identity: forward.identity,
+                    backend,
+                    copies,
+                    packet: forward.packet.to_vec(),
+                };
+                let mut delayed = self.delayed.lock().unwrap_or_else(|e| e.into_inner());
+                delayed.push(Instant::now() + delay, packet);
+            }
+        }
+    }
+
+    /// Forwards the packets chaos held back that are due
+    async fn release_delayed(&self) {
+        let due = self
+            .delayed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .due(Instant::now());
+        for delayed in due {
+            let forward = Forward {
+                message: delayed.message,
+                source: delayed.source,
+                destination: delayed.destination,
+                identity: delayed.identity,
+                packet: &delayed.packet,
+            };
+            for _ in 0..delayed.copies {
+                self.transmit(&forward, delayed.backend).await;
+            }
+        }
+    }
+
+    /// Sends `forward.packet` of the session with `backend`, tracking the health of the backend
+    async fn transmit(&self, forward: &Forward<'_>, backend: SocketAddr) {
+        // a lost handshake costs the client a 5 second retry, a lost data packet only a retransmit
+        let retries = match forward.message {
+            MessageType::TransportData => 0,
+            _ => SEND_RETRIES,
+        };
+        let to_backend = forward.destination == backend;
+        let client = to_backend.then_some(forward.source);
+        let result = self
+            .send_to(forward.packet, forward.destination, client, retries)
+            .await;
+        match result {
+            Ok(()) => {
+                if to_backend && self.health.succeeded(backend) {
+                    tracing::info!("backend {} is reachable again", backend);
+                    self.emit(|| RouterEvent::BackendUp { backend });
+                }
+                let client = match to_backend {
+                    true => forward.source,
+                    false => forward.destination,
+                };
+                self.metrics
+                    .forwarded(backend, client.ip(), forward.message, forward.packet.len());
+                self.emit(|| RouterEvent::Forwarded {
+                    message: forward.message,
+                    source: forward.source,
+                    destination: forward.destination,
+                });
+            }
+            Err(failure) => {
+                if to_backend && !failure.transient {
+                    self.metrics.send_failed(backend);
+                    if self.health.failed(backend) {
+                        tracing::warn!("backend {} is down after repeated send failures", backend);
+                        self.emit(|| RouterEvent::BackendDown { backend });
+                        self.purge_sessions(backend).await;
+                    }
+                }
+                self.record_timeline(
+                    forward.identity,
+                    TimelineEvent::Dropped {
+                        message: forward.message,
+                        reason: failure.reason.clone(),
+                    },
+                )
+                .await;
+                self.drop_packet(Some(forward.message), forward.source, failure.reason)
+            }
+        }
+    }
+
+    async fn send_to(
+        &self,
+        data: &[u8],
+        addr: SocketAddr,
+        client: Option<SocketAddr>,
+        retries: u32,
+    ) -> Result<(), SendFailure> {
+        if let Some(proxy) = self.proxied.get(&addr) {
+            let relay = self
+                .associations
+                .read()
+                .unwrap()
+                .get(proxy)
+                .and_then(Option::as_ref)
+                .filter(|association| !association.is_closed())
+                .map(|association| association.relay);
+            return match relay {
+                Some(relay) => {
+                    let wrapped = socks::wrap(addr, data);
+                    self.send_direct(&wrapped, relay, None, retries).await
+                }
+                None => Err(SendFailure {
+                    reason: DropReason::NoProxyAssociation { proxy: *proxy },
+                    transient: false,
+                }),
+            };
+        }
+        self.send_direct(data, addr, client, retries).await
+    }
+
+    /// Sends `data` to `addr`, retrying up to `retries` times with a growing delay while the
+    /// error is transient
+    ///
+    /// Sends to backends on behalf of a `client` go through [`PacketTransport::send_to_backend`].
+    async fn send_direct(
+        &self,
+        data: &[u8],
+        addr: SocketAddr,
+        client: Option<SocketAddr>,
+        retries: u32,
+    ) -> Result<(), SendFailure> {
+        let mut delay = SEND_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let sent = match client {
+                Some(client) => self.transport.send_to_backend(data, addr, client).await,
+                None => self.transport.send_to(data, addr).await,
+            };
+            let source = match sent {
+                Ok(_) => return Ok(()),
+                Err(source) => source,
+            };
+            let transient = is_transient(&source);
+            if transient && attempt < retries {
+                debug!("retrying send to {} in {:?}: {}", addr, delay, source);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+                continue;
+            }
+            let kind = source.kind();
+            let err = Error::Send {
+                addr,
+                peer: self.names.get(&addr).cloned(),
+                source,
+            };
+            debug!("{}", Report(&err));
+            return Err(SendFailure {
+                reason: DropReason::SendFailed {
+                    destination: addr,
+                    kind,
+                },
+                transient,
+            });
+        }
+    }
+
+    /// Routes one datagram received from `source`, sending the result through the transport
+    ///
+    /// Embedders with their own receive loop call this directly after [`set_peers`](Self::set_peers).
+    /// It can't match any [`Horizon`], see [`process_packet_at`](Self::process_packet_at).
+    pub async fn process_packet(&self, source: SocketAddr, data: &[u8]) {
+        self.process_packet_at(source, None, data).await
+    }
+
+    /// Routes one datagram received from `source` on the local address `local`, if known
+    ///
+    /// This is what [`run`](Self::run) does for every datagram it receives.
+    #[tracing::instrument(
+        name = "packet",
+        level = "debug",
+        skip_all,
+        fields(source = %source, message = Empty, identity = Empty, peer = Empty, backend = Empty)
+    )]
+    pub async fn process_packet_at(
+        &self,
+        mut source: SocketAddr,
+        local: Option<IpAddr>,
+        data: &[u8],
+    ) {
+        let mut data = data;
+        self.metrics.received();
+
+        // packets from a SOCKS5 relay carry the backend address they originate from
+        if self.is_relay(source) {
+            match socks::unwrap(data) {
+                Ok((origin, offset)) => {
+                    source = origin;
+                    data = &data[offset..];
+                    Span::current().record("source", display(source));
+                }
+                Err(e) => {
+                    debug!("invalid datagram from SOCKS5 relay {}: {}", source, e);
+                    return self.drop_packet(None, source, DropReason::InvalidRelayHeader);
+                }
+            }
+        }
+        let size = data.len();
+        self.metrics.received_size(size);
+
+        if self.is_oversized(size) {
+            self.metrics.oversized();
+            return self.drop_packet(None, source, DropReason::Oversized);
+        }
+
+        if self.is_denied(source) {
+            self.metrics.denied();
+            return self.drop_packet(None, source, DropReason::Denylisted);
+        }
+
+        if !is_wg_packet(size, data) {
+            return self.drop_packet(None, source, DropReason::NotWireguard);
+        }
 
         let sessions = self.sessions.to_owned();
 
-        match WireguardPacket::try_from((data, size)) {
-            Ok(packet) => match packet {
-                WireguardPacket::HandshakeInitiation(packet) => {
-                    // tracing::trace!("processing initiation packet {:?}", packet);
-                    let mut sessions = sessions.lock().await;
-                    match sessions.get(&packet.sender).cloned() {
-                        Some(session) => {
-                            let _ = self.socket.send_to(&data[..size], session.1).await;
-                        }
-                        None => match peers.iter().find(|p| {
-                            let peer_mac =
-                                utils::mac(p.precomputed_hash_label_mac1.as_slice(), &data[..116]);
-                            tracing::trace!(
-                                "comparing {:?} to peer {:?}",
-                                &packet.mac1.as_slice(),
-                                &peer_mac
-                            );
-                            &packet.mac1 == &peer_mac
-                        }) {
-                            Some(backend) => {
-                                tracing::trace!("found backend with address {}", backend.address);
-                                sessions.insert(packet.sender, (peer, backend.address));
-                                tracing::trace!("forwarding");
-                                let _ = self.socket.send_to(&data[..size], backend.address).await;
-                            }
-                            None => debug!("dropping packet to unknown backend"),
-                        },
+        let packet = match WireguardPacket::parse(data) {
+            Ok(packet) => packet,
+            Err(err) => return self.drop_packet(None, source, DropReason::Invalid(err)),
+        };
+        let message = packet.message_type();
+        self.metrics.received_message(message);
+        Span::current().record("message", tracing::field::debug(message));
+        let forward = |destination, identity| Forward {
+            message,
+            source,
+            destination,
+            identity,
+            packet: data,
+        };
+        let dropped = |reason| self.drop_packet(Some(message), source, reason);
+        match packet {
+            WireguardPacket::HandshakeInitiation(packet) => {
+                Span::current().record("identity", display(packet.sender()));
+                let mut sessions = sessions.lock().await;
+                if let Some(session) = sessions.get_mut(&packet.sender()) {
+                    session.touch(data.len());
+                    self.record_session(session, true);
+                    let (to, backend) = (session.to, session.backend);
+                    // the race is still on until a backend responded
+                    let raced = match session.answered {
+                        Some(_) => Vec::new(),
+                        None => session.raced.clone(),
+                    };
+                    sessions
+                        .timelines
+                        .record(packet.sender(), TimelineEvent::Retransmit);
+                    drop(sessions);
+                    if raced.is_empty() {
+                        return self.forward(forward(to, packet.sender()), backend).await;
                     }
+                    for backend in raced {
+                        self.forward(forward(backend, packet.sender()), backend)
+                            .await;
+                    }
+                    return;
                 }
-                WireguardPacket::HandshakeResponse(packet) => {
-                    let mut sessions = sessions.lock().await;
-                    match sessions.get(&packet.receiver).cloned() {
-                        Some(session) => {
-                            sessions.insert(packet.sender, (peer, session.0));
-                            let _ = self.socket.send_to(&data[..size], session.0).await;
-                        }
-                        None => debug!("dropping response packet, no matching session"),
+                if self.lockdown.is_active() && !has_tunnel(&sessions, source) {
+                    self.metrics.locked_out();
+                    return dropped(DropReason::Lockdown);
+                }
+                if self
+                    .max_sessions
+                    .is_some_and(|max| sessions.initiated() >= max)
+                {
+                    self.metrics.session_limited();
+                    return dropped(DropReason::SessionLimit);
+                }
+                if let Some(limit) = self.memory_limit {
+                    if self.memory_usage(&sessions).total() >= limit {
+                        // a tenth of the room the queues leave is freed at once
+                        let room = limit.saturating_sub(self.queue_bytes());
+                        self.shed_sessions(&mut sessions, limit - room / 10);
+                    }
+                    if self.memory_usage(&sessions).total() >= limit {
+                        return dropped(DropReason::MemoryLimit);
                     }
                 }
-                WireguardPacket::CookieReply(packet) => {
-                    let sessions = sessions.lock().await;
-                    match sessions.get(&packet.receiver) {
-                        Some((client, _)) => {
-                            let _ = self.socket.send_to(&data[..size], client).await;
+                let covered = &data[..HandshakeInitiation::MAC1_OFFSET];
+                let candidates = self.matching(local, covered, packet.mac1());
+                let candidates = match (candidates.is_empty(), &self.honeypot) {
+                    (false, _) => candidates,
+                    (true, None) => return dropped(DropReason::UnknownBackend),
+                    (true, Some((honeypot, sample_rate))) => {
+                        self.metrics.honeypot_initiation();
+                        if (OsRng.next_u32() as f64 / u32::MAX as f64) < *sample_rate {
+                            let encode = |bytes: &[u8]| {
+                                base64::engine::general_purpose::STANDARD.encode(bytes)
+                            };
+                            tracing::info!(
+                                client = %source,
+                                local = ?local,
+                                sender = %packet.sender(),
+                                ephemeral = %encode(packet.ephemeral()),
+                                mac1 = %encode(packet.mac1()),
+                                mac2 = %encode(packet.mac2()),
+                                size,
+                                "initiation for no peer routed to the honeypot"
+                            );
+                        }
+                        vec![honeypot]
+                    }
+                };
+                let candidates = self.pinned(source, candidates);
+                let candidates = match self.narrow(&sessions, candidates) {
+                    Ok(candidates) => candidates,
+                    Err(reason) => {
+                        if reason == DropReason::PeerSessionLimit {
+                            self.metrics.session_limited();
+                        }
+                        return dropped(reason);
+                    }
+                };
+                let raced = self.racing(source, &candidates);
+                let chosen = match raced.first() {
+                    Some(first) => Some(*first),
+                    None => {
+                        // the policy may take a while, without holding up the session table
+                        let counts = session_counts(&sessions, &candidates);
+                        drop(sessions);
+                        let chosen = self
+                            .choose(counts, source, packet.sender(), data, &candidates)
+                            .await;
+                        sessions = self.sessions.lock().await;
+                        // the index may have been taken meanwhile, e.g. by an imported session
+                        if let Some(session) = sessions.get(&packet.sender()) {
+                            let (to, backend) = (session.to, session.backend);
+                            drop(sessions);
+                            return self.forward(forward(to, packet.sender()), backend).await;
                         }
-                        None => debug!("dropping cookie packet, no matching session"),
+                        chosen
                     }
+                };
+                let Some(backend) = chosen else {
+                    return dropped(DropReason::RejectedByPolicy);
+                };
+                // which of the backend's keys, as there are several while one is rotated
+                let pubkey = backend
+                    .matching_key(covered, packet.mac1())
+                    .map(|key| base64::engine::general_purpose::STANDARD.encode(key));
+                let span = tracing::info_span!(
+                    parent: None,
+                    "session",
+                    client = %source,
+                    client_index = %packet.sender(),
+                    backend = %backend.address,
+                    peer = backend.name.as_deref(),
+                    pubkey = pubkey.as_deref(),
+                    backend_index = Empty,
+                );
+                span.follows_from(Span::current());
+                // racing, the first backend only stands in until one responds
+                let mut session = Session::new(source, backend.address, backend.address, span);
+                session.raced = raced.iter().map(|p| p.address).collect();
+                session.touch(data.len());
+                session.handshakes = tunnel_handshakes(&sessions, source, backend.address);
+                if session.handshakes.len() == self.rekey_threshold + 1 {
+                    tracing::info!(
+                        parent: &session.span,
+                        "tunnel started {} handshakes within {}s",
+                        session.handshakes.len(),
+                        REKEY_WINDOW.as_secs()
+                    );
+                    self.metrics.excessive_rekey();
+                    self.emit(|| RouterEvent::ExcessiveRekeys {
+                        client: source,
+                        backend: backend.address,
+                        handshakes: session.handshakes.len(),
+                    });
                 }
-                WireguardPacket::TransportData((header, _, _)) => {
-                    let sessions = sessions.lock().await;
-                    match sessions.get(&header.receiver) {
-                        Some(session) => {
-                            let _ = self.socket.send_to(&data[..size], session.1).await;
+                self.record_session(&session, true);
+                tracing::debug!(parent: &session.span, "session created");
+                sessions.insert(packet.sender(), session);
+                sessions
+                    .timelines
+                    .start(packet.sender(), source, backend.address);
+                drop(sessions);
+                self.metrics.session_created();
+                if raced.is_empty() {
+                    self.session_created(source, backend.address, packet.sender());
+                    return self
+                        .forward(forward(backend.address, packet.sender()), backend.address)
+                        .await;
+                }
+                for backend in raced {
+                    self.forward(forward(backend.address, packet.sender()), backend.address)
+                        .await;
+                }
+            }
+            WireguardPacket::HandshakeResponse(packet) => {
+                Span::current().record("identity", display(packet.receiver()));
+                let mut sessions = sessions.lock().await;
+                let Some(session) = sessions.get_mut(&packet.receiver()) else {
+                    let event = TimelineEvent::Dropped {
+                        message,
+                        reason: DropReason::NoSession,
+                    };
+                    sessions.timelines.record(packet.receiver(), event);
+                    return dropped(DropReason::NoSession);
+                };
+                // a backend that lost the race, whose response would only confuse the client
+                if session.answered.is_some()
+                    && session.backend != source
+                    && session.raced.contains(&source)
+                {
+                    self.metrics
+                        .handshake_rtt(source, session.created.elapsed());
+                    self.metrics.race_lost();
+                    let event = TimelineEvent::Dropped {
+                        message,
+                        reason: DropReason::LostRace,
+                    };
+                    sessions.timelines.record(packet.receiver(), event);
+                    return dropped(DropReason::LostRace);
+                }
+                // the first backend to respond wins it
+                let won = session.answered.is_none() && session.raced.contains(&source);
+                let first = session.backend;
+                if won {
+                    session.to = source;
+                    session.backend = source;
+                    session.sender = source;
+                    session.span.record("backend", display(source));
+                }
+                session.touch(data.len());
+                self.record_session(session, true);
+                // the initiation was forwarded when the client's session was created
+                if session.backend == source && session.to == source {
+                    self.metrics
+                        .handshake_rtt(source, session.created.elapsed());
+                }
+                let client = session.from;
+                session
+                    .span
+                    .record("backend_index", display(packet.sender()));
+                tracing::debug!(parent: &session.span, "session established");
+                session.answered = Some(packet.sender());
+                let answer = session.answer(source);
+                sessions.insert(packet.sender(), answer);
+                if won {
+                    sessions.reassign(first, source);
+                }
+                let timelines = &mut sessions.timelines;
+                if won {
+                    timelines.assign(packet.receiver(), source);
+                }
+                timelines.alias(packet.sender(), packet.receiver());
+                let backend_index = packet.sender();
+                timelines.record(packet.receiver(), TimelineEvent::Response { backend_index });
+                drop(sessions);
+                if won {
+                    self.session_created(client, source, packet.receiver());
+                }
+                self.policy.on_session(&SessionEvent::Established {
+                    client,
+                    backend: source,
+                    client_index: packet.receiver(),
+                    backend_index: packet.sender(),
+                });
+                self.emit(|| RouterEvent::SessionEstablished {
+                    client,
+                    backend: source,
+                    client_index: packet.receiver(),
+                    backend_index: packet.sender(),
+                });
+                self.forward(forward(client, packet.sender()), source).await;
+            }
+            WireguardPacket::CookieReply(packet) => {
+                Span::current().record("identity", display(packet.receiver()));
+                let mut sessions = sessions.lock().await;
+                let client = sessions.get(&packet.receiver()).map(|session| {
+                    self.record_session(session, true);
+                    (session.from, session.backend)
+                });
+                let event = match client {
+                    Some(_) => TimelineEvent::CookieReply,
+                    None => TimelineEvent::Dropped {
+                        message,
+                        reason: DropReason::NoSession,
+                    },
+                };
+                sessions.timelines.record(packet.receiver(), event);
+                drop(sessions);
+                match client {
+                    Some((client, backend)) => {
+                        self.forward(forward(client, packet.receiver()), backend)
+                            .await
+                    }
+                    None => dropped(DropReason::NoSession),
+                }
+            }
+            WireguardPacket::TransportData(header, _) => {
+                Span::current().record("identity", display(header.receiver()));
+                // the receiver index is owned by the peer that allocated it, the From side
+                let mut sessions = sessions.lock().await;
+                let owner = sessions.get_mut(&header.receiver()).map(|session| {
+                    session.touch(data.len());
+                    let confirmed = !session.carried_data.swap(true, Ordering::Relaxed);
+                    let moved = (session.sender != source)
+                        .then(|| std::mem::replace(&mut session.sender, source));
+                    self.record_session(session, confirmed || moved.is_some());
+                    (session.from, session.backend, moved)
+                });
+                let event = match owner {
+                    Some((_, _, Some(from))) => {
+                        Some(TimelineEvent::EndpointChanged { from, to: source })
+                    }
+                    Some(_) => None,
+                    None => match self.unmatched_data {
+                        // a client's packet goes to the default backend below
+                        UnmatchedData::Forward(_)
+                            if !self.peers.iter().any(|p| p.address == source) =>
+                        {
+                            None
+                        }
+                        _ => Some(TimelineEvent::Dropped {
+                            message,
+                            reason: DropReason::NoSession,
+                        }),
+                    },
+                };
+                if let Some(event) = event {
+                    sessions.timelines.record(header.receiver(), event);
+                }
+                drop(sessions);
+                let owner = owner.map(|(owner, backend, _)| (owner, backend));
+                match owner {
+                    Some((owner, backend)) => {
+                        self.forward(forward(owner, header.receiver()), backend)
+                            .await
+                    }
+                    None => {
+                        self.metrics.unmatched_data();
+                        match self.unmatched_data {
+                            // a backend's packets would only bounce between backends
+                            UnmatchedData::Forward(backend)
+                                if !self.peers.iter().any(|p| p.address == source) =>
+                            {
+                                debug!("forwarding unmatched transport data to {}", backend);
+                                self.forward(forward(backend, header.receiver()), backend)
+                                    .await
+                            }
+                            UnmatchedData::Log => {
+                                self.log_unmatched(source, header.receiver());
+                                dropped(DropReason::NoSession)
+                            }
+                            _ => dropped(DropReason::NoSession),
                         }
-                        None => {}
                     }
                 }
+            }
+            // a message type this router doesn't know how to route
+            _ => dropped(DropReason::NotWireguard),
+        }
+    }
+
+    /// Tells affinity, the policy and subscribers that the session of `client_index` goes to
+    /// `backend`
+    fn session_created(&self, client: SocketAddr, backend: SocketAddr, client_index: Identity) {
+        if let Some(affinity) = &self.affinity {
+            affinity.record(client.ip(), backend);
+        }
+        self.policy.on_session(&SessionEvent::Created {
+            client,
+            backend,
+            client_index,
+        });
+        self.emit(|| RouterEvent::SessionCreated {
+            client,
+            backend,
+            client_index,
+        });
+    }
+
+    /// Reads the datagrams already waiting behind `first`, if it was read whole, into `batch`, handshakes ahead of
+    /// transport data, applying the backpressure policy once it is full
+    ///
+    /// `batch` stays empty if nothing was waiting, so `first` can be handled without copying it.
+    /// A receive error is returned after the datagrams read before it.
+    fn read_ahead(
+        &self,
+        batch: &mut Batch,
+        mut first: Option<(SocketAddr, Option<IpAddr>, &[u8])>,
+        ahead: &mut [u8],
+    ) -> Option<io::Error> {
+        for _ in 1..self.batch_size * READ_AHEAD_LIMIT {
+            let (size, source, local) = match self.transport.try_recv_from_to(ahead) {
+                Ok(Some(received)) => received,
+                Ok(None) => return None,
+                Err(e) => return Some(e),
+            };
+            if let Some((source, local, data)) = first.take() {
+                batch.push(self.is_bulk(source, data), source, local, data.to_vec());
+            }
+            if !self.whole(source, size) {
+                continue;
+            }
+            let data = ahead[..size].to_vec();
+            let bulk = self.is_bulk(source, &data);
+            if batch.len() < self.batch_size {
+                batch.push(bulk, source, local, data);
+                continue;
+            }
+            match self.backpressure.of(bulk) {
+                Backpressure::Block => {
+                    self.metrics.queue_blocked();
+                    batch.overflow = Some((source, local, data));
+                    return None;
+                }
+                Backpressure::DropNewest => self.shed(bulk, source),
+                Backpressure::DropOldest => match batch.pop_oldest(bulk) {
+                    Some((oldest, _, _)) => {
+                        self.shed(bulk, oldest);
+                        batch.push(bulk, source, local, data);
+                    }
+                    None => self.shed(bulk, source),
+                },
+            }
+        }
+        None
+    }
+
+    /// Routes a datagram like [`process_packet_at`](Self::process_packet_at), counting and
+    /// logging a panic while doing so rather than unwinding the loop
+    ///
+    /// Fails once the panics reach the limit set by [`RouterBuilder::max_panics`].
+    async fn handle_packet(
+        &self,
+        source: SocketAddr,
+        local: Option<IpAddr>,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let Err(panic) = catch_unwind(self.process_packet_at(source, local, data)).await else {
+            return Ok(());
+        };
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("non-string payload");
+        tracing::error!(
+            "panicked routing {} bytes of type {:?} from {} to {:?}: {}",
+            data.len(),
+            data.first(),
+            source,
+            local,
+            message
+        );
+        self.metrics.panicked();
+        self.metrics.dropped();
+        let panics = self.panics.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.max_panics {
+            Some(max) if panics >= max => Err(Error::Panicked(panics)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a datagram of `size` read from `source` fit the receive buffer, dropping it otherwise
+    fn whole(&self, source: SocketAddr, size: usize) -> bool {
+        if size <= self.buffer_size {
+            return true;
+        }
+        self.metrics.received();
+        self.metrics.truncated();
+        self.drop_packet(None, source, DropReason::Truncated);
+        false
+    }
+
+    /// Drops a datagram from `source` under backpressure
+    fn shed(&self, bulk: bool, source: SocketAddr) {
+        self.metrics.queue_dropped(bulk);
+        let message = bulk.then_some(MessageType::TransportData);
+        self.drop_packet(message, source, DropReason::QueueFull);
+    }
+
+    /// Whether a datagram is transport data, which is handled after the handshakes read with it
+    fn is_bulk(&self, source: SocketAddr, data: &[u8]) -> bool {
+        let data = match self.is_relay(source) {
+            true => match socks::unwrap(data) {
+                Ok((_, offset)) => &data[offset..],
+                Err(_) => data,
             },
-            Err(err) => debug!(
-                "dropping invalid packet with size {} of type {}: {}",
-                size, data[0], err
-            ),
+            false => data,
+        };
+        data.first() == Some(&MessageType::TransportData.code())
+    }
+
+    /// Logs an unmatched packet unless one was logged within [`UNMATCHED_LOG_INTERVAL`]
+    fn log_unmatched(&self, source: SocketAddr, receiver: Identity) {
+        let mut log = self.unmatched_log.lock().unwrap();
+        let (last, suppressed) = &mut *log;
+        if last.is_some_and(|last| last.elapsed() < UNMATCHED_LOG_INTERVAL) {
+            *suppressed += 1;
+            return;
         }
+        tracing::info!(
+            "transport data from {} for unknown index {}, {} more since the last one logged",
+            source,
+            receiver,
+            suppressed
+        );
+        *log = (Some(Instant::now()), 0);
     }
 
-    pub async fn run(
-        mut self,
-        config_rx: Receiver<Result<Event, notify::Error>>,
-    ) -> Result<(), io::Error> {
-        // TODO:
-        // refresh peers based on config
-        // then trigger a GC for sessions
-        let mut peers = crate::config::settings().read().unwrap().peers.to_owned();
-        tracing::info!("loaded {} peers", peers.len());
+    /// Forgets sessions that were idle for longer than the session timeout, or the handshake
+    /// timeout if they carried no transport data yet
+    async fn expire_sessions(&self) {
+        self.collect_garbage(false).await;
+    }
 
-        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+    /// Forgets idle sessions, and with `removed_peers` the sessions of backends that are no
+    /// longer among the peers
+    ///
+    /// Only the sessions due to expire are looked at, unless peers were removed or memory is
+    /// short, which takes a scan of all sessions.
+    async fn collect_garbage(&self, removed_peers: bool) -> GcReport {
+        let started = Instant::now();
+        let mut sessions = self.sessions.lock().await;
+        let known: HashSet<SocketAddr> = self
+            .peers
+            .iter()
+            .chain(self.honeypot.as_ref().map(|(honeypot, _)| honeypot))
+            .map(|p| p.address)
+            .collect();
+        // close to the memory limit, sessions are forgotten after half their timeout
+        let pressure = self.memory_limit.is_some_and(|limit| {
+            let room = limit.saturating_sub(self.queue_bytes());
+            self.memory_usage(&sessions).total() >= limit - room / 5
+        });
+        if pressure {
+            debug!("collecting garbage under memory pressure");
+        }
+        let now = Instant::now();
+        let deadline = |session: &Session| {
+            let timeout = match session.carried_data.load(Ordering::Relaxed) {
+                true => self.session_timeout,
+                false => self.handshake_timeout,
+            };
+            match pressure {
+                true => session.last_seen + timeout / 2,
+                false => session.last_seen + timeout,
+            }
+        };
+        let eviction = |session: &Session| {
+            if deadline(session) <= now {
+                match session.carried_data.load(Ordering::Relaxed) {
+                    true => Some(EvictionReason::Idle),
+                    false => Some(EvictionReason::Unanswered),
+                }
+            } else if removed_peers && !known.contains(&session.backend) {
+                Some(EvictionReason::RemovedPeer)
+            } else {
+                None
+            }
+        };
+        let (mut expired, mut removed) = (0, 0);
+        let mut ended = Vec::new();
+        let mut forget = |index: Identity, session: &Session, reason| {
+            match reason {
+                EvictionReason::RemovedPeer => {
+                    debug!(parent: &session.span, "session evicted, its peer was removed");
+                    removed += 1;
+                }
+                _ => {
+                    debug!(parent: &session.span, "session expired");
+                    expired += 1;
+                }
+            }
+            if session.initiated() {
+                let idle = now.saturating_duration_since(session.last_seen);
+                ended.push((index, session.client(), session.backend, idle, reason));
+            }
+        };
+        if removed_peers || pressure {
+            sessions.retain(|index, session| match eviction(session) {
+                Some(reason) => {
+                    forget(*index, session, reason);
+                    false
+                }
+                None => true,
+            });
+        } else {
+            for index in sessions.due(now) {
+                let Some(session) = sessions.get(&index) else {
+                    continue;
+                };
+                match eviction(session) {
+                    Some(reason) => {
+                        forget(index, session, reason);
+                        sessions.remove(&index);
+                    }
+                    None => {
+                        let deadline = deadline(session);
+                        sessions.schedule(index, deadline);
+                    }
+                }
+            }
+        }
+        let mut evicted = Vec::with_capacity(ended.len());
+        for (index, client, backend, idle, reason) in ended {
+            let event = match reason {
+                EvictionReason::RemovedPeer => TimelineEvent::Purged,
+                _ => TimelineEvent::Expired { idle },
+            };
+            sessions.timelines.record(index, event);
+            evicted.push(Eviction {
+                client,
+                backend,
+                client_index: index,
+                reason,
+            });
+        }
+        if expired > 0 {
+            debug!("expired {} idle sessions", expired);
+            self.metrics.sessions_expired(expired);
+        }
+        if removed > 0 {
+            tracing::info!("evicted {} sessions of removed peers", removed);
+            self.metrics.sessions_purged(removed);
+        }
+        GcReport {
+            evicted,
+            remaining: sessions.initiated(),
+            took: started.elapsed(),
+        }
+    }
 
-        tokio::spawn(async move {
-            while let Ok(event) = config_rx.recv() {
-                if tx.send(event).await.is_err() {
-                    break;
+    /// Forgets the sessions routed to `backend`, so the initiations their clients retransmit
+    /// are routed anew rather than to the same backend
+    async fn purge_sessions(&self, backend: SocketAddr) {
+        let mut sessions = self.sessions.lock().await;
+        let before = sessions.len();
+        let mut ended = Vec::new();
+        sessions.retain(|index, session| {
+            // pinned clients wait for their backend to come back
+            let keep =
+                session.backend != backend || self.pins.get(session.client().ip()) == Some(backend);
+            if !keep {
+                debug!(parent: &session.span, "session purged, its backend is down");
+                if session.initiated() {
+                    ended.push(*index);
                 }
             }
+            keep
         });
+        for index in ended {
+            sessions.timelines.record(index, TimelineEvent::Purged);
+        }
+        let purged = before - sessions.len();
+        if purged > 0 {
+            tracing::info!("purged {} sessions of backend {}", purged, backend);
+            self.metrics.sessions_purged(purged as u64);
+        }
+    }
+
+    /// Ejects the backends whose handshake loss stands out among the backends sharing their pubkey
+    fn eject_outliers(&self, detection: &OutlierDetection) {
+        let losses = self.metrics.handshake_loss();
+        let rates = self.metrics.backend_rates();
+        let mut pools: HashMap<[u8; 32], Vec<SocketAddr>> = HashMap::new();
+        for peer in &self.peers {
+            pools.entry(peer.pub_key).or_default().push(peer.address);
+        }
+        for pool in pools.values() {
+            let measured: Vec<(SocketAddr, f64)> = pool
+                .iter()
+                .filter(|backend| {
+                    rates.get(backend).is_some_and(|rates| {
+                        rates.handshakes_per_min >= detection.min_handshakes as f64
+                    })
+                })
+                .filter_map(|backend| losses.get(backend).map(|loss| (*backend, *loss)))
+                .collect();
+            let most = (pool.len() * detection.max_ejection_percent as usize / 100).max(1);
+            let ejected = pool.iter().filter(|b| self.health.is_ejected(**b)).count();
+            let candidates: Vec<(SocketAddr, f64)> = measured
+                .iter()
+                .copied()
+                .filter(|(backend, _)| !self.health.is_ejected(*backend))
+                .collect();
+            let room = most.saturating_sub(ejected);
+            for backend in detection.outliers(&candidates, room) {
+                tracing::warn!(
+                    "ejecting backend {} for {}s, its handshake loss of {:.0}% is an outlier",
+                    backend,
+                    detection.ejection_secs,
+                    losses[&backend] * 100.0
+                );
+                self.health
+                    .eject(backend, Duration::from_secs(detection.ejection_secs));
+            }
+        }
+    }
 
-        // lets just use a 70kb buffer
-        let mut buf: Vec<u8> = vec![0; 1024 * 70];
+    /// Routes packets until the transport fails, picking up peer list changes from `peers_rx`
+    pub async fn run(mut self, mut peers_rx: watch::Receiver<Vec<Peer>>) -> Result<(), Error> {
+        let peers = peers_rx.borrow_and_update().clone();
+        tracing::info!("loaded {} peers", peers.len());
+        self.set_peers(peers).await;
+
+        // a byte beyond the buffer size tells truncated datagrams from those filling it exactly
+        let mut buf: Vec<u8> = vec![0; self.buffer_size + 1];
+        // datagrams read ahead are copied out of this one
+        let mut ahead: Vec<u8> = vec![0; self.buffer_size + 1];
+
+        // sessions expire at most half a timeout late
+        let mut expiry =
+            tokio::time::interval((self.handshake_timeout / 2).max(Duration::from_millis(1)));
+        expiry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut heartbeat = self.heartbeat.take();
+        let mut beats = tokio::time::interval(
+            heartbeat
+                .as_ref()
+                .map_or(Duration::from_secs(3600), |(interval, _)| *interval),
+        );
+        beats.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // maintenance windows and schedule rules start and end on the minute, which this notices
+        // within a second
+        let mut schedules = tokio::time::interval(Duration::from_secs(1));
+        schedules.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut outliers = tokio::time::interval(
+            self.outliers
+                .map_or(Duration::from_secs(3600), |detection| {
+                    Duration::from_secs(detection.interval_secs.max(1))
+                }),
+        );
+        outliers.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         loop {
+            let delayed = self
+                .delayed
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .next();
             select! {
-                result = self.socket.recv_from(&mut buf) => {
-                    match result {
-                        Ok((size, peer)) => {
-                            self.handle_packet(size, peer, &buf, &peers).await;
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
+                // apply peer changes before routing any packet received after them
+                biased;
+                Ok(()) = peers_rx.changed() => {
+                    // TODO: trigger a GC for sessions of removed peers
+                    let peers = peers_rx.borrow_and_update().clone();
+                    tracing::info!("reloaded {} peers", peers.len());
+                    self.set_peers(peers).await;
+                }
+                // timers come before the socket too, which is always ready under load, so the
+                // watchdog is fed and sessions expire however busy the router is
+                _ = beats.tick(), if heartbeat.is_some() => {
+                    if let Some((_, beat)) = &mut heartbeat {
+                        beat();
+                    }
+                }
+                _ = expiry.tick() => self.expire_sessions().await,
+                Some(report_tx) = self.gc.1.recv() => {
+                    let report = self.collect_garbage(true).await;
+                    tracing::info!(
+                        "garbage collection evicted {} sessions in {:?}, {} left",
+                        report.evicted.len(),
+                        report.took,
+                        report.remaining
+                    );
+                    let _ = report_tx.send(report);
+                }
+                _ = schedules.tick() => self.update_schedules(chrono::Utc::now()),
+                _ = outliers.tick(), if self.outliers.is_some() => {
+                    if let Some(detection) = &self.outliers {
+                        self.eject_outliers(detection);
                     }
                 }
-                Some(event) = rx.recv() => {
-                    match event {
-                        Ok(event) => {
-                            tracing::info!("config changed, reloading peers");
-                            peers = crate::config::settings().read().unwrap().peers.to_owned();
+                _ = tokio::time::sleep_until(delayed.unwrap_or_else(Instant::now).into()),
+                    if delayed.is_some() => self.release_delayed().await,
+                result = self.transport.recv_from_to(&mut buf) => {
+                    let (size, peer, local) = result.map_err(Error::Recv)?;
+                    let mut batch = Batch::new(self.fair_queuing);
+                    let first = self.whole(peer, size).then_some((peer, local, &buf[..size]));
+                    let failed = self.read_ahead(&mut batch, first, &mut ahead);
+                    if batch.is_empty() {
+                        if let Some((peer, local, data)) = first {
+                            self.handle_packet(peer, local, data).await?;
                         }
-                        Err(e) => {
-                            tracing::error!("config watcher error: {:?}", e);
+                    } else {
+                        for (peer, local, data) in batch.drain() {
+                            self.handle_packet(peer, local, &data).await?;
                         }
                     }
+                    if let Some(e) = failed {
+                        return Err(Error::Recv(e));
+                    }
+                }
+                Some((request, explanation_tx)) = self.explain_requests.1.recv() => {
+                    let _ = explanation_tx.send(self.explain(&request).await);
+                }
+                Some((datagram, events_tx)) = self.injected.1.recv() => {
+                    tracing::info!("routing a datagram injected as from {}", datagram.source);
+                    // the loop routes nothing else meanwhile, so these events are all its own
+                    let mut events = self.events.subscribe();
+                    self.handle_packet(datagram.source, datagram.local, &datagram.packet)
+                        .await?;
+                    let mut caused = Vec::new();
+                    while let Ok(event) = events.try_recv() {
+                        caused.push(event);
+                    }
+                    let _ = events_tx.send(caused);
                 }
             }
         }
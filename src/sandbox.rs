@@ -0,0 +1,219 @@
+/*
+* sandbox.rs confines the router once it is initialized, so a bug in the packet path
+* can neither touch the filesystem beyond the config nor make arbitrary syscalls
+*/
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use landlock::{
+    ABI, Access, AccessFs, RestrictionStatus, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetError, RulesetStatus, path_beneath_rules,
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+use wireguard_router::error::Error;
+
+/// Newest Landlock ABI handled, older kernels enforce what they support
+const ABI: ABI = ABI::V6;
+
+/// Syscalls of the receive loop, config reloads, SOCKS associations and service notifications
+const SYSCALLS: &[libc::c_long] = &[
+    // memory and threads
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_futex,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_prctl,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    // event loop
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    // files, for config reloads
+    libc::SYS_openat,
+    libc::SYS_getcwd,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_lseek,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    // sockets
+    libc::SYS_socket,
+    libc::SYS_bind,
+    libc::SYS_connect,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    // connections to the admin API
+    libc::SYS_accept4,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_sendmmsg,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+];
+
+/// The files a sandboxed router reads and writes once started
+///
+/// Landlock confines single threads, so every thread of the runtime confines itself as it
+/// starts, before it runs any task, and the main thread once the router is initialized.
+pub struct Sandbox {
+    /// the config file and the peers file, whose directories can be read
+    pub read: Vec<PathBuf>,
+    /// the checkpoints, whose directories can be written
+    pub write: Vec<PathBuf>,
+}
+
+static SANDBOX: OnceLock<Sandbox> = OnceLock::new();
+
+impl Sandbox {
+    /// Makes this the sandbox of the process, which is only set up once
+    pub fn install(self) -> &'static Sandbox {
+        SANDBOX.get_or_init(|| self)
+    }
+
+    /// The sandbox of the process, none unless `sandbox = true`
+    pub fn get() -> Option<&'static Sandbox> {
+        SANDBOX.get()
+    }
+
+    /// Confines the calling thread, for `on_thread_start`, aborting as a thread that can't be
+    /// confined must not run any task
+    pub fn confine_thread(&self) {
+        if let Err(e) = self.restrict() {
+            tracing::error!("failed to confine a runtime thread: {}", e);
+            std::process::abort();
+        }
+    }
+
+    /// Confines the calling thread and those it spawns from now on, reporting how much of the
+    /// restriction the kernel enforces
+    pub fn confine(&self) -> Result<(), Error> {
+        let status = self.restrict().map_err(|e| Error::Sandbox {
+            layer: "filesystem",
+            source: e.into(),
+        })?;
+        match status.ruleset {
+            RulesetStatus::FullyEnforced => tracing::info!("filesystem access restricted"),
+            RulesetStatus::PartiallyEnforced => {
+                tracing::info!(
+                    "filesystem access partially restricted, the kernel's Landlock is older"
+                )
+            }
+            RulesetStatus::NotEnforced => {
+                tracing::warn!("filesystem access is not restricted, the kernel lacks Landlock")
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits the calling thread to reading beneath the directories of `read`, and writing
+    /// beneath those of `write`
+    ///
+    /// The directories rather than the files are allowed, as editors replace files on save, and
+    /// the router replaces the files it writes likewise.
+    fn restrict(&self) -> Result<RestrictionStatus, RulesetError> {
+        fn dirs(paths: &[PathBuf]) -> impl Iterator<Item = &Path> {
+            paths.iter().map(|path| match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            })
+        }
+        Ruleset::default()
+            .handle_access(AccessFs::from_all(ABI))?
+            .create()?
+            .add_rules(path_beneath_rules(
+                dirs(&self.read),
+                AccessFs::from_read(ABI),
+            ))?
+            .add_rules(path_beneath_rules(
+                dirs(&self.write),
+                AccessFs::from_read(ABI) | AccessFs::from_write(ABI),
+            ))?
+            .restrict_self()
+    }
+}
+
+/// Fails every syscall outside [`SYSCALLS`] with EPERM, in all threads of the process
+pub fn restrict_syscalls() -> Result<(), Error> {
+    filter()
+        .and_then(|program| seccompiler::apply_filter_all_threads(&program))
+        .map_err(|e| Error::Sandbox {
+            layer: "syscalls",
+            source: e.into(),
+        })?;
+    tracing::info!("syscalls restricted");
+    Ok(())
+}
+
+fn filter() -> Result<BpfProgram, seccompiler::Error> {
+    let rules = SYSCALLS
+        .iter()
+        .map(|&syscall| (syscall, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    Ok(filter.try_into()?)
+}
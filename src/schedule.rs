@@ -0,0 +1,231 @@
+/*
+* schedule.rs contains recurring time windows given as cron expressions, e.g. for backend maintenance
+* or for settings a backend only takes at certain times
+*/
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Peer;
+
+/// Windows longer than this are refused, as checking them steps through every minute
+pub const MAX_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The minutes matching a cron expression of the five fields `minute hour day month weekday`
+///
+/// Fields are `*`, numbers, ranges like `1-5` and lists of them, each optionally with a step like
+/// `*/15`. Months and weekdays may be given by their three letter English names, and Sunday
+/// is both 0 and 7. As in cron, a minute matches if either the day or the weekday matches when
+/// both are restricted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Schedule {
+    /// Whether the minute given by its fields matches, `weekday` counting from 0 for Sunday
+    pub fn matches(&self, minute: u32, hour: u32, day: u32, month: u32, weekday: u32) -> bool {
+        let set = |field: u64, value: u32| value < 64 && field & (1 << value) != 0;
+        let day_matches = set(self.days, day);
+        let weekday_matches = set(self.weekdays, weekday);
+        let date_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        };
+        set(self.minutes, minute)
+            && set(self.hours, hour)
+            && set(self.months, month)
+            && date_matches
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, String> {
+        const MONTHS: &[&str] = &[
+            "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+        ];
+        const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields in cron expression {expression:?}, got {}",
+                fields.len()
+            ));
+        };
+        let mut weekdays = parse_field(weekday, 0, 7, WEEKDAYS)?;
+        // Sunday is 7 as well
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            expression: expression.to_string(),
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])?,
+            days: parse_field(day, 1, 31, &[])?,
+            months: parse_field(month, 1, 12, MONTHS)?,
+            weekdays,
+            days_restricted: !day.starts_with('*'),
+            weekdays_restricted: !weekday.starts_with('*'),
+        })
+    }
+}
+
+/// The values of a cron field between `min` and `max` as a bit set, `names` standing for `min` onwards
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |value: &str| -> Result<u32, String> {
+        let lowercase = value.to_ascii_lowercase();
+        let number = match names.iter().position(|name| *name == lowercase) {
+            Some(index) => min + index as u32,
+            None => value
+                .parse()
+                .map_err(|_| format!("invalid value {value:?} in cron field {field:?}"))?,
+        };
+        match number {
+            number if (min..=max).contains(&number) => Ok(number),
+            _ => Err(format!(
+                "{value} is out of range {min}-{max} in cron field {field:?}"
+            )),
+        }
+    };
+
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step {step:?} in cron field {field:?}")),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // a start with a step runs to the end, like `5/10`
+            None if part.contains('/') => (value(range)?, max),
+            None => {
+                let value = value(range)?;
+                (value, value)
+            }
+        };
+        if start > end {
+            return Err(format!(
+                "range {range:?} is reversed in cron field {field:?}"
+            ));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// A recurring window of `duration`, starting at every minute of `schedule`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "WindowConfig", into = "WindowConfig")]
+pub struct Window {
+    pub schedule: Schedule,
+    pub duration: Duration,
+}
+
+/// The config form of a [`Window`], e.g. `{ cron = "0 3 * * sun", minutes = 60 }`
+#[derive(Deserialize, Serialize)]
+struct WindowConfig {
+    cron: String,
+    minutes: u64,
+}
+
+impl TryFrom<WindowConfig> for Window {
+    type Error = String;
+
+    fn try_from(config: WindowConfig) -> Result<Self, String> {
+        let duration = Duration::from_secs(config.minutes * 60);
+        if duration.is_zero() || duration > MAX_WINDOW {
+            return Err(format!(
+                "window of {} minutes is not between 1 minute and {} days",
+                config.minutes,
+                MAX_WINDOW.as_secs() / 86400
+            ));
+        }
+        Ok(Window {
+            schedule: config.cron.parse()?,
+            duration,
+        })
+    }
+}
+
+impl From<Window> for WindowConfig {
+    fn from(window: Window) -> Self {
+        WindowConfig {
+            cron: window.schedule.expression,
+            minutes: window.duration.as_secs() / 60,
+        }
+    }
+}
+
+/// Settings a backend takes in place of its own while the window is open, e.g. a weight sending
+/// it most new sessions overnight or a lower session limit during business hours
+///
+/// The config form is that of the window with the settings besides it, e.g.
+/// `{ cron = "0 22 * * *", minutes = 480, weight = 9 }`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Rule {
+    #[serde(flatten)]
+    pub window: Window,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+}
+
+impl Rule {
+    /// `peer` with the settings the rule sets replacing its own
+    pub fn apply(&self, peer: &Peer) -> Peer {
+        Peer {
+            max_sessions: self.max_sessions.or(peer.max_sessions),
+            primary: self.primary.unwrap_or(peer.primary),
+            weight: self.weight.or(peer.weight),
+            ..peer.clone()
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl Window {
+    /// Whether `now` lies within the window, the cron expression being read in the time zone of `now`
+    pub fn contains<Tz: chrono::TimeZone>(&self, now: chrono::DateTime<Tz>) -> bool {
+        use chrono::{Datelike, TimeDelta, Timelike};
+
+        let minutes = self.duration.as_secs().div_ceil(60) as i64;
+        (0..minutes).any(|back| {
+            let start = now.clone() - TimeDelta::minutes(back);
+            self.schedule.matches(
+                start.minute(),
+                start.hour(),
+                start.day(),
+                start.month(),
+                start.weekday().num_days_from_sunday(),
+            )
+        })
+    }
+}
@@ -0,0 +1,109 @@
+/*
+* service.rs runs the router as a Windows service, e.g. one created with
+* `sc.exe create wireguard-router binPath= "C:\path\to\wireguard-router.exe service"`
+*/
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+use wireguard_router::error::{Error, Report};
+
+use crate::RunArgs;
+
+pub const NAME: &str = "wireguard-router";
+
+/// Arguments for the service thread, which the dispatcher starts without arguments of ours
+static ARGS: OnceLock<RunArgs> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hands the process to the service control manager, returning once the service stopped
+pub fn run(args: RunArgs) -> Result<(), Error> {
+    let _ = ARGS.set(args);
+    service_dispatcher::start(NAME, ffi_service_main).map_err(|e| Error::Service(e.into()))
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = serve() {
+        tracing::error!("{}", Report(&e));
+    }
+}
+
+fn serve() -> Result<(), Error> {
+    let service_error = |e: windows_service::Error| Error::Service(e.into());
+
+    // services start in the system directory, look for the config next to the binary instead
+    if let Some(dir) = std::env::current_exe()
+        .ok()
+        .as_deref()
+        .and_then(Path::parent)
+    {
+        std::env::set_current_dir(dir).map_err(|e| Error::Service(e.into()))?;
+    }
+
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+    let mut stop_tx = Some(stop_tx);
+    let status = service_control_handler::register(NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop) = stop_tx.take() {
+                let _ = stop.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .map_err(service_error)?;
+
+    let report = |state, exit_code| {
+        status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: match state {
+                ServiceState::Running => {
+                    ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+                }
+                _ => ServiceControlAccept::empty(),
+            },
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::ZERO,
+            process_id: None,
+        })
+    };
+    report(ServiceState::Running, ServiceExitCode::NO_ERROR).map_err(service_error)?;
+
+    let args = ARGS.get().cloned().unwrap_or(RunArgs {
+        listen: Vec::new(),
+        config: None,
+        overrides: Vec::new(),
+        print_effective_config: false,
+        no_watch: false,
+    });
+    let result = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Error::Service(e.into()))
+        .and_then(|runtime| {
+            runtime.block_on(async {
+                tokio::select! {
+                    result = crate::run(args) => result,
+                    _ = stop_rx => Ok(()),
+                }
+            })
+        });
+
+    let exit_code = match result {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    report(ServiceState::Stopped, exit_code).map_err(service_error)?;
+    result
+}
@@ -0,0 +1,581 @@
+/*
+* snmp.rs answers SNMPv2c requests for the router's statistics, so network management systems can
+* poll it like any other network element
+*/
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+use crate::Peer;
+use crate::error::Error;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::router::SessionTable;
+
+/// netSnmpPlaypen, NET-SNMP's arc for experiments, to be replaced by an enterprise's own
+pub const DEFAULT_OID: &str = "1.3.6.1.4.1.8072.9999.9999";
+
+/// `system` of SNMPv2-MIB
+const SYSTEM: [u32; 7] = [1, 3, 6, 1, 2, 1, 1];
+/// Responses are kept within one unfragmented datagram over Ethernet
+const MAX_RESPONSE: usize = 1472;
+/// The statistics answered with are refreshed at most this often, so a walk sees one state
+const REFRESH: Duration = Duration::from_secs(1);
+
+const VERSION_2C: i64 = 1;
+const TOO_BIG: i64 = 1;
+const NOT_WRITABLE: i64 = 17;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// e.g. `0.0.0.0:161`, bound before privileges are dropped
+    pub listen: SocketAddr,
+    /// requests with another community are ignored
+    pub community: String,
+    /// the arc the router's objects are registered under, and its sysObjectID
+    #[serde(default = "default_oid")]
+    pub oid: String,
+}
+
+fn default_oid() -> String {
+    DEFAULT_OID.to_string()
+}
+
+/// What the agent reports on
+pub struct Sources {
+    pub metrics: Arc<Metrics>,
+    pub sessions: SessionTable,
+    pub health: Arc<Health>,
+    pub peers: watch::Receiver<Vec<Peer>>,
+}
+
+/// An object identifier, ordered as SNMP walks them
+pub type Oid = Vec<u32>;
+
+/// Parses the dotted form of an object identifier, e.g. `1.3.6.1.4.1`
+pub fn parse_oid(value: &str) -> Result<Oid, Error> {
+    let oid = value
+        .trim_start_matches('.')
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<Oid, _>>()
+        .map_err(|e| Error::InvalidConfig(format!("invalid OID {value:?}: {e}")))?;
+    if oid.len() < 2 || oid[0] > 2 || (oid[0] < 2 && oid[1] >= 40) {
+        return Err(Error::InvalidConfig(format!("invalid OID {value:?}")));
+    }
+    Ok(oid)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    Null,
+    ObjectId(Oid),
+    Counter32(u32),
+    Gauge32(u32),
+    /// hundredths of a second
+    TimeTicks(u32),
+    Counter64(u64),
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PduType {
+    Get,
+    GetNext,
+    Response,
+    Set,
+    GetBulk,
+}
+
+impl PduType {
+    fn tag(self) -> u8 {
+        match self {
+            PduType::Get => 0xa0,
+            PduType::GetNext => 0xa1,
+            PduType::Response => 0xa2,
+            PduType::Set => 0xa3,
+            PduType::GetBulk => 0xa5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pdu {
+    pub kind: PduType,
+    pub request_id: i64,
+    /// non-repeaters of a GetBulk request
+    pub error_status: i64,
+    /// max-repetitions of a GetBulk request
+    pub error_index: i64,
+    pub bindings: Vec<(Oid, Value)>,
+}
+
+/// An SNMPv2c message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub community: Vec<u8>,
+    pub pdu: Pdu,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bindings = Vec::new();
+        for (oid, value) in &self.pdu.bindings {
+            bindings.extend(encode_binding(oid, value));
+        }
+        let mut pdu = integer(self.pdu.request_id);
+        pdu.extend(integer(self.pdu.error_status));
+        pdu.extend(integer(self.pdu.error_index));
+        pdu.extend(tlv(0x30, &bindings));
+
+        let mut message = integer(VERSION_2C);
+        message.extend(tlv(0x04, &self.community));
+        message.extend(tlv(self.pdu.kind.tag(), &pdu));
+        tlv(0x30, &message)
+    }
+
+    /// The message in `datagram`, `None` unless it is a well-formed SNMPv2c message
+    pub fn decode(datagram: &[u8]) -> Option<Message> {
+        let mut message = Reader(Reader(datagram).expect(0x30)?);
+        if message.integer()? != VERSION_2C {
+            return None;
+        }
+        let community = message.expect(0x04)?.to_vec();
+        let (tag, pdu) = message.next()?;
+        let kind = match tag {
+            0xa0 => PduType::Get,
+            0xa1 => PduType::GetNext,
+            0xa2 => PduType::Response,
+            0xa3 => PduType::Set,
+            0xa5 => PduType::GetBulk,
+            _ => return None,
+        };
+        let mut pdu = Reader(pdu);
+        let request_id = pdu.integer()?;
+        let error_status = pdu.integer()?;
+        let error_index = pdu.integer()?;
+        let mut list = Reader(pdu.expect(0x30)?);
+        let mut bindings = Vec::new();
+        while !list.0.is_empty() {
+            let mut binding = Reader(list.expect(0x30)?);
+            let oid = decode_oid(binding.expect(0x06)?)?;
+            let (tag, content) = binding.next()?;
+            bindings.push((oid, decode_value(tag, content)?));
+        }
+        Some(Message {
+            community,
+            pdu: Pdu {
+                kind,
+                request_id,
+                error_status,
+                error_index,
+                bindings,
+            },
+        })
+    }
+}
+
+/// The objects an agent answers with, in walk order
+#[derive(Clone, Debug, Default)]
+pub struct Mib(BTreeMap<Oid, Value>);
+
+impl Mib {
+    pub fn insert(&mut self, oid: Oid, value: Value) {
+        self.0.insert(oid, value);
+    }
+
+    pub fn get(&self, oid: &[u32]) -> Option<&Value> {
+        self.0.get(oid)
+    }
+
+    /// The first object after `oid`
+    pub fn next(&self, oid: &[u32]) -> Option<(&Oid, &Value)> {
+        self.0
+            .range::<[u32], _>((Bound::Excluded(oid), Bound::Unbounded))
+            .next()
+    }
+
+    /// The router's statistics under `base`, and the system group
+    ///
+    /// - `base.1`: scalars, `.1.0` active sessions, `.2.0` received, `.3.0` forwarded and `.4.0`
+    ///   dropped packets, `.5.0` created sessions, `.6.0` backends and `.7.0` backends down
+    /// - `base.2.1.<column>.<backend>`: the backend table, with columns `1` index, `2` address,
+    ///   `3` name, `4` status, up(1) or down(2), `5` sessions, `6` forwarded initiations, `7`
+    ///   of those answered, `8` packets and `9` bytes in either direction
+    pub async fn collect(base: &[u32], sources: &Sources, uptime: Duration) -> Mib {
+        let mut mib = Mib::default();
+        let oid = |suffix: &[u32]| [base, suffix].concat();
+        let system = |suffix: &[u32]| [&SYSTEM[..], suffix].concat();
+        let description = format!("wireguard-router {}", env!("CARGO_PKG_VERSION"));
+        mib.insert(
+            system(&[1, 0]),
+            Value::OctetString(description.into_bytes()),
+        );
+        mib.insert(system(&[2, 0]), Value::ObjectId(base.to_vec()));
+        let ticks = (uptime.as_millis() / 10) as u32;
+        mib.insert(system(&[3, 0]), Value::TimeTicks(ticks));
+
+        let counters = sources.metrics.snapshot();
+        let mut backends: Vec<Peer> = sources.peers.borrow().clone();
+        let mut seen = HashSet::new();
+        backends.retain(|peer| seen.insert(peer.address));
+        let down = backends
+            .iter()
+            .filter(|peer| sources.health.is_down(peer.address))
+            .count();
+        let scalars = [
+            Value::Gauge32(sources.sessions.count().await as u32),
+            Value::Counter64(counters.received),
+            Value::Counter64(counters.forwarded),
+            Value::Counter64(counters.dropped),
+            Value::Counter64(counters.sessions_created),
+            Value::Gauge32(backends.len() as u32),
+            Value::Gauge32(down as u32),
+        ];
+        for (scalar, value) in (1..).zip(scalars) {
+            mib.insert(oid(&[1, scalar, 0]), value);
+        }
+
+        let mut sessions: HashMap<SocketAddr, usize> = HashMap::new();
+        for client in sources.sessions.clients(None).await {
+            *sessions.entry(client.backend).or_default() += client.sessions;
+        }
+        let totals = sources.metrics.backend_totals();
+        for (index, peer) in (1..).zip(&backends) {
+            let traffic = totals.get(&peer.address).copied().unwrap_or_default();
+            let status = match sources.health.is_down(peer.address) {
+                true => 2,
+                false => 1,
+            };
+            let name = peer.name.clone().unwrap_or_default();
+            let columns = [
+                Value::Integer(index.into()),
+                Value::OctetString(peer.address.to_string().into_bytes()),
+                Value::OctetString(name.into_bytes()),
+                Value::Integer(status),
+                Value::Gauge32(sessions.get(&peer.address).copied().unwrap_or(0) as u32),
+                Value::Counter64(traffic.handshakes),
+                Value::Counter64(traffic.answered),
+                Value::Counter64(traffic.packets),
+                Value::Counter64(traffic.bytes),
+            ];
+            for (column, value) in (1..).zip(columns) {
+                mib.insert(oid(&[2, 1, column, index]), value);
+            }
+        }
+        mib
+    }
+}
+
+/// The response to the SNMP `request` from `mib`, `None` if it is to be ignored
+///
+/// Requests that don't parse, carry another `community` or aren't requests are ignored, as a
+/// wrong community is by other agents. Sets fail, every object being read-only.
+pub fn respond(request: &[u8], community: &str, mib: &Mib) -> Option<Vec<u8>> {
+    let request = Message::decode(request)?;
+    if request.community != community.as_bytes() {
+        return None;
+    }
+    let pdu = request.pdu;
+    let next = |oid: &Oid| match mib.next(oid) {
+        Some((oid, value)) => (oid.clone(), value.clone()),
+        None => (oid.clone(), Value::EndOfMibView),
+    };
+    let (mut error_status, mut error_index) = (0, 0);
+    let bindings = match pdu.kind {
+        PduType::Get => pdu
+            .bindings
+            .iter()
+            .map(|(oid, _)| {
+                let value = mib.get(oid).cloned().unwrap_or(Value::NoSuchObject);
+                (oid.clone(), value)
+            })
+            .collect(),
+        PduType::GetNext => pdu.bindings.iter().map(|(oid, _)| next(oid)).collect(),
+        PduType::GetBulk => bulk(&pdu, next),
+        PduType::Set => {
+            (error_status, error_index) = (NOT_WRITABLE, 1);
+            pdu.bindings.clone()
+        }
+        PduType::Response => return None,
+    };
+    let mut response = Message {
+        community: request.community,
+        pdu: Pdu {
+            kind: PduType::Response,
+            request_id: pdu.request_id,
+            error_status,
+            error_index,
+            bindings,
+        },
+    };
+    let mut encoded = response.encode();
+    if encoded.len() > MAX_RESPONSE {
+        response.pdu.error_status = TOO_BIG;
+        response.pdu.error_index = 0;
+        response.pdu.bindings.clear();
+        encoded = response.encode();
+    }
+    Some(encoded)
+}
+
+/// The bindings of a GetBulk, as many repetitions as fit in a response
+fn bulk(pdu: &Pdu, next: impl Fn(&Oid) -> (Oid, Value)) -> Vec<(Oid, Value)> {
+    let non_repeaters = pdu.error_status.clamp(0, pdu.bindings.len() as i64) as usize;
+    let max_repetitions = pdu.error_index.max(0);
+    let (singles, repeated) = pdu.bindings.split_at(non_repeaters);
+    let mut bindings: Vec<_> = singles.iter().map(|(oid, _)| next(oid)).collect();
+    let mut size: usize = bindings
+        .iter()
+        .map(|(o, v)| encode_binding(o, v).len())
+        .sum();
+    let mut last: Vec<Oid> = repeated.iter().map(|(oid, _)| oid.clone()).collect();
+    for _ in 0..max_repetitions {
+        let row: Vec<_> = last.iter().map(&next).collect();
+        size += row
+            .iter()
+            .map(|(o, v)| encode_binding(o, v).len())
+            .sum::<usize>();
+        // room for the header, a response with no repetitions at all is too big
+        if size > MAX_RESPONSE - 64 && bindings.len() > non_repeaters {
+            break;
+        }
+        let ended = row.iter().all(|(_, value)| *value == Value::EndOfMibView);
+        last = row.iter().map(|(oid, _)| oid.clone()).collect();
+        bindings.extend(row);
+        if ended || last.is_empty() {
+            break;
+        }
+    }
+    bindings
+}
+
+/// The agent's socket, bound while the router may still bind privileged ports
+pub struct Agent {
+    socket: UdpSocket,
+    community: String,
+    base: Oid,
+    started: Instant,
+}
+
+impl Agent {
+    pub async fn bind(config: Config) -> Result<Agent, Error> {
+        let base = parse_oid(&config.oid)?;
+        let socket = UdpSocket::bind(config.listen).await.map_err(Error::Bind)?;
+        tracing::info!("SNMP agent listening on: {}", config.listen);
+        Ok(Agent {
+            socket,
+            community: config.community,
+            base,
+            started: Instant::now(),
+        })
+    }
+
+    /// Answers requests until the process exits
+    pub async fn serve(self, sources: Sources) {
+        let mut buf = vec![0; 65535];
+        let mut mib: Option<(Instant, Mib)> = None;
+        loop {
+            let (size, source) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::debug!("SNMP agent failed to receive: {}", e);
+                    continue;
+                }
+            };
+            let current = match &mib {
+                Some((collected, mib)) if collected.elapsed() < REFRESH => mib,
+                _ => {
+                    let collected =
+                        Mib::collect(&self.base, &sources, self.started.elapsed()).await;
+                    &mib.insert((Instant::now(), collected)).1
+                }
+            };
+            let Some(response) = respond(&buf[..size], &self.community, current) else {
+                tracing::debug!("ignoring SNMP request from {}", source);
+                continue;
+            };
+            if let Err(e) = self.socket.send_to(&response, source).await {
+                tracing::debug!("failed to answer SNMP request from {}: {}", source, e);
+            }
+        }
+    }
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        encoded.push(0x80 | (bytes.len() - skip) as u8);
+        encoded.extend(&bytes[skip..]);
+    }
+    encoded.extend(content);
+    encoded
+}
+
+fn integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // the shortest two's complement form
+    let mut skip = 0;
+    while skip < 7
+        && ((bytes[skip] == 0 && bytes[skip + 1] & 0x80 == 0)
+            || (bytes[skip] == 0xff && bytes[skip + 1] & 0x80 != 0))
+    {
+        skip += 1;
+    }
+    tlv(0x02, &bytes[skip..])
+}
+
+fn unsigned(tag: u8, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+    // a leading zero keeps it from reading as negative
+    let mut content = Vec::with_capacity(9);
+    if bytes[skip] & 0x80 != 0 {
+        content.push(0);
+    }
+    content.extend(&bytes[skip..]);
+    tlv(tag, &content)
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let first = oid.first().copied().unwrap_or(0) * 40 + oid.get(1).copied().unwrap_or(0);
+    for &arc in std::iter::once(&first).chain(oid.iter().skip(2)) {
+        let mut groups = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            groups.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        content.extend(groups.iter().rev());
+    }
+    tlv(0x06, &content)
+}
+
+fn encode_binding(oid: &[u32], value: &Value) -> Vec<u8> {
+    let mut binding = encode_oid(oid);
+    binding.extend(match value {
+        Value::Integer(value) => integer(*value),
+        Value::OctetString(bytes) => tlv(0x04, bytes),
+        Value::Null => tlv(0x05, &[]),
+        Value::ObjectId(oid) => encode_oid(oid),
+        Value::Counter32(value) => unsigned(0x41, (*value).into()),
+        Value::Gauge32(value) => unsigned(0x42, (*value).into()),
+        Value::TimeTicks(value) => unsigned(0x43, (*value).into()),
+        Value::Counter64(value) => unsigned(0x46, *value),
+        Value::NoSuchObject => tlv(0x80, &[]),
+        Value::NoSuchInstance => tlv(0x81, &[]),
+        Value::EndOfMibView => tlv(0x82, &[]),
+    });
+    tlv(0x30, &binding)
+}
+
+fn decode_oid(content: &[u8]) -> Option<Oid> {
+    let mut arcs = Vec::new();
+    let mut arc: u32 = 0;
+    for &byte in content {
+        arc = arc.checked_mul(128)? | u32::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    let (&first, rest) = arcs.split_first()?;
+    if content.last()? & 0x80 != 0 {
+        return None;
+    }
+    let (top, second) = match first {
+        0..40 => (0, first),
+        40..80 => (1, first - 40),
+        _ => (2, first - 80),
+    };
+    Some([&[top, second][..], rest].concat())
+}
+
+fn decode_unsigned(content: &[u8], max: u64) -> Option<u64> {
+    if content.is_empty() || content.len() > 9 {
+        return None;
+    }
+    let value = content.iter().try_fold(0u64, |value, &b| {
+        Some(value.checked_mul(256)? | u64::from(b))
+    })?;
+    (value <= max).then_some(value)
+}
+
+fn decode_value(tag: u8, content: &[u8]) -> Option<Value> {
+    let u32 = |content| decode_unsigned(content, u32::MAX.into()).map(|v| v as u32);
+    Some(match tag {
+        0x02 => Value::Integer(decode_integer(content)?),
+        0x04 => Value::OctetString(content.to_vec()),
+        0x05 => Value::Null,
+        0x06 => Value::ObjectId(decode_oid(content)?),
+        0x41 => Value::Counter32(u32(content)?),
+        0x42 => Value::Gauge32(u32(content)?),
+        0x43 => Value::TimeTicks(u32(content)?),
+        0x46 => Value::Counter64(decode_unsigned(content, u64::MAX)?),
+        0x80 => Value::NoSuchObject,
+        0x81 => Value::NoSuchInstance,
+        0x82 => Value::EndOfMibView,
+        _ => return None,
+    })
+}
+
+fn decode_integer(content: &[u8]) -> Option<i64> {
+    if content.is_empty() || content.len() > 8 {
+        return None;
+    }
+    let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(
+        content
+            .iter()
+            .fold(sign, |value: i64, &b| (value << 8) | i64::from(b)),
+    )
+}
+
+/// Reads BER elements off the front of a buffer
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// The tag and content of the next element
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = match first {
+            0..=0x7f => (usize::from(first), rest),
+            0x81..=0x84 => {
+                let (bytes, rest) = rest.split_at_checked(usize::from(first & 0x7f))?;
+                let len = bytes.iter().fold(0, |len, &b| len << 8 | usize::from(b));
+                (len, rest)
+            }
+            _ => return None,
+        };
+        let (content, rest) = rest.split_at_checked(len)?;
+        self.0 = rest;
+        Some((tag, content))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        self.next()
+            .and_then(|(found, content)| (found == tag).then_some(content))
+    }
+
+    fn integer(&mut self) -> Option<i64> {
+        decode_integer(self.expect(0x02)?)
+    }
+}
@@ -0,0 +1,183 @@
+/*
+* socks.rs implements the client side of SOCKS5 UDP-ASSOCIATE (RFC 1928)
+* so backends only reachable through a proxy egress can be forwarded to
+*/
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// How long the router waits for a proxy to complete the UDP ASSOCIATE handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A live UDP association with a SOCKS5 proxy.
+///
+/// The association is only valid as long as the TCP control connection stays open,
+/// which is held by a background task for the lifetime of the association.
+pub struct Association {
+    pub proxy: SocketAddr,
+    /// the UDP relay address datagrams have to be sent to
+    pub relay: SocketAddr,
+    closed: Arc<AtomicBool>,
+}
+
+impl Association {
+    /// Associates with `proxy`, failing with [`io::ErrorKind::TimedOut`] unless it answers
+    /// within `timeout`
+    pub async fn connect(proxy: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let (mut control, relay) = tokio::time::timeout(timeout, handshake(proxy))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("SOCKS5: {proxy} did not answer within {timeout:?}"),
+                )
+            })??;
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_flag = closed.clone();
+        tokio::spawn(async move {
+            // the proxy must not send anything on the control connection, wait for EOF
+            let mut buf = [0u8; 64];
+            while let Ok(n) = control.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+            tracing::warn!("SOCKS5 association with {} closed", proxy);
+            closed_flag.store(true, Ordering::Relaxed);
+        });
+
+        Ok(Association {
+            proxy,
+            relay,
+            closed,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Negotiates a UDP association over a new control connection to `proxy`, returning the
+/// connection and the relay address
+async fn handshake(proxy: SocketAddr) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut control = TcpStream::connect(proxy).await?;
+
+    control.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await?;
+    let mut choice = [0u8; 2];
+    control.read_exact(&mut choice).await?;
+    if choice != [VERSION, METHOD_NO_AUTH] {
+        return Err(protocol_error("proxy requires an unsupported auth method"));
+    }
+
+    // we don't know which address the datagrams will come from, so send all zeros
+    let mut request = vec![VERSION, CMD_UDP_ASSOCIATE, 0x00];
+    write_addr(
+        &mut request,
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+    );
+    control.write_all(&request).await?;
+
+    let mut reply = [0u8; 3];
+    control.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(protocol_error("invalid reply version"));
+    }
+    if reply[1] != 0x00 {
+        return Err(protocol_error(&format!(
+            "UDP ASSOCIATE rejected with code {}",
+            reply[1]
+        )));
+    }
+    let mut relay = read_addr(&mut control).await?;
+    // proxies commonly answer with an unspecified address, meaning "the address you connected to"
+    if relay.ip().is_unspecified() {
+        relay.set_ip(proxy.ip());
+    }
+    Ok((control, relay))
+}
+
+/// Prefixes `data` with the UDP request header addressing `dst`
+pub fn wrap(dst: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 22);
+    // RSV RSV FRAG
+    out.extend_from_slice(&[0x00, 0x00, 0x00]);
+    write_addr(&mut out, dst);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Parses the UDP request header of a datagram received from a relay,
+/// returning the original source address and the offset of the payload
+pub fn unwrap(data: &[u8]) -> io::Result<(SocketAddr, usize)> {
+    if data.len() < 4 {
+        return Err(protocol_error("datagram too short"));
+    }
+    if data[2] != 0x00 {
+        return Err(protocol_error("fragmented datagrams are not supported"));
+    }
+    let (ip, offset): (IpAddr, usize) = match data[3] {
+        ATYP_IPV4 if data.len() >= 10 => {
+            let octets: [u8; 4] = data[4..8].try_into().unwrap();
+            (Ipv4Addr::from(octets).into(), 8)
+        }
+        ATYP_IPV6 if data.len() >= 22 => {
+            let octets: [u8; 16] = data[4..20].try_into().unwrap();
+            (Ipv6Addr::from(octets).into(), 20)
+        }
+        ATYP_DOMAIN => return Err(protocol_error("domain source addresses are not supported")),
+        _ => return Err(protocol_error("invalid address")),
+    };
+    let port = u16::from_be_bytes([data[offset], data[offset + 1]]);
+    Ok((SocketAddr::new(ip.to_canonical(), port), offset + 2))
+}
+
+fn write_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_addr(stream: &mut TcpStream) -> io::Result<SocketAddr> {
+    let ip: IpAddr = match stream.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).into()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return Err(protocol_error("unsupported relay address type")),
+    };
+    let port = stream.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("SOCKS5: {msg}"))
+}
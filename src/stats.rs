@@ -0,0 +1,106 @@
+/*
+* stats.rs logs a periodic summary of the router's traffic and backends, a heartbeat for deployments
+* that only keep logs
+*/
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use wireguard_router::Peer;
+use wireguard_router::event::RouterEvent;
+use wireguard_router::health::Health;
+use wireguard_router::metrics::Metrics;
+use wireguard_router::packet::MessageType;
+use wireguard_router::router::SessionTable;
+
+/// Packets of one interval by message type, `None` for datagrams that did not parse
+#[derive(Default)]
+struct Tally {
+    initiations: u64,
+    responses: u64,
+    cookies: u64,
+    data: u64,
+    unparsed: u64,
+}
+
+impl Tally {
+    fn add(&mut self, message: Option<MessageType>) {
+        match message {
+            Some(MessageType::HandshakeInitiation) => self.initiations += 1,
+            Some(MessageType::HandshakeResponse) => self.responses += 1,
+            Some(MessageType::CookieReply) => self.cookies += 1,
+            Some(MessageType::TransportData) => self.data += 1,
+            Some(_) | None => self.unparsed += 1,
+        }
+    }
+}
+
+/// What a summary reports on
+pub struct Sources {
+    pub metrics: Arc<Metrics>,
+    pub sessions: SessionTable,
+    pub health: Arc<Health>,
+    pub peers: watch::Receiver<Vec<Peer>>,
+    pub events: broadcast::Receiver<RouterEvent>,
+}
+
+/// Logs a summary every `interval` until the router stops
+///
+/// The totals come from the counters, the breakdown by message type from the router's events,
+/// which may miss some under load; `missed_events` says how many.
+pub async fn log(interval: Duration, sources: Sources) {
+    let Sources {
+        metrics,
+        sessions,
+        health,
+        peers,
+        mut events,
+    } = sources;
+    let mut ticks = tokio::time::interval(interval.max(Duration::from_secs(1)));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    let mut last = metrics.snapshot();
+    let (mut forwarded, mut dropped, mut missed) = (Tally::default(), Tally::default(), 0);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(RouterEvent::Forwarded { message, .. }) => forwarded.add(Some(message)),
+                Ok(RouterEvent::Dropped { message, .. }) => dropped.add(message),
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => missed += skipped,
+                Err(RecvError::Closed) => return,
+            },
+            _ = ticks.tick() => {
+                let now = metrics.snapshot();
+                let active = sessions.count().await;
+                let backends = peers.borrow().len();
+                let down: Vec<String> = health.down().iter().map(ToString::to_string).collect();
+                tracing::info!(
+                    received = now.received - last.received,
+                    forwarded = now.forwarded - last.forwarded,
+                    forwarded_initiations = forwarded.initiations,
+                    forwarded_responses = forwarded.responses,
+                    forwarded_cookies = forwarded.cookies,
+                    forwarded_data = forwarded.data,
+                    dropped = now.dropped - last.dropped,
+                    dropped_initiations = dropped.initiations,
+                    dropped_responses = dropped.responses,
+                    dropped_cookies = dropped.cookies,
+                    dropped_data = dropped.data,
+                    dropped_unparsed = dropped.unparsed,
+                    missed_events = missed,
+                    sessions = active,
+                    backends,
+                    backends_down = down.len(),
+                    down = %down.join(","),
+                    "stats"
+                );
+                last = now;
+                (forwarded, dropped, missed) = (Tally::default(), Tally::default(), 0);
+            }
+        }
+    }
+}
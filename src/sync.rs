@@ -0,0 +1,69 @@
+/*
+* sync.rs warm-starts the session table from a sibling router's admin API, so a router that was
+* just restarted or added behind the same ECMP route routes the established tunnels right away
+*/
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use wireguard_router::Peer;
+use wireguard_router::error::Error;
+use wireguard_router::packet::Identity;
+use wireguard_router::router::{SessionInfo, SessionTable};
+
+use crate::config::WarmStartConfig;
+use crate::ctl;
+
+/// The part of a `GET /sessions/export` JSON document warm starts use
+#[derive(Deserialize)]
+struct Export {
+    sessions: Vec<ExportedSession>,
+}
+
+#[derive(Deserialize)]
+struct ExportedSession {
+    client: SocketAddr,
+    backend: SocketAddr,
+    client_index: Identity,
+    backend_index: Option<Identity>,
+    age_secs: u64,
+    idle_secs: u64,
+    packets: u64,
+    bytes: u64,
+}
+
+impl From<ExportedSession> for SessionInfo {
+    fn from(session: ExportedSession) -> Self {
+        SessionInfo {
+            client: session.client,
+            backend: session.backend,
+            client_index: session.client_index,
+            backend_index: session.backend_index,
+            age: Duration::from_secs(session.age_secs),
+            idle: Duration::from_secs(session.idle_secs),
+            packets: session.packets,
+            bytes: session.bytes,
+        }
+    }
+}
+
+/// Imports the established sessions of the sibling router with one of `peers` into `sessions`,
+/// returning how many were taken over
+pub async fn warm_start(
+    config: &WarmStartConfig,
+    sessions: &SessionTable,
+    peers: &[Peer],
+) -> Result<usize, Error> {
+    let url = format!(
+        "{}/sessions/export?format=json",
+        config.url.trim_end_matches('/')
+    );
+    let body = ctl::request(reqwest::Method::GET, &url, None, config.token.as_deref()).await?;
+    let export: Export = serde_json::from_str(&body).map_err(|e| Error::AdminRequest {
+        url,
+        source: e.into(),
+    })?;
+    let exported: Vec<SessionInfo> = export.sessions.into_iter().map(Into::into).collect();
+    Ok(sessions.import(&exported, peers).await)
+}
@@ -0,0 +1,263 @@
+/*
+* syslog.rs sends the log to a syslog server as RFC 5424 messages, next to the lines on stderr
+*/
+
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Registry;
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::fmt::format::{DefaultFields, Writer};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::reload;
+use wireguard_router::error::Error;
+
+use crate::config::SyslogConfig;
+
+/// Messages waiting for the writer thread, further ones are dropped rather than stall routing
+const QUEUE: usize = 1024;
+
+static HANDLE: OnceLock<reload::Handle<Option<Syslog>, Registry>> = OnceLock::new();
+
+/// The layer of the log [`start`] fills in once the config is loaded
+pub fn layer() -> reload::Layer<Option<Syslog>, Registry> {
+    let (layer, handle) = reload::Layer::new(None);
+    let _ = HANDLE.set(handle);
+    layer
+}
+
+/// Sends the log to the server of `config` from now on
+///
+/// UDP and Unix sockets are connected right away, so a wrong address fails the startup, while a
+/// TCP server is connected to by the writer thread, and reconnected to whenever it goes away.
+pub fn start(config: &SyslogConfig) -> Result<(), Error> {
+    let invalid =
+        |e: String| Error::InvalidConfig(format!("syslog address {}: {e}", config.address));
+    let sink = match config.address.split_once("://") {
+        Some(("udp", address)) => Sink::udp(address).map_err(|e| invalid(e.to_string()))?,
+        Some(("tcp", address)) => Sink::Tcp {
+            address: address.to_string(),
+            stream: None,
+        },
+        #[cfg(unix)]
+        Some(("unix", path)) => Sink::unix(path.into()).map_err(|e| invalid(e.to_string()))?,
+        _ => return Err(invalid("expected udp://, tcp:// or unix://".to_string())),
+    };
+    let app_name = &config.app_name;
+    if app_name.is_empty() || app_name.len() > 48 || !app_name.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return Err(Error::InvalidConfig(
+            "syslog app_name must be 1 to 48 printable ASCII characters".to_string(),
+        ));
+    }
+
+    let (tx, rx) = sync_channel(QUEUE);
+    std::thread::Builder::new()
+        .name("syslog".to_string())
+        .spawn(move || write(sink, rx))
+        .map_err(|e| invalid(e.to_string()))?;
+    let layer = Syslog {
+        tx,
+        facility: config.facility as u8,
+        hostname: hostname(),
+        app_name: app_name.clone(),
+        pid: std::process::id(),
+    };
+    if let Some(handle) = HANDLE.get() {
+        handle
+            .reload(Some(layer))
+            .map_err(|e| invalid(e.to_string()))?;
+    }
+    tracing::info!("sending the log to syslog at {}", config.address);
+    Ok(())
+}
+
+/// Formats events as RFC 5424 messages, the spans and fields of their message as on stderr
+pub struct Syslog {
+    tx: SyncSender<Vec<u8>>,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl<S> Layer<S> for Syslog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = String::new();
+        let _ = DefaultFields::new().format_fields(Writer::new(&mut fields), attrs);
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut recorded = String::new();
+            let _ = DefaultFields::new().format_fields(Writer::new(&mut recorded), values);
+            if !fields.is_empty() && !recorded.is_empty() {
+                fields.push(' ');
+            }
+            fields.push_str(&recorded);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let severity = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let mut message = format!(
+            "<{}>1 {} {} {} {} - - ",
+            self.facility * 8 + severity,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            self.app_name,
+            self.pid
+        );
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            message.push_str(span.name());
+            let extensions = span.extensions();
+            if let Some(SpanFields(fields)) = extensions.get::<SpanFields>()
+                && !fields.is_empty()
+            {
+                let _ = write!(message, "{{{fields}}}");
+            }
+            message.push_str(": ");
+        }
+        let _ = DefaultFields::new().format_fields(Writer::new(&mut message), event);
+        // dropped if the queue is full, the writer thread being stuck on the server
+        let _ = self.tx.try_send(message.into_bytes());
+    }
+}
+
+/// The fields of a span without the colors of those on stderr
+struct SpanFields(String);
+
+/// Where the writer thread sends messages
+enum Sink {
+    Udp(UdpSocket),
+    /// framed by octet counting, see RFC 6587
+    Tcp {
+        address: String,
+        stream: Option<TcpStream>,
+    },
+    #[cfg(unix)]
+    Unix {
+        path: PathBuf,
+        socket: UnixDatagram,
+    },
+}
+
+impl Sink {
+    fn udp(address: &str) -> io::Result<Sink> {
+        let server = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the host has no addresses"))?;
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        Ok(Sink::Udp(socket))
+    }
+
+    #[cfg(unix)]
+    fn unix(path: PathBuf) -> io::Result<Sink> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&path)?;
+        Ok(Sink::Unix { path, socket })
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Udp(socket) => socket.send(message).map(|_| ()),
+            Sink::Tcp { address, stream } => {
+                let connected = match stream {
+                    Some(stream) => stream,
+                    None => stream.insert(TcpStream::connect(address.as_str())?),
+                };
+                let result = connected
+                    .write_all(format!("{} ", message.len()).as_bytes())
+                    .and_then(|()| connected.write_all(message));
+                if result.is_err() {
+                    *stream = None;
+                }
+                result
+            }
+            #[cfg(unix)]
+            Sink::Unix { path, socket } => match socket.send(message) {
+                Ok(_) => Ok(()),
+                // the syslog daemon restarted and bound a new socket
+                Err(_) => {
+                    socket.connect(&*path)?;
+                    socket.send(message).map(|_| ())
+                }
+            },
+        }
+    }
+}
+
+/// Sends the messages of `rx` to `sink`, warning once per outage
+fn write(mut sink: Sink, rx: Receiver<Vec<u8>>) {
+    let mut failing = false;
+    for message in rx {
+        match sink.send(&message) {
+            Ok(()) if failing => {
+                failing = false;
+                tracing::info!("sending to syslog again");
+            }
+            Ok(()) => {}
+            Err(e) if !failing => {
+                failing = true;
+                tracing::warn!(
+                    "failed to send to syslog, dropping messages until it recovers: {}",
+                    e
+                );
+                // no reconnecting in a busy loop while the server is down
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            Err(_) => std::thread::sleep(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// The host name of the header, the nil value if it can't be told
+fn hostname() -> String {
+    #[cfg(unix)]
+    let name = nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok());
+    #[cfg(not(unix))]
+    let name = std::env::var("COMPUTERNAME").ok();
+    name.filter(|name| !name.is_empty() && name.len() <= 255)
+        .filter(|name| name.bytes().all(|b| b.is_ascii_graphic()))
+        .unwrap_or_else(|| "-".to_string())
+}
@@ -0,0 +1,78 @@
+/*
+* systemd.rs reports the router's state to systemd when it runs as a `Type=notify` service, and
+* logs to its journal
+*/
+
+use std::fs::File;
+use std::io;
+use std::net::UdpSocket;
+use std::os::fd::{AsFd, FromRawFd};
+use std::os::unix::fs::MetadataExt;
+use std::time::Duration;
+
+use sd_notify::NotifyState;
+use socket2::{Socket, Type};
+use tracing_journald::{Priority, PriorityMappings};
+
+/// The UDP sockets passed through socket activation, empty when not socket activated
+pub fn listen_sockets() -> io::Result<Vec<UdpSocket>> {
+    sd_notify::listen_fds()?
+        .map(|fd| {
+            // SAFETY: systemd hands the descriptors from SD_LISTEN_FDS_START on to this process
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            if socket.r#type()? != Type::DGRAM {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("socket activation passed fd {fd}, which is not a datagram socket"),
+                ));
+            }
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+/// Tells systemd the listeners are bound and the config is loaded
+pub fn ready() {
+    notify(NotifyState::Ready);
+}
+
+/// Half the watchdog timeout systemd expects pings within, if the unit sets `WatchdogSec`
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec) / 2)
+}
+
+pub fn watchdog() {
+    notify(NotifyState::Watchdog);
+}
+
+fn notify(state: NotifyState) {
+    // a no-op outside of systemd, where NOTIFY_SOCKET is unset
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        tracing::warn!("failed to notify systemd: {}", e);
+    }
+}
+
+/// A layer logging to the journal with the fields of events and their spans, if stderr is
+/// connected to it
+///
+/// systemd names the stream in `JOURNAL_STREAM`, which is inherited by child processes whose
+/// stderr may go elsewhere, so its device and inode are compared with stderr's.
+pub fn journal() -> Option<tracing_journald::Layer> {
+    let stream = std::env::var("JOURNAL_STREAM").ok()?;
+    let (device, inode) = stream.split_once(':')?;
+    let stderr = File::from(io::stderr().as_fd().try_clone_to_owned().ok()?)
+        .metadata()
+        .ok()?;
+    if (device.parse(), inode.parse()) != (Ok(stderr.dev()), Ok(stderr.ino())) {
+        return None;
+    }
+    // falls back to stderr if the journal's socket can't be opened
+    // fields are prefixed with `F_`, e.g. `F_BACKEND`, as the packet span has one named `message`
+    let layer = tracing_journald::layer().ok()?;
+    Some(layer.with_priority_mappings(PriorityMappings {
+        info: Priority::Informational,
+        debug: Priority::Debug,
+        ..PriorityMappings::new()
+    }))
+}
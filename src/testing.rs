@@ -0,0 +1,64 @@
+/*
+* testing.rs builds syntactically valid WireGuard messages, for tests of the router and of
+* code embedding it
+*/
+
+use crate::PeerKey;
+use crate::packet::{
+    CookieReply, HandshakeInitiation, HandshakeResponse, MessageType, TransportDataHeader,
+};
+use crate::utils::mac;
+
+/// An initiation from `sender` with a valid mac1 for the peer with `pub_key`
+///
+/// The ephemeral key, encrypted static and timestamp are zero, so a router routes it like a real
+/// one but the peer can't complete the handshake.
+pub fn initiation(sender: u32, pub_key: &[u8; 32]) -> Vec<u8> {
+    let mut packet = header(MessageType::HandshakeInitiation, HandshakeInitiation::SIZE);
+    packet[4..8].copy_from_slice(&sender.to_le_bytes());
+    sign(&mut packet, HandshakeInitiation::MAC1_OFFSET, pub_key);
+    packet
+}
+
+/// A response from `sender` to the initiation of `receiver`, with a valid mac1 for the
+/// initiating peer with `pub_key`
+pub fn response(sender: u32, receiver: u32, pub_key: &[u8; 32]) -> Vec<u8> {
+    let mut packet = header(MessageType::HandshakeResponse, HandshakeResponse::SIZE);
+    packet[4..8].copy_from_slice(&sender.to_le_bytes());
+    packet[8..12].copy_from_slice(&receiver.to_le_bytes());
+    sign(&mut packet, HandshakeResponse::MAC1_OFFSET, pub_key);
+    packet
+}
+
+/// A cookie reply to the handshake message of `receiver`, with a zero nonce and cookie
+pub fn cookie_reply(receiver: u32) -> Vec<u8> {
+    let mut packet = header(MessageType::CookieReply, CookieReply::SIZE);
+    packet[4..8].copy_from_slice(&receiver.to_le_bytes());
+    packet
+}
+
+/// Transport data for the session of `receiver`, with `payload_len` zero bytes after the header
+///
+/// Real packets carry at least a 16 byte authentication tag, so shorter payloads don't parse.
+pub fn transport(receiver: u32, counter: u64, payload_len: usize) -> Vec<u8> {
+    let mut packet = header(
+        MessageType::TransportData,
+        TransportDataHeader::SIZE + payload_len,
+    );
+    packet[4..8].copy_from_slice(&receiver.to_le_bytes());
+    packet[8..16].copy_from_slice(&counter.to_le_bytes());
+    packet
+}
+
+fn header(message: MessageType, size: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; size];
+    packet[0] = message.code();
+    packet
+}
+
+/// Sets the mac1 at `offset` of `packet` to that of everything before it, keyed for `pub_key`
+fn sign(packet: &mut [u8], offset: usize, pub_key: &[u8; 32]) {
+    let key = PeerKey::new(*pub_key).precomputed_hash_label_mac1;
+    let mac1 = mac(&key, &packet[..offset]);
+    packet[offset..][..16].copy_from_slice(&mac1);
+}
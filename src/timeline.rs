@@ -0,0 +1,224 @@
+/*
+* timeline.rs keeps the recent events of each session, to reconstruct what happened to a client
+* without a packet capture
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use crate::event::DropReason;
+use crate::packet::{Identity, MessageType};
+
+/// Sessions whose timeline is kept by default, the oldest ones being forgotten first
+pub const DEFAULT_TIMELINES: usize = 10_000;
+/// Events kept per session, the oldest ones being forgotten first
+pub const TIMELINE_EVENTS: usize = 32;
+
+/// Something that happened to a session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TimelineEvent {
+    /// the client's initiation was routed to the backend, starting the session
+    Initiation,
+    /// the client sent its initiation again before the backend responded
+    Retransmit,
+    /// the backend responded, establishing the session
+    Response { backend_index: Identity },
+    /// the backend answered with a cookie, being under load
+    CookieReply,
+    /// packets with one of the session's indices arrived from a new address, e.g. as the client
+    /// roamed or its NAT mapping changed
+    EndpointChanged { from: SocketAddr, to: SocketAddr },
+    /// the session was forgotten after being idle for this long
+    Expired { idle: Duration },
+    /// the session was forgotten as its backend went down
+    Purged,
+    /// the session was forgotten to keep the router within its memory limit
+    Evicted,
+    /// a packet with one of the session's indices was dropped
+    Dropped {
+        message: MessageType,
+        reason: DropReason,
+    },
+}
+
+/// A [`TimelineEvent`] and when it happened
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimelineEntry {
+    pub at: SystemTime,
+    pub event: TimelineEvent,
+}
+
+/// The recent events of one session, as returned by
+/// [`SessionTable::timeline`](crate::router::SessionTable::timeline)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionTimeline {
+    pub client: SocketAddr,
+    pub backend: SocketAddr,
+    pub client_index: Identity,
+    /// none until the backend responded
+    pub backend_index: Option<Identity>,
+    /// the oldest first, up to [`TIMELINE_EVENTS`] of them
+    pub events: Vec<TimelineEntry>,
+}
+
+struct Timeline {
+    client: SocketAddr,
+    backend: SocketAddr,
+    backend_index: Option<Identity>,
+    events: VecDeque<TimelineEntry>,
+}
+
+/// The timelines of the most recent sessions, outliving the sessions themselves so their end
+/// can be looked up too
+pub(crate) struct Timelines {
+    capacity: usize,
+    /// client index -> timeline
+    by_index: HashMap<Identity, Timeline>,
+    /// backend index -> client index of its session
+    aliases: HashMap<Identity, Identity>,
+    /// the client indices in the order their sessions started, the oldest first
+    order: VecDeque<Identity>,
+    /// the events of all timelines
+    events: usize,
+}
+
+impl Default for Timelines {
+    fn default() -> Self {
+        Timelines::new(DEFAULT_TIMELINES)
+    }
+}
+
+impl Timelines {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Timelines {
+            capacity,
+            by_index: HashMap::new(),
+            aliases: HashMap::new(),
+            order: VecDeque::new(),
+            events: 0,
+        }
+    }
+
+    /// Starts the timeline of a session with its initiation, replacing any earlier one of the
+    /// client index and forgetting the oldest timeline beyond the capacity
+    pub(crate) fn start(
+        &mut self,
+        client_index: Identity,
+        client: SocketAddr,
+        backend: SocketAddr,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(replaced) = self.by_index.remove(&client_index) {
+            self.order.retain(|index| *index != client_index);
+            self.forget(replaced);
+        }
+        while self.order.len() >= self.capacity {
+            if !self.forget_oldest() {
+                break;
+            }
+        }
+        self.by_index.insert(
+            client_index,
+            Timeline {
+                client,
+                backend,
+                backend_index: None,
+                events: VecDeque::new(),
+            },
+        );
+        self.order.push_back(client_index);
+        self.record(client_index, TimelineEvent::Initiation);
+    }
+
+    /// Moves the session of `client_index` to `backend`, as the first to respond to an
+    /// initiation raced between several
+    pub(crate) fn assign(&mut self, client_index: Identity, backend: SocketAddr) {
+        if let Some(timeline) = self.by_index.get_mut(&client_index) {
+            timeline.backend = backend;
+        }
+    }
+
+    /// Forgets the oldest timeline, returning whether there was one
+    pub(crate) fn forget_oldest(&mut self) -> bool {
+        let Some(oldest) = self.order.pop_front() else {
+            return false;
+        };
+        if let Some(timeline) = self.by_index.remove(&oldest) {
+            self.forget(timeline);
+        }
+        true
+    }
+
+    fn forget(&mut self, timeline: Timeline) {
+        self.events -= timeline.events.len();
+        if let Some(alias) = timeline.backend_index {
+            self.aliases.remove(&alias);
+        }
+    }
+
+    /// Approximate bytes held by the timelines
+    pub(crate) fn footprint(&self) -> usize {
+        let timeline = size_of::<(Identity, Timeline)>() + 2 * size_of::<(Identity, Identity)>();
+        self.by_index.len() * timeline + self.events * size_of::<TimelineEntry>()
+    }
+
+    /// Files the events of `backend_index` under the session of `client_index`
+    pub(crate) fn alias(&mut self, backend_index: Identity, client_index: Identity) {
+        if let Some(timeline) = self.by_index.get_mut(&client_index) {
+            if let Some(previous) = timeline.backend_index.replace(backend_index) {
+                self.aliases.remove(&previous);
+            }
+            self.aliases.insert(backend_index, client_index);
+        }
+    }
+
+    /// Adds `event` to the timeline of the session with either index `index`, if it is kept
+    pub(crate) fn record(&mut self, index: Identity, event: TimelineEvent) {
+        let index = self.aliases.get(&index).copied().unwrap_or(index);
+        let Some(timeline) = self.by_index.get_mut(&index) else {
+            return;
+        };
+        if timeline.events.len() == TIMELINE_EVENTS {
+            timeline.events.pop_front();
+        } else {
+            self.events += 1;
+        }
+        timeline.events.push_back(TimelineEntry {
+            at: SystemTime::now(),
+            event,
+        });
+    }
+
+    /// The timeline of the session with either index `index`
+    pub(crate) fn get(&self, index: Identity) -> Option<SessionTimeline> {
+        let index = self.aliases.get(&index).copied().unwrap_or(index);
+        self.by_index
+            .get(&index)
+            .map(|timeline| timeline.view(index))
+    }
+
+    /// The timelines of the sessions of `client`, the oldest first
+    pub(crate) fn of_client(&self, client: IpAddr) -> Vec<SessionTimeline> {
+        self.order
+            .iter()
+            .filter_map(|index| Some((index, self.by_index.get(index)?)))
+            .filter(|(_, timeline)| timeline.client.ip() == client)
+            .map(|(index, timeline)| timeline.view(*index))
+            .collect()
+    }
+}
+
+impl Timeline {
+    fn view(&self, client_index: Identity) -> SessionTimeline {
+        SessionTimeline {
+            client: self.client,
+            backend: self.backend,
+            client_index,
+            backend_index: self.backend_index,
+            events: self.events.iter().cloned().collect(),
+        }
+    }
+}
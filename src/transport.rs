@@ -0,0 +1,920 @@
+/*
+* transport.rs abstracts the datagram I/O the router is driven by
+*/
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+#[cfg(feature = "quic")]
+use crate::quic::{self, Links};
+#[cfg(feature = "tunnel")]
+use crate::tunnel::Tunnel;
+
+pub mod mock;
+
+/// The datagram I/O a [`Router`](crate::router::Router) receives packets from and forwards them with.
+///
+/// Implementations should report source addresses in canonical form,
+/// i.e. v4 peers as v4 addresses rather than v4-mapped v6 ones.
+pub trait PacketTransport: Send + Sync {
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Sends the datagrams to each backend in `egress` the way given for it, replacing the
+    /// settings given before
+    ///
+    /// Transports that can't steer datagrams ignore this, as by default.
+    fn set_backend_egress(&self, egress: HashMap<SocketAddr, Egress>) {
+        let _ = egress;
+    }
+
+    /// Sends a datagram from `client` on to one of the router's backends
+    ///
+    /// Transports can send these through sockets of their own, e.g. so errors are attributed to
+    /// the backend they are about. By default this is `send_to`.
+    fn send_to_backend(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        client: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        let _ = client;
+        self.send_to(buf, target)
+    }
+
+    /// Receives a datagram like `recv_from_to`, but only if one is waiting already
+    ///
+    /// The router reads ahead with this to handle handshakes before the transport data queued
+    /// in front of them. Transports that always return `Ok(None)`, as by default, are handled in
+    /// the order they are received.
+    fn try_recv_from_to(
+        &self,
+        _buf: &mut [u8],
+    ) -> io::Result<Option<(usize, SocketAddr, Option<IpAddr>)>> {
+        Ok(None)
+    }
+
+    /// Like `recv_from`, also returning the local address the datagram was sent to if it is known
+    fn recv_from_to(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr, Option<IpAddr>)>> + Send {
+        async move {
+            let (size, source) = self.recv_from(buf).await?;
+            Ok((size, source, None))
+        }
+    }
+}
+
+impl PacketTransport for UdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            match UdpSocket::recv_from(self, buf).await {
+                Ok((size, peer)) => {
+                    return Ok((size, SocketAddr::new(peer.ip().to_canonical(), peer.port())));
+                }
+                // see Listeners::recv_from
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, target).await
+    }
+}
+
+/// How datagrams to a backend leave the router, unset settings keeping those of the listeners
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Egress {
+    /// fwmark of the datagrams, see [`Listeners::fwmark`]
+    pub fwmark: Option<u32>,
+    /// device the datagrams are sent through regardless of the routing table, e.g. an uplink or
+    /// tunnel the backend is only reachable over
+    pub interface: Option<String>,
+    /// local address the datagrams are sent from
+    pub source: Option<IpAddr>,
+    /// name of the WireGuard tunnel the datagrams are carried in, see
+    /// [`Listeners::tunnels`], the other settings then not applying
+    pub tunnel: Option<String>,
+    /// whether the datagrams are carried in a QUIC link to the backend, another router, see
+    /// [`Listeners::quic`], the other settings then not applying
+    pub quic: bool,
+}
+
+/// How a datagram to a backend is sent
+enum Route {
+    /// from the listeners, or the sockets of the fixed source port
+    Listeners,
+    Connected(Arc<UdpSocket>),
+    #[cfg(feature = "tunnel")]
+    Tunnel(Arc<Tunnel>),
+    #[cfg(feature = "quic")]
+    Quic(Arc<Links>),
+}
+
+/// UDP sockets to listen on, at most one per address family.
+///
+/// A v6 socket without a v4 sibling is bound dual-stack, so v4 clients and backends
+/// are still reachable through it using v4-mapped addresses.
+pub struct Listeners {
+    pub v4: Option<UdpSocket>,
+    pub v6: Option<UdpSocket>,
+    /// the sockets bound to a fixed source port towards backends
+    fixed: Option<Box<Listeners>>,
+    connected: Option<Connected>,
+    /// the fwmark and device of every socket, see [`Listeners::fwmark`] and
+    /// [`Listeners::bind_to_device`]
+    egress: Egress,
+    /// backend -> its own egress, merged with `egress`, see [`PacketTransport::set_backend_egress`]
+    backend_egress: Mutex<HashMap<SocketAddr, Egress>>,
+    /// the sockets connected to backends with an egress of their own, unless all are connected,
+    /// also receiving what comes back through the tunnels and QUIC links
+    steered: Connected,
+    /// by name, see [`Listeners::tunnels`]
+    #[cfg(feature = "tunnel")]
+    tunnels: HashMap<String, Arc<Tunnel>>,
+    /// see [`Listeners::quic`]
+    #[cfg(feature = "quic")]
+    quic: Option<Arc<Links>>,
+}
+
+/// The source port the router sends to backends from, which is where backends reply to
+///
+/// In the config this is `"shared"`, `{ fixed = <port> }` or `"per_session"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourcePort {
+    /// the port clients send to
+    #[default]
+    Shared,
+    /// a second port on the listen addresses, e.g. for backends only accepting the router
+    /// from a known port that is not public
+    Fixed(u16),
+    /// an ephemeral port for every client, so backends, or NATs in front of them, see each
+    /// client as a peer of its own
+    PerSession,
+}
+
+impl Listeners {
+    pub fn bind(addrs: &[SocketAddr]) -> io::Result<Self> {
+        Self::bind_in(addrs, None)
+    }
+
+    /// Binds like [`bind`](Self::bind), with the sockets, and every one opened later, bound to
+    /// `device`, e.g. a VRF to keep the router within its routing domain
+    ///
+    /// The listen addresses are looked up in the device's routing table, so they may be ones
+    /// only local within the VRF. This is only supported on Linux.
+    pub fn bind_to_device(addrs: &[SocketAddr], device: &str) -> io::Result<Self> {
+        Self::bind_in(addrs, Some(device))
+    }
+
+    fn bind_in(addrs: &[SocketAddr], device: Option<&str>) -> io::Result<Self> {
+        let has_v4 = addrs.iter().any(|a| a.is_ipv4());
+        let mut listeners = Listeners::unbound();
+        listeners.egress.interface = device.map(str::to_string);
+
+        for addr in addrs {
+            let slot = match addr {
+                SocketAddr::V4(_) => &mut listeners.v4,
+                SocketAddr::V6(_) => &mut listeners.v6,
+            };
+            if slot.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("only one listener per address family is supported, got {addr}"),
+                ));
+            }
+            let socket = bind_socket(*addr, has_v4, device)?;
+            receive_local_addresses(&socket)?;
+            *slot = Some(socket);
+        }
+
+        Ok(listeners)
+    }
+
+    /// Wraps sockets bound elsewhere, e.g. ones passed in by a service manager
+    pub fn from_std(sockets: impl IntoIterator<Item = std::net::UdpSocket>) -> io::Result<Self> {
+        let mut listeners = Listeners::unbound();
+
+        for socket in sockets {
+            let addr = socket.local_addr()?;
+            let slot = match addr {
+                SocketAddr::V4(_) => &mut listeners.v4,
+                SocketAddr::V6(_) => &mut listeners.v6,
+            };
+            if slot.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("only one listener per address family is supported, got {addr}"),
+                ));
+            }
+            socket.set_nonblocking(true)?;
+            let socket = UdpSocket::from_std(socket)?;
+            receive_local_addresses(&socket)?;
+            *slot = Some(socket);
+        }
+
+        Ok(listeners)
+    }
+
+    /// Sends to backends through a UDP socket connected to each of them instead of the listeners
+    ///
+    /// The kernel reports ICMP errors, e.g. port unreachable, only on connected sockets, and
+    /// errors of sends such as `EPERM` or `ENETUNREACH` are otherwise easily confused between
+    /// backends. The sockets are bound to an ephemeral port on the listener's address, which
+    /// backends see as the router's source port and reply to. They are opened on the first send
+    /// to each backend and kept until the listeners are dropped.
+    pub fn connect_backends(mut self) -> Self {
+        if self.connected.is_none() {
+            self.connected = Some(Connected::new(None));
+        }
+        self
+    }
+
+    /// Sends to backends from `port` rather than the port clients send to
+    ///
+    /// Sockets opened per session are connected like those of
+    /// [`connect_backends`](Self::connect_backends), and closed once no datagram was sent or
+    /// received through them for `idle`, which should be the router's session timeout. A fixed
+    /// port can't be combined with connected sockets, which each have a port of their own.
+    pub fn source_port(mut self, port: SourcePort, idle: Duration) -> io::Result<Self> {
+        match port {
+            SourcePort::Shared => {}
+            SourcePort::Fixed(_) if self.connected.is_some() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a fixed source port can't be combined with sockets connected to backends",
+                ));
+            }
+            SourcePort::Fixed(port) => {
+                let only_v6 = self.v4.is_some();
+                let mut fixed = Listeners::unbound();
+                for (listener, slot) in [(&self.v4, &mut fixed.v4), (&self.v6, &mut fixed.v6)] {
+                    if let Some(listener) = listener {
+                        let addr = SocketAddr::new(listener.local_addr()?.ip(), port);
+                        let device = self.egress.interface.as_deref();
+                        let socket = bind_socket(addr, only_v6, device)?;
+                        if let Some(mark) = self.egress.fwmark {
+                            set_mark(&socket, mark)?;
+                        }
+                        *slot = Some(socket);
+                    }
+                }
+                self.fixed = Some(Box::new(fixed));
+            }
+            SourcePort::PerSession => self.connected = Some(Connected::new(Some(idle))),
+        }
+        Ok(self)
+    }
+
+    /// Opens at most `limit` sockets per session, [`DEFAULT_SESSION_SOCKETS`] unless set
+    ///
+    /// Beyond it, sessions without a socket are sent from the listeners like with
+    /// [`SourcePort::Shared`], or dropped if their backend has an egress of its own, until
+    /// sockets are closed as idle. Only sockets per session are limited.
+    pub fn max_session_sockets(mut self, limit: usize) -> Self {
+        if let Some(connected) = &mut self.connected {
+            connected.limit = limit;
+        }
+        self
+    }
+
+    /// Marks every datagram sent with `mark`, e.g. for policy routing to steer the router's traffic
+    /// to an uplink or routing table, which is only supported on Linux and needs CAP_NET_ADMIN
+    ///
+    /// Backends with an egress of their own, see [`PacketTransport::set_backend_egress`], are
+    /// sent to through a socket connected to each of them like those of
+    /// [`connect_backends`](Self::connect_backends), with their settings instead.
+    pub fn fwmark(mut self, mark: u32) -> io::Result<Self> {
+        for socket in self.sockets() {
+            set_mark(socket, mark)?;
+        }
+        self.egress.fwmark = Some(mark);
+        Ok(self)
+    }
+
+    /// Sends to the backends whose egress names one of `tunnels` through it, wrapped in UDP over
+    /// IP packets from the router's address inside the tunnel, see [`crate::tunnel`]
+    #[cfg(feature = "tunnel")]
+    pub fn tunnels(mut self, tunnels: Vec<Tunnel>) -> Self {
+        for tunnel in tunnels {
+            let tunnel = Arc::new(tunnel);
+            tokio::spawn(tunnel.clone().receive(self.steered.sender.clone()));
+            self.tunnels.insert(tunnel.name().to_string(), tunnel);
+        }
+        self
+    }
+
+    /// Sends to the backends whose egress has `quic` set through QUIC `links`, and replies to the
+    /// routers that connected to them through their link, see [`crate::quic`]
+    #[cfg(feature = "quic")]
+    pub fn quic(mut self, links: Links) -> Self {
+        let links = Arc::new(links);
+        links.start(self.steered.sender.clone());
+        self.quic = Some(links);
+        self
+    }
+
+    fn unbound() -> Self {
+        Listeners {
+            v4: None,
+            v6: None,
+            fixed: None,
+            connected: None,
+            egress: Egress::default(),
+            backend_egress: Mutex::new(HashMap::new()),
+            steered: Connected::new(None),
+            #[cfg(feature = "tunnel")]
+            tunnels: HashMap::new(),
+            #[cfg(feature = "quic")]
+            quic: None,
+        }
+    }
+
+    /// How a datagram from `client` is sent to `target`, opening a socket connected to it if
+    /// there is none
+    fn route(&self, target: SocketAddr, client: SocketAddr) -> io::Result<Route> {
+        let backend_egress = self.backend_egress.lock().unwrap();
+        let own = backend_egress.get(&target);
+        if own.is_some_and(|egress| egress.quic) {
+            #[cfg(feature = "quic")]
+            if let Some(links) = &self.quic {
+                return Ok(Route::Quic(links.clone()));
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no QUIC links to send through",
+            ));
+        }
+        if let Some(name) = own.and_then(|egress| egress.tunnel.as_deref()) {
+            #[cfg(feature = "tunnel")]
+            if let Some(tunnel) = self.tunnels.get(name) {
+                return Ok(Route::Tunnel(tunnel.clone()));
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no tunnel {name:?}"),
+            ));
+        }
+        let connected = match (&self.connected, own) {
+            (Some(connected), _) => connected,
+            (None, Some(_)) => &self.steered,
+            (None, None) => return Ok(Route::Listeners),
+        };
+        let egress = own.unwrap_or(&self.egress);
+        let key = (target, connected.idle.map(|_| client));
+        let mut backends = connected.backends.lock().unwrap();
+        // the backend's egress changed since its socket was opened
+        if backends
+            .get(&key)
+            .is_some_and(|backend| backend.egress != *egress)
+        {
+            backends.remove(&key);
+        }
+        if let Some(backend) = backends.get(&key) {
+            // an error received since the last send, e.g. ICMP port unreachable
+            if let Some(e) = backend.error.lock().unwrap().take() {
+                return Err(e);
+            }
+            *backend.used.lock().unwrap() = Instant::now();
+            return Ok(Route::Connected(backend.socket.clone()));
+        }
+        if connected.idle.is_some() && backends.len() >= connected.limit {
+            if own.is_some() {
+                return Err(io::Error::other(format!(
+                    "{} sockets per session are open, none left for {target}",
+                    backends.len()
+                )));
+            }
+            return Ok(Route::Listeners);
+        }
+        let (local, peer, only_v6) = match egress.source {
+            Some(source) if source.is_ipv4() != target.is_ipv4() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("can't send to {target} from {source}"),
+                ));
+            }
+            Some(source) => (SocketAddr::new(source, 0), target, true),
+            None => {
+                let (socket, peer) = self.socket_for(target).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("no listener for the address family of {target}"),
+                    )
+                })?;
+                let mapped = peer.is_ipv6() && target.is_ipv4();
+                (SocketAddr::new(socket.local_addr()?.ip(), 0), peer, !mapped)
+            }
+        };
+        let mut backend = Backend::connect(local, peer, only_v6, egress.clone())?;
+        let reader = tokio::spawn(read_backend(
+            backend.socket.clone(),
+            target,
+            backend.error.clone(),
+            backend.used.clone(),
+            connected.sender.clone(),
+        ));
+        backend.reader = Some(reader.abort_handle());
+        let socket = backend.socket.clone();
+        backends.insert(key, backend);
+        Ok(Route::Connected(socket))
+    }
+
+    /// Every socket datagrams are received on, apart from connected ones
+    fn sockets(&self) -> impl Iterator<Item = &UdpSocket> {
+        let fixed = self
+            .fixed
+            .iter()
+            .flat_map(|fixed| fixed.v4.iter().chain(fixed.v6.iter()));
+        self.v4.iter().chain(self.v6.iter()).chain(fixed)
+    }
+
+    /// Picks the socket `addr` is reachable from, translating the address if needed.
+    ///
+    /// v4 destinations prefer the v4 listener and fall back to a dual-stack v6 listener
+    /// using the v4-mapped address. v6 destinations can only be reached via the v6 listener.
+    fn socket_for(&self, addr: SocketAddr) -> Option<(&UdpSocket, SocketAddr)> {
+        match addr {
+            SocketAddr::V4(v4) => match (&self.v4, &self.v6) {
+                (Some(socket), _) => Some((socket, addr)),
+                (None, Some(socket)) => Some((
+                    socket,
+                    SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+                )),
+                (None, None) => None,
+            },
+            SocketAddr::V6(_) => self.v6.as_ref().map(|socket| (socket, addr)),
+        }
+    }
+}
+
+impl PacketTransport for Listeners {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, peer, _) = self.recv_from_to(buf).await?;
+        Ok((size, peer))
+    }
+
+    async fn recv_from_to(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        loop {
+            let socket = select! {
+                result = readable(self.v4.as_ref()) => result?,
+                result = readable(self.v6.as_ref()) => result?,
+                result = readable(self.fixed.as_ref().and_then(|fixed| fixed.v4.as_ref())) => result?,
+                result = readable(self.fixed.as_ref().and_then(|fixed| fixed.v6.as_ref())) => result?,
+                Some((backend, data)) = received(self.connected.as_ref()) => {
+                    return Ok((copy_datagram(&data, buf), backend, None));
+                }
+                Some((backend, data)) = received(Some(&self.steered)) => {
+                    return Ok((copy_datagram(&data, buf), backend, None));
+                }
+            };
+            match try_recv(socket, buf) {
+                Ok(received) => return Ok(received),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // Windows reports ICMP port unreachable for an earlier send on the next receive
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn try_recv_from_to(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<Option<(usize, SocketAddr, Option<IpAddr>)>> {
+        for socket in self.sockets() {
+            loop {
+                match try_recv(socket, buf) {
+                    Ok(received) => return Ok(Some(received)),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let received = self
+            .connected
+            .iter()
+            .chain([&self.steered])
+            .find_map(|connected| {
+                let mut received = connected.received.try_lock().ok()?;
+                received.try_recv().ok()
+            });
+        Ok(received.map(|(backend, data)| (copy_datagram(&data, buf), backend, None)))
+    }
+
+    fn set_backend_egress(&self, egress: HashMap<SocketAddr, Egress>) {
+        let merged = egress
+            .into_iter()
+            .map(|(backend, egress)| {
+                let merged = Egress {
+                    fwmark: egress.fwmark.or(self.egress.fwmark),
+                    interface: egress.interface.or_else(|| self.egress.interface.clone()),
+                    source: egress.source,
+                    tunnel: egress.tunnel,
+                    quic: egress.quic,
+                };
+                (backend, merged)
+            })
+            .collect();
+        *self.backend_egress.lock().unwrap() = merged;
+    }
+
+    async fn send_to_backend(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        client: SocketAddr,
+    ) -> io::Result<usize> {
+        match self.route(target, client)? {
+            Route::Connected(socket) => socket.send(buf).await,
+            #[cfg(feature = "tunnel")]
+            Route::Tunnel(tunnel) => tunnel.send(buf, target).await,
+            #[cfg(feature = "quic")]
+            Route::Quic(links) => links.send(buf, target),
+            Route::Listeners => match &self.fixed {
+                Some(fixed) => fixed.send_to(buf, target).await,
+                None => self.send_to(buf, target).await,
+            },
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        // a router chained to this one
+        #[cfg(feature = "quic")]
+        if let Some(connection) = self
+            .quic
+            .as_ref()
+            .and_then(|links| links.connection(target))
+        {
+            return quic::send(&connection, buf);
+        }
+        match self.socket_for(target) {
+            Some((socket, target)) => socket.send_to(buf, target).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("no listener for the address family of {target}"),
+            )),
+        }
+    }
+}
+
+/// Datagrams received on the sockets connected to backends, queued for the router
+const CONNECTED_QUEUE: usize = 1024;
+
+/// Sockets opened per session at most, see [`Listeners::max_session_sockets`]
+pub const DEFAULT_SESSION_SOCKETS: usize = 4096;
+
+/// Sockets connected to backends, by backend, and by client if there is one per session
+type Backends = HashMap<(SocketAddr, Option<SocketAddr>), Backend>;
+
+/// The sockets connected to backends, see [`Listeners::connect_backends`]
+struct Connected {
+    backends: Arc<Mutex<Backends>>,
+    /// after which sockets per session are closed if unused, none if they are per backend
+    idle: Option<Duration>,
+    /// sockets per session opened at most
+    limit: usize,
+    sender: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    received: tokio::sync::Mutex<mpsc::Receiver<(SocketAddr, Vec<u8>)>>,
+}
+
+struct Backend {
+    socket: Arc<UdpSocket>,
+    /// the last error the socket reported while receiving, returned by the next send
+    error: Arc<Mutex<Option<io::Error>>>,
+    /// when a datagram was last sent or received through the socket
+    used: Arc<Mutex<Instant>>,
+    reader: Option<AbortHandle>,
+    /// the settings the socket was opened with
+    egress: Egress,
+}
+
+impl Connected {
+    fn new(idle: Option<Duration>) -> Self {
+        let (sender, received) = mpsc::channel(CONNECTED_QUEUE);
+        let backends = Arc::new(Mutex::new(HashMap::new()));
+        if let Some(idle) = idle {
+            tokio::spawn(sweep(Arc::downgrade(&backends), idle));
+        }
+        Connected {
+            backends,
+            idle,
+            limit: DEFAULT_SESSION_SOCKETS,
+            sender,
+            received: tokio::sync::Mutex::new(received),
+        }
+    }
+}
+
+/// Closes the sockets per session unused for `idle`, looking for them twice within it until the
+/// listeners are dropped
+async fn sweep(backends: Weak<Mutex<Backends>>, idle: Duration) {
+    let mut interval = tokio::time::interval(idle / 2);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(backends) = backends.upgrade() else {
+            return;
+        };
+        backends
+            .lock()
+            .unwrap()
+            .retain(|_, backend| backend.used.lock().unwrap().elapsed() < idle);
+    }
+}
+
+impl Backend {
+    fn connect(
+        local: SocketAddr,
+        peer: SocketAddr,
+        only_v6: bool,
+        egress: Egress,
+    ) -> io::Result<Self> {
+        Ok(Backend {
+            socket: Arc::new(connect_socket(local, peer, only_v6, &egress)?),
+            error: Arc::new(Mutex::new(None)),
+            used: Arc::new(Mutex::new(Instant::now())),
+            reader: None,
+            egress,
+        })
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        if let Some(reader) = &self.reader {
+            reader.abort();
+        }
+    }
+}
+
+/// Queues the datagrams `backend` sends to its connected socket, keeping the errors reported
+/// on it for the next send
+async fn read_backend(
+    socket: Arc<UdpSocket>,
+    backend: SocketAddr,
+    error: Arc<Mutex<Option<io::Error>>>,
+    used: Arc<Mutex<Instant>>,
+    sender: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+) {
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(size) => {
+                *used.lock().unwrap() = Instant::now();
+                // like a full socket buffer, the datagram is dropped if the router is behind
+                if let Err(mpsc::error::TrySendError::Closed(_)) =
+                    sender.try_send((backend, buf[..size].to_vec()))
+                {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => *error.lock().unwrap() = Some(e),
+        }
+    }
+}
+
+/// Waits for a datagram from a connected backend, or never completes without connected sockets
+async fn received(connected: Option<&Connected>) -> Option<(SocketAddr, Vec<u8>)> {
+    match connected {
+        Some(connected) => connected.received.lock().await.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Copies `data` into `buf`, truncating it like a receive would
+fn copy_datagram(data: &[u8], buf: &mut [u8]) -> usize {
+    let size = data.len().min(buf.len());
+    buf[..size].copy_from_slice(&data[..size]);
+    size
+}
+
+/// Receives a datagram if one is waiting, with the addresses in canonical form
+fn try_recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    let (size, peer, local) = try_recv_from_to(socket, buf)?;
+    // dual-stack listeners report v4 peers as v4-mapped v6 addresses
+    let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+    Ok((size, peer, local.map(|local| local.to_canonical())))
+}
+
+/// Whether binding a v6 listener failed with `err` because the host has no IPv6, e.g. it was
+/// booted with `ipv6.disable=1` or has no v6 address, rather than for a reason to report
+pub fn ipv6_unavailable(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::AddrNotAvailable
+        || Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)).is_err()
+}
+
+/// Has the kernel report the local address each datagram was sent to, which
+/// wildcard listeners can't tell otherwise
+#[cfg(target_os = "linux")]
+fn receive_local_addresses(socket: &UdpSocket) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => setsockopt(socket, sockopt::Ipv4PacketInfo, &true)?,
+        // also covers v4 datagrams received dual-stack, as v4-mapped addresses
+        SocketAddr::V6(_) => setsockopt(socket, sockopt::Ipv6RecvPacketInfo, &true)?,
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn receive_local_addresses(_: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets the fwmark of the datagrams sent through `socket`
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &impl std::os::fd::AsFd, mark: u32) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    setsockopt(socket, sockopt::Mark, &mark)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark<S>(_: &S, _: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "fwmarks are only supported on Linux",
+    ))
+}
+
+/// Binds `socket` to the network device `device`, so it sends and receives only through it
+#[cfg(target_os = "linux")]
+fn set_device(socket: &impl std::os::fd::AsFd, device: &str) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    setsockopt(socket, sockopt::BindToDevice, &device.into())?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_device<S>(_: &S, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to a device is only supported on Linux",
+    ))
+}
+
+/// Moves the calling thread into the network namespace `name`, one of `ip netns` under
+/// `/run/netns` or the path of a namespace file, e.g. `/proc/<pid>/ns/net`
+///
+/// Sockets opened afterwards by the thread, and threads it starts, belong to the namespace, so
+/// a router on a single-threaded runtime enters it before binding its listeners. This is only
+/// supported on Linux and needs CAP_SYS_ADMIN.
+#[cfg(target_os = "linux")]
+pub fn enter_netns(name: &str) -> io::Result<()> {
+    use nix::sched::{CloneFlags, setns};
+
+    let path = match name.contains('/') {
+        true => std::path::PathBuf::from(name),
+        false => std::path::Path::new("/run/netns").join(name),
+    };
+    let namespace = std::fs::File::open(path)?;
+    setns(namespace, CloneFlags::CLONE_NEWNET)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enter_netns(_: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network namespaces are only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn try_recv_from_to(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    use std::os::fd::AsRawFd;
+
+    use nix::sys::socket::{ControlMessageOwned, MsgFlags, SockaddrStorage, recvmsg};
+    use tokio::io::Interest;
+
+    socket.try_io(Interest::READABLE, || {
+        let mut control = nix::cmsg_space!(nix::libc::in6_pktinfo);
+        let mut iov = [io::IoSliceMut::new(buf)];
+        let message = recvmsg::<SockaddrStorage>(
+            socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut control),
+            MsgFlags::empty(),
+        )?;
+        let peer = message.address.and_then(|address| {
+            match (address.as_sockaddr_in(), address.as_sockaddr_in6()) {
+                (Some(v4), _) => Some(SocketAddr::from(std::net::SocketAddrV4::from(*v4))),
+                (_, Some(v6)) => Some(SocketAddr::from(std::net::SocketAddrV6::from(*v6))),
+                _ => None,
+            }
+        });
+        let peer = peer.ok_or_else(|| io::Error::other("datagram without a source address"))?;
+        let local = message.cmsgs().ok().and_then(|mut cmsgs| {
+            cmsgs.find_map(|cmsg| match cmsg {
+                ControlMessageOwned::Ipv4PacketInfo(info) => Some(IpAddr::from(
+                    std::net::Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)),
+                )),
+                ControlMessageOwned::Ipv6PacketInfo(info) => Some(IpAddr::from(
+                    std::net::Ipv6Addr::from(info.ipi6_addr.s6_addr),
+                )),
+                _ => None,
+            })
+        });
+        Ok((message.bytes, peer, local))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn try_recv_from_to(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+    socket
+        .try_recv_from(buf)
+        .map(|(size, peer)| (size, peer, None))
+}
+
+/// Waits for `socket` to become readable, or never completes if the listener isn't bound.
+async fn readable(socket: Option<&UdpSocket>) -> io::Result<&UdpSocket> {
+    match socket {
+        Some(socket) => socket.readable().await.map(|_| socket),
+        None => std::future::pending().await,
+    }
+}
+
+/// A socket bound to `local` sending only to `peer`, receiving datagrams only from it
+fn connect_socket(
+    local: SocketAddr,
+    peer: SocketAddr,
+    only_v6: bool,
+    egress: &Egress,
+) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))?;
+    if local.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(device) = &egress.interface {
+        set_device(&socket, device)?;
+    }
+    if let Some(mark) = egress.fwmark {
+        set_mark(&socket, mark)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&local.into())?;
+    socket.connect(&peer.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn bind_socket(addr: SocketAddr, only_v6: bool, device: Option<&str>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        // a v4 listener on the same port would conflict with a dual-stack v6 socket
+        socket.set_only_v6(only_v6).map_err(|e| match only_v6 {
+            // some platforms, e.g. OpenBSD, have no dual-stack sockets at all
+            false => io::Error::new(
+                e.kind(),
+                format!(
+                    "dual-stack sockets are unsupported here, also listen on a v4 address: {e}"
+                ),
+            ),
+            true => e,
+        })?;
+    }
+    // before binding, as the address may only be local within the device
+    if let Some(device) = device {
+        set_device(&socket, device)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
@@ -0,0 +1,150 @@
+/*
+* mock.rs is an in-memory transport for driving a router deterministically in tests
+*/
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{Notify, watch};
+
+use super::PacketTransport;
+
+enum Inbound {
+    /// source, local address it was sent to, payload
+    Packet(SocketAddr, Option<IpAddr>, Vec<u8>),
+    Error(io::ErrorKind),
+}
+
+#[derive(Default)]
+struct Inner {
+    inbox: Mutex<VecDeque<Inbound>>,
+    inbox_ready: Notify,
+    sent: Mutex<Vec<(SocketAddr, Vec<u8>)>>,
+    send_errors: Mutex<VecDeque<io::ErrorKind>>,
+    /// whether the receiving side is parked waiting on an empty inbox
+    idle: watch::Sender<bool>,
+}
+
+/// A loopback transport backed by in-memory queues.
+///
+/// Cloning yields another handle to the same queues, so a test keeps one handle
+/// while the router owns the other. Packets are received in the order they were
+/// queued, which makes the interleaving of clients and backends fully controllable.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    inner: Arc<Inner>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a packet as if it arrived from `from`
+    pub fn push(&self, from: SocketAddr, data: &[u8]) {
+        self.enqueue(Inbound::Packet(from, None, data.to_vec()), false);
+    }
+
+    /// Queues a packet as if it arrived from `from` on the local address `local`
+    pub fn push_to(&self, from: SocketAddr, local: IpAddr, data: &[u8]) {
+        self.enqueue(Inbound::Packet(from, Some(local), data.to_vec()), false);
+    }
+
+    /// Queues a packet ahead of everything else that is still pending
+    pub fn push_front(&self, from: SocketAddr, data: &[u8]) {
+        self.enqueue(Inbound::Packet(from, None, data.to_vec()), true);
+    }
+
+    /// Queues a receive error, returned once all packets queued before it were received
+    pub fn push_recv_error(&self, kind: io::ErrorKind) {
+        self.enqueue(Inbound::Error(kind), false);
+    }
+
+    /// Makes the next send fail with `kind` instead of being recorded
+    pub fn fail_next_send(&self, kind: io::ErrorKind) {
+        self.inner.send_errors.lock().unwrap().push_back(kind);
+    }
+
+    /// Waits until every queued packet was received and fully processed,
+    /// i.e. the receiver came back for more while the inbox was empty
+    pub async fn settle(&self) {
+        let mut idle = self.inner.idle.subscribe();
+        let _ = idle.wait_for(|idle| *idle).await;
+    }
+
+    /// The number of queued datagrams the router hasn't received yet
+    pub fn pending(&self) -> usize {
+        self.inner.inbox.lock().unwrap().len()
+    }
+
+    /// Drains the packets sent so far, oldest first
+    pub fn take_sent(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut *self.inner.sent.lock().unwrap())
+    }
+
+    fn enqueue(&self, inbound: Inbound, front: bool) {
+        let mut inbox = self.inner.inbox.lock().unwrap();
+        match front {
+            true => inbox.push_front(inbound),
+            false => inbox.push_back(inbound),
+        }
+        self.inner.idle.send_replace(false);
+        self.inner.inbox_ready.notify_one();
+    }
+}
+
+impl PacketTransport for MockTransport {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, from, _) = self.recv_from_to(buf).await?;
+        Ok((size, from))
+    }
+
+    async fn recv_from_to(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        loop {
+            let notified = self.inner.inbox_ready.notified();
+            {
+                let mut inbox = self.inner.inbox.lock().unwrap();
+                match inbox.pop_front() {
+                    Some(Inbound::Packet(from, local, data)) => {
+                        let size = data.len().min(buf.len());
+                        buf[..size].copy_from_slice(&data[..size]);
+                        return Ok((size, from, local));
+                    }
+                    Some(Inbound::Error(kind)) => return Err(kind.into()),
+                    None => {
+                        self.inner.idle.send_replace(true);
+                    }
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn try_recv_from_to(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<Option<(usize, SocketAddr, Option<IpAddr>)>> {
+        match self.inner.inbox.lock().unwrap().pop_front() {
+            Some(Inbound::Packet(from, local, data)) => {
+                let size = data.len().min(buf.len());
+                buf[..size].copy_from_slice(&data[..size]);
+                Ok(Some((size, from, local)))
+            }
+            Some(Inbound::Error(kind)) => Err(kind.into()),
+            None => Ok(None),
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        if let Some(kind) = self.inner.send_errors.lock().unwrap().pop_front() {
+            return Err(kind.into());
+        }
+        self.inner.sent.lock().unwrap().push((target, buf.to_vec()));
+        Ok(buf.len())
+    }
+}
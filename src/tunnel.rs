@@ -0,0 +1,301 @@
+/*
+* tunnel.rs carries the datagrams to some backends inside an outbound WireGuard tunnel the router
+* terminates itself, so backends on a private overlay are reachable without a tunnel on the host
+*
+* The router's datagrams are wrapped in UDP over IP packets from its address inside the tunnel.
+* The UDP payloads of packets coming back to that address are routed as if the backends had sent
+* them to the router directly.
+*/
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::Engine;
+use boringtun::noise::{Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc;
+
+use crate::error::Error;
+use crate::pcap;
+use crate::utils::checksum;
+
+/// How often the timers of a tunnel are run, for its handshakes, keepalives and rekeying
+const TIMER_TICK: Duration = Duration::from_millis(250);
+
+/// What encapsulation adds to a packet, the transport data header and the AEAD tag
+const OVERHEAD: usize = 32;
+
+/// Size of a handshake initiation, which encapsulating may send instead of the packet
+const HANDSHAKE_SIZE: usize = 148;
+
+const UDP_HEADER: usize = 8;
+
+/// A datagram from a backend, as passed on to the router
+type Received = (SocketAddr, Vec<u8>);
+
+/// An outbound WireGuard tunnel, a `[[tunnels]]` entry in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// what peers name the tunnel by, in `tunnel = "..."`
+    pub name: String,
+    /// file holding the base64 private key of the router's end
+    pub private_key_file: PathBuf,
+    /// base64 public key of the far end
+    pub public_key: String,
+    /// file holding a base64 preshared key, if the far end has one for the router
+    pub preshared_key_file: Option<PathBuf>,
+    /// where the far end listens
+    pub endpoint: SocketAddr,
+    /// the router's address inside the tunnel, which backends answer to
+    pub address: IpAddr,
+    /// the port the router sends from inside the tunnel
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// keepalive interval, for a far end that can't reach the router unless it sends first
+    pub keepalive_secs: Option<u16>,
+}
+
+fn default_port() -> u16 {
+    51820
+}
+
+/// An outbound WireGuard tunnel backends are sent to through, see
+/// [`Listeners::tunnels`](crate::transport::Listeners::tunnels)
+pub struct Tunnel {
+    name: String,
+    /// the router's end inside the tunnel
+    local: SocketAddr,
+    endpoint: SocketAddr,
+    tunn: Mutex<Tunn>,
+    /// connected to the endpoint
+    socket: UdpSocket,
+}
+
+impl Tunnel {
+    /// Reads the keys and opens a socket towards the far end, the handshake is made on the first
+    /// send
+    pub async fn open(config: &Config) -> Result<Self, Error> {
+        let invalid = |field: &str| {
+            Error::InvalidInput(format!(
+                "tunnel {:?}: {field} is not a base64 32 byte key",
+                config.name
+            ))
+        };
+        let private_key = read_key(&config.private_key_file)?;
+        let preshared_key = match &config.preshared_key_file {
+            Some(path) => Some(read_key(path)?),
+            None => None,
+        };
+        let public_key = decode_key(&config.public_key).ok_or_else(|| invalid("public_key"))?;
+        let tunn = Tunn::new(
+            StaticSecret::from(private_key),
+            PublicKey::from(public_key),
+            preshared_key,
+            config.keepalive_secs,
+            // the upper 24 bits of the session indices
+            OsRng.next_u32() >> 8,
+            None,
+        );
+        let local: SocketAddr = match config.endpoint {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await.map_err(Error::Bind)?;
+        socket.connect(config.endpoint).await.map_err(Error::Bind)?;
+        Ok(Tunnel {
+            name: config.name.clone(),
+            local: SocketAddr::new(config.address, config.port),
+            endpoint: config.endpoint,
+            tunn: Mutex::new(tunn),
+            socket,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends `payload` through the tunnel to `backend`, from the router's address inside it
+    ///
+    /// Datagrams sent before the handshake completed are queued by the tunnel, and sent once it
+    /// did.
+    pub async fn send(&self, payload: &[u8], backend: SocketAddr) -> io::Result<usize> {
+        let packet = udp_packet(self.local, backend, payload)?;
+        let mut buf = vec![0; (packet.len() + OVERHEAD).max(HANDSHAKE_SIZE)];
+        let datagram = match self.tunn.lock().unwrap().encapsulate(&packet, &mut buf) {
+            TunnResult::WriteToNetwork(datagram) => datagram.to_vec(),
+            TunnResult::Err(e) => {
+                return Err(io::Error::other(format!("tunnel {}: {e:?}", self.name)));
+            }
+            _ => return Ok(payload.len()),
+        };
+        self.socket.send(&datagram).await?;
+        Ok(payload.len())
+    }
+
+    /// Receives what comes back through the tunnel and runs its timers, passing the datagrams
+    /// of backends to the router's address on to `sender` until it is closed
+    pub(crate) async fn receive(self: Arc<Self>, sender: mpsc::Sender<Received>) {
+        let mut datagram = vec![0; u16::MAX as usize];
+        let mut buf = vec![0; u16::MAX as usize];
+        let mut timers = tokio::time::interval(TIMER_TICK);
+        loop {
+            let (to_network, received) = select! {
+                received = self.socket.recv(&mut datagram) => match received {
+                    Ok(size) => self.decapsulate(&datagram[..size], &mut buf),
+                    // e.g. the far end is unreachable, the handshake is retried by the timers
+                    Err(e) => {
+                        tracing::debug!("tunnel {}: {}", self.name, e);
+                        continue;
+                    }
+                },
+                _ = timers.tick() => {
+                    if sender.is_closed() {
+                        return;
+                    }
+                    (self.update_timers(&mut buf), None)
+                }
+            };
+            for datagram in to_network {
+                if let Err(e) = self.socket.send(&datagram).await {
+                    tracing::debug!("tunnel {}: {}", self.name, e);
+                }
+            }
+            if let Some(received) = received
+                && sender.send(received).await.is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// The datagrams to send to the far end in answer to `datagram`, and the backend's datagram
+    /// it carried, if any
+    fn decapsulate(&self, datagram: &[u8], buf: &mut [u8]) -> (Vec<Vec<u8>>, Option<Received>) {
+        let mut tunn = self.tunn.lock().unwrap();
+        let mut to_network = Vec::new();
+        match tunn.decapsulate(Some(self.endpoint.ip()), datagram, buf) {
+            TunnResult::WriteToNetwork(answer) => {
+                to_network.push(answer.to_vec());
+                // the packets queued while the handshake was made
+                while let TunnResult::WriteToNetwork(queued) = tunn.decapsulate(None, &[], buf) {
+                    to_network.push(queued.to_vec());
+                }
+            }
+            TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+                let received = pcap::udp(packet)
+                    .filter(|(_, destination, _)| *destination == self.local)
+                    .map(|(source, _, payload)| (source, payload.to_vec()));
+                return (to_network, received);
+            }
+            TunnResult::Err(e) => tracing::debug!("tunnel {}: {:?}", self.name, e),
+            TunnResult::Done => {}
+        }
+        (to_network, None)
+    }
+
+    fn update_timers(&self, buf: &mut [u8]) -> Vec<Vec<u8>> {
+        match self.tunn.lock().unwrap().update_timers(buf) {
+            TunnResult::WriteToNetwork(datagram) => vec![datagram.to_vec()],
+            TunnResult::Err(e) => {
+                tracing::debug!("tunnel {}: {:?}", self.name, e);
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn decode_key(encoded: &str) -> Option<[u8; 32]> {
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?
+        .try_into()
+        .ok()
+}
+
+fn read_key(path: &Path) -> Result<[u8; 32], Error> {
+    let encoded = std::fs::read_to_string(path).map_err(|source| Error::ReadFile {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    decode_key(&encoded).ok_or_else(|| {
+        Error::InvalidInput(format!(
+            "{} does not hold a base64 32 byte key",
+            path.display()
+        ))
+    })
+}
+
+/// A UDP over IP packet from `source` to `destination` carrying `payload`
+pub fn udp_packet(
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: &[u8],
+) -> io::Result<Vec<u8>> {
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "datagram too long");
+    let udp_len = u16::try_from(UDP_HEADER + payload.len()).map_err(|_| too_long())?;
+    let mut segment = Vec::with_capacity(usize::from(udp_len));
+    segment.extend_from_slice(&source.port().to_be_bytes());
+    segment.extend_from_slice(&destination.port().to_be_bytes());
+    segment.extend_from_slice(&udp_len.to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(payload);
+
+    let (mut packet, pseudo_header) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let total_len = udp_len.checked_add(20).ok_or_else(too_long)?;
+            let mut header = vec![0x45, 0];
+            header.extend_from_slice(&total_len.to_be_bytes());
+            // no identification, don't fragment
+            header.extend_from_slice(&[0, 0, 0x40, 0]);
+            header.extend_from_slice(&[64, 17, 0, 0]);
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+            let header_checksum = checksum(&header);
+            header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&destination.octets());
+            pseudo_header.extend_from_slice(&[0, 17]);
+            pseudo_header.extend_from_slice(&udp_len.to_be_bytes());
+            (header, pseudo_header)
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            header.extend_from_slice(&[17, 64]);
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&destination.octets());
+            pseudo_header.extend_from_slice(&u32::from(udp_len).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, 17]);
+            (header, pseudo_header)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't send to {destination} from {source} inside a tunnel"),
+            ));
+        }
+    };
+    let udp_checksum = match checksum(&[pseudo_header, segment.clone()].concat()) {
+        // zero means no checksum
+        0 => 0xffff,
+        udp_checksum => udp_checksum,
+    };
+    segment[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+    packet.extend_from_slice(&segment);
+    Ok(packet)
+}
@@ -20,11 +20,33 @@ pub fn mac(key: &[u8], input: &[u8]) -> [u8; 16] {
         .unwrap()
 }
 
-/// heuristics taken from https://wiki.wireshark.org/WireGuard
-/// It tests the first byte for a valid message type (1, 2, 3, or 4) and checks that the next three reserved bytes are zero.
+/// The internet checksum of RFC 1071
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// An initiation from `sender` to the peer with `pub_key`, empty but for a valid mac1, e.g. to
+/// ask where a router would route that peer's initiations
+pub fn initiation_to(pub_key: &[u8; 32], sender: u32) -> Vec<u8> {
+    use crate::packet::HandshakeInitiation;
+
+    let mut packet = vec![0u8; HandshakeInitiation::SIZE];
+    packet[0] = crate::packet::MessageType::HandshakeInitiation.code();
+    packet[4..8].copy_from_slice(&sender.to_le_bytes());
+    let key = crate::PeerKey::new(*pub_key).precomputed_hash_label_mac1;
+    let mac1 = mac(&key, &packet[..HandshakeInitiation::MAC1_OFFSET]);
+    packet[HandshakeInitiation::MAC1_OFFSET..][..16].copy_from_slice(&mac1);
+    packet
+}
+
+/// heuristics taken from https://wiki.wireshark.org/WireGuard, see [`crate::packet::is_wg_packet`]
 pub fn is_wg_packet(size: usize, packet: &[u8]) -> bool {
-    size > 4
-        && 0x01 <= packet[0]
-        && packet[0] <= 0x04
-        && (packet[1] | packet[2] | packet[3]) == 0x00
+    crate::packet::is_wg_packet(&packet[..size])
 }
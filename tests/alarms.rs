@@ -0,0 +1,77 @@
+#![cfg(feature = "alarms")]
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use wireguard_router::alarm::{self, Detectors, Kind};
+use wireguard_router::event::{DropReason, RouterEvent};
+use wireguard_router::packet::{Identity, MessageType};
+
+fn created() -> RouterEvent {
+    RouterEvent::SessionCreated {
+        client: "192.0.2.1:40000".parse().unwrap(),
+        backend: "10.0.0.1:51820".parse().unwrap(),
+        client_index: Identity([1, 0, 0, 0]),
+    }
+}
+
+#[test]
+fn alarms_fire_once_and_resolve() {
+    let mut detectors = Detectors::new(alarm::Config {
+        forward_errors_per_min: u64::MAX,
+        ..Default::default()
+    });
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+
+    assert!(detectors.check(100, at(0)).is_empty());
+    let alarms = detectors.check(40, at(60));
+    assert_eq!(alarms.len(), 1);
+    assert_eq!(
+        (alarms[0].kind, alarms[0].firing),
+        (Kind::SessionDrop, true)
+    );
+    assert!(detectors.check(30, at(70)).is_empty());
+
+    // the drop leaves the window
+    let alarms = detectors.check(30, at(400));
+    assert_eq!(
+        (alarms[0].kind, alarms[0].firing),
+        (Kind::SessionDrop, false)
+    );
+
+    for _ in 0..30 {
+        detectors.observe(&created(), at(410));
+    }
+    let alarms = detectors.check(30, at(420));
+    assert_eq!(alarms.len(), 1);
+    assert_eq!(alarms[0].kind, Kind::HandshakeFailures);
+    assert_eq!(alarms[0].value, 1.0);
+}
+
+#[test]
+fn send_failures_are_counted_per_minute() {
+    let mut detectors = Detectors::new(alarm::Config {
+        forward_errors_per_min: 2,
+        ..Default::default()
+    });
+    let start = Instant::now();
+    let failed = RouterEvent::Dropped {
+        message: Some(MessageType::TransportData),
+        source: "192.0.2.1:40000".parse().unwrap(),
+        reason: DropReason::SendFailed {
+            destination: "10.0.0.1:51820".parse().unwrap(),
+            kind: io::ErrorKind::ConnectionRefused,
+        },
+    };
+    for _ in 0..3 {
+        detectors.observe(&failed, start);
+    }
+    let alarms = detectors.check(0, start + Duration::from_secs(10));
+    assert_eq!(
+        (alarms[0].kind, alarms[0].value),
+        (Kind::ForwardErrors, 3.0)
+    );
+    let alarms = detectors.check(0, start + Duration::from_secs(70));
+    assert!(!alarms[0].firing);
+}
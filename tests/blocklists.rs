@@ -0,0 +1,88 @@
+#![cfg(feature = "blocklists")]
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use wireguard_router::blocklist::{self, Config, Feed, Format};
+use wireguard_router::denylist::Denylist;
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn plain_and_json_lists_are_parsed() {
+    let plain = "# Spamhaus DROP\n192.0.2.0/24 ; SBL1\n\n2001:db8::/32\n198.51.100.7 extra\n";
+    let networks = blocklist::parse(plain, Format::Plain).unwrap();
+    let networks: Vec<String> = networks.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        networks,
+        ["192.0.2.0/24", "2001:db8::/32", "198.51.100.7/32"]
+    );
+
+    let json =
+        r#"["192.0.2.1", {"cidr": "198.51.100.0/24", "source": "x"}, {"ip": "2001:db8::1"}]"#;
+    let networks = blocklist::parse(json, Format::Json).unwrap();
+    assert_eq!(networks.len(), 3);
+    assert!(networks[1].contains(ip("198.51.100.200")));
+
+    assert!(blocklist::parse("192.0.2.0/33\n", Format::Plain).is_err());
+    assert!(blocklist::parse(r#"[{"asn": 64496}]"#, Format::Json).is_err());
+}
+
+/// Serves the bodies one request after another, the last one from then on
+async fn serve(listener: TcpListener, bodies: Vec<&'static str>) {
+    for i in 0.. {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request).await.unwrap();
+        let body = bodies[i.min(bodies.len() - 1)];
+        let response = match body {
+            "" => "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n".to_string(),
+            body => format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        };
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn feeds_replace_their_networks_and_keep_them_on_failure() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/drop.txt", listener.local_addr().unwrap());
+    tokio::spawn(serve(
+        listener,
+        vec!["192.0.2.0/24\n", "198.51.100.0/24\n", ""],
+    ));
+    let denylist = Denylist::default();
+    denylist.set("config", vec!["203.0.113.1".parse().unwrap()]);
+    let config = Config {
+        feeds: vec![Feed {
+            url: url.clone(),
+            format: None,
+        }],
+        interval_secs: 1,
+    };
+    tokio::spawn(blocklist::refresh(config, denylist.clone()));
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(denylist.contains(ip("192.0.2.9")));
+    assert!(denylist.contains(ip("203.0.113.1")));
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(!denylist.contains(ip("192.0.2.9")));
+    assert!(denylist.contains(ip("198.51.100.9")));
+
+    // the feed is unavailable from now on
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(denylist.contains(ip("198.51.100.9")));
+    assert_eq!(
+        denylist.sources(),
+        [("config".to_string(), 1), (format!("feed:{url}"), 1)]
+    );
+}
@@ -0,0 +1,170 @@
+#![cfg(all(feature = "runtime", feature = "watch"))]
+
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::Command;
+
+use common::*;
+
+/// A fresh directory for the files of the test `name`
+fn scratch(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("wireguard-router-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the binary with `args`, returning what it printed
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_wireguard-router"))
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs the binary with `args`, expecting it to fail, returning what it logged
+#[cfg(feature = "admin")]
+fn fail(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_wireguard-router"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    // the log goes to stdout
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// A raw IP capture of the UDP datagrams `(source, destination, payload)`, a second apart
+fn capture(datagrams: &[(SocketAddr, SocketAddr, Vec<u8>)]) -> Vec<u8> {
+    let mut file = Vec::new();
+    file.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    file.extend_from_slice(&[2, 0, 4, 0]);
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&65535u32.to_le_bytes());
+    file.extend_from_slice(&101u32.to_le_bytes()); // raw IP
+    for (i, (source, destination, payload)) in datagrams.iter().enumerate() {
+        let (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) = (source.ip(), destination.ip())
+        else {
+            panic!("only IPv4 is captured");
+        };
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&((20 + 8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+        packet.extend_from_slice(&source_ip.octets());
+        packet.extend_from_slice(&destination_ip.octets());
+        packet.extend_from_slice(&source.port().to_be_bytes());
+        packet.extend_from_slice(&destination.port().to_be_bytes());
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+
+        file.extend_from_slice(&(100 + i as u32).to_le_bytes());
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        file.extend_from_slice(&packet);
+    }
+    file
+}
+
+#[test]
+fn decode_matches_captured_initiations_to_peers() {
+    let dir = scratch("decode");
+    let backend = peer("10.0.0.1:51820", 1);
+    let config = dir.join("config.toml");
+    std::fs::write(
+        &config,
+        "peers = [{ address = \"10.0.0.1:51820\", \
+         pubkey = \"AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=\" }]\n",
+    )
+    .unwrap();
+    let pcap = dir.join("capture.pcap");
+    let client = addr("192.0.2.1:40000");
+    std::fs::write(
+        &pcap,
+        capture(&[
+            (client, backend.address, initiation(7, &backend)),
+            (
+                client,
+                backend.address,
+                initiation(8, &peer("10.0.0.2:51820", 2)),
+            ),
+            (client, backend.address, b"not wireguard".to_vec()),
+        ]),
+    )
+    .unwrap();
+
+    let output = run(&[
+        "decode",
+        "--pcap",
+        pcap.to_str().unwrap(),
+        "--config",
+        config.to_str().unwrap(),
+    ]);
+    let records: Vec<&str> = output.split('#').skip(1).collect();
+    assert_eq!(records.len(), 3, "{output}");
+    assert!(records[0].starts_with("1 100.000000 192.0.2.1:40000 -> 10.0.0.1:51820"));
+    assert!(records[0].contains("type: HandshakeInitiation (1), 148 bytes"));
+    assert!(records[0].contains("matches peer: 10.0.0.1:51820"));
+    assert!(records[1].contains("matches no configured peer"));
+    assert!(records[2].contains("not a WireGuard message (13 bytes)"));
+}
+
+#[test]
+fn replay_routes_a_capture_like_the_router_did() {
+    let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+    let output = run(&[
+        "replay",
+        &format!("{data}/replay.pcap"),
+        "--config",
+        &format!("{data}/replay.toml"),
+    ]);
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "#1 100.000000 192.0.2.1:40000 -> 203.0.113.1:51337",
+            "  session 00000007 created: 192.0.2.1:40000 -> 10.0.0.1:51820",
+            "  forwarded HandshakeInitiation: 192.0.2.1:40000 -> 10.0.0.1:51820",
+            "#3 102.000000 10.0.0.1:51820 -> 203.0.113.1:51337",
+            "  session 00000007 established with backend index 00000009: 192.0.2.1:40000 -> 10.0.0.1:51820",
+            "  forwarded HandshakeResponse: 10.0.0.1:51820 -> 192.0.2.1:40000",
+            "#5 104.000000 192.0.2.1:40000 -> 203.0.113.1:51337",
+            "  forwarded TransportData: 192.0.2.1:40000 -> 10.0.0.1:51820",
+            "#7 106.000000 10.0.0.1:51820 -> 203.0.113.1:51337",
+            "  forwarded TransportData: 10.0.0.1:51820 -> 192.0.2.1:40000",
+            "#9 108.000000 192.0.2.2:40000 -> 203.0.113.1:51337",
+            "  dropped HandshakeInitiation from 192.0.2.2:40000: unknown backend",
+            "replayed 5 datagrams, skipped 4 the router sent, 1 sessions:",
+            "  00000007 192.0.2.1:40000 -> 10.0.0.1:51820 backend index 00000009, 4 packets, 352 bytes",
+        ]
+    );
+}
+
+#[cfg(feature = "admin")]
+#[test]
+fn admin_settings_are_checked_when_the_config_loads() {
+    let dir = scratch("admin");
+    let config = dir.join("config.toml");
+    std::fs::write(
+        &config,
+        "[admin]\nlisten = \"127.0.0.1:0\"\nprofiling = true\n",
+    )
+    .unwrap();
+    let pcap = dir.join("capture.pcap");
+    std::fs::write(&pcap, capture(&[])).unwrap();
+
+    let log = fail(&[
+        "replay",
+        pcap.to_str().unwrap(),
+        "--config",
+        config.to_str().unwrap(),
+    ]);
+    assert!(log.contains("admin profiling needs"), "{log}");
+}
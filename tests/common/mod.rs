@@ -0,0 +1,112 @@
+//! Harness driving a [`Router`] over a [`MockTransport`]
+
+#![allow(dead_code)]
+
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use wireguard_router::Peer;
+use wireguard_router::denylist::Denylist;
+use wireguard_router::error::Error;
+use wireguard_router::router::{
+    Explainer, GcTrigger, Injector, Lockdown, MemoryMeter, Pins, Router, RouterBuilder,
+    SessionTable,
+};
+use wireguard_router::testing;
+use wireguard_router::transport::mock::MockTransport;
+
+// not every test uses them, like the rest of this module
+#[allow(unused_imports)]
+pub use wireguard_router::testing::{cookie_reply, transport};
+
+pub struct Harness {
+    pub net: MockTransport,
+    pub peers: watch::Sender<Vec<Peer>>,
+    pub router: JoinHandle<Result<(), Error>>,
+    pub sessions: SessionTable,
+    pub gc: GcTrigger,
+    pub lockdown: Lockdown,
+    pub pins: Pins,
+    pub denylist: Denylist,
+    pub memory: MemoryMeter,
+    pub explainer: Explainer,
+    pub injector: Injector,
+}
+
+impl Harness {
+    pub fn start(peers: Vec<Peer>) -> Self {
+        Self::start_with(peers, |router| router)
+    }
+
+    /// Starts the router after letting `configure` customize it
+    pub fn start_with(
+        peers: Vec<Peer>,
+        configure: impl FnOnce(RouterBuilder<MockTransport>) -> RouterBuilder<MockTransport>,
+    ) -> Self {
+        let net = MockTransport::new();
+        let (peers_tx, peers_rx) = watch::channel(peers);
+        let router = configure(Router::builder(net.clone())).build();
+        let sessions = router.session_table();
+        let gc = router.gc_trigger();
+        let lockdown = router.lockdown();
+        let pins = router.pins();
+        let denylist = router.denylist();
+        let memory = router.memory_meter();
+        let explainer = router.explainer();
+        let injector = router.injector();
+        let router = tokio::spawn(router.run(peers_rx));
+        Harness {
+            net,
+            peers: peers_tx,
+            router,
+            sessions,
+            gc,
+            lockdown,
+            pins,
+            denylist,
+            memory,
+            explainer,
+            injector,
+        }
+    }
+
+    /// Delivers `data` from `from` and returns everything the router sent in response
+    pub async fn deliver(&self, from: SocketAddr, data: &[u8]) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.net.push(from, data);
+        self.net.settle().await;
+        self.net.take_sent()
+    }
+
+    /// Like [`deliver`](Self::deliver), with `data` sent to the local address `local`
+    pub async fn deliver_to(
+        &self,
+        from: SocketAddr,
+        local: IpAddr,
+        data: &[u8],
+    ) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.net.push_to(from, local, data);
+        self.net.settle().await;
+        self.net.take_sent()
+    }
+}
+
+pub fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+/// A peer whose public key is `[seed; 32]`
+pub fn peer(address: &str, seed: u8) -> Peer {
+    Peer::new(addr(address), [seed; 32])
+}
+
+/// The public key of the clients, which responses carry the mac1 for
+pub const CLIENT_KEY: [u8; 32] = [0xc1; 32];
+
+pub fn initiation(sender: u32, peer: &Peer) -> Vec<u8> {
+    testing::initiation(sender, &peer.pub_key)
+}
+
+pub fn response(sender: u32, receiver: u32) -> Vec<u8> {
+    testing::response(sender, receiver, &CLIENT_KEY)
+}
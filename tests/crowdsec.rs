@@ -0,0 +1,139 @@
+#![cfg(feature = "crowdsec")]
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use wireguard_router::crowdsec::{self, Alert, Bans, Config, Floods, Stream};
+use wireguard_router::denylist::Denylist;
+use wireguard_router::event::{DropReason, RouterEvent};
+use wireguard_router::packet::{Identity, MessageType};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn stream(json: &str) -> Stream {
+    serde_json::from_str(json).unwrap()
+}
+
+#[test]
+fn bans_follow_the_decision_stream() {
+    let mut bans = Bans::default();
+    bans.apply(
+        stream(
+            r#"{"new": [
+                {"scope": "Ip", "value": "192.0.2.1", "type": "ban", "duration": "4h"},
+                {"scope": "Range", "value": "198.51.100.0/24", "type": "ban"},
+                {"scope": "Ip", "value": "192.0.2.1", "type": "ban", "scenario": "other"},
+                {"scope": "Ip", "value": "192.0.2.2", "type": "captcha"},
+                {"scope": "Country", "value": "XX", "type": "ban"}
+            ], "deleted": null}"#,
+        ),
+        true,
+    );
+    let banned: Vec<String> = bans.networks().iter().map(ToString::to_string).collect();
+    assert_eq!(banned, ["192.0.2.1/32", "198.51.100.0/24"]);
+
+    // the address stays banned by its other decision
+    bans.apply(
+        stream(
+            r#"{"new": null, "deleted": [
+                {"scope": "Ip", "value": "192.0.2.1", "type": "ban"},
+                {"scope": "Range", "value": "198.51.100.0/24", "type": "ban"}
+            ]}"#,
+        ),
+        false,
+    );
+    let banned: Vec<String> = bans.networks().iter().map(ToString::to_string).collect();
+    assert_eq!(banned, ["192.0.2.1/32"]);
+
+    bans.apply(Stream::default(), true);
+    assert!(bans.networks().is_empty());
+}
+
+#[test]
+fn floods_are_reported_once_per_window() {
+    let window = Duration::from_secs(10);
+    let mut floods = Floods::new(3, window);
+    let start = Instant::now();
+    let initiation = |port| RouterEvent::Dropped {
+        message: Some(MessageType::HandshakeInitiation),
+        source: format!("192.0.2.1:{port}").parse().unwrap(),
+        reason: DropReason::UnknownBackend,
+    };
+    let created = RouterEvent::SessionCreated {
+        client: "192.0.2.1:40000".parse().unwrap(),
+        backend: "10.0.0.1:51820".parse().unwrap(),
+        client_index: Identity([1, 0, 0, 0]),
+    };
+    let mut observe = |event: &RouterEvent, secs| {
+        floods.observe(event, start + Duration::from_secs(secs), SystemTime::now())
+    };
+    for port in 0..3 {
+        assert_eq!(observe(&initiation(port), 0), None);
+    }
+    let flood = observe(&created, 1).unwrap();
+    assert_eq!(flood.source, ip("192.0.2.1"));
+    assert_eq!(flood.initiations, 4);
+    assert_eq!(observe(&initiation(4), 2), None);
+    // the flood goes on past the window
+    for secs in 5..11 {
+        observe(&initiation(5), secs);
+    }
+    assert!(observe(&initiation(6), 12).is_some());
+
+    let alert = Alert::flood(&flood, 3, window);
+    let alert = serde_json::to_value(&alert).unwrap();
+    assert_eq!(alert["scenario"], crowdsec::FLOOD_SCENARIO);
+    assert_eq!(alert["source"]["value"], "192.0.2.1");
+    assert_eq!(alert["events_count"], 4);
+    assert_eq!(alert["leakspeed"], "10s");
+}
+
+#[tokio::test]
+async fn bans_of_the_local_api_are_denylisted() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let answers = [
+            r#"{"new": [{"scope": "Ip", "value": "192.0.2.1", "type": "ban"}], "deleted": []}"#,
+            r#"{"new": [{"scope": "Range", "value": "198.51.100.0/24", "type": "ban"}], "deleted": [{"scope": "Ip", "value": "192.0.2.1", "type": "ban"}]}"#,
+            r#"{"new": null, "deleted": null}"#,
+        ];
+        for i in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let len = stream.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..len]).to_lowercase();
+            assert!(request.contains("x-api-key: bouncer-key"));
+            assert_eq!(request.contains("startup=true"), i == 0);
+            let body = answers[i.min(answers.len() - 1)];
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    let config = Config {
+        url,
+        api_key: "bouncer-key".to_string(),
+        interval_secs: 1,
+        machine_id: None,
+        password: None,
+        flood_initiations: 100,
+        flood_window_secs: 10,
+    };
+    let denylist = Denylist::default();
+    tokio::spawn(crowdsec::bounce(config, denylist.clone()));
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(denylist.contains(ip("192.0.2.1")));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(!denylist.contains(ip("192.0.2.1")));
+    assert!(denylist.contains(ip("198.51.100.7")));
+    assert_eq!(denylist.sources(), [(crowdsec::SOURCE.to_string(), 1)]);
+}
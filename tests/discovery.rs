@@ -0,0 +1,30 @@
+#![cfg(feature = "runtime")]
+
+mod common;
+
+use common::*;
+use wireguard_router::discovery::PeerSet;
+
+fn addresses(peers: &[wireguard_router::Peer]) -> Vec<String> {
+    peers.iter().map(|p| p.address.to_string()).collect()
+}
+
+#[test]
+fn discovered_peers_follow_the_configured_ones() {
+    let (set, rx) = PeerSet::new(vec![peer("10.0.0.1:51820", 1)]);
+
+    set.set_discovered("b", vec![peer("10.0.1.1:51820", 2)]);
+    set.set_discovered("a", vec![peer("10.0.2.1:51820", 3)]);
+    assert_eq!(
+        addresses(&rx.borrow()),
+        ["10.0.0.1:51820", "10.0.2.1:51820", "10.0.1.1:51820"]
+    );
+
+    // each source replaces only its own peers
+    set.set_discovered("b", vec![]);
+    set.set_configured(vec![peer("10.0.0.2:51820", 1)]);
+    assert_eq!(
+        addresses(&rx.borrow()),
+        ["10.0.0.2:51820", "10.0.2.1:51820"]
+    );
+}
@@ -0,0 +1,60 @@
+#![cfg(feature = "runtime")]
+
+mod common;
+
+use common::*;
+use wireguard_router::event::{DropReason, RouterEvent};
+use wireguard_router::packet::{Identity, MessageType};
+use wireguard_router::router::Router;
+use wireguard_router::transport::mock::MockTransport;
+
+#[tokio::test]
+async fn packets_can_be_injected_and_observed() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let client = addr("192.0.2.1:40000");
+    let net = MockTransport::new();
+    let mut router = Router::new(net.clone());
+    router.set_peers(vec![backend.clone()]).await;
+    let mut events = router.subscribe();
+
+    let init = initiation(1, &backend);
+    router.process_packet(client, &init).await;
+    assert_eq!(net.take_sent(), vec![(backend.address, init)]);
+    assert_eq!(
+        events.try_recv().unwrap(),
+        RouterEvent::SessionCreated {
+            client,
+            backend: backend.address,
+            client_index: Identity::from_u32(1),
+        }
+    );
+    assert_eq!(
+        events.try_recv().unwrap(),
+        RouterEvent::Forwarded {
+            message: MessageType::HandshakeInitiation,
+            source: client,
+            destination: backend.address,
+        }
+    );
+
+    router.process_packet(client, &transport(9, 0, 16)).await;
+    assert!(net.take_sent().is_empty());
+    assert_eq!(
+        events.try_recv().unwrap(),
+        RouterEvent::Dropped {
+            message: Some(MessageType::TransportData),
+            source: client,
+            reason: DropReason::NoSession,
+        }
+    );
+
+    router.process_packet(client, b"hello").await;
+    assert_eq!(
+        events.try_recv().unwrap(),
+        RouterEvent::Dropped {
+            message: None,
+            source: client,
+            reason: DropReason::NotWireguard,
+        }
+    );
+}
@@ -0,0 +1,134 @@
+#![cfg(feature = "export")]
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use wireguard_router::event::{DropReason, RouterEvent};
+use wireguard_router::export::{self, Kind, Queue, Record, Sink};
+use wireguard_router::packet::{Identity, MessageType};
+
+fn created(port: u16) -> RouterEvent {
+    RouterEvent::SessionCreated {
+        client: SocketAddr::from(([192, 0, 2, 1], port)),
+        backend: "10.0.0.1:51820".parse().unwrap(),
+        client_index: Identity([1, 0, 0, 0]),
+    }
+}
+
+fn record(port: u16) -> Record {
+    Record::from_event(&created(port), 0).unwrap()
+}
+
+#[test]
+fn session_and_handshake_events_are_exported() {
+    let record = Record::from_event(&created(40000), 1234).unwrap();
+    assert_eq!(record.kind, Kind::SessionCreated);
+    assert_eq!(record.timestamp_ms, 1234);
+    assert_eq!(record.backend, Some("10.0.0.1:51820".parse().unwrap()));
+
+    let dropped = |message| RouterEvent::Dropped {
+        message: Some(message),
+        source: "192.0.2.1:40000".parse().unwrap(),
+        reason: DropReason::UnknownBackend,
+    };
+    let record = Record::from_event(&dropped(MessageType::HandshakeInitiation), 0).unwrap();
+    assert_eq!(record.kind, Kind::HandshakeDropped);
+    assert_eq!(record.message, Some("initiation"));
+    assert_eq!(record.reason, Some(DropReason::UnknownBackend.to_string()));
+
+    assert_eq!(
+        Record::from_event(&dropped(MessageType::TransportData), 0),
+        None
+    );
+    let forwarded = RouterEvent::Forwarded {
+        message: MessageType::HandshakeInitiation,
+        source: "192.0.2.1:40000".parse().unwrap(),
+        destination: "10.0.0.1:51820".parse().unwrap(),
+    };
+    assert_eq!(Record::from_event(&forwarded, 0), None);
+}
+
+#[test]
+fn records_are_json_lines() {
+    let lines = export::json_lines(&[record(1), record(2)]);
+    let rows: Vec<serde_json::Value> = lines
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["kind"], "session_created");
+    assert_eq!(rows[0]["client"], "192.0.2.1:1");
+    assert_eq!(rows[1]["backend_index"], serde_json::Value::Null);
+}
+
+#[test]
+fn full_queue_drops_the_oldest() {
+    let mut queue = Queue::new(3);
+    for port in 0..5 {
+        queue.push(record(port));
+    }
+    assert_eq!(queue.take_dropped(), 2);
+
+    let batch = queue.batch(2);
+    assert_eq!(batch, vec![record(2), record(3)]);
+    queue.push(record(5));
+    // only the newer of the failed batch fits back, before the records queued meanwhile
+    queue.requeue(batch);
+    assert_eq!(queue.take_dropped(), 1);
+    assert_eq!(queue.batch(10), vec![record(3), record(4), record(5)]);
+}
+
+#[tokio::test]
+async fn batches_are_inserted_into_clickhouse() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        // the whole request, its body being the rows
+        while !request.ends_with(b"}\n") {
+            let read = stream.read(&mut buf).await.unwrap();
+            assert!(read > 0);
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    });
+
+    let (tx, rx) = broadcast::channel(16);
+    let config = export::Config {
+        sink: Sink::Clickhouse {
+            url,
+            table: "events".to_string(),
+            user: None,
+            password: None,
+        },
+        batch_size: 10,
+        flush_secs: 60,
+        queue: 100,
+        instance: Some("router-1".to_string()),
+    };
+    let exporting = tokio::spawn(export::export(config, rx));
+    tx.send(created(1)).unwrap();
+    tx.send(created(2)).unwrap();
+    // what is queued is sent once the router stops
+    drop(tx);
+
+    let request = tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(request.starts_with("POST /?query=INSERT+INTO+events+FORMAT+JSONEachRow "));
+    assert_eq!(request.matches("\"instance\":\"router-1\"").count(), 2);
+    tokio::time::timeout(Duration::from_secs(10), exporting)
+        .await
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,82 @@
+#![cfg(feature = "runtime")]
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use wireguard_router::health::{BackendState, Health, OutlierDetection, Thresholds};
+
+#[test]
+fn flapping_backends_recover_only_after_consecutive_successes() {
+    let health = Health::new(Thresholds {
+        down_after: 2,
+        up_after: 3,
+        ..Default::default()
+    });
+    let backend: SocketAddr = "10.0.0.1:51820".parse().unwrap();
+
+    assert!(!health.failed(backend));
+    assert_eq!(health.states()[&backend], BackendState::Failing);
+    assert!(health.failed(backend));
+    assert_eq!(health.states()[&backend], BackendState::Down);
+    assert!(health.is_down(backend));
+
+    assert!(!health.succeeded(backend));
+    assert!(!health.succeeded(backend));
+    assert_eq!(health.states()[&backend], BackendState::Recovering);
+    // a failure while recovering starts the count over
+    assert!(!health.failed(backend));
+    assert_eq!(health.states()[&backend], BackendState::Down);
+
+    for _ in 0..2 {
+        assert!(!health.succeeded(backend));
+    }
+    assert!(health.succeeded(backend));
+    assert!(health.states().is_empty());
+    assert!(!health.is_down(backend));
+}
+
+#[test]
+fn recovered_backends_start_slowly() {
+    let health = Health::new(Thresholds {
+        down_after: 1,
+        slow_start_secs: 3600,
+        ..Default::default()
+    });
+    let recovered: SocketAddr = "10.0.0.1:51820".parse().unwrap();
+    let other: SocketAddr = "10.0.0.2:51820".parse().unwrap();
+
+    assert!(health.failed(recovered));
+    assert!(health.succeeded(recovered));
+    // within the first seconds of an hour long ramp, hardly any session is admitted
+    let admitted = (0..100).filter(|_| health.admits(recovered)).count();
+    assert!(admitted < 5);
+    assert!(health.admits(other));
+}
+
+#[test]
+fn backends_losing_far_more_handshakes_are_ejected() {
+    let detection = OutlierDetection::default();
+    let pool: Vec<(SocketAddr, f64)> = (1..=5)
+        .map(|i| {
+            (
+                format!("10.0.0.{i}:51820").parse().unwrap(),
+                0.02 * i as f64,
+            )
+        })
+        .chain([("10.0.0.9:51820".parse().unwrap(), 0.9)])
+        .collect();
+    let outlier = pool[5].0;
+
+    assert_eq!(detection.outliers(&pool, 1), vec![outlier]);
+    assert!(detection.outliers(&pool, 0).is_empty());
+    // a pool losing handshakes across the board has no outlier
+    let lossy: Vec<(SocketAddr, f64)> = pool.iter().map(|(b, _)| (*b, 0.5)).collect();
+    assert!(detection.outliers(&lossy, 1).is_empty());
+
+    let health = Health::new(Thresholds::default());
+    health.eject(outlier, Duration::from_secs(60));
+    assert!(health.is_ejected(outlier));
+    assert_eq!(health.states()[&outlier], BackendState::Ejected);
+    health.eject(outlier, Duration::ZERO);
+    assert!(!health.is_ejected(outlier));
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "js")]
+
+// Errors become `JsError`s, which only exist on wasm32, so only accepted input is tested natively
+
+use base64::Engine;
+use wireguard_router::js::{initiation_matches, is_wg_packet, parse};
+use wireguard_router::testing::{cookie_reply, initiation, response, transport};
+
+const KEY: [u8; 32] = [1; 32];
+
+fn encode(key: &[u8; 32]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key)
+}
+
+#[test]
+fn parses_transport_data() {
+    let Ok(header) = parse(&transport(7, 42, 32)) else {
+        panic!("expected transport data to parse");
+    };
+    assert_eq!(header.message_type, 4);
+    assert_eq!(header.sender_index, None);
+    assert_eq!(header.receiver_index, Some(7));
+    assert_eq!(header.counter, Some(42));
+    assert_eq!(header.payload_len, Some(32));
+}
+
+#[test]
+fn parses_handshakes() {
+    let Ok(header) = parse(&initiation(7, &KEY)) else {
+        panic!("expected an initiation to parse");
+    };
+    assert_eq!(header.message_type, 1);
+    assert_eq!(header.sender_index, Some(7));
+    assert_eq!(header.receiver_index, None);
+
+    let Ok(header) = parse(&response(9, 7, &KEY)) else {
+        panic!("expected a response to parse");
+    };
+    assert_eq!(header.message_type, 2);
+    assert_eq!(header.sender_index, Some(9));
+    assert_eq!(header.receiver_index, Some(7));
+
+    let Ok(header) = parse(&cookie_reply(7)) else {
+        panic!("expected a cookie reply to parse");
+    };
+    assert_eq!(header.message_type, 3);
+    assert_eq!(header.receiver_index, Some(7));
+    assert_eq!(header.counter, None);
+}
+
+#[test]
+fn classifies_packets() {
+    assert!(is_wg_packet(&transport(7, 42, 32)));
+    assert!(is_wg_packet(&initiation(7, &KEY)));
+    assert!(!is_wg_packet(&[0x09; 32]));
+}
+
+#[test]
+fn matches_initiations_by_mac1() {
+    let packet = initiation(7, &KEY);
+    assert!(matches!(
+        initiation_matches(&encode(&KEY), &packet),
+        Ok(true)
+    ));
+    assert!(matches!(
+        initiation_matches(&encode(&[2; 32]), &packet),
+        Ok(false)
+    ));
+    assert!(matches!(
+        initiation_matches(&encode(&KEY), &transport(7, 42, 32)),
+        Ok(false)
+    ));
+}
@@ -0,0 +1,190 @@
+#![cfg(all(feature = "lua", feature = "runtime"))]
+
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use wireguard_router::policy::lua::LuaPolicy;
+
+const BUDGET: Duration = Duration::from_millis(100);
+
+#[tokio::test]
+async fn hook_selects_least_loaded_backend() {
+    let policy = LuaPolicy::from_source(
+        r#"
+        function on_initiation(info)
+            local best, best_sessions = nil, math.huge
+            for i, candidate in ipairs(info.candidates) do
+                if candidate.sessions < best_sessions then
+                    best, best_sessions = i, candidate.sessions
+                end
+            end
+            return best
+        end
+        "#,
+        "test",
+        BUDGET,
+    )
+    .unwrap();
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |r| r.policy(policy));
+    let client = addr("192.0.2.1:40000");
+
+    assert_eq!(
+        h.deliver(client, &initiation(1, &first)).await[0].0,
+        first.address
+    );
+    assert_eq!(
+        h.deliver(client, &initiation(2, &first)).await[0].0,
+        second.address
+    );
+    assert_eq!(
+        h.deliver(client, &initiation(3, &first)).await[0].0,
+        first.address
+    );
+}
+
+#[tokio::test]
+async fn hook_denylists_sources_from_lifecycle_events() {
+    // allows a single session per client address
+    let policy = LuaPolicy::from_source(
+        r#"
+        seen = {}
+        function on_initiation(info)
+            local ip = info.source:match("^(.*):%d+$")
+            if seen[ip] then
+                log.info("denying " .. ip)
+                return false
+            end
+        end
+        function on_session_created(event)
+            seen[event.client:match("^(.*):%d+$")] = true
+        end
+        "#,
+        "test",
+        BUDGET,
+    )
+    .unwrap();
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.policy(policy));
+
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &initiation(1, &backend))
+            .await
+            .len(),
+        1
+    );
+    assert!(
+        h.deliver(addr("192.0.2.1:40001"), &initiation(2, &backend))
+            .await
+            .is_empty()
+    );
+    assert_eq!(
+        h.deliver(addr("192.0.2.2:40000"), &initiation(3, &backend))
+            .await
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn failing_hook_falls_back_to_first_match() {
+    let policy = LuaPolicy::from_source(
+        r#"function on_initiation(info) error("boom") end"#,
+        "test",
+        BUDGET,
+    )
+    .unwrap();
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.policy(policy));
+
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &initiation(1, &backend))
+            .await[0]
+            .0,
+        backend.address
+    );
+}
+
+#[tokio::test]
+async fn candidates_count_live_sessions() {
+    let policy = LuaPolicy::from_source(
+        r#"
+        function on_initiation(info)
+            if info.candidates[1].sessions > 0 then
+                return 2
+            end
+            return 1
+        end
+        "#,
+        "test",
+        BUDGET,
+    )
+    .unwrap();
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |r| {
+        r.policy(policy).session_timeout(Duration::from_millis(20))
+    });
+    let client = addr("192.0.2.1:40000");
+
+    assert_eq!(
+        h.deliver(client, &initiation(1, &first)).await[0].0,
+        first.address
+    );
+    assert_eq!(
+        h.deliver(client, &initiation(2, &first)).await[0].0,
+        second.address
+    );
+
+    // the sessions that expired no longer count
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    h.gc.run().await.unwrap();
+    assert_eq!(
+        h.deliver(client, &initiation(3, &first)).await[0].0,
+        first.address
+    );
+}
+
+#[tokio::test]
+async fn hooks_running_out_of_time_fall_back_to_first_match() {
+    let policy = LuaPolicy::from_source(
+        r#"
+        function on_initiation(info) while true do end end
+        function on_session_created(event) while true do end end
+        "#,
+        "test",
+        BUDGET,
+    )
+    .unwrap();
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.policy(policy));
+
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &initiation(1, &backend))
+            .await[0]
+            .0,
+        backend.address
+    );
+}
+
+#[tokio::test]
+async fn out_of_range_candidates_fall_back_to_first_match() {
+    let policy = LuaPolicy::from_source(
+        r#"function on_initiation(info) return math.mininteger end"#,
+        "test",
+        BUDGET,
+    )
+    .unwrap();
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.policy(policy));
+
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &initiation(1, &backend))
+            .await[0]
+            .0,
+        backend.address
+    );
+}
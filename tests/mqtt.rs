@@ -0,0 +1,160 @@
+#![cfg(feature = "mqtt")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use wireguard_router::event::RouterEvent;
+use wireguard_router::health::{Health, Thresholds};
+use wireguard_router::metrics::Metrics;
+use wireguard_router::mqtt::{self, Sources};
+
+fn config(broker: &str) -> mqtt::Config {
+    mqtt::Config {
+        broker: broker.to_string(),
+        client_id: "router-test".to_string(),
+        username: None,
+        password: None,
+        topic_prefix: "site-1/router".to_string(),
+        interval_secs: 60,
+    }
+}
+
+/// Reads an MQTT control packet, its type and flags and the rest of it
+async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let header = stream.read_u8().await.unwrap();
+    let (mut len, mut shift) = (0usize, 0);
+    loop {
+        let byte = stream.read_u8().await.unwrap();
+        len |= usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut rest = vec![0; len];
+    stream.read_exact(&mut rest).await.unwrap();
+    (header, rest)
+}
+
+/// Accepts one client and collects what it publishes by topic, along with whether it was
+/// retained, until it disconnects
+async fn broker(listener: TcpListener) -> HashMap<String, Vec<(Vec<u8>, bool)>> {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut published: HashMap<String, Vec<_>> = HashMap::new();
+    loop {
+        let (header, rest) = read_packet(&mut stream).await;
+        match header >> 4 {
+            // CONNECT
+            1 => stream.write_all(&[0x20, 0x02, 0, 0]).await.unwrap(),
+            // PUBLISH
+            3 => {
+                let qos = (header >> 1) & 0x03;
+                let topic_len = usize::from(u16::from_be_bytes([rest[0], rest[1]]));
+                let topic = String::from_utf8(rest[2..2 + topic_len].to_vec()).unwrap();
+                let mut payload = &rest[2 + topic_len..];
+                if qos > 0 {
+                    let id = &payload[..2];
+                    stream.write_all(&[0x40, 0x02, id[0], id[1]]).await.unwrap();
+                    payload = &payload[2..];
+                }
+                let retained = header & 0x01 == 1;
+                published
+                    .entry(topic)
+                    .or_default()
+                    .push((payload.to_vec(), retained));
+            }
+            // PINGREQ
+            12 => stream.write_all(&[0xd0, 0]).await.unwrap(),
+            // DISCONNECT
+            14 => return published,
+            other => panic!("unexpected packet type {other}"),
+        }
+    }
+}
+
+#[test]
+fn broker_addresses_are_checked() {
+    assert!(config("mqtt://broker.example.com").options().is_ok());
+    assert!(config("mqtts://[::1]:8883").options().is_ok());
+    assert!(config("http://broker.example.com").options().is_err());
+    assert!(config("mqtt://broker.example.com:port").options().is_err());
+    let mut wildcard = config("mqtt://broker.example.com");
+    wildcard.topic_prefix = "site-1/#".to_string();
+    assert!(wildcard.options().is_err());
+}
+
+/// Other features compile in their own rustls providers, so none can be the process default
+#[cfg(any(feature = "quic", feature = "kubernetes"))]
+#[test]
+fn tls_brokers_are_configured_alongside_other_tls_features() {
+    assert!(config("mqtts://broker.example.com").options().is_ok());
+}
+
+#[tokio::test]
+async fn stats_and_health_transitions_are_published() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = config(&format!("mqtt://{}", listener.local_addr().unwrap()));
+    let broker = tokio::spawn(broker(listener));
+
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let harness = Harness::start_with(vec![backend.clone()], |router| {
+        router.metrics(metrics.clone())
+    });
+    harness
+        .deliver(addr("192.0.2.1:40000"), &initiation(1, &backend))
+        .await;
+    let (events, events_rx) = broadcast::channel(16);
+    let sources = Sources {
+        metrics,
+        sessions: harness.sessions.clone(),
+        health: Arc::new(Health::new(Thresholds::default())),
+        peers: harness.peers.subscribe(),
+        events: events_rx,
+    };
+    let options = config.options().unwrap();
+    let publishing = tokio::spawn(mqtt::publish(config, options, sources));
+
+    // the first stats go out right away
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    events
+        .send(RouterEvent::BackendDown {
+            backend: backend.address,
+        })
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    drop(events);
+    tokio::time::timeout(Duration::from_secs(10), publishing)
+        .await
+        .unwrap()
+        .unwrap();
+    let published = tokio::time::timeout(Duration::from_secs(10), broker)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let status: Vec<_> = published["site-1/router/status"]
+        .iter()
+        .map(|(payload, retained)| (payload.as_slice(), *retained))
+        .collect();
+    assert_eq!(status, [(&b"online"[..], true), (&b"offline"[..], true)]);
+
+    let (stats, retained) = &published["site-1/router/stats"][0];
+    let stats: serde_json::Value = serde_json::from_slice(stats).unwrap();
+    assert!(retained);
+    assert_eq!(stats["sessions"], 1);
+    assert_eq!(stats["sessions_created"], 1);
+    assert_eq!(stats["backends"], 1);
+
+    let (transition, retained) = &published["site-1/router/backends/10.0.0.1:51820"][0];
+    let transition: serde_json::Value = serde_json::from_slice(transition).unwrap();
+    assert!(retained);
+    assert_eq!(transition["state"], "down");
+}
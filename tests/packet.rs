@@ -0,0 +1,117 @@
+#![cfg(feature = "runtime")]
+
+mod common;
+
+use common::{CLIENT_KEY, cookie_reply, initiation, peer, response, transport};
+use wireguard_router::packet::{
+    HandshakeInitiation, HandshakeResponse, Identity, MessageType, ParseError, WireguardPacket,
+    is_wg_packet,
+};
+use wireguard_router::{PeerKey, utils};
+
+#[test]
+fn exposes_indices_and_counters_as_integers() {
+    let data = initiation(0xdeadbeef, &peer("127.0.0.1:1", 1));
+    let Ok(WireguardPacket::HandshakeInitiation(packet)) = WireguardPacket::parse(&data) else {
+        panic!("expected an initiation");
+    };
+    assert_eq!(packet.sender_index(), 0xdeadbeef);
+    assert_eq!(
+        packet.mac1(),
+        &data[HandshakeInitiation::MAC1_OFFSET..][..16]
+    );
+
+    let data = response(7, 8);
+    let Ok(WireguardPacket::HandshakeResponse(packet)) = WireguardPacket::parse(&data) else {
+        panic!("expected a response");
+    };
+    assert_eq!((packet.sender_index(), packet.receiver_index()), (7, 8));
+
+    let data = cookie_reply(9);
+    let packet = WireguardPacket::parse(&data).unwrap();
+    assert_eq!(packet.message_type(), MessageType::CookieReply);
+
+    let data = transport(10, 1 << 40, 32);
+    let Ok(WireguardPacket::TransportData(header, payload)) = WireguardPacket::parse(&data) else {
+        panic!("expected transport data");
+    };
+    assert_eq!((header.receiver_index(), header.counter()), (10, 1 << 40));
+    assert_eq!(payload.len(), 32);
+}
+
+#[test]
+fn built_handshakes_carry_a_valid_mac1() {
+    let backend = peer("127.0.0.1:1", 1);
+    let data = initiation(1, &backend);
+    let (covered, mac1) = data.split_at(HandshakeInitiation::MAC1_OFFSET);
+    assert_eq!(
+        backend.matching_key(covered, mac1[..16].try_into().unwrap()),
+        Some(&backend.pub_key)
+    );
+
+    let data = response(2, 1);
+    let (covered, mac1) = data.split_at(HandshakeResponse::MAC1_OFFSET);
+    let key = PeerKey::new(CLIENT_KEY).precomputed_hash_label_mac1;
+    assert_eq!(utils::mac(&key, covered), mac1[..16]);
+}
+
+#[test]
+fn rejects_truncated_and_unknown_messages() {
+    assert!(matches!(
+        WireguardPacket::parse(&[]),
+        Err(ParseError::TooShort)
+    ));
+    assert!(matches!(
+        WireguardPacket::parse(&response(1, 2)[..91]),
+        Err(ParseError::TooShort)
+    ));
+    assert!(matches!(
+        WireguardPacket::parse(&transport(1, 0, 8)),
+        Err(ParseError::TooShort)
+    ));
+    assert!(matches!(
+        WireguardPacket::parse(&[0x05; 32]),
+        Err(ParseError::Invalid)
+    ));
+}
+
+#[test]
+fn rejects_oversized_handshake_messages() {
+    let mut data = initiation(1, &peer("127.0.0.1:1", 1));
+    data.push(0);
+    assert_eq!(
+        WireguardPacket::parse(&data).err(),
+        Some(ParseError::TooLong)
+    );
+    let mut data = response(2, 1);
+    data.push(0);
+    assert_eq!(
+        WireguardPacket::parse(&data).err(),
+        Some(ParseError::TooLong)
+    );
+    let mut data = cookie_reply(1);
+    data.push(0);
+    assert_eq!(
+        WireguardPacket::parse(&data).err(),
+        Some(ParseError::TooLong)
+    );
+}
+
+#[test]
+fn heuristic_checks_type_and_reserved_bytes() {
+    assert!(is_wg_packet(&cookie_reply(1)));
+    assert!(!is_wg_packet(&[0x01, 0, 0]));
+    assert!(!is_wg_packet(&[0x01, 0, 1, 0, 0]));
+    assert!(!is_wg_packet(&[0x05, 0, 0, 0, 0]));
+}
+
+#[test]
+fn identities_use_little_endian_indices() {
+    let identity = Identity::from([0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(identity.as_u32(), 0x1234_5678);
+    assert_eq!(Identity::from_u32(0x1234_5678), identity);
+    assert_eq!(identity.to_string(), "12345678");
+    assert_eq!(format!("{identity:?}"), "Identity(12345678)");
+    assert_eq!("0x12345678".parse::<Identity>().unwrap(), identity);
+    assert!("not hex".parse::<Identity>().is_err());
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::time::Duration;
+
+use wireguard_router::pcap::{Datagram, Reader};
+
+fn capture(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut file = Vec::new();
+    file.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    file.extend_from_slice(&[2, 0, 4, 0]);
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&65535u32.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes()); // ethernet
+    for (i, frame) in frames.iter().enumerate() {
+        file.extend_from_slice(&(100 + i as u32).to_le_bytes());
+        file.extend_from_slice(&250u32.to_le_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        file.extend_from_slice(frame);
+    }
+    file
+}
+
+fn ipv4_udp(protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    let total = (20 + 8 + payload.len()) as u16;
+    frame.extend_from_slice(&[0x45, 0]);
+    frame.extend_from_slice(&total.to_be_bytes());
+    frame.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+    frame.extend_from_slice(&[192, 0, 2, 1, 10, 0, 0, 1]);
+    frame.extend_from_slice(&40000u16.to_be_bytes());
+    frame.extend_from_slice(&51820u16.to_be_bytes());
+    frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn udp_datagrams_are_extracted() {
+    let file = capture(&[
+        ipv4_udp(17, b"first"),
+        ipv4_udp(6, b"tcp"),
+        ipv4_udp(17, b"second"),
+    ]);
+    let datagrams: Vec<Datagram> = Reader::new(file.as_slice())
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    assert_eq!(datagrams.len(), 2);
+    assert_eq!(
+        datagrams[0],
+        Datagram {
+            timestamp: Duration::from_micros(100_000_250),
+            source: "192.0.2.1:40000".parse().unwrap(),
+            destination: "10.0.0.1:51820".parse().unwrap(),
+            payload: b"first".to_vec(),
+        }
+    );
+    assert_eq!(datagrams[1].payload, b"second");
+}
+
+#[test]
+fn other_formats_are_rejected() {
+    assert!(Reader::new([0x0a, 0x0d, 0x0d, 0x0a].repeat(6).as_slice()).is_err());
+}
+
+#[test]
+fn truncated_records_are_reported() {
+    let mut file = capture(&[ipv4_udp(17, b"first")]);
+    file.truncate(file.len() - 3);
+    let mut reader = Reader::new(file.as_slice()).unwrap();
+    let err = reader.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn records_beyond_the_snapshot_length_are_rejected() {
+    let mut file = capture(&[]);
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    file.extend_from_slice(&u32::MAX.to_le_bytes());
+    let mut reader = Reader::new(file.as_slice()).unwrap();
+    let err = reader.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // one byte beyond the snapshot length of 65535
+    let mut file = capture(&[]);
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&65536u32.to_le_bytes());
+    file.extend_from_slice(&65536u32.to_le_bytes());
+    file.extend_from_slice(&[0; 65536]);
+    let mut reader = Reader::new(file.as_slice()).unwrap();
+    let err = reader.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,284 @@
+use std::time::Duration;
+
+use config::{File, FileFormat};
+use serde::Deserialize;
+use wireguard_router::error::PeerError;
+use wireguard_router::{Peer, PeerConfig};
+
+const PUBKEY: &str = "qysSgVefiE5BfBZYPfBkzuZVq7RnrFBu48npdGcsrQM=";
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    #[allow(dead_code)]
+    peers: Vec<Peer>,
+}
+
+fn parse(toml: &str) -> Result<Config, config::ConfigError> {
+    config::Config::builder()
+        .add_source(File::from_str(toml, FileFormat::Toml))
+        .build()?
+        .try_deserialize()
+}
+
+fn peer_config(address: &str, pubkey: &str) -> PeerConfig {
+    PeerConfig {
+        address: address.to_string(),
+        pubkey: pubkey.to_string(),
+        proxy: None,
+        name: None,
+        max_sessions: None,
+        other_pubkeys: Vec::new(),
+        maintenance: Vec::new(),
+        primary: false,
+        weight: None,
+        schedule: Vec::new(),
+        fwmark: None,
+        interface: None,
+        source_address: None,
+        tunnel: None,
+        quic: false,
+    }
+}
+
+#[test]
+fn valid_peer_is_built() {
+    let peer = Peer::try_from(PeerConfig {
+        proxy: Some("127.0.0.1:1080".to_string()),
+        ..peer_config("127.0.0.1:51820", PUBKEY)
+    })
+    .unwrap();
+    assert_eq!(peer.address, "127.0.0.1:51820".parse().unwrap());
+    assert_eq!(peer.proxy, Some("127.0.0.1:1080".parse().unwrap()));
+}
+
+#[test]
+fn invalid_fields_are_reported() {
+    let err = Peer::try_from(peer_config("localhost", PUBKEY)).unwrap_err();
+    assert!(matches!(err, PeerError::InvalidAddress { .. }));
+    assert_eq!(err.field(), "address");
+
+    let err = Peer::try_from(peer_config("127.0.0.1:51820", "not base64!")).unwrap_err();
+    assert!(matches!(err, PeerError::InvalidPubKeyEncoding(_)));
+    assert_eq!(err.field(), "pubkey");
+
+    let err = Peer::try_from(peer_config("127.0.0.1:51820", "AAAA")).unwrap_err();
+    assert_eq!(err, PeerError::InvalidPubKeyLength(3));
+}
+
+#[test]
+fn config_errors_name_the_field() {
+    let err = parse(r#"peers = [{ address = "127.0.0.1:51820", pubkey = "AAAA" }]"#).unwrap_err();
+    assert!(
+        err.to_string().contains("invalid field `pubkey`"),
+        "unexpected error: {err}"
+    );
+
+    let err = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1", pubkey = "{PUBKEY}" }}]"#
+    ))
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("invalid field `address`"),
+        "unexpected error: {err}"
+    );
+    assert_eq!(
+        err.to_string()
+            .matches("invalid socket address syntax")
+            .count(),
+        1,
+        "unexpected error: {err}"
+    );
+}
+
+#[test]
+fn peers_round_trip_through_serialize() {
+    let peers = vec![
+        Peer::try_from(PeerConfig {
+            proxy: Some("[::1]:1080".to_string()),
+            name: Some("ctf".to_string()),
+            ..peer_config("[2001:db8::1]:51820", PUBKEY)
+        })
+        .unwrap(),
+        Peer::try_from(peer_config("127.0.0.1:51820", PUBKEY)).unwrap(),
+    ];
+
+    let written = config::Config::try_from(&std::collections::HashMap::from([("peers", &peers)]))
+        .unwrap()
+        .try_deserialize::<Config>()
+        .unwrap();
+
+    assert_eq!(written.peers.len(), 2);
+    for (read, peer) in written.peers.iter().zip(&peers) {
+        assert_eq!(read.address, peer.address);
+        assert_eq!(read.pub_key, peer.pub_key);
+        assert_eq!(
+            read.precomputed_hash_label_mac1,
+            peer.precomputed_hash_label_mac1
+        );
+        assert_eq!(read.proxy, peer.proxy);
+        assert_eq!(read.name, peer.name);
+    }
+    assert_eq!(PeerConfig::from(&peers[0]).pubkey, PUBKEY);
+}
+
+#[test]
+fn duplicate_pubkeys_are_rejected() {
+    const OTHER: &str = "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=";
+    let peer = |address, pubkey, name: &str| {
+        Peer::try_from(PeerConfig {
+            name: Some(name.to_string()),
+            ..peer_config(address, pubkey)
+        })
+        .unwrap()
+    };
+
+    let peers = [
+        peer("127.0.0.1:51820", PUBKEY, "a"),
+        peer("127.0.0.1:51820", OTHER, "b"),
+    ];
+    let shared = wireguard_router::check_peers(&peers).unwrap();
+    assert_eq!(shared.len(), 1);
+    assert_eq!(
+        (shared[0].0.name.as_deref(), shared[0].1.name.as_deref()),
+        (Some("a"), Some("b"))
+    );
+
+    let peers = [
+        peer("127.0.0.1:51820", PUBKEY, "a"),
+        peer("127.0.0.1:51821", PUBKEY, "c"),
+    ];
+    let err = wireguard_router::check_peers(&peers).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("peers a (127.0.0.1:51820) and c (127.0.0.1:51821) share the pubkey {PUBKEY}")
+    );
+}
+
+#[test]
+fn errors_are_located_at_the_field() {
+    let json = r#"{"address": "127.0.0.1:51820", "pubkey": "AAAA", "name": "ctf"}"#;
+    let err = serde_json::from_str::<Peer>(json).unwrap_err();
+    assert!(
+        err.to_string().starts_with("invalid field `pubkey`"),
+        "unexpected error: {err}"
+    );
+    // just past the value, not at the end of the peer
+    assert_eq!(err.column(), json.find("\"AAAA\"").unwrap() + 6);
+}
+
+#[test]
+fn rotated_keys_are_listed_under_pubkey() {
+    const OLD: &str = "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=";
+    let config = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = ["{PUBKEY}", "{OLD}"] }}]"#
+    ))
+    .unwrap();
+    let peer = &config.peers[0];
+    assert_eq!(PeerConfig::from(peer).pubkey, PUBKEY);
+    assert_eq!(PeerConfig::from(peer).other_pubkeys, [OLD]);
+
+    let err = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = ["{PUBKEY}", "AAAA"] }}]"#
+    ))
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("invalid field `pubkey`"),
+        "unexpected error: {err}"
+    );
+}
+
+#[test]
+fn maintenance_windows_follow_cron() {
+    let config = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = "{PUBKEY}", maintenance = [{{ cron = "30 3 1-7 * sun", minutes = 90 }}] }}]"#
+    ))
+    .unwrap();
+    let schedule = &config.peers[0].maintenance[0].schedule;
+    // the first seven days of a month or any Sunday, as cron ORs restricted days and weekdays
+    assert!(schedule.matches(30, 3, 2, 6, 3));
+    assert!(schedule.matches(30, 3, 20, 6, 0));
+    assert!(!schedule.matches(30, 3, 20, 6, 1));
+    assert!(!schedule.matches(31, 3, 2, 6, 3));
+
+    for (cron, minutes) in [("60 * * * *", 60), ("* * * *", 60), ("0 3 * * *", 0)] {
+        let err = parse(&format!(
+            r#"peers = [{{ address = "127.0.0.1:51820", pubkey = "{PUBKEY}", maintenance = [{{ cron = "{cron}", minutes = {minutes} }}] }}]"#
+        ))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("cron") || err.to_string().contains("window"),
+            "unexpected error: {err}"
+        );
+    }
+}
+
+#[test]
+fn fwmark_is_read_and_written() {
+    let config = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = "{PUBKEY}", fwmark = 0x200 }}]"#
+    ))
+    .unwrap();
+    let peer = &config.peers[0];
+    assert_eq!(peer.fwmark, Some(0x200));
+    let json = serde_json::to_value(peer).unwrap();
+    assert_eq!(json["fwmark"], 512);
+    let unmarked = serde_json::to_value(Peer::new(peer.address, peer.pub_key)).unwrap();
+    assert!(unmarked.get("fwmark").is_none());
+}
+
+#[test]
+fn egress_settings_are_read_and_written() {
+    let config = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = "{PUBKEY}", interface = "wg-uplink", source_address = "192.0.2.10", tunnel = "overlay", quic = true }}]"#
+    ))
+    .unwrap();
+    let peer = &config.peers[0];
+    assert_eq!(peer.interface.as_deref(), Some("wg-uplink"));
+    assert_eq!(peer.source, Some("192.0.2.10".parse().unwrap()));
+    let json = serde_json::to_value(peer).unwrap();
+    assert_eq!(json["interface"], "wg-uplink");
+    assert_eq!(json["source_address"], "192.0.2.10");
+    assert_eq!(json["tunnel"], "overlay");
+    assert_eq!(json["quic"], true);
+    let direct = serde_json::to_value(Peer::new(peer.address, peer.pub_key)).unwrap();
+    assert!(direct.get("interface").is_none());
+    assert!(direct.get("source_address").is_none());
+    assert!(direct.get("quic").is_none());
+
+    let err = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = "{PUBKEY}", source_address = "192.0.2.10:1" }}]"#
+    ))
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("source_address"),
+        "unexpected error: {err}"
+    );
+}
+
+#[test]
+fn schedule_rules_take_a_window_and_settings() {
+    let config = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = "{PUBKEY}", weight = 1, schedule = [{{ cron = "0 22 * * *", minutes = 480, weight = 9, primary = true }}] }}]"#
+    ))
+    .unwrap();
+    let peer = &config.peers[0];
+    let rule = &peer.schedule[0];
+    assert_eq!(rule.window.duration, Duration::from_secs(8 * 60 * 60));
+    assert_eq!(rule.max_sessions, None);
+    let scheduled = rule.apply(peer);
+    assert_eq!(scheduled.weight, Some(9));
+    assert!(scheduled.primary);
+
+    let json = serde_json::to_value(peer).unwrap();
+    assert_eq!(json["schedule"][0]["cron"], "0 22 * * *");
+    assert_eq!(json["schedule"][0]["weight"], 9);
+
+    let err = parse(&format!(
+        r#"peers = [{{ address = "127.0.0.1:51820", pubkey = "{PUBKEY}", schedule = [{{ cron = "0 22 * * *", weight = 9 }}] }}]"#
+    ))
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("minutes"),
+        "unexpected error: {err}"
+    );
+}
@@ -0,0 +1,138 @@
+#![cfg(feature = "runtime")]
+
+mod common;
+
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::*;
+use wireguard_router::Peer;
+use wireguard_router::error::Error;
+use wireguard_router::metrics::Metrics;
+use wireguard_router::packet::MessageType;
+use wireguard_router::policy::{Forward, Initiation, RoutingPolicy, Verdict};
+
+/// Prefers the last matching peer and refuses to forward transport data to port 9
+struct LastMatch;
+
+impl RoutingPolicy for LastMatch {
+    fn select<'a>(&self, _initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        candidates.last().copied()
+    }
+
+    fn check_forward(&self, forward: &Forward) -> Verdict {
+        match (forward.message, forward.destination.port()) {
+            (MessageType::TransportData, 9) => Verdict::Drop("discard port".to_string()),
+            _ => Verdict::Forward,
+        }
+    }
+}
+
+/// Panics on initiations from port 666, like a buggy plugin might
+struct Faulty;
+
+impl RoutingPolicy for Faulty {
+    fn select<'a>(&self, initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        assert_ne!(initiation.source.port(), 666, "cursed port");
+        candidates.first().copied()
+    }
+}
+
+/// Picks the first candidate once released, like a script taking its time
+struct Gated {
+    entered: Mutex<mpsc::Sender<()>>,
+    release: Mutex<mpsc::Receiver<()>>,
+}
+
+impl RoutingPolicy for Gated {
+    fn select<'a>(&self, _initiation: &Initiation, candidates: &[&'a Peer]) -> Option<&'a Peer> {
+        self.entered.lock().unwrap().send(()).unwrap();
+        self.release.lock().unwrap().recv().unwrap();
+        candidates.first().copied()
+    }
+
+    fn blocks(&self) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn policy_selects_among_matching_peers() {
+    // two backends sharing a key
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let h = Harness::start_with(vec![first, second.clone()], |r| r.policy(LastMatch));
+
+    let init = initiation(1, &second);
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &init).await,
+        vec![(second.address, init)]
+    );
+}
+
+#[tokio::test]
+async fn policy_vetoes_forwarding() {
+    let backend = peer("10.0.0.1:9", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.policy(LastMatch));
+    let client = addr("192.0.2.1:40000");
+
+    assert_eq!(h.deliver(client, &initiation(1, &backend)).await.len(), 1);
+    assert_eq!(h.deliver(backend.address, &response(2, 1)).await.len(), 1);
+    assert!(h.deliver(client, &transport(2, 0, 16)).await.is_empty());
+    assert_eq!(
+        h.deliver(backend.address, &transport(1, 0, 16)).await.len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn panicking_policies_drop_the_packet_and_routing_goes_on() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.policy(Faulty).metrics(metrics.clone()).max_panics(2)
+    });
+
+    let cursed = addr("192.0.2.1:666");
+    assert!(h.deliver(cursed, &initiation(1, &backend)).await.is_empty());
+    assert_eq!(metrics.snapshot().panics, 1);
+    let init = initiation(2, &backend);
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &init).await,
+        vec![(backend.address, init)]
+    );
+
+    h.net.push(cursed, &initiation(3, &backend));
+    match h.router.await.unwrap() {
+        Err(Error::Panicked(2)) => {}
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn blocking_policies_decide_without_locking_the_sessions() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let (entered, entering) = mpsc::channel();
+    let (releasing, release) = mpsc::channel();
+    let gated = Gated {
+        entered: Mutex::new(entered),
+        release: Mutex::new(release),
+    };
+    let h = Harness::start_with(vec![backend.clone()], |r| r.policy(gated));
+
+    let init = initiation(1, &backend);
+    h.net.push(addr("192.0.2.1:40000"), &init);
+    tokio::task::spawn_blocking(move || entering.recv().unwrap())
+        .await
+        .unwrap();
+    let count = tokio::time::timeout(Duration::from_secs(1), h.sessions.count())
+        .await
+        .expect("the session table was locked while the policy decided");
+    assert_eq!(count, 0);
+
+    releasing.send(()).unwrap();
+    h.net.settle().await;
+    assert_eq!(h.net.take_sent(), vec![(backend.address, init)]);
+    assert_eq!(h.sessions.count().await, 1);
+}
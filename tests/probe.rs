@@ -0,0 +1,56 @@
+#![cfg(all(feature = "doctor", feature = "tunnel"))]
+
+use std::io;
+use std::time::Duration;
+
+use boringtun::noise::{Tunn, TunnResult};
+use tokio::net::UdpSocket;
+use wireguard_router::Peer;
+use wireguard_router::probe;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// A boringtun backend with `key`, answering the handshakes of the peer with `peer_key`
+async fn backend(key: [u8; 32], peer_key: &StaticSecret) -> Peer {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let peer_key = boringtun::x25519::PublicKey::from(PublicKey::from(peer_key).to_bytes());
+    let mut tunn = Tunn::new(key.into(), peer_key, None, None, 1, None);
+    tokio::spawn(async move {
+        let mut datagram = [0; 2048];
+        let mut buf = [0; 2048];
+        loop {
+            let (size, probe) = socket.recv_from(&mut datagram).await.unwrap();
+            if let TunnResult::WriteToNetwork(response) =
+                tunn.decapsulate(Some(probe.ip()), &datagram[..size], &mut buf)
+            {
+                socket.send_to(response, probe).await.unwrap();
+            }
+        }
+    });
+    let public = PublicKey::from(&StaticSecret::from(key));
+    Peer::new(address, public.to_bytes())
+}
+
+#[tokio::test]
+async fn backends_answer_handshakes_from_their_peers() {
+    let local = StaticSecret::from([1; 32]);
+    let backend = backend([2; 32], &local).await;
+
+    probe::handshake(&local, &backend, Duration::from_secs(2))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn backends_ignore_handshakes_from_other_keys() {
+    let backend = backend([2; 32], &StaticSecret::from([3; 32])).await;
+
+    let err = probe::handshake(
+        &StaticSecret::from([1; 32]),
+        &backend,
+        Duration::from_millis(200),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
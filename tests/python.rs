@@ -0,0 +1,132 @@
+#![cfg(feature = "python")]
+
+use wireguard_router::packet::HandshakeInitiation;
+use wireguard_router::python::pyo3::prelude::*;
+use wireguard_router::python::pyo3::types::{PyBytes, PyDict};
+use wireguard_router::python::{self, pyo3};
+use wireguard_router::testing::{initiation, transport};
+
+const PUBKEY: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+/// Runs `f` with the extension module as Python imports it
+fn with_module(f: impl FnOnce(Python<'_>, &Bound<'_, PyModule>)) {
+    Python::initialize();
+    Python::attach(|py| {
+        let module = PyModule::new(py, "wireguard_router").unwrap();
+        python::wireguard_router(&module).unwrap();
+        f(py, &module);
+    });
+}
+
+fn peer<'py>(module: &Bound<'py, PyModule>, address: &str, pubkey: &str) -> Bound<'py, PyAny> {
+    module
+        .getattr("Peer")
+        .unwrap()
+        .call1((address, pubkey))
+        .unwrap()
+}
+
+fn matches(peer: &Bound<'_, PyAny>, packet: &[u8]) -> bool {
+    peer.call_method1("matches", (PyBytes::new(peer.py(), packet),))
+        .unwrap()
+        .extract()
+        .unwrap()
+}
+
+#[test]
+fn parses_transport_data() {
+    with_module(|py, module| {
+        let packet = PyBytes::new(py, &transport(7, 42, 32));
+        let fields = module.getattr("parse").unwrap().call1((packet,)).unwrap();
+        let fields = fields.cast::<PyDict>().unwrap();
+        let field = |name: &str| fields.get_item(name).unwrap().unwrap().extract::<u64>();
+        assert_eq!(field("type").unwrap(), 4);
+        assert_eq!(field("receiver").unwrap(), 7);
+        assert_eq!(field("counter").unwrap(), 42);
+        assert_eq!(field("payload_len").unwrap(), 32);
+    });
+}
+
+#[test]
+fn reports_invalid_input() {
+    with_module(|py, module| {
+        let parse = module.getattr("parse").unwrap();
+        for packet in [&transport(7, 42, 8)[..], &[0x09; 32]] {
+            let err = parse.call1((PyBytes::new(py, packet),)).unwrap_err();
+            assert!(err.is_instance_of::<pyo3::exceptions::PyValueError>(py));
+        }
+        let err = module
+            .getattr("mac")
+            .unwrap()
+            .call1((PyBytes::new(py, &[0; 33]), PyBytes::new(py, b"data")))
+            .unwrap_err();
+        assert!(err.is_instance_of::<pyo3::exceptions::PyValueError>(py));
+        let err = module
+            .getattr("Peer")
+            .unwrap()
+            .call1(("10.0.0.1:51820", "not a key"))
+            .unwrap_err();
+        assert!(err.is_instance_of::<pyo3::exceptions::PyValueError>(py));
+    });
+}
+
+#[test]
+fn classifies_packets() {
+    with_module(|py, module| {
+        let is_wg_packet = module.getattr("is_wg_packet").unwrap();
+        let classify = |packet: &[u8]| {
+            is_wg_packet
+                .call1((PyBytes::new(py, packet),))
+                .unwrap()
+                .extract::<bool>()
+                .unwrap()
+        };
+        assert!(classify(&transport(7, 42, 32)));
+        assert!(!classify(&[0x09; 32]));
+    });
+}
+
+#[test]
+fn mac1_round_trips_through_peers() {
+    with_module(|py, module| {
+        let backend = peer(module, "10.0.0.1:51820", PUBKEY);
+        assert_eq!(
+            backend
+                .getattr("address")
+                .unwrap()
+                .extract::<String>()
+                .unwrap(),
+            "10.0.0.1:51820"
+        );
+        assert_eq!(
+            backend
+                .getattr("pubkey")
+                .unwrap()
+                .extract::<String>()
+                .unwrap(),
+            PUBKEY
+        );
+
+        // an initiation signed with the mac1 Python computed matches like a real one
+        let mut packet = initiation(7, &[1; 32]);
+        let mac1 = backend
+            .call_method1("mac1", (PyBytes::new(py, &packet),))
+            .unwrap()
+            .extract::<Vec<u8>>()
+            .unwrap();
+        let offset = HandshakeInitiation::MAC1_OFFSET;
+        assert_eq!(mac1, packet[offset..offset + 16]);
+        packet[offset..offset + 16].fill(0);
+        assert!(!matches(&backend, &packet));
+        packet[offset..offset + 16].copy_from_slice(&mac1);
+        assert!(matches(&backend, &packet));
+
+        let other = peer(
+            module,
+            "10.0.0.2:51820",
+            "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=",
+        );
+        assert!(!matches(&other, &packet));
+        assert!(!matches(&backend, &transport(7, 42, 32)));
+    });
+}
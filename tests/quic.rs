@@ -0,0 +1,74 @@
+#![cfg(feature = "quic")]
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use wireguard_router::quic::{Config, Links};
+use wireguard_router::transport::{Egress, Listeners, PacketTransport};
+
+/// A CA and a certificate for 127.0.0.1 it issued, shared by both routers
+fn config(listen: Option<&str>) -> Config {
+    let data = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/quic");
+    Config {
+        listen: listen.map(|listen| listen.parse().unwrap()),
+        cert_file: data.join("router.pem"),
+        key_file: data.join("router.key"),
+        ca_file: data.join("ca.pem"),
+        server_name: None,
+        keepalive_secs: 10,
+    }
+}
+
+#[tokio::test]
+async fn routers_are_chained_over_quic() {
+    let links = Links::open(&config(Some("127.0.0.1:0"))).unwrap();
+    let far = links.local_addr().unwrap();
+    let far_router = Listeners::bind(&["127.0.0.1:0".parse().unwrap()])
+        .unwrap()
+        .quic(links);
+    let router = Listeners::bind(&["127.0.0.1:0".parse().unwrap()])
+        .unwrap()
+        .quic(Links::open(&config(None)).unwrap());
+    let egress = Egress {
+        quic: true,
+        ..Egress::default()
+    };
+    router.set_backend_egress([(far, egress)].into());
+
+    // datagrams are dropped until the link is established
+    let client = "192.0.2.1:40000".parse().unwrap();
+    let mut buf = [0; 16];
+    let (size, near) = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let _ = router.send_to_backend(b"ping", far, client).await;
+            let received =
+                tokio::time::timeout(Duration::from_millis(100), far_router.recv_from(&mut buf));
+            if let Ok(received) = received.await {
+                break received.unwrap();
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(&buf[..size], b"ping");
+
+    // the far router replies through the link the router connected with
+    far_router.send_to(b"pong", near).await.unwrap();
+    let (size, source) = tokio::time::timeout(Duration::from_secs(5), router.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..size], b"pong");
+    assert_eq!(source, far);
+}
+
+#[test]
+fn links_need_readable_certificates() {
+    let mut missing = config(None);
+    missing.ca_file = PathBuf::from("/nonexistent/ca.pem");
+    let err = Links::open(&missing).err().unwrap();
+    assert!(
+        err.to_string().contains("ca.pem"),
+        "unexpected error: {err}"
+    );
+}
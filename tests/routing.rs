@@ -0,0 +1,1645 @@
+#![cfg(feature = "runtime")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::*;
+use wireguard_router::PeerKey;
+use wireguard_router::affinity::{Affinity, AffinityTable};
+use wireguard_router::chaos::Chaos;
+use wireguard_router::error::Error;
+use wireguard_router::event::{DropReason, RouterEvent};
+use wireguard_router::health::Thresholds;
+use wireguard_router::metrics::Metrics;
+use wireguard_router::packet::{Identity, MessageType};
+use wireguard_router::policy::{LeastSessions, LowestLatency};
+use wireguard_router::router::{
+    Backpressure, BackpressurePolicy, Datagram, Decision, EvictionReason, Honeypot, Horizon,
+    Router, RouterBuilder, SessionHit, SessionInfo, SessionQuery, UnmatchedData,
+};
+use wireguard_router::schedule::{Rule, Window};
+use wireguard_router::timeline::TimelineEvent;
+use wireguard_router::transport::mock::MockTransport;
+use wireguard_router::utils;
+
+const CLIENT: u32 = 0x1111_1111;
+const BACKEND: u32 = 0x2222_2222;
+
+#[tokio::test]
+async fn full_session_is_routed_both_ways() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![peer("10.0.0.2:51820", 2), backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    let init = initiation(CLIENT, &backend);
+    assert_eq!(
+        h.deliver(client, &init).await,
+        vec![(backend.address, init)]
+    );
+
+    let resp = response(BACKEND, CLIENT);
+    assert_eq!(
+        h.deliver(backend.address, &resp).await,
+        vec![(client, resp)]
+    );
+
+    let data = transport(BACKEND, 0, 32);
+    assert_eq!(
+        h.deliver(client, &data).await,
+        vec![(backend.address, data)]
+    );
+
+    let data = transport(CLIENT, 0, 64);
+    assert_eq!(
+        h.deliver(backend.address, &data).await,
+        vec![(client, data)]
+    );
+}
+
+#[tokio::test]
+async fn initiation_for_unknown_peer_is_dropped() {
+    let h = Harness::start(vec![peer("10.0.0.1:51820", 1)]);
+
+    let init = initiation(CLIENT, &peer("10.0.0.9:51820", 9));
+    assert!(h.deliver(addr("192.0.2.1:40000"), &init).await.is_empty());
+}
+
+#[tokio::test]
+async fn packets_without_session_are_dropped() {
+    let h = Harness::start(vec![peer("10.0.0.1:51820", 1)]);
+    let client = addr("192.0.2.1:40000");
+
+    assert!(
+        h.deliver(client, &response(BACKEND, CLIENT))
+            .await
+            .is_empty()
+    );
+    assert!(h.deliver(client, &cookie_reply(CLIENT)).await.is_empty());
+    assert!(
+        h.deliver(client, &transport(BACKEND, 0, 32))
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn non_wireguard_packets_are_ignored() {
+    let h = Harness::start(vec![peer("10.0.0.1:51820", 1)]);
+
+    assert!(
+        h.deliver(addr("192.0.2.1:40000"), b"GET / HTTP/1.1\r\n")
+            .await
+            .is_empty()
+    );
+    // a valid type byte at the wrong size
+    assert!(
+        h.deliver(addr("192.0.2.1:40000"), &[1, 0, 0, 0, 0, 0])
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn cookie_reply_reaches_the_client() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    let cookie = cookie_reply(CLIENT);
+    assert_eq!(
+        h.deliver(backend.address, &cookie).await,
+        vec![(client, cookie)]
+    );
+}
+
+#[tokio::test]
+async fn retransmitted_initiation_follows_the_session() {
+    let first = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![first.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &first)).await;
+
+    // the same sender index sticks to its backend even if the peer list changes
+    let second = peer("10.0.0.2:51820", 2);
+    h.peers.send_replace(vec![second.clone()]);
+    let init = initiation(CLIENT, &second);
+    assert_eq!(h.deliver(client, &init).await, vec![(first.address, init)]);
+}
+
+#[tokio::test]
+async fn sessions_span_address_families() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("[2001:db8::1]:40000");
+
+    let init = initiation(CLIENT, &backend);
+    assert_eq!(
+        h.deliver(client, &init).await,
+        vec![(backend.address, init)]
+    );
+    let resp = response(BACKEND, CLIENT);
+    assert_eq!(
+        h.deliver(backend.address, &resp).await,
+        vec![(client, resp)]
+    );
+}
+
+#[tokio::test]
+async fn transport_data_goes_to_the_owner_of_its_receiver_index() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("[2001:db8::1]:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    // the client addresses the index the backend chose, and the backend the client's
+    let data = transport(BACKEND, 0, 32);
+    assert_eq!(
+        h.deliver(client, &data).await,
+        vec![(backend.address, data)]
+    );
+    let data = transport(CLIENT, 0, 32);
+    assert_eq!(
+        h.deliver(backend.address, &data).await,
+        vec![(client, data)]
+    );
+}
+
+#[tokio::test]
+async fn interleaved_clients_keep_their_sessions() {
+    let a = peer("10.0.0.1:51820", 1);
+    let b = peer("10.0.0.2:51820", 2);
+    let h = Harness::start(vec![a.clone(), b.clone()]);
+    let client_a = addr("192.0.2.1:40000");
+    let client_b = addr("192.0.2.2:40000");
+
+    // both handshakes are in flight at the same time, the responses overtake each other
+    h.net.push(client_a, &initiation(1, &a));
+    h.net.push(client_b, &initiation(2, &b));
+    h.net.settle().await;
+    h.net.push(b.address, &response(20, 2));
+    h.net.push_front(a.address, &response(10, 1));
+    h.net.settle().await;
+
+    let sent: Vec<_> = h
+        .net
+        .take_sent()
+        .into_iter()
+        .map(|(to, p)| (to, p[0]))
+        .collect();
+    assert_eq!(
+        sent,
+        vec![(a.address, 1), (b.address, 1), (client_a, 2), (client_b, 2)]
+    );
+
+    assert_eq!(
+        h.deliver(client_b, &transport(20, 0, 16)).await[0].0,
+        b.address
+    );
+    assert_eq!(
+        h.deliver(client_a, &transport(10, 0, 16)).await[0].0,
+        a.address
+    );
+}
+
+#[tokio::test]
+async fn reloaded_peers_are_routed() {
+    let h = Harness::start(vec![]);
+    let client = addr("192.0.2.1:40000");
+    let backend = peer("10.0.0.1:51820", 1);
+
+    assert!(
+        h.deliver(client, &initiation(CLIENT, &backend))
+            .await
+            .is_empty()
+    );
+
+    h.peers.send_replace(vec![backend.clone()]);
+    let init = initiation(CLIENT + 1, &backend);
+    assert_eq!(
+        h.deliver(client, &init).await,
+        vec![(backend.address, init)]
+    );
+}
+
+#[tokio::test]
+async fn send_errors_do_not_stop_routing() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    h.net.fail_next_send(io::ErrorKind::ConnectionRefused);
+    assert!(
+        h.deliver(client, &initiation(CLIENT, &backend))
+            .await
+            .is_empty()
+    );
+
+    let resp = response(BACKEND, CLIENT);
+    assert_eq!(
+        h.deliver(backend.address, &resp).await,
+        vec![(client, resp)]
+    );
+}
+
+#[tokio::test]
+async fn imported_sessions_route_transport_data_right_away() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+    let established = SessionInfo {
+        client,
+        backend: backend.address,
+        client_index: Identity::from(CLIENT),
+        backend_index: Some(Identity::from(BACKEND)),
+        age: Duration::from_secs(600),
+        idle: Duration::from_secs(1),
+        packets: 1000,
+        bytes: 1 << 20,
+    };
+    let unanswered = SessionInfo {
+        client: addr("192.0.2.2:40000"),
+        client_index: Identity::from(CLIENT + 1),
+        backend_index: None,
+        ..established.clone()
+    };
+    let imported = h
+        .sessions
+        .import(
+            &[established.clone(), unanswered, established],
+            std::slice::from_ref(&backend),
+        )
+        .await;
+    assert_eq!(imported, 1);
+
+    let data = transport(BACKEND, 0, 32);
+    assert_eq!(
+        h.deliver(client, &data).await,
+        vec![(backend.address, data)]
+    );
+    let data = transport(CLIENT, 0, 32);
+    assert_eq!(
+        h.deliver(backend.address, &data).await,
+        vec![(client, data)]
+    );
+    let page = h.sessions.sessions(&SessionQuery::default(), 10).await;
+    assert_eq!(page.sessions.len(), 1);
+    assert!(page.sessions[0].age >= Duration::from_secs(600));
+}
+
+#[tokio::test]
+async fn imports_skip_unknown_backends_and_stop_at_the_session_limit() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.max_sessions(2));
+    let session = |n: u32, backend: SocketAddr| SessionInfo {
+        client: addr(&format!("192.0.2.{n}:40000")),
+        backend,
+        client_index: Identity::from(CLIENT + n),
+        backend_index: Some(Identity::from(BACKEND + n)),
+        age: Duration::from_secs(600),
+        idle: Duration::from_secs(1),
+        packets: 1000,
+        bytes: 1 << 20,
+    };
+    let exported: Vec<SessionInfo> = [addr("10.0.0.9:51820")]
+        .into_iter()
+        .chain([backend.address; 3])
+        .enumerate()
+        .map(|(n, backend)| session(n as u32 + 1, backend))
+        .collect();
+
+    let imported = h
+        .sessions
+        .import(&exported, std::slice::from_ref(&backend))
+        .await;
+    assert_eq!(imported, 2);
+    let page = h.sessions.sessions(&SessionQuery::default(), 10).await;
+    assert!(page.sessions.iter().all(|s| s.backend == backend.address));
+    let data = transport(BACKEND + 1, 0, 32);
+    assert!(h.deliver(exported[0].client, &data).await.is_empty());
+}
+
+#[tokio::test]
+async fn recv_errors_stop_the_router() {
+    let h = Harness::start(vec![]);
+
+    h.net.push_recv_error(io::ErrorKind::BrokenPipe);
+    match h.router.await.unwrap() {
+        Err(Error::Recv(err)) => assert_eq!(err.kind(), io::ErrorKind::BrokenPipe),
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn new_sessions_are_dropped_at_the_session_limit() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.max_sessions(1));
+    let client = addr("192.0.2.1:40000");
+
+    let init = initiation(CLIENT, &backend);
+    assert_eq!(h.deliver(client, &init).await.len(), 1);
+    assert!(
+        h.deliver(client, &initiation(CLIENT + 1, &backend))
+            .await
+            .is_empty()
+    );
+    // retransmissions of tracked sessions still pass
+    assert_eq!(h.deliver(client, &init).await.len(), 1);
+}
+
+#[tokio::test]
+async fn established_sessions_count_once_towards_the_session_limit() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.max_sessions(2));
+    let client = addr("192.0.2.1:40000");
+
+    assert_eq!(
+        h.deliver(client, &initiation(CLIENT, &backend)).await.len(),
+        1
+    );
+    assert_eq!(
+        h.deliver(backend.address, &response(BACKEND, CLIENT))
+            .await
+            .len(),
+        1
+    );
+    assert_eq!(h.sessions.count().await, 1);
+    assert_eq!(
+        h.deliver(client, &initiation(CLIENT + 1, &backend))
+            .await
+            .len(),
+        1
+    );
+    assert!(
+        h.deliver(client, &initiation(CLIENT + 2, &backend))
+            .await
+            .is_empty()
+    );
+}
+
+#[test]
+#[should_panic(expected = "buffer size")]
+fn empty_receive_buffers_are_rejected() {
+    let _ = Router::builder(MockTransport::new()).buffer_size(0);
+}
+
+#[tokio::test]
+async fn full_backends_are_passed_over() {
+    // two backends of one peer, both taking a single session
+    let first = peer("10.0.0.1:51820", 1).with_max_sessions(Some(1));
+    let second = peer("10.0.0.2:51820", 1).with_max_sessions(Some(1));
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |r| {
+        r.metrics(metrics.clone())
+    });
+    let client = addr("192.0.2.1:40000");
+
+    let sent = h.deliver(client, &initiation(CLIENT, &first)).await;
+    assert_eq!(sent[0].0, first.address);
+    // the response doesn't count as another session of the backend
+    h.deliver(first.address, &response(BACKEND, CLIENT)).await;
+    let sent = h.deliver(client, &initiation(CLIENT + 1, &first)).await;
+    assert_eq!(sent[0].0, second.address);
+    assert!(
+        h.deliver(client, &initiation(CLIENT + 2, &first))
+            .await
+            .is_empty()
+    );
+    assert_eq!(metrics.snapshot().sessions_limited, 1);
+}
+
+#[tokio::test]
+async fn rates_cover_the_last_minute() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| r.metrics(metrics.clone()));
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    let rates = metrics.rates();
+    assert_eq!(rates.handshakes_per_min, 1.0);
+    assert_eq!(rates.packets_per_sec, 2.0 / 60.0);
+    assert_eq!(rates.bytes_per_sec, (148.0 + 92.0) / 60.0);
+    assert_eq!(metrics.backend_rates()[&backend.address], rates);
+}
+
+#[tokio::test]
+async fn initiations_to_any_key_of_a_peer_are_routed() {
+    let backend = peer("10.0.0.1:51820", 1).with_other_keys(vec![PeerKey::new([2; 32])]);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    let old_key = peer("10.0.0.1:51820", 2);
+    let sent = h.deliver(client, &initiation(CLIENT, &old_key)).await;
+    assert_eq!(sent[0].0, backend.address);
+    let sent = h.deliver(client, &initiation(CLIENT + 1, &backend)).await;
+    assert_eq!(sent[0].0, backend.address);
+}
+
+#[tokio::test]
+async fn idle_sessions_expire() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.session_timeout(Duration::from_millis(20))
+            .metrics(metrics.clone())
+    });
+    let client = addr("192.0.2.1:40000");
+
+    assert_eq!(
+        h.deliver(client, &initiation(CLIENT, &backend)).await.len(),
+        1
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(
+        h.deliver(backend.address, &response(BACKEND, CLIENT))
+            .await
+            .is_empty()
+    );
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.received, 2);
+    assert_eq!(snapshot.forwarded, 1);
+    assert_eq!(snapshot.dropped, 1);
+    assert_eq!(snapshot.sessions_created, 1);
+    assert_eq!(snapshot.sessions_expired, 1);
+}
+
+#[tokio::test]
+async fn sessions_without_data_expire_sooner() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.session_timeout(Duration::from_secs(60))
+            .handshake_timeout(Duration::from_millis(20))
+    });
+    let client = addr("192.0.2.1:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+    h.deliver(client, &transport(BACKEND, 0, 32)).await;
+    h.deliver(addr("192.0.2.2:40000"), &initiation(CLIENT + 1, &backend))
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let data = transport(CLIENT, 0, 32);
+    assert_eq!(
+        h.deliver(backend.address, &data).await,
+        vec![(client, data)]
+    );
+    assert!(
+        h.deliver(backend.address, &response(BACKEND + 1, CLIENT + 1))
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn active_sessions_outlive_their_first_deadline() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.session_timeout(Duration::from_millis(100))
+            .handshake_timeout(Duration::from_millis(100))
+    });
+    let client = addr("192.0.2.1:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    for counter in 0..10 {
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let data = transport(BACKEND, counter, 32);
+        assert_eq!(
+            h.deliver(client, &data).await,
+            vec![(backend.address, data)]
+        );
+        let data = transport(CLIENT, counter, 32);
+        assert_eq!(
+            h.deliver(backend.address, &data).await,
+            vec![(client, data)]
+        );
+    }
+    assert_eq!(h.sessions.count().await, 1);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(h.sessions.count().await, 0);
+}
+
+#[tokio::test]
+async fn heartbeat_runs_with_the_receive_loop() {
+    let beats = Arc::new(AtomicUsize::new(0));
+    let counter = beats.clone();
+    let _h = Harness::start_with(vec![], |r| {
+        r.heartbeat(Duration::from_millis(10), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(beats.load(Ordering::Relaxed) >= 2);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn heartbeat_runs_while_datagrams_keep_arriving() {
+    let beats = Arc::new(AtomicUsize::new(0));
+    let counter = beats.clone();
+    let h = Harness::start_with(vec![], |r| {
+        r.heartbeat(Duration::from_millis(10), move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+    });
+    // more than the router gets through while the test waits, so the socket is never drained
+    let client = addr("192.0.2.1:40000");
+    for _ in 0..500_000 {
+        h.net.push(client, &transport(CLIENT, 0, 32));
+    }
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(beats.load(Ordering::Relaxed) >= 2);
+    assert!(h.net.pending() > 0, "the router drained the socket");
+}
+
+#[tokio::test]
+async fn backends_in_maintenance_are_drained() {
+    let always = Window {
+        schedule: "* * * * *".parse().unwrap(),
+        duration: Duration::from_secs(60),
+    };
+    let drained = peer("10.0.0.1:51820", 1).with_maintenance(vec![always]);
+    let other = peer("10.0.0.2:51820", 1);
+    let h = Harness::start(vec![drained.clone(), other.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    let sent = h.deliver(client, &initiation(CLIENT, &drained)).await;
+    assert_eq!(sent[0].0, other.address);
+
+    let h = Harness::start(vec![drained.clone()]);
+    assert!(
+        h.deliver(client, &initiation(CLIENT, &drained))
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn open_schedule_rules_override_backend_settings() {
+    let always = Window {
+        schedule: "* * * * *".parse().unwrap(),
+        duration: Duration::from_secs(60),
+    };
+    let rule = Rule {
+        window: always,
+        max_sessions: None,
+        primary: Some(true),
+        weight: None,
+    };
+    let first = peer("10.0.0.1:51820", 1);
+    let cheaper = peer("10.0.0.2:51820", 1).with_schedule(vec![rule]);
+    let h = Harness::start_with(vec![first.clone(), cheaper.clone()], |r| {
+        r.timezone("Pacific/Auckland".parse().unwrap())
+    });
+    let client = addr("192.0.2.1:40000");
+
+    let sent = h.deliver(client, &initiation(CLIENT, &first)).await;
+    assert_eq!(sent[0].0, cheaper.address);
+}
+
+#[tokio::test]
+async fn unmatched_data_can_go_to_a_default_backend() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.metrics(metrics.clone())
+            .unmatched_data(UnmatchedData::Forward(backend.address))
+    });
+    let client = addr("192.0.2.1:40000");
+
+    // e.g. a tunnel the backend still holds after the router restarted
+    let data = transport(BACKEND, 7, 32);
+    assert_eq!(
+        h.deliver(client, &data).await,
+        vec![(backend.address, data)]
+    );
+    // the backend's replies can't be told apart by client
+    assert!(
+        h.deliver(backend.address, &transport(CLIENT, 7, 32))
+            .await
+            .is_empty()
+    );
+    assert_eq!(metrics.snapshot().unmatched_data, 2);
+}
+
+#[tokio::test]
+async fn counters_carry_over_a_checkpoint() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| r.metrics(metrics.clone()));
+    let client = addr("192.0.2.1:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    let checkpoint = metrics.checkpoint();
+    assert_eq!(checkpoint.backends[&backend.address].handshakes, 1);
+    assert_eq!(checkpoint.backends[&backend.address].bytes, 148 + 92);
+    let json = serde_json::to_string(&checkpoint).unwrap();
+
+    // after a restart, the counters continue where they were
+    let restarted = Metrics::default();
+    restarted.restore(&serde_json::from_str(&json).unwrap());
+    assert_eq!(restarted.checkpoint(), checkpoint);
+}
+
+#[tokio::test]
+async fn clients_are_listed_per_backend() {
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 2);
+    let h = Harness::start(vec![first.clone(), second.clone()]);
+    let client = addr("192.0.2.1:40000");
+    let other = addr("192.0.2.2:40000");
+
+    h.deliver(client, &initiation(CLIENT, &first)).await;
+    h.deliver(first.address, &response(BACKEND, CLIENT)).await;
+    h.deliver(other, &initiation(CLIENT + 1, &second)).await;
+
+    let clients = h.sessions.clients(Some(first.address)).await;
+    assert_eq!(clients.len(), 1);
+    assert_eq!(
+        (clients[0].client, clients[0].backend, clients[0].sessions),
+        (client, first.address, 1)
+    );
+    assert_eq!(h.sessions.clients(None).await.len(), 2);
+}
+
+#[tokio::test]
+async fn sessions_are_searched_and_paged() {
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 2);
+    let h = Harness::start(vec![first.clone(), second.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &first)).await;
+    h.deliver(first.address, &response(BACKEND, CLIENT)).await;
+    h.deliver(client, &initiation(CLIENT + 1, &second)).await;
+    h.deliver(addr("192.0.2.2:40000"), &initiation(CLIENT + 2, &first))
+        .await;
+
+    let query = SessionQuery {
+        client: Some(client.ip()),
+        ..Default::default()
+    };
+    let page = h.sessions.sessions(&query, 10).await;
+    let indices: Vec<_> = page.sessions.iter().map(|s| s.client_index).collect();
+    assert_eq!(
+        indices,
+        vec![Identity::from_u32(CLIENT), Identity::from_u32(CLIENT + 1)]
+    );
+    assert_eq!(
+        page.sessions[0].backend_index,
+        Some(Identity::from_u32(BACKEND))
+    );
+    assert_eq!(page.next, None);
+
+    let query = SessionQuery {
+        backend: Some(first.address),
+        ..Default::default()
+    };
+    let page = h.sessions.sessions(&query, 1).await;
+    assert_eq!(page.sessions[0].client_index, Identity::from_u32(CLIENT));
+    let query = SessionQuery {
+        after: page.next,
+        ..query
+    };
+    let page = h.sessions.sessions(&query, 1).await;
+    assert_eq!(
+        page.sessions[0].client_index,
+        Identity::from_u32(CLIENT + 2)
+    );
+    assert_eq!(page.next, None);
+}
+
+#[tokio::test]
+async fn sessions_count_their_traffic() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+    let initiation = initiation(CLIENT, &backend);
+    let response = response(BACKEND, CLIENT);
+    let data = transport(BACKEND, 0, 32);
+
+    h.deliver(client, &initiation).await;
+    h.deliver(backend.address, &response).await;
+    h.deliver(client, &data).await;
+
+    let page = h.sessions.sessions(&SessionQuery::default(), 10).await;
+    assert_eq!(page.sessions[0].packets, 3);
+    assert_eq!(
+        page.sessions[0].bytes,
+        (initiation.len() + response.len() + data.len()) as u64
+    );
+}
+
+#[tokio::test]
+async fn sessions_prefer_the_backend_answering_fastest() {
+    let slow = peer("10.0.0.1:51820", 1);
+    let fast = peer("10.0.0.2:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![slow.clone(), fast.clone()], |r| {
+        r.metrics(metrics.clone())
+            .policy(LowestLatency::new(metrics.clone()))
+    });
+    let client = addr("192.0.2.1:40000");
+
+    // both backends are measured first, in config order
+    let sent = h.deliver(client, &initiation(CLIENT, &slow)).await;
+    assert_eq!(sent[0].0, slow.address);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    h.deliver(slow.address, &response(BACKEND, CLIENT)).await;
+    let sent = h.deliver(client, &initiation(CLIENT + 1, &slow)).await;
+    assert_eq!(sent[0].0, fast.address);
+    h.deliver(fast.address, &response(BACKEND + 1, CLIENT + 1))
+        .await;
+
+    let rtts = metrics.handshake_rtts();
+    assert!(rtts[&slow.address] >= Duration::from_millis(50));
+    assert!(rtts[&fast.address] < rtts[&slow.address]);
+    let sent = h.deliver(client, &initiation(CLIENT + 2, &slow)).await;
+    assert_eq!(sent[0].0, fast.address);
+}
+
+#[tokio::test]
+async fn unanswered_initiations_count_as_handshake_loss() {
+    let answering = peer("10.0.0.1:51820", 1);
+    let silent = peer("10.0.0.2:51820", 2);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![answering.clone(), silent.clone()], |r| {
+        r.metrics(metrics.clone())
+    });
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &answering)).await;
+    h.deliver(answering.address, &response(BACKEND, CLIENT))
+        .await;
+    h.deliver(client, &initiation(CLIENT + 1, &silent)).await;
+    h.deliver(client, &initiation(CLIENT + 2, &silent)).await;
+
+    let loss = metrics.handshake_loss();
+    assert_eq!(loss[&answering.address], 0.0);
+    assert_eq!(loss[&silent.address], 1.0);
+}
+
+#[tokio::test]
+async fn sessions_go_to_the_least_loaded_backend() {
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |r| {
+        r.policy(LeastSessions)
+    });
+    let client = addr("192.0.2.1:40000");
+
+    let mut backends = Vec::new();
+    for index in 0..4 {
+        let sent = h.deliver(client, &initiation(CLIENT + index, &first)).await;
+        backends.push(sent[0].0);
+    }
+    assert_eq!(
+        backends,
+        [first.address, second.address, first.address, second.address]
+    );
+}
+
+#[tokio::test]
+async fn clients_return_to_their_backend() {
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let affinity = Arc::new(AffinityTable::new(Duration::from_secs(3600)));
+    // as restored after a restart of the router
+    affinity.restore(&[Affinity {
+        client: "2001:db8:0:100::".parse().unwrap(),
+        backend: second.address,
+        used: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    }]);
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |r| {
+        r.affinity(affinity.clone())
+    });
+
+    // clients are identified by their /56 prefix
+    let client = addr("[2001:db8:0:1ab::7]:40000");
+    let sent = h.deliver(client, &initiation(CLIENT, &first)).await;
+    assert_eq!(sent[0].0, second.address);
+
+    let other = addr("192.0.2.1:40000");
+    let sent = h.deliver(other, &initiation(CLIENT + 1, &first)).await;
+    assert_eq!(sent[0].0, first.address);
+    assert_eq!(affinity.get(other.ip()), Some(first.address));
+}
+
+#[tokio::test]
+async fn listeners_reach_their_own_peers() {
+    let staging = peer("10.1.0.1:51820", 1).with_name(Some("staging".to_string()));
+    let production = peer("10.2.0.1:51820", 1).with_name(Some("production".to_string()));
+    let internal = "10.0.0.1".parse().unwrap();
+    let horizons = vec![Horizon {
+        address: internal,
+        peers: vec!["staging".to_string()],
+    }];
+    let h = Harness::start_with(vec![staging.clone(), production.clone()], |router| {
+        router.horizons(horizons)
+    });
+
+    let init = initiation(CLIENT, &staging);
+    let sent = h.deliver_to(addr("10.0.0.7:40000"), internal, &init).await;
+    assert_eq!(sent, vec![(staging.address, init.clone())]);
+
+    let init = initiation(CLIENT + 1, &production);
+    let sent = h
+        .deliver_to(
+            addr("192.0.2.1:40000"),
+            "192.0.2.100".parse().unwrap(),
+            &init,
+        )
+        .await;
+    assert_eq!(sent, vec![(production.address, init.clone())]);
+
+    // without a local address, only the peers outside every horizon are reachable
+    let init = initiation(CLIENT + 2, &production);
+    let sent = h.deliver(addr("192.0.2.2:40000"), &init).await;
+    assert_eq!(sent, vec![(production.address, init)]);
+}
+
+#[tokio::test]
+async fn handshakes_overtake_queued_transport_data() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    let data: Vec<Vec<u8>> = (0..3)
+        .map(|counter| transport(BACKEND, counter, 32))
+        .collect();
+    for packet in &data {
+        h.net.push(client, packet);
+    }
+    let init = initiation(CLIENT + 1, &backend);
+    h.net.push(addr("192.0.2.2:40000"), &init);
+    h.net.settle().await;
+
+    let mut expected = vec![(backend.address, init)];
+    expected.extend(data.into_iter().map(|packet| (backend.address, packet)));
+    assert_eq!(h.net.take_sent(), expected);
+}
+
+#[tokio::test]
+async fn a_batch_size_of_one_keeps_the_arrival_order() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |router| router.batch_size(1));
+    let client = addr("192.0.2.1:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    let data = transport(BACKEND, 0, 32);
+    h.net.push(client, &data);
+    let init = initiation(CLIENT + 1, &backend);
+    h.net.push(addr("192.0.2.2:40000"), &init);
+    h.net.settle().await;
+
+    assert_eq!(
+        h.net.take_sent(),
+        vec![(backend.address, data), (backend.address, init)]
+    );
+}
+
+#[tokio::test]
+async fn full_batches_shed_the_oldest_data() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let backpressure = BackpressurePolicy {
+        handshake: Backpressure::Block,
+        data: Backpressure::DropOldest,
+    };
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |router| {
+        router
+            .metrics(metrics.clone())
+            .batch_size(2)
+            .backpressure(backpressure)
+    });
+    let client = addr("192.0.2.1:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    let data: Vec<Vec<u8>> = (0..4)
+        .map(|counter| transport(BACKEND, counter, 32))
+        .collect();
+    for packet in &data {
+        h.net.push(client, packet);
+    }
+    let init = initiation(CLIENT + 1, &backend);
+    h.net.push(addr("192.0.2.2:40000"), &init);
+    h.net.settle().await;
+
+    // the initiation was read beyond the full batch, which its kind waits for
+    assert_eq!(
+        h.net.take_sent(),
+        vec![
+            (backend.address, data[2].clone()),
+            (backend.address, data[3].clone()),
+            (backend.address, init),
+        ]
+    );
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.queue_dropped_data, 2);
+    assert_eq!(snapshot.queue_blocked, 1);
+}
+
+#[tokio::test]
+async fn fair_queuing_shares_batches_between_sessions() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |router| router.fair_queuing(true));
+    let bulk = addr("192.0.2.1:40000");
+    let interactive = addr("192.0.2.2:40000");
+    h.deliver(bulk, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+    h.deliver(interactive, &initiation(CLIENT + 1, &backend))
+        .await;
+    h.deliver(backend.address, &response(BACKEND + 1, CLIENT + 1))
+        .await;
+
+    let large: Vec<Vec<u8>> = (0..4)
+        .map(|counter| transport(BACKEND, counter, 1384))
+        .collect();
+    let small: Vec<Vec<u8>> = (0..2)
+        .map(|counter| transport(BACKEND + 1, counter, 32))
+        .collect();
+    for packet in &large {
+        h.net.push(bulk, packet);
+    }
+    for packet in &small {
+        h.net.push(interactive, packet);
+    }
+    h.net.settle().await;
+
+    // one large datagram per round fits the quantum, the small ones all fit the first round
+    let order = [
+        &large[0], &small[0], &small[1], &large[1], &large[2], &large[3],
+    ];
+    let expected: Vec<_> = order
+        .into_iter()
+        .map(|packet| (backend.address, packet.clone()))
+        .collect();
+    assert_eq!(h.net.take_sent(), expected);
+}
+
+#[tokio::test]
+async fn backends_failing_sends_are_avoided() {
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |router| {
+        router.metrics(metrics.clone()).health(Thresholds {
+            down_after: 2,
+            ..Default::default()
+        })
+    });
+
+    // transient errors are retried for handshakes, and don't count against the backend
+    h.net.fail_next_send(io::ErrorKind::WouldBlock);
+    let init = initiation(CLIENT, &first);
+    let sent = h.deliver(addr("192.0.2.1:40000"), &init).await;
+    assert_eq!(sent, vec![(first.address, init)]);
+
+    for client in 1..3 {
+        h.net.fail_next_send(io::ErrorKind::ConnectionRefused);
+        let init = initiation(CLIENT + client, &first);
+        let source = addr(&format!("192.0.2.{}:40000", client + 1));
+        assert!(h.deliver(source, &init).await.is_empty());
+    }
+    assert_eq!(metrics.backend_send_failures()[&first.address], 2);
+
+    let init = initiation(CLIENT + 3, &first);
+    let sent = h.deliver(addr("192.0.2.4:40000"), &init).await;
+    assert_eq!(sent, vec![(second.address, init)]);
+}
+
+#[tokio::test]
+async fn secondaries_stand_in_while_the_primary_is_down() {
+    let secondary = peer("10.0.0.1:51820", 1);
+    let primary = peer("10.0.0.2:51820", 1).with_primary(true);
+    let h = Harness::start_with(vec![secondary.clone(), primary.clone()], |router| {
+        router.health(Thresholds {
+            down_after: 1,
+            ..Default::default()
+        })
+    });
+
+    let init = initiation(CLIENT, &primary);
+    let sent = h.deliver(addr("192.0.2.1:40000"), &init).await;
+    assert_eq!(sent, vec![(primary.address, init)]);
+
+    h.net.fail_next_send(io::ErrorKind::ConnectionRefused);
+    let init = initiation(CLIENT + 1, &primary);
+    assert!(h.deliver(addr("192.0.2.2:40000"), &init).await.is_empty());
+    let init = initiation(CLIENT + 2, &primary);
+    let sent = h.deliver(addr("192.0.2.3:40000"), &init).await;
+    assert_eq!(sent, vec![(secondary.address, init)]);
+}
+
+#[tokio::test]
+async fn sessions_of_down_backends_are_routed_anew() {
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |router| {
+        router.metrics(metrics.clone()).health(Thresholds {
+            down_after: 1,
+            ..Default::default()
+        })
+    });
+    let client = addr("192.0.2.1:40000");
+    let init = initiation(CLIENT, &first);
+    assert_eq!(h.deliver(client, &init).await, vec![(first.address, init)]);
+
+    h.net.fail_next_send(io::ErrorKind::ConnectionRefused);
+    let other = initiation(CLIENT + 1, &first);
+    assert!(h.deliver(addr("192.0.2.2:40000"), &other).await.is_empty());
+    assert_eq!(metrics.snapshot().sessions_purged, 2);
+
+    // the client retransmits its initiation, which no longer sticks to the failed backend
+    let init = initiation(CLIENT, &first);
+    assert_eq!(h.deliver(client, &init).await, vec![(second.address, init)]);
+}
+
+#[tokio::test]
+async fn tunnels_rekeying_too_often_are_flagged() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |router| {
+        router.metrics(metrics.clone()).rekey_threshold(2)
+    });
+    let client = addr("192.0.2.1:40000");
+
+    // another endpoint of the same address is a tunnel of its own
+    h.deliver(addr("192.0.2.1:40001"), &initiation(CLIENT + 10, &backend))
+        .await;
+    for handshake in 0..4 {
+        h.deliver(client, &initiation(CLIENT + handshake, &backend))
+            .await;
+    }
+    assert_eq!(metrics.snapshot().excessive_rekeys, 1);
+}
+
+#[tokio::test]
+async fn initiations_for_no_peer_reach_the_honeypot() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let stranger = peer("10.0.0.1:51820", 2);
+    let honeypot = addr("10.0.0.99:51820");
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend], |router| {
+        router.metrics(metrics.clone()).honeypot(Honeypot {
+            address: honeypot,
+            sample_rate: 1.0,
+        })
+    });
+    let client = addr("192.0.2.1:40000");
+
+    let init = initiation(CLIENT, &stranger);
+    assert_eq!(h.deliver(client, &init).await, vec![(honeypot, init)]);
+    let answer = response(BACKEND, CLIENT);
+    assert_eq!(h.deliver(honeypot, &answer).await, vec![(client, answer)]);
+    assert_eq!(metrics.snapshot().honeypot_initiations, 1);
+}
+
+#[tokio::test]
+async fn sessions_are_split_by_weight() {
+    let unweighted = peer("10.0.0.1:51820", 1);
+    let stable = peer("10.0.0.2:51820", 1).with_weight(Some(1));
+    let canary = peer("10.0.0.3:51820", 1).with_weight(Some(1));
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(
+        vec![unweighted.clone(), stable.clone(), canary.clone()],
+        |router| router.metrics(metrics.clone()),
+    );
+
+    let mut arms = HashMap::new();
+    for index in 0..100 {
+        let client = addr(&format!("192.0.2.{}:40000", index + 1));
+        let sent = h
+            .deliver(client, &initiation(CLIENT + index, &stable))
+            .await;
+        *arms.entry(sent[0].0).or_insert(0) += 1;
+    }
+    assert_eq!(arms.get(&unweighted.address), None);
+    assert!(arms[&stable.address] > 0 && arms[&canary.address] > 0);
+
+    // each arm's handshakes and answers are counted on their own
+    let sent = h
+        .deliver(addr("192.0.2.1:40000"), &initiation(CLIENT + 100, &stable))
+        .await;
+    h.deliver(sent[0].0, &response(BACKEND, CLIENT + 100)).await;
+    let totals = metrics.backend_totals();
+    assert_eq!(totals[&sent[0].0].answered, 1);
+    assert_eq!(
+        totals[&stable.address].handshakes + totals[&canary.address].handshakes,
+        101
+    );
+}
+
+#[tokio::test]
+async fn received_datagrams_are_counted_by_type_and_size() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| r.metrics(metrics.clone()));
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+    h.deliver(client, &transport(BACKEND, 0, 16)).await;
+    h.deliver(client, &transport(BACKEND, 1, 1400)).await;
+    h.deliver(client, b"not wireguard").await;
+
+    let types: HashMap<MessageType, u64> = metrics.received_by_type().into_iter().collect();
+    assert_eq!(types[&MessageType::HandshakeInitiation], 1);
+    assert_eq!(types[&MessageType::HandshakeResponse], 1);
+    assert_eq!(types[&MessageType::TransportData], 2);
+    let sizes = metrics.received_sizes();
+    assert_eq!(sizes.count, 5);
+    assert_eq!(sizes.sum, 148 + 92 + 32 + 1416 + 13);
+    // the keepalive and the garbage are at most 32 bytes, the large data packet above 1280
+    assert_eq!(sizes.buckets[0], (32, 2));
+    let below_1280 = sizes.buckets.iter().find(|(bound, _)| *bound == 1280);
+    assert_eq!(below_1280, Some(&(1280, 4)));
+}
+
+#[tokio::test]
+async fn session_timelines_outlive_their_sessions() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.session_timeout(Duration::from_millis(20))
+    });
+    let client = addr("192.0.2.1:40000");
+    let roamed = addr("192.0.2.1:40001");
+
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+    h.deliver(client, &transport(BACKEND, 0, 32)).await;
+    h.deliver(roamed, &transport(BACKEND, 1, 32)).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    h.deliver(roamed, &transport(BACKEND, 2, 32)).await;
+
+    let timeline = h
+        .sessions
+        .timeline(Identity::from_u32(BACKEND))
+        .await
+        .unwrap();
+    assert_eq!(timeline.client, client);
+    assert_eq!(timeline.client_index, Identity::from_u32(CLIENT));
+    assert_eq!(timeline.backend_index, Some(Identity::from_u32(BACKEND)));
+    let events: Vec<TimelineEvent> = timeline.events.into_iter().map(|e| e.event).collect();
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[0], TimelineEvent::Initiation);
+    assert_eq!(
+        events[1],
+        TimelineEvent::Response {
+            backend_index: Identity::from_u32(BACKEND)
+        }
+    );
+    assert_eq!(
+        events[2],
+        TimelineEvent::EndpointChanged {
+            from: client,
+            to: roamed
+        }
+    );
+    assert!(matches!(events[3], TimelineEvent::Expired { .. }));
+    assert!(matches!(events[4], TimelineEvent::Dropped { .. }));
+    assert_eq!(h.sessions.timelines(client.ip()).await.len(), 1);
+}
+
+#[tokio::test]
+async fn garbage_collection_evicts_sessions_of_removed_peers() {
+    let kept = peer("10.0.0.1:51820", 1);
+    let removed = peer("10.0.0.2:51820", 2);
+    let h = Harness::start(vec![kept.clone(), removed.clone()]);
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &kept)).await;
+    h.deliver(client, &initiation(CLIENT + 1, &removed)).await;
+    h.deliver(removed.address, &response(BACKEND, CLIENT + 1))
+        .await;
+    h.peers.send(vec![kept.clone()]).unwrap();
+    h.net.settle().await;
+
+    let report = h.gc.run().await.unwrap();
+    assert_eq!(report.remaining, 1);
+    assert_eq!(report.evicted.len(), 1);
+    assert_eq!(report.evicted[0].client, client);
+    assert_eq!(report.evicted[0].backend, removed.address);
+    assert_eq!(
+        report.evicted[0].client_index,
+        Identity::from_u32(CLIENT + 1)
+    );
+    assert_eq!(report.evicted[0].reason, EvictionReason::RemovedPeer);
+    // the backend's index went with the session
+    let data = transport(BACKEND, 0, 32);
+    assert!(h.deliver(client, &data).await.is_empty());
+    assert_eq!(h.sessions.count().await, 1);
+}
+
+#[tokio::test]
+async fn lockdown_pauses_new_tunnels_only() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| r.metrics(metrics.clone()));
+    let established = addr("192.0.2.1:40000");
+    let newcomer = addr("192.0.2.2:40000");
+
+    h.deliver(established, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+    assert!(!h.lockdown.set(true));
+
+    let init = initiation(CLIENT + 1, &backend);
+    assert!(h.deliver(newcomer, &init).await.is_empty());
+    let data = transport(BACKEND, 0, 32);
+    assert_eq!(h.deliver(established, &data).await.len(), 1);
+    // the established tunnel rekeys
+    let rekey = initiation(CLIENT + 2, &backend);
+    assert_eq!(h.deliver(established, &rekey).await.len(), 1);
+    assert_eq!(metrics.snapshot().locked_out, 1);
+
+    h.lockdown.set(false);
+    assert_eq!(h.deliver(newcomer, &init).await.len(), 1);
+}
+
+#[tokio::test]
+async fn oversized_and_truncated_datagrams_are_dropped() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.metrics(metrics.clone())
+            .buffer_size(512)
+            .max_datagram_size(256)
+    });
+    let client = addr("192.0.2.1:40000");
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+
+    let data = transport(BACKEND, 0, 256 - 32);
+    assert_eq!(
+        h.deliver(client, &data).await,
+        vec![(backend.address, data)]
+    );
+    assert!(
+        h.deliver(client, &transport(BACKEND, 1, 256))
+            .await
+            .is_empty()
+    );
+    // a datagram filling the buffer exactly is read whole
+    assert!(
+        h.deliver(client, &transport(BACKEND, 2, 512 - 32))
+            .await
+            .is_empty()
+    );
+    assert!(
+        h.deliver(client, &transport(BACKEND, 3, 1024))
+            .await
+            .is_empty()
+    );
+    let snapshot = metrics.snapshot();
+    assert_eq!((snapshot.oversized, snapshot.truncated), (2, 1));
+    assert_eq!(snapshot.received, 6);
+}
+
+#[tokio::test]
+async fn denylisted_sources_are_dropped() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        r.metrics(metrics.clone())
+            .deny(vec!["198.51.100.0/24".parse().unwrap()])
+    });
+    let listed = addr("198.51.100.7:40000");
+    let client = addr("192.0.2.1:40000");
+
+    assert!(
+        h.deliver(listed, &initiation(CLIENT, &backend))
+            .await
+            .is_empty()
+    );
+    h.deliver(client, &initiation(CLIENT + 1, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT + 1))
+        .await;
+    // a feed listing the client cuts off its established session, but never a backend
+    h.denylist.set(
+        "feed:test",
+        vec!["192.0.2.0/28".parse().unwrap(), "10.0.0.1".parse().unwrap()],
+    );
+    assert_eq!(h.denylist.len(), 3);
+    let data = transport(BACKEND, 0, 32);
+    assert!(h.deliver(client, &data).await.is_empty());
+    let data = transport(CLIENT + 1, 0, 32);
+    assert_eq!(h.deliver(backend.address, &data).await.len(), 1);
+    let mapped = addr("[::ffff:192.0.2.1]:40000");
+    assert!(h.denylist.contains(mapped.ip()));
+    assert_eq!(metrics.snapshot().denied, 2);
+
+    h.denylist.set("feed:test", Vec::new());
+    let data = transport(BACKEND, 1, 32);
+    assert_eq!(h.deliver(client, &data).await.len(), 1);
+    assert_eq!(
+        h.denylist.sources(),
+        [(wireguard_router::router::CONFIG_DENYLIST.to_string(), 1)]
+    );
+    let explanation = h
+        .explainer
+        .explain(Datagram {
+            source: listed,
+            local: None,
+            packet: initiation(CLIENT + 2, &backend),
+        })
+        .await
+        .unwrap();
+    assert_eq!(explanation.decision, Decision::Drop(DropReason::Denylisted));
+}
+
+#[tokio::test]
+async fn routing_decisions_are_explained_without_routing() {
+    let full = peer("10.0.0.1:51820", 1).with_max_sessions(Some(1));
+    let spare = peer("10.0.0.2:51820", 1);
+    let h = Harness::start(vec![full.clone(), spare.clone()]);
+    let client = addr("192.0.2.1:40000");
+    let explain = |packet: Vec<u8>| Datagram {
+        source: client,
+        local: None,
+        packet,
+    };
+
+    h.deliver(client, &initiation(CLIENT, &full)).await;
+    let explanation = h
+        .explainer
+        .explain(explain(initiation(CLIENT + 1, &full)))
+        .await
+        .unwrap();
+    assert_eq!(explanation.message, Some(MessageType::HandshakeInitiation));
+    assert_eq!(explanation.session, None);
+    assert_eq!(explanation.matched.len(), 2);
+    assert_eq!(explanation.matched[0].sessions, 1);
+    assert_eq!(explanation.candidates, vec![spare.address]);
+    assert_eq!(explanation.decision, Decision::Forward(spare.address));
+    // a pubkey stands in for a captured initiation
+    let by_key = utils::initiation_to(&[1; 32], CLIENT + 2);
+    let explanation = h.explainer.explain(explain(by_key)).await.unwrap();
+    assert_eq!(explanation.decision, Decision::Forward(spare.address));
+
+    let explanation = h
+        .explainer
+        .explain(explain(initiation(CLIENT, &full)))
+        .await
+        .unwrap();
+    let hit = SessionHit {
+        index: Identity::from_u32(CLIENT),
+        client,
+        backend: full.address,
+    };
+    assert_eq!(explanation.session, Some(hit));
+    let unknown = initiation(CLIENT + 3, &peer("10.0.0.3:51820", 3));
+    let explanation = h.explainer.explain(explain(unknown)).await.unwrap();
+    assert_eq!(
+        explanation.decision,
+        Decision::Drop(DropReason::UnknownBackend)
+    );
+    assert!(h.net.take_sent().is_empty());
+    assert_eq!(h.sessions.count().await, 1);
+}
+
+#[tokio::test]
+async fn injected_datagrams_are_routed_like_received_ones() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start(vec![backend.clone()]);
+    let client = addr("192.0.2.1:40000");
+    let init = initiation(CLIENT, &backend);
+
+    let datagram = Datagram {
+        source: client,
+        local: None,
+        packet: init.clone(),
+    };
+    let events = h.injector.inject(datagram).await.unwrap();
+    assert_eq!(
+        events,
+        vec![
+            RouterEvent::SessionCreated {
+                client,
+                backend: backend.address,
+                client_index: Identity::from_u32(CLIENT),
+            },
+            RouterEvent::Forwarded {
+                message: MessageType::HandshakeInitiation,
+                source: client,
+                destination: backend.address,
+            },
+        ]
+    );
+    assert_eq!(h.net.take_sent(), vec![(backend.address, init)]);
+    assert_eq!(h.sessions.count().await, 1);
+}
+
+#[tokio::test]
+async fn raced_initiations_go_to_the_first_backend_to_respond() {
+    let slow = peer("10.0.0.1:51820", 1);
+    let fast = peer("10.0.0.2:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![slow.clone(), fast.clone()], |r| {
+        r.race_initiations(true).metrics(metrics.clone())
+    });
+    let client = addr("192.0.2.1:40000");
+
+    let init = initiation(CLIENT, &slow);
+    assert_eq!(
+        h.deliver(client, &init).await,
+        vec![(slow.address, init.clone()), (fast.address, init.clone())]
+    );
+    // until one responds, a retransmit goes to both again
+    assert_eq!(h.deliver(client, &init).await.len(), 2);
+
+    let resp = response(BACKEND, CLIENT);
+    assert_eq!(h.deliver(fast.address, &resp).await, vec![(client, resp)]);
+    assert!(
+        h.deliver(slow.address, &response(BACKEND + 1, CLIENT))
+            .await
+            .is_empty()
+    );
+    assert_eq!(metrics.snapshot().races_lost, 1);
+
+    let data = transport(BACKEND, 0, 32);
+    assert_eq!(h.deliver(client, &data).await, vec![(fast.address, data)]);
+    assert_eq!(h.deliver(client, &init).await, vec![(fast.address, init)]);
+    let timeline = h
+        .sessions
+        .timeline(Identity::from_u32(CLIENT))
+        .await
+        .unwrap();
+    assert_eq!(timeline.backend, fast.address);
+    // the session counts against the winner's limit
+    let explanation = h
+        .explainer
+        .explain(Datagram {
+            source: addr("192.0.2.2:40000"),
+            local: None,
+            packet: initiation(CLIENT + 1, &slow),
+        })
+        .await
+        .unwrap();
+    let sessions: Vec<usize> = explanation.matched.iter().map(|m| m.sessions).collect();
+    assert_eq!(sessions, vec![0, 1]);
+}
+
+#[tokio::test]
+async fn pinned_clients_stay_on_their_backend() {
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let client = addr("192.0.2.1:40000");
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![first.clone(), second.clone()], |router| {
+        router
+            .pin(client.ip(), second.address)
+            .metrics(metrics.clone())
+            .health(Thresholds {
+                down_after: 1,
+                ..Default::default()
+            })
+    });
+    let init = initiation(CLIENT, &first);
+    assert_eq!(
+        h.deliver(client, &init).await,
+        vec![(second.address, init.clone())]
+    );
+
+    // another pinned client brings the backend down, which keeps the sessions of both
+    let other = addr("192.0.2.2:40000");
+    assert_eq!(h.pins.pin(other.ip(), second.address), None);
+    h.net.fail_next_send(io::ErrorKind::ConnectionRefused);
+    assert!(
+        h.deliver(other, &initiation(CLIENT + 1, &first))
+            .await
+            .is_empty()
+    );
+    assert_eq!(metrics.snapshot().sessions_purged, 0);
+    assert_eq!(h.deliver(client, &init).await, vec![(second.address, init)]);
+
+    assert_eq!(h.pins.unpin(other.ip()), Some(second.address));
+    let init = initiation(CLIENT + 2, &first);
+    assert_eq!(h.deliver(other, &init).await, vec![(first.address, init)]);
+    assert_eq!(h.pins.list(), vec![(client.ip(), second.address)]);
+}
+
+#[tokio::test]
+async fn sessions_are_evicted_at_the_memory_limit() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let client = addr("192.0.2.1:40000");
+    let configure = |r: RouterBuilder<MockTransport>| r.timelines(0).batch_size(1);
+
+    // what the router holds empty, and per index of a session
+    let probe = Harness::start_with(vec![backend.clone()], configure);
+    let empty = probe.memory.usage().await.total();
+    probe.deliver(client, &initiation(CLIENT, &backend)).await;
+    let session = probe.memory.usage().await.total() - empty;
+
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| {
+        configure(r)
+            .metrics(metrics.clone())
+            .memory_limit(empty + 3 * session)
+    });
+    assert_eq!(h.memory.limit(), Some(empty + 3 * session));
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+    h.deliver(client, &transport(BACKEND, 0, 32)).await;
+    let unanswered = addr("192.0.2.2:40000");
+    h.deliver(unanswered, &initiation(CLIENT + 1, &backend))
+        .await;
+
+    // the session without data makes room for the new one
+    let init = initiation(CLIENT + 2, &backend);
+    assert_eq!(
+        h.deliver(addr("192.0.2.3:40000"), &init).await,
+        vec![(backend.address, init)]
+    );
+    assert_eq!(metrics.snapshot().sessions_shed, 1);
+    assert_eq!(h.memory.usage().await.sessions, 3 * session);
+    let data = transport(BACKEND, 1, 32);
+    assert_eq!(
+        h.deliver(client, &data).await,
+        vec![(backend.address, data)]
+    );
+    assert!(
+        h.deliver(backend.address, &response(BACKEND + 1, CLIENT + 1))
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn chaos_drops_duplicates_and_delays_packets() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let client = addr("192.0.2.1:40000");
+    let init = initiation(CLIENT, &backend);
+
+    let dropping = Harness::start_with(vec![backend.clone()], |r| {
+        r.chaos(Chaos {
+            drop: 1.0,
+            ..Default::default()
+        })
+    });
+    assert!(dropping.deliver(client, &init).await.is_empty());
+
+    let duplicating = Harness::start_with(vec![backend.clone()], |r| {
+        r.chaos(Chaos {
+            duplicate: 1.0,
+            ..Default::default()
+        })
+    });
+    assert_eq!(
+        duplicating.deliver(client, &init).await,
+        vec![
+            (backend.address, init.clone()),
+            (backend.address, init.clone())
+        ]
+    );
+
+    let delaying = Harness::start_with(vec![backend.clone()], |r| {
+        r.chaos(Chaos {
+            latency_ms: 50,
+            ..Default::default()
+        })
+    });
+    assert!(delaying.deliver(client, &init).await.is_empty());
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(delaying.net.take_sent(), vec![(backend.address, init)]);
+}
+
+#[tokio::test]
+async fn client_rates_are_only_kept_when_tracked() {
+    let backend = peer("10.0.0.1:51820", 1).with_name(Some("vpn".to_string()));
+    let metrics = Arc::new(Metrics::default());
+    let h = Harness::start_with(vec![backend.clone()], |r| r.metrics(metrics.clone()));
+    let client = addr("192.0.2.1:40000");
+
+    h.deliver(client, &initiation(CLIENT, &backend)).await;
+    assert!(metrics.client_rates().is_empty());
+    assert_eq!(metrics.peer_names()[&backend.address], "vpn");
+
+    metrics.track_clients();
+    h.deliver(backend.address, &response(BACKEND, CLIENT)).await;
+    let rates = metrics.client_rates();
+    assert_eq!(rates.keys().collect::<Vec<_>>(), vec![&client.ip()]);
+    assert!(rates[&client.ip()].packets_per_sec > 0.0);
+}
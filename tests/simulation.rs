@@ -0,0 +1,189 @@
+#![cfg(feature = "runtime")]
+
+//! Deterministic simulations of clients, routers and backends on a turmoil network with simulated
+//! time, lossy links and a randomized schedule, checking that no packet ever reaches a backend
+//! it isn't meant for
+
+mod common;
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use common::*;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use turmoil::net::UdpSocket;
+use wireguard_router::Peer;
+use wireguard_router::router::Router;
+use wireguard_router::transport::PacketTransport;
+use wireguard_router::utils;
+
+const PORT: u16 = 51820;
+const ROUTERS: usize = 2;
+const BACKENDS: usize = 3;
+const CLIENTS: usize = 8;
+/// handshakes each client completes, a rekey after the first
+const HANDSHAKES: u32 = 3;
+const DATA_PER_HANDSHAKE: u64 = 5;
+/// schedules explored, a failing one is replayed by its seed
+const SEEDS: u64 = 16;
+
+/// A router's socket on the simulated network
+struct SimSocket(UdpSocket);
+
+impl PacketTransport for SimSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.0.send_to(buf, target).await
+    }
+}
+
+fn backend_peer(backend: usize) -> Peer {
+    let address = SocketAddr::new(turmoil::lookup(format!("backend-{backend}")), PORT);
+    Peer::new(address, [backend as u8 + 1; 32])
+}
+
+async fn router() -> turmoil::Result {
+    let peers = (0..BACKENDS).map(backend_peer).collect();
+    // kept alive for as long as the router runs
+    let (_peers_tx, peers_rx) = watch::channel(peers);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).await?;
+    Router::builder(SimSocket(socket))
+        .build()
+        .run(peers_rx)
+        .await?;
+    Ok(())
+}
+
+/// Answers initiations and echoes transport data, failing the simulation on any packet that
+/// belongs to another backend
+async fn backend(backend: usize) -> turmoil::Result {
+    let own = backend_peer(backend);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).await?;
+    // our receiver index of each session, to the client's
+    let mut sessions = HashMap::new();
+    let mut buf = [0; 1500];
+    loop {
+        let (size, router) = socket.recv_from(&mut buf).await?;
+        let packet = &buf[..size];
+        let index = u32::from_le_bytes(packet[4..8].try_into().unwrap());
+        match packet[0] {
+            0x01 => {
+                let mac1 = utils::mac(&own.precomputed_hash_label_mac1, &packet[..116]);
+                if mac1[..] != packet[116..132] {
+                    return Err(format!("backend-{backend} got an initiation for another").into());
+                }
+                let ours = (backend as u32 + 1) << 24 | sessions.len() as u32;
+                sessions.insert(ours, index);
+                socket.send_to(&response(ours, index), router).await?;
+            }
+            0x04 => {
+                let Some(&client) = sessions.get(&index) else {
+                    return Err(format!("backend-{backend} got data of session {index:#x}").into());
+                };
+                let counter = u64::from_le_bytes(packet[8..16].try_into().unwrap());
+                socket
+                    .send_to(&transport(client, counter, 32), router)
+                    .await?;
+            }
+            kind => return Err(format!("backend-{backend} got a packet of type {kind}").into()),
+        }
+    }
+}
+
+/// Completes its handshakes with one backend through one router, retrying lost ones, and
+/// checks everything it gets back belongs to one of its sessions
+async fn client(client: usize) -> turmoil::Result {
+    let target = backend_peer(client % BACKENDS);
+    let router = SocketAddr::new(
+        turmoil::lookup(format!("router-{}", client % ROUTERS)),
+        PORT,
+    );
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 40000)).await?;
+    let mut ours = HashSet::new();
+    let mut buf = [0; 1500];
+
+    for handshake in 0..HANDSHAKES {
+        let sender = (client as u32 + 1) << 16 | handshake;
+        ours.insert(sender);
+        let backend = 'handshake: loop {
+            socket.send_to(&initiation(sender, &target), router).await?;
+            let retry = tokio::time::sleep(Duration::from_millis(200));
+            tokio::pin!(retry);
+            loop {
+                let (size, _) = tokio::select! {
+                    received = socket.recv_from(&mut buf) => received?,
+                    _ = &mut retry => continue 'handshake,
+                };
+                let receiver = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+                check(client, &ours, &buf[..size])?;
+                if buf[0] == 0x02 && receiver == sender {
+                    break 'handshake u32::from_le_bytes(buf[4..8].try_into().unwrap());
+                }
+            }
+        };
+
+        for counter in 0..DATA_PER_HANDSHAKE {
+            socket
+                .send_to(&transport(backend, counter, 32), router)
+                .await?;
+            // lost packets are not retransmitted, as by a tunnel
+            if let Ok(received) =
+                timeout(Duration::from_millis(100), socket.recv_from(&mut buf)).await
+            {
+                let (size, _) = received?;
+                check(client, &ours, &buf[..size])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails unless `packet` is addressed to one of the sessions `ours`
+fn check(client: usize, ours: &HashSet<u32>, packet: &[u8]) -> turmoil::Result {
+    let receiver = match packet[0] {
+        0x02 => &packet[8..12],
+        0x04 => &packet[4..8],
+        kind => return Err(format!("client-{client} got a packet of type {kind}").into()),
+    };
+    let receiver = u32::from_le_bytes(receiver.try_into().unwrap());
+    match ours.contains(&receiver) {
+        true => Ok(()),
+        false => Err(format!("client-{client} got a packet for session {receiver:#x}").into()),
+    }
+}
+
+#[test]
+fn sessions_are_never_routed_to_another_backend() {
+    for seed in 0..SEEDS {
+        let mut sim = turmoil::Builder::new()
+            .rng_seed(seed)
+            .enable_random_order()
+            .min_message_latency(Duration::from_millis(1))
+            .max_message_latency(Duration::from_millis(20))
+            // links are down about 5% of the time, for 5ms on average
+            .fail_rate(0.01)
+            .repair_rate(0.2)
+            .simulation_duration(Duration::from_secs(60))
+            .build();
+
+        for r in 0..ROUTERS {
+            sim.host(format!("router-{r}"), router);
+        }
+        for b in 0..BACKENDS {
+            sim.host(format!("backend-{b}"), move || backend(b));
+        }
+        for c in 0..CLIENTS {
+            sim.client(format!("client-{c}"), client(c));
+        }
+
+        if let Err(e) = sim.run() {
+            panic!("seed {seed}: {e}");
+        }
+    }
+}
@@ -0,0 +1,188 @@
+#![cfg(feature = "snmp")]
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::*;
+use wireguard_router::health::{Health, Thresholds};
+use wireguard_router::metrics::Metrics;
+use wireguard_router::snmp::{self, Message, Mib, Pdu, PduType, Sources, Value};
+
+const BASE: [u32; 7] = [1, 3, 6, 1, 4, 1, 99999];
+
+fn request(community: &str, kind: PduType, oids: &[&[u32]]) -> Vec<u8> {
+    request_bulk(community, kind, oids, 0, 0)
+}
+
+fn request_bulk(
+    community: &str,
+    kind: PduType,
+    oids: &[&[u32]],
+    non_repeaters: i64,
+    max_repetitions: i64,
+) -> Vec<u8> {
+    Message {
+        community: community.as_bytes().to_vec(),
+        pdu: Pdu {
+            kind,
+            request_id: 42,
+            error_status: non_repeaters,
+            error_index: max_repetitions,
+            bindings: oids.iter().map(|oid| (oid.to_vec(), Value::Null)).collect(),
+        },
+    }
+    .encode()
+}
+
+fn answer(request: &[u8], mib: &Mib) -> Pdu {
+    let response = snmp::respond(request, "public", mib).expect("a response");
+    let response = Message::decode(&response).expect("a valid response");
+    assert_eq!(response.pdu.kind, PduType::Response);
+    assert_eq!(response.pdu.request_id, 42);
+    response.pdu
+}
+
+fn mib() -> Mib {
+    let mut mib = Mib::default();
+    mib.insert(
+        vec![1, 3, 6, 1, 2, 1, 1, 1, 0],
+        Value::OctetString(b"router".to_vec()),
+    );
+    mib.insert(vec![1, 3, 6, 1, 4, 1, 99999, 1, 1, 0], Value::Gauge32(7));
+    mib.insert(
+        vec![1, 3, 6, 1, 4, 1, 99999, 1, 2, 0],
+        Value::Counter64(u64::MAX),
+    );
+    mib
+}
+
+#[test]
+fn messages_round_trip() {
+    let message = Message {
+        community: b"public".to_vec(),
+        pdu: Pdu {
+            kind: PduType::Response,
+            request_id: -300,
+            error_status: 0,
+            error_index: 0,
+            bindings: vec![
+                (vec![1, 3, 6, 1, 4, 1, 200_000, 0], Value::Integer(-1)),
+                (vec![2, 999, 3], Value::ObjectId(BASE.to_vec())),
+                (vec![1, 3], Value::OctetString(vec![b'x'; 300])),
+                (vec![1, 3, 1], Value::Counter64(u64::MAX)),
+                (vec![1, 3, 2], Value::Gauge32(0x8000_0000)),
+                (vec![1, 3, 3], Value::EndOfMibView),
+            ],
+        },
+    };
+    assert_eq!(Message::decode(&message.encode()), Some(message));
+}
+
+#[test]
+fn objects_are_walked_in_order() {
+    let mib = mib();
+    let pdu = answer(
+        &request(
+            "public",
+            PduType::Get,
+            &[&[1, 3, 6, 1, 4, 1, 99999, 1, 1, 0], &[1, 3]],
+        ),
+        &mib,
+    );
+    assert_eq!(pdu.bindings[0].1, Value::Gauge32(7));
+    assert_eq!(pdu.bindings[1].1, Value::NoSuchObject);
+
+    let mut oid = vec![1, 3];
+    let mut walked = Vec::new();
+    loop {
+        let pdu = answer(&request("public", PduType::GetNext, &[&oid]), &mib);
+        let (next, value) = pdu.bindings[0].clone();
+        if value == Value::EndOfMibView {
+            break;
+        }
+        assert!(next > oid);
+        walked.push(value);
+        oid = next;
+    }
+    assert_eq!(walked.len(), 3);
+
+    let pdu = answer(
+        &request_bulk("public", PduType::GetBulk, &[&[1, 3], &[1, 3]], 1, 10),
+        &mib,
+    );
+    // the non-repeater once, then the repetitions up to the end of the MIB
+    let values: Vec<_> = pdu
+        .bindings
+        .iter()
+        .map(|(_, value)| value.clone())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Value::OctetString(b"router".to_vec()),
+            Value::OctetString(b"router".to_vec()),
+            Value::Gauge32(7),
+            Value::Counter64(u64::MAX),
+            Value::EndOfMibView,
+        ]
+    );
+}
+
+#[test]
+fn other_communities_and_sets_are_refused() {
+    let mib = mib();
+    let oid: &[u32] = &[1, 3, 6, 1, 4, 1, 99999, 1, 1, 0];
+    assert_eq!(
+        snmp::respond(&request("private", PduType::Get, &[oid]), "public", &mib),
+        None
+    );
+    assert_eq!(snmp::respond(b"\x30\x03\x02\x01", "public", &mib), None);
+
+    let pdu = answer(&request("public", PduType::Set, &[oid]), &mib);
+    assert_eq!((pdu.error_status, pdu.error_index), (17, 1));
+}
+
+#[test]
+fn oids_are_checked() {
+    assert_eq!(snmp::parse_oid(".1.3.6.1.4.1.99999").unwrap(), BASE);
+    assert!(snmp::parse_oid("1.3.x").is_err());
+    assert!(snmp::parse_oid("3.1").is_err());
+}
+
+#[tokio::test]
+async fn backends_are_reported_with_their_sessions() {
+    let backend = peer("10.0.0.1:51820", 1);
+    let metrics = Arc::new(Metrics::default());
+    let harness = Harness::start_with(vec![backend.clone()], |router| {
+        router.metrics(metrics.clone())
+    });
+    let sent = harness
+        .deliver(addr("192.0.2.1:40000"), &initiation(1, &backend))
+        .await;
+    assert_eq!(sent.len(), 1);
+
+    let sources = Sources {
+        metrics,
+        sessions: harness.sessions.clone(),
+        health: Arc::new(Health::new(Thresholds::default())),
+        peers: harness.peers.subscribe(),
+    };
+    let mib = Mib::collect(&BASE, &sources, Duration::from_secs(5)).await;
+    let object = |suffix: &[u32]| mib.get(&[&BASE[..], suffix].concat()).cloned();
+    assert_eq!(object(&[1, 1, 0]), Some(Value::Gauge32(1)));
+    assert_eq!(object(&[1, 6, 0]), Some(Value::Gauge32(1)));
+    assert_eq!(
+        object(&[2, 1, 2, 1]),
+        Some(Value::OctetString(b"10.0.0.1:51820".to_vec()))
+    );
+    // up, with the session and its initiation
+    assert_eq!(object(&[2, 1, 4, 1]), Some(Value::Integer(1)));
+    assert_eq!(object(&[2, 1, 5, 1]), Some(Value::Gauge32(1)));
+    assert_eq!(object(&[2, 1, 6, 1]), Some(Value::Counter64(1)));
+    assert_eq!(
+        mib.get(&[1, 3, 6, 1, 2, 1, 1, 3, 0]),
+        Some(&Value::TimeTicks(500))
+    );
+}
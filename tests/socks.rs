@@ -0,0 +1,78 @@
+#![cfg(feature = "runtime")]
+
+mod common;
+
+use std::io;
+use std::time::Duration;
+
+use common::*;
+use tokio::net::TcpListener;
+use wireguard_router::socks::{self, Association};
+
+#[test]
+fn datagrams_round_trip_through_the_header() {
+    for dst in [addr("192.0.2.10:51820"), addr("[2001:db8::10]:51820")] {
+        let wrapped = socks::wrap(dst, b"payload");
+        let (source, offset) = socks::unwrap(&wrapped).unwrap();
+        assert_eq!(source, dst);
+        assert_eq!(&wrapped[offset..], b"payload");
+    }
+}
+
+#[test]
+fn ipv4_mapped_sources_are_canonicalized() {
+    let wrapped = socks::wrap(addr("[::ffff:192.0.2.10]:51820"), b"payload");
+    assert_eq!(socks::unwrap(&wrapped).unwrap().0, addr("192.0.2.10:51820"));
+}
+
+#[test]
+fn invalid_headers_are_rejected() {
+    let v4 = socks::wrap(addr("192.0.2.10:51820"), b"");
+    let v6 = socks::wrap(addr("[2001:db8::10]:51820"), b"");
+    let mut fragmented = v4.clone();
+    fragmented[2] = 1;
+    let domain = [
+        0, 0, 0, 3, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0, 80,
+    ];
+    let unknown = [0, 0, 0, 9, 0, 0];
+
+    for data in [
+        &v4[..3],
+        &v4[..v4.len() - 1],
+        &v6[..v6.len() - 1],
+        &fragmented,
+        &domain,
+        &unknown,
+    ] {
+        let err = socks::unwrap(data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{data:?}");
+    }
+}
+
+#[tokio::test]
+async fn silent_proxies_time_out() {
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let err = Association::connect(proxy.local_addr().unwrap(), Duration::from_millis(100))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn silent_proxies_do_not_hold_up_routing() {
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut proxied = peer("192.0.2.1:51820", 1);
+    proxied.proxy = Some(proxy.local_addr().unwrap());
+    let direct = peer("192.0.2.2:51820", 2);
+    let harness = Harness::start(vec![proxied, direct.clone()]);
+
+    let sent = tokio::time::timeout(
+        Duration::from_secs(1),
+        harness.deliver(addr("198.51.100.1:40000"), &initiation(1, &direct)),
+    )
+    .await
+    .expect("routing waited for the proxy");
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].0, direct.address);
+}
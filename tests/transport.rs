@@ -0,0 +1,171 @@
+#![cfg(feature = "runtime")]
+
+use std::io;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use wireguard_router::transport::{self, Egress, Listeners, PacketTransport, SourcePort};
+
+#[tokio::test]
+async fn connected_backends_report_their_errors() {
+    let listeners = Listeners::bind(&["127.0.0.1:0".parse().unwrap()])
+        .unwrap()
+        .connect_backends();
+    let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = backend.local_addr().unwrap();
+    let client = "192.0.2.1:40000".parse().unwrap();
+
+    listeners
+        .send_to_backend(b"ping", address, client)
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let (size, router) = backend.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..size], b"ping");
+    assert_ne!(router, listeners.v4.as_ref().unwrap().local_addr().unwrap());
+
+    backend.send_to(b"pong", router).await.unwrap();
+    let (size, source, _) = listeners.recv_from_to(&mut buf).await.unwrap();
+    assert_eq!((&buf[..size], source), (&b"pong"[..], address));
+
+    // the port unreachable for this send fails the next one
+    drop(backend);
+    listeners
+        .send_to_backend(b"ping", address, client)
+        .await
+        .unwrap();
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if let Err(e) = listeners.send_to_backend(b"ping", address, client).await {
+            assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+            return;
+        }
+    }
+    panic!("the port unreachable was not reported");
+}
+
+#[tokio::test]
+async fn sessions_can_send_from_ports_of_their_own() {
+    let listeners = Listeners::bind(&["127.0.0.1:0".parse().unwrap()])
+        .unwrap()
+        .source_port(SourcePort::PerSession, Duration::from_secs(180))
+        .unwrap();
+    let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = backend.local_addr().unwrap();
+    let clients = [
+        "192.0.2.1:40000".parse().unwrap(),
+        "192.0.2.2:40000".parse().unwrap(),
+    ];
+
+    let mut buf = [0; 16];
+    let mut ports = Vec::new();
+    for client in clients.into_iter().chain(clients) {
+        listeners
+            .send_to_backend(b"ping", address, client)
+            .await
+            .unwrap();
+        ports.push(backend.recv_from(&mut buf).await.unwrap().1.port());
+    }
+    assert_ne!(ports[0], ports[1]);
+    assert_eq!(ports[0..2], ports[2..4]);
+}
+
+#[tokio::test]
+async fn sessions_beyond_the_socket_limit_share_the_listeners_port() {
+    let listeners = Listeners::bind(&["127.0.0.1:0".parse().unwrap()])
+        .unwrap()
+        .source_port(SourcePort::PerSession, Duration::from_millis(200))
+        .unwrap()
+        .max_session_sockets(1);
+    let shared = listeners.v4.as_ref().unwrap().local_addr().unwrap().port();
+    let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = backend.local_addr().unwrap();
+    let clients: [std::net::SocketAddr; 3] = [
+        "192.0.2.1:40000".parse().unwrap(),
+        "192.0.2.2:40000".parse().unwrap(),
+        "192.0.2.3:40000".parse().unwrap(),
+    ];
+
+    let mut buf = [0; 16];
+    let mut send = async |client| {
+        listeners
+            .send_to_backend(b"ping", address, client)
+            .await
+            .unwrap();
+        backend.recv_from(&mut buf).await.unwrap().1.port()
+    };
+    assert_ne!(send(clients[0]).await, shared);
+    assert_eq!(send(clients[1]).await, shared);
+
+    // the idle socket is closed by the next sweep, making room for another session
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_ne!(send(clients[2]).await, shared);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn sockets_bound_to_a_device_send_through_it() {
+    let listeners = Listeners::bind_to_device(&["127.0.0.1:0".parse().unwrap()], "lo")
+        .unwrap()
+        .connect_backends();
+    let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = backend.local_addr().unwrap();
+    listeners
+        .send_to_backend(b"ping", address, "192.0.2.1:40000".parse().unwrap())
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let (size, _) = backend.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..size], b"ping");
+
+    assert!(Listeners::bind_to_device(&["127.0.0.1:0".parse().unwrap()], "missing0").is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn backends_can_be_sent_to_from_an_address_of_their_own() {
+    let listeners = Listeners::bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+    let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = backend.local_addr().unwrap();
+    let client = "192.0.2.1:40000".parse().unwrap();
+    let egress = Egress {
+        interface: Some("lo".to_string()),
+        source: Some("127.0.0.2".parse().unwrap()),
+        ..Egress::default()
+    };
+    listeners.set_backend_egress([(address, egress)].into());
+
+    listeners
+        .send_to_backend(b"ping", address, client)
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let (size, router) = backend.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..size], b"ping");
+    assert_eq!(router.ip().to_string(), "127.0.0.2");
+
+    backend.send_to(b"pong", router).await.unwrap();
+    let (size, source, _) = listeners.recv_from_to(&mut buf).await.unwrap();
+    assert_eq!((&buf[..size], source), (&b"pong"[..], address));
+
+    // other backends are still sent to from the listeners
+    listeners.set_backend_egress([].into());
+    listeners
+        .send_to_backend(b"ping", address, client)
+        .await
+        .unwrap();
+    let (_, router) = backend.recv_from(&mut buf).await.unwrap();
+    assert_eq!(router, listeners.v4.as_ref().unwrap().local_addr().unwrap());
+}
+
+#[test]
+fn hosts_without_ipv6_are_recognized_by_the_bind_error() {
+    assert!(transport::ipv6_unavailable(&io::Error::from(
+        io::ErrorKind::AddrNotAvailable
+    )));
+    if std::net::UdpSocket::bind("[::]:0").is_ok() {
+        assert!(!transport::ipv6_unavailable(&io::Error::from(
+            io::ErrorKind::AddrInUse
+        )));
+    }
+}
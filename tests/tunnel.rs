@@ -0,0 +1,85 @@
+#![cfg(feature = "tunnel")]
+
+use std::net::SocketAddr;
+
+use base64::Engine;
+use boringtun::noise::{Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use tokio::net::UdpSocket;
+use wireguard_router::transport::{Egress, Listeners, PacketTransport};
+use wireguard_router::tunnel::{self, Config, Tunnel};
+
+fn encode(key: &[u8; 32]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key)
+}
+
+#[tokio::test]
+async fn backends_are_reached_through_the_tunnel() {
+    let router_key = StaticSecret::from([1; 32]);
+    let far_key = StaticSecret::from([2; 32]);
+    let far = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let path = std::env::temp_dir().join(format!("wg-router-tunnel-{}.key", std::process::id()));
+    std::fs::write(&path, encode(&router_key.to_bytes())).unwrap();
+    let config = Config {
+        name: "overlay".to_string(),
+        private_key_file: path.clone(),
+        public_key: encode(PublicKey::from(&far_key).as_bytes()),
+        preshared_key_file: None,
+        endpoint: far.local_addr().unwrap(),
+        address: "10.8.0.1".parse().unwrap(),
+        port: 51820,
+        keepalive_secs: None,
+    };
+    let opened = Tunnel::open(&config).await;
+    std::fs::remove_file(&path).unwrap();
+    let listeners = Listeners::bind(&["127.0.0.1:0".parse().unwrap()])
+        .unwrap()
+        .tunnels(vec![opened.unwrap()]);
+    let backend: SocketAddr = "10.8.0.2:51820".parse().unwrap();
+    let router_address: SocketAddr = "10.8.0.1:51820".parse().unwrap();
+    let egress = Egress {
+        tunnel: Some("overlay".to_string()),
+        ..Egress::default()
+    };
+    listeners.set_backend_egress([(backend, egress)].into());
+
+    listeners
+        .send_to_backend(b"ping", backend, "192.0.2.1:40000".parse().unwrap())
+        .await
+        .unwrap();
+
+    // the far end answers the handshake, then receives the datagram queued meanwhile
+    let mut far_tunn = Tunn::new(far_key, PublicKey::from(&router_key), None, None, 1, None);
+    let mut datagram = [0; 2048];
+    let mut buf = [0; 2048];
+    let (size, router) = far.recv_from(&mut datagram).await.unwrap();
+    match far_tunn.decapsulate(Some(router.ip()), &datagram[..size], &mut buf) {
+        TunnResult::WriteToNetwork(response) => far.send_to(response, router).await.unwrap(),
+        _ => panic!("no handshake response"),
+    };
+    let packet = loop {
+        let (size, _) = far.recv_from(&mut datagram).await.unwrap();
+        match far_tunn.decapsulate(Some(router.ip()), &datagram[..size], &mut buf) {
+            TunnResult::WriteToTunnelV4(packet, source) => {
+                assert_eq!(source.to_string(), "10.8.0.1");
+                break packet.to_vec();
+            }
+            // the keepalive confirming the handshake
+            TunnResult::Done => continue,
+            _ => panic!("unexpected datagram"),
+        }
+    };
+    assert_eq!(
+        packet,
+        tunnel::udp_packet(router_address, backend, b"ping").unwrap()
+    );
+
+    let answer = tunnel::udp_packet(backend, router_address, b"pong").unwrap();
+    match far_tunn.encapsulate(&answer, &mut buf) {
+        TunnResult::WriteToNetwork(datagram) => far.send_to(datagram, router).await.unwrap(),
+        _ => panic!("the session is not established"),
+    };
+    let mut received = [0; 16];
+    let (size, source, _) = listeners.recv_from_to(&mut received).await.unwrap();
+    assert_eq!((&received[..size], source), (&b"pong"[..], backend));
+}
@@ -0,0 +1,86 @@
+#![cfg(all(feature = "wasm-plugin", feature = "runtime"))]
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use common::*;
+use wireguard_router::policy::wasm::WasmPolicy;
+
+fn load(wat: &str) -> WasmPolicy {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "wg-router-policy-{}-{}.wat",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, wat).unwrap();
+    let policy = WasmPolicy::load(&path, Duration::from_millis(50)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    policy
+}
+
+#[tokio::test]
+async fn module_selects_backend_by_port() {
+    // picks the candidate listening on port 51821
+    let policy = load(
+        r#"(module
+            (import "wg_router" "candidate_port" (func $port (param i32) (result i32)))
+            (func (export "select") (param i64 i64 i32 i32 i32) (result i32)
+                (local $i i32)
+                (block $done
+                    (loop $next
+                        (br_if $done (i32.ge_s (local.get $i) (local.get 4)))
+                        (if (i32.eq (call $port (local.get $i)) (i32.const 51821))
+                            (then (return (local.get $i))))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br $next)))
+                (i32.const -1)))"#,
+    );
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51821", 1);
+    let h = Harness::start_with(vec![first, second.clone()], |r| r.policy(policy));
+
+    let init = initiation(1, &second);
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &init).await,
+        vec![(second.address, init)]
+    );
+}
+
+#[tokio::test]
+async fn module_drops_transport_data() {
+    let policy = load(
+        r#"(module
+            (func (export "check_forward")
+                (param i32 i64 i64 i32 i64 i64 i32 i32 i32) (result i32)
+                (i32.eq (local.get 0) (i32.const 4))))"#,
+    );
+    let backend = peer("10.0.0.1:51820", 1);
+    let h = Harness::start_with(vec![backend.clone()], |r| r.policy(policy));
+    let client = addr("192.0.2.1:40000");
+
+    assert_eq!(h.deliver(client, &initiation(1, &backend)).await.len(), 1);
+    assert_eq!(h.deliver(backend.address, &response(2, 1)).await.len(), 1);
+    assert!(h.deliver(client, &transport(2, 0, 16)).await.is_empty());
+}
+
+#[tokio::test]
+async fn runaway_module_falls_back_to_first_match() {
+    let policy = load(
+        r#"(module
+            (func (export "select") (param i64 i64 i32 i32 i32) (result i32)
+                (loop $forever (br $forever))
+                (i32.const 1)))"#,
+    );
+    let first = peer("10.0.0.1:51820", 1);
+    let second = peer("10.0.0.2:51820", 1);
+    let h = Harness::start_with(vec![first.clone(), second], |r| r.policy(policy));
+
+    let init = initiation(1, &first);
+    assert_eq!(
+        h.deliver(addr("192.0.2.1:40000"), &init).await,
+        vec![(first.address, init)]
+    );
+}